@@ -1,56 +1,588 @@
-use regex::Regex;
+use ahash::AHashMap;
 
+/// One resolved bibliography entry: string macros expanded and `crossref` fields inherited.
 #[derive(Debug, Clone, PartialEq)]
 pub struct BibEntry {
     pub key: String,
     pub entry_type: String,
-    pub author: Option<String>,
-    pub title: Option<String>,
-    pub year: Option<String>,
-    pub journal: Option<String>,
+    /// Every field in file order, names lowercased
+    pub fields: Vec<(String, String)>,
+    /// 1-based line of the key
+    pub line: usize,
+    /// Byte offset of the key within its line
+    pub column: usize,
+}
+
+impl BibEntry {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// A field with its TeX grouping braces removed, for display.
+    pub fn plain(&self, name: &str) -> Option<String> {
+        self.field(name).map(|v| v.chars().filter(|&c| c != '{' && c != '}').collect())
+    }
+
+    /// Where the work appeared: the journal, the proceedings or the publisher.
+    pub fn venue(&self) -> Option<String> {
+        ["journal", "journaltitle", "booktitle", "publisher", "school", "institution"].iter().find_map(|name| self.plain(name))
+    }
+}
+
+/// A piece of a field value; pieces are joined with `#`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValuePart {
+    Braced(String),
+    Quoted(String),
+    Number(String),
+    Macro(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub value: Vec<ValuePart>,
+    /// The value with string macros expanded and whitespace collapsed
+    pub expanded: String,
+    /// The comma and whitespace before the field
+    leading: String,
+    /// The field exactly as written, from its name to the end of its value
+    raw: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEntry {
+    /// As written, e.g. `Article`
+    pub entry_type: String,
+    pub key: String,
+    pub fields: Vec<Field>,
+    pub line: usize,
+    pub column: usize,
+    /// From `@` through the key
+    head: String,
+    /// From the end of the last field through the closing delimiter
+    tail: String,
+}
+
+/// Everything in a `.bib` file, in order, so that writing it back reproduces the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BibItem {
+    Entry(RawEntry),
+    String { name: String, value: String, raw: String },
+    Preamble { value: String, raw: String },
+    Comment(String),
+    /// Text between entries (which BibTeX ignores), and anything that failed to parse
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BibError {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BibFile {
+    pub items: Vec<BibItem>,
+    pub errors: Vec<BibError>,
+}
+
+#[allow(dead_code)]
+impl BibFile {
+    /// Write the file back out.
+    pub fn write(&self) -> String {
+        let mut out = String::new();
+        for item in &self.items {
+            match item {
+                BibItem::Entry(entry) => {
+                    out.push_str(&entry.head);
+                    for field in &entry.fields {
+                        out.push_str(&field.leading);
+                        out.push_str(&field.raw);
+                    }
+                    out.push_str(&entry.tail);
+                }
+                BibItem::String { raw, .. } | BibItem::Preamble { raw, .. } | BibItem::Comment(raw) | BibItem::Text(raw) => out.push_str(raw),
+            }
+        }
+        out
+    }
+
+    /// The entries with `crossref` resolved: a child inherits every field it lacks from its parent,
+    /// and the parent's title becomes its `booktitle`.
+    pub fn entries(&self) -> Vec<BibEntry> {
+        let mut entries: Vec<BibEntry> = self.items.iter().filter_map(|item| match item {
+            BibItem::Entry(raw) => Some(BibEntry {
+                key: raw.key.clone(),
+                entry_type: raw.entry_type.to_lowercase(),
+                fields: raw.fields.iter().map(|f| (f.name.to_lowercase(), f.expanded.clone())).collect(),
+                line: raw.line,
+                column: raw.column,
+            }),
+            _ => None,
+        }).collect();
+
+        let by_key: AHashMap<String, usize> = entries.iter().enumerate().map(|(i, e)| (e.key.to_lowercase(), i)).collect();
+        for i in 0..entries.len() {
+            let Some(&parent) = entries[i].field("crossref").and_then(|k| by_key.get(&k.to_lowercase())) else {
+                continue;
+            };
+            if parent == i {
+                continue;
+            }
+            let inherited: Vec<(String, String)> = entries[parent].fields.iter()
+                .filter(|(name, _)| name != "crossref")
+                .flat_map(|(name, value)| {
+                    let as_booktitle = (name == "title").then(|| ("booktitle".to_string(), value.clone()));
+                    std::iter::once((name.clone(), value.clone())).chain(as_booktitle)
+                })
+                .collect();
+            for (name, value) in inherited {
+                if entries[i].field(&name).is_none() {
+                    entries[i].fields.push((name, value));
+                }
+            }
+        }
+        entries
+    }
 }
 
 pub struct BibParser;
 
 impl BibParser {
+    /// Parse the entries of a `.bib` file, skipping anything malformed.
     pub fn parse(content: &str) -> Vec<BibEntry> {
-        let mut entries = Vec::new();
-        // Regex to match BibTeX entries: @type{key, fields...}
-        let entry_re = Regex::new(r"@(\w+)\s*\{\s*([^,]+),([\s\S]*?)\n\}").unwrap();
-        let field_re = Regex::new(r"(\w+)\s*=\s*(?:\{([\s\S]*?)\}|([^{},\s][^,]*))").unwrap();
-
-        for cap in entry_re.captures_iter(content) {
-            let entry_type = cap[1].to_lowercase();
-            let key = cap[2].trim().to_string();
-            let fields_content = &cap[3];
-
-            let mut author = None;
-            let mut title = None;
-            let mut year = None;
-            let mut journal = None;
-
-            for f_cap in field_re.captures_iter(fields_content) {
-                let name = f_cap[1].to_lowercase();
-                let value = f_cap.get(2).or(f_cap.get(3)).map(|m| m.as_str().trim().to_string());
-
-                match name.as_str() {
-                    "author" => author = value,
-                    "title" => title = value,
-                    "year" => year = value,
-                    "journal" => journal = value,
-                    _ => {}
-                }
-            }
-
-            entries.push(BibEntry {
-                key,
-                entry_type,
-                author,
-                title,
-                year,
-                journal,
-            });
+        Self::parse_file(content).entries()
+    }
+
+    /// Parse a `.bib` file completely, collecting syntax errors instead of stopping at them.
+    pub fn parse_file(content: &str) -> BibFile {
+        let mut parser = Parser {
+            src: content,
+            pos: 0,
+            line_starts: std::iter::once(0).chain(content.match_indices('\n').map(|(i, _)| i + 1)).collect(),
+            macros: default_macros(),
+            file: BibFile::default(),
+        };
+        parser.run();
+
+        // Keys BibTeX would complain about
+        let mut seen = AHashMap::new();
+        let mut errors = Vec::new();
+        for item in &parser.file.items {
+            if let BibItem::Entry(entry) = item {
+                if seen.insert(entry.key.to_lowercase(), entry.line).is_some() {
+                    errors.push(BibError { line: entry.line, message: format!("duplicate entry key `{}`", entry.key) });
+                }
+            }
         }
-        entries
+        for item in &parser.file.items {
+            if let BibItem::Entry(entry) = item {
+                if let Some(field) = entry.fields.iter().find(|f| f.name.eq_ignore_ascii_case("crossref")) {
+                    if !seen.contains_key(&field.expanded.to_lowercase()) {
+                        errors.push(BibError { line: entry.line, message: format!("`{}` crossrefs unknown entry `{}`", entry.key, field.expanded) });
+                    }
+                }
+            }
+        }
+        parser.file.errors.extend(errors);
+        parser.file.errors.sort_by_key(|e| e.line);
+        parser.file
+    }
+}
+
+fn default_macros() -> AHashMap<String, String> {
+    let months = ["january", "february", "march", "april", "may", "june", "july", "august", "september", "october", "november", "december"];
+    months.iter().map(|m| {
+        let mut name = m.to_string();
+        name[..1].make_ascii_uppercase();
+        (m[..3].to_string(), name)
+    }).collect()
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+    line_starts: Vec<usize>,
+    macros: AHashMap<String, String>,
+    file: BibFile,
+}
+
+type Parsed<T> = Result<T, BibError>;
+
+impl<'a> Parser<'a> {
+    fn run(&mut self) {
+        while self.pos < self.src.len() {
+            let Some(at) = self.src[self.pos..].find('@').map(|i| self.pos + i) else {
+                self.push_text(self.src.len());
+                break;
+            };
+            self.push_text(at);
+            if !self.at_item() {
+                // An `@` in free text, such as an email address
+                self.push_text(at + 1);
+                continue;
+            }
+            if let Err(error) = self.item() {
+                // Skip to the next `@` and keep the broken text as is
+                self.file.errors.push(error);
+                let resume = self.src[at + 1..].find('@').map_or(self.src.len(), |i| at + 1 + i);
+                self.pos = at;
+                self.push_text(resume);
+                self.pos = resume;
+            }
+        }
+    }
+
+    fn push_text(&mut self, end: usize) {
+        if end > self.pos {
+            let text = self.src[self.pos..end].to_string();
+            match self.file.items.last_mut() {
+                Some(BibItem::Text(previous)) => previous.push_str(&text),
+                _ => self.file.items.push(BibItem::Text(text)),
+            }
+        }
+        self.pos = end;
+    }
+
+    /// Whether the `@` at `pos` starts an item: a type followed by `{` or `(`, or a brace-less `@comment`.
+    fn at_item(&self) -> bool {
+        let rest = self.src[self.pos + 1..].trim_start();
+        let (name, after) = rest.split_at(rest.find(|c| !is_identifier_char(c)).unwrap_or(rest.len()));
+        !name.is_empty() && (name.eq_ignore_ascii_case("comment") || matches!(after.trim_start().chars().next(), Some('{') | Some('(')))
+    }
+
+    fn item(&mut self) -> Parsed<()> {
+        let start = self.pos;
+        self.pos += 1;
+        self.skip_whitespace();
+        let entry_type = self.identifier();
+        if entry_type.is_empty() {
+            return Err(self.error("expected an entry type after `@`"));
+        }
+        self.skip_whitespace();
+
+        let lower = entry_type.to_lowercase();
+        if lower == "comment" && !matches!(self.peek(), Some('{') | Some('(')) {
+            // `@comment` without braces runs to the end of the line
+            let end = self.src[self.pos..].find('\n').map_or(self.src.len(), |i| self.pos + i);
+            self.pos = end;
+            self.file.items.push(BibItem::Comment(self.src[start..end].to_string()));
+            return Ok(());
+        }
+
+        let close = match self.peek() {
+            Some('{') => '}',
+            Some('(') => ')',
+            _ => return Err(self.error(&format!("expected `{{` or `(` after `@{}`", entry_type))),
+        };
+        self.pos += 1;
+
+        match lower.as_str() {
+            "comment" => {
+                self.pos -= 1;
+                self.balanced(close)?;
+                self.file.items.push(BibItem::Comment(self.src[start..self.pos].to_string()));
+            }
+            "preamble" => {
+                self.skip_whitespace();
+                let (_, value) = self.value()?;
+                self.skip_whitespace();
+                self.expect(close)?;
+                self.file.items.push(BibItem::Preamble { value, raw: self.src[start..self.pos].to_string() });
+            }
+            "string" => {
+                self.skip_whitespace();
+                let field = self.field()?;
+                self.skip_whitespace();
+                self.expect(close)?;
+                self.macros.insert(field.name.to_lowercase(), field.expanded.clone());
+                self.file.items.push(BibItem::String { name: field.name, value: field.expanded, raw: self.src[start..self.pos].to_string() });
+            }
+            _ => {
+                self.skip_whitespace();
+                let key_start = self.pos;
+                while self.peek().is_some_and(|c| c != ',' && c != close && !c.is_whitespace()) {
+                    self.pos += self.peek().map_or(1, char::len_utf8);
+                }
+                let key = self.src[key_start..self.pos].to_string();
+                if key.is_empty() {
+                    return Err(self.error(&format!("`@{}` entry without a key", entry_type)));
+                }
+                let (line, column) = self.line_col(key_start);
+                let head = self.src[start..self.pos].to_string();
+
+                let mut fields = Vec::new();
+                let tail_start;
+                loop {
+                    let lead_start = self.pos;
+                    self.skip_whitespace();
+                    if self.peek() == Some(close) {
+                        tail_start = lead_start;
+                        break;
+                    }
+                    self.expect(',')?;
+                    self.skip_whitespace();
+                    // A trailing comma before the closing delimiter
+                    if self.peek() == Some(close) {
+                        tail_start = lead_start;
+                        break;
+                    }
+                    let field_start = self.pos;
+                    let mut field = self.field()?;
+                    field.leading = self.src[lead_start..field_start].to_string();
+                    fields.push(field);
+                }
+                self.pos += 1;
+                self.file.items.push(BibItem::Entry(RawEntry {
+                    entry_type,
+                    key,
+                    fields,
+                    line,
+                    column,
+                    head,
+                    tail: self.src[tail_start..self.pos].to_string(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// `name = value`
+    fn field(&mut self) -> Parsed<Field> {
+        let start = self.pos;
+        let name = self.identifier();
+        if name.is_empty() {
+            return Err(self.error("expected a field name"));
+        }
+        self.skip_whitespace();
+        self.expect('=')?;
+        self.skip_whitespace();
+        let (value, expanded) = self.value()?;
+        Ok(Field { name, value, expanded, leading: String::new(), raw: self.src[start..self.pos].to_string() })
+    }
+
+    /// A value: braced, quoted, numeric or macro parts joined by `#`. Returns the parts and their expansion.
+    fn value(&mut self) -> Parsed<(Vec<ValuePart>, String)> {
+        let mut parts = Vec::new();
+        let mut expanded = String::new();
+        loop {
+            let part_start = self.pos;
+            let part = match self.peek() {
+                Some('{') => {
+                    self.balanced('}')?;
+                    ValuePart::Braced(self.src[part_start + 1..self.pos - 1].to_string())
+                }
+                Some('"') => {
+                    self.quoted()?;
+                    ValuePart::Quoted(self.src[part_start + 1..self.pos - 1].to_string())
+                }
+                Some(c) if c.is_ascii_digit() => {
+                    while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        self.pos += 1;
+                    }
+                    ValuePart::Number(self.src[part_start..self.pos].to_string())
+                }
+                Some(c) if is_identifier_char(c) => {
+                    let name = self.identifier();
+                    match self.macros.get(&name.to_lowercase()) {
+                        Some(_) => {}
+                        None => {
+                            let line = self.line_col(part_start).0;
+                            self.file.errors.push(BibError { line, message: format!("undefined string macro `{}`", name) });
+                        }
+                    }
+                    ValuePart::Macro(name)
+                }
+                _ => return Err(self.error("expected a field value")),
+            };
+            match &part {
+                ValuePart::Braced(s) | ValuePart::Quoted(s) | ValuePart::Number(s) => expanded.push_str(s),
+                ValuePart::Macro(name) => expanded.push_str(self.macros.get(&name.to_lowercase()).map_or(name.as_str(), |v| v.as_str())),
+            }
+            parts.push(part);
+
+            let before = self.pos;
+            self.skip_whitespace();
+            if self.peek() == Some('#') {
+                self.pos += 1;
+                self.skip_whitespace();
+            } else {
+                self.pos = before;
+                break;
+            }
+        }
+        Ok((parts, expanded.split_whitespace().collect::<Vec<_>>().join(" ")))
+    }
+
+    /// Skip a `{...}` (or `(...)`) group with nested braces, leaving `pos` after it.
+    ///
+    /// Parentheses inside braces are text, so `(a {b) c})` closes at the last `)`.
+    fn balanced(&mut self, close: char) -> Parsed<()> {
+        let start = self.pos;
+        let open = if close == ')' { '(' } else { '{' };
+        let (mut braces, mut parens) = (0usize, 0usize);
+        for (i, c) in self.src[start..].char_indices() {
+            match c {
+                '{' => braces += 1,
+                '}' => braces = braces.saturating_sub(1),
+                '(' if close == ')' && braces == 0 => parens += 1,
+                ')' if close == ')' && braces == 0 => parens -= 1,
+                _ => continue,
+            }
+            let depth = if close == ')' { parens } else { braces };
+            if depth == 0 {
+                self.pos = start + i + 1;
+                return Ok(());
+            }
+        }
+        self.pos = start;
+        Err(self.error(&format!("unbalanced `{}`", open)))
+    }
+
+    /// Skip a `"..."` value, in which quotes inside braces do not count.
+    fn quoted(&mut self) -> Parsed<()> {
+        let start = self.pos;
+        let mut depth = 0;
+        for (i, c) in self.src[start + 1..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                '"' if depth == 0 => {
+                    self.pos = start + 1 + i + 1;
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        Err(self.error("unterminated quoted value"))
+    }
+
+    fn identifier(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(is_identifier_char) {
+            self.pos += 1;
+        }
+        self.src[start..self.pos].to_string()
+    }
+
+    fn expect(&mut self, c: char) -> Parsed<()> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            let found = self.peek().map_or("end of file".to_string(), |f| format!("`{}`", f));
+            Err(self.error(&format!("expected `{}`, found {}", c, found)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += self.peek().map_or(1, char::len_utf8);
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|&s| s <= offset);
+        (line, offset - self.line_starts[line - 1])
+    }
+
+    fn error(&self, message: &str) -> BibError {
+        BibError { line: self.line_col(self.pos).0, message: message.to_string() }
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ':' | '.' | '+' | '/' | '\'')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bib_parser_round_trip_and_macros() {
+        let source = r#"% A comment line
+@string{ acm = "ACM" }
+@preamble{ "\newcommand{\noop}[1]{}" }
+@comment{ anything {goes} here }
+
+@InProceedings{smith2020,
+  author    = {Smith, John and {Doe}, Jane},
+  title     = "The {GPU} Way",
+  booktitle = acm # { Conf.},
+  month     = jan,
+  year      = 2020,
+  crossref  = {proc2020},
+}
+
+@proceedings(proc2020,
+  title = {Proceedings of Things},
+  publisher = {Springer}, doi = {10.1000/xyz}
+)
+@article{broken,
+  title = {Unclosed
+@book{ok, title = unknownmacro}
+"#;
+        let file = BibParser::parse_file(source);
+        assert_eq!(file.write(), source);
+
+        let entries = file.entries();
+        let keys: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, vec!["smith2020", "proc2020", "ok"]);
+
+        let smith = &entries[0];
+        assert_eq!(smith.entry_type, "inproceedings");
+        assert_eq!((smith.line, smith.column), (6, 15));
+        assert_eq!(smith.field("booktitle"), Some("ACM Conf."));
+        assert_eq!(smith.field("month"), Some("January"));
+        assert_eq!(smith.field("year"), Some("2020"));
+        assert_eq!(smith.plain("title").as_deref(), Some("The GPU Way"));
+        // Inherited through crossref
+        assert_eq!(smith.field("publisher"), Some("Springer"));
+        assert_eq!(smith.field("doi"), Some("10.1000/xyz"));
+        let names: Vec<&str> = smith.fields.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(&names[..6], &["author", "title", "booktitle", "month", "year", "crossref"]);
+
+        let errors: Vec<(usize, &str)> = file.errors.iter().map(|e| (e.line, e.message.as_str())).collect();
+        assert_eq!(errors, vec![
+            (20, "unbalanced `{`"),
+            (21, "undefined string macro `unknownmacro`"),
+        ]);
+    }
+
+    #[test]
+    fn test_bib_parser_recovery_and_delimiters() {
+        let source = r#"Mail me@example.org about @ signs.
+@comment(a {b) c})
+@preamble( "\def\x{1}" # "\relax" )
+@article(paren, title = {A (nested) title}, journal = nojournal)
+@book{missing title = {No comma}}
+@misc{after, note = {Still parsed}}
+"#;
+        let file = BibParser::parse_file(source);
+        assert_eq!(file.write(), source);
+
+        assert_eq!(file.items[0], BibItem::Text("Mail me@example.org about @ signs.\n".to_string()));
+        assert_eq!(file.items[1], BibItem::Comment("@comment(a {b) c})".to_string()));
+        assert!(matches!(&file.items[3], BibItem::Preamble { value, .. } if value == r"\def\x{1}\relax"));
+
+        let entries = file.entries();
+        let keys: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+        assert_eq!(keys, vec!["paren", "after"]);
+        assert_eq!(entries[0].field("title"), Some("A (nested) title"));
+        // An undefined macro is reported but expands to its own name
+        assert_eq!(entries[0].field("journal"), Some("nojournal"));
+        assert_eq!(entries[1].field("note"), Some("Still parsed"));
+
+        let errors: Vec<(usize, &str)> = file.errors.iter().map(|e| (e.line, e.message.as_str())).collect();
+        assert_eq!(errors, vec![
+            (4, "undefined string macro `nojournal`"),
+            (5, "expected `,`, found `t`"),
+        ]);
     }
 }
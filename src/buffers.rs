@@ -0,0 +1,282 @@
+use crate::editor::Editor;
+use crate::vfs::Vfs;
+use log::warn;
+use std::path::{Path, PathBuf};
+
+/// One open file: its editor (text, cursor, undo history) and what was last read from or written to disk.
+pub struct Buffer {
+    pub file_name: String,
+    pub editor: Editor,
+    saved_text: String,
+    pub dirty: bool,
+}
+
+impl Buffer {
+    pub fn text(&self) -> String {
+        self.editor.get_text()
+    }
+
+    /// Recompute whether the buffer differs from the file on disk.
+    pub fn update_dirty(&mut self) {
+        self.dirty = self.editor.get_text() != self.saved_text;
+    }
+}
+
+/// What the tab bar shows for a buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tab {
+    pub file_name: String,
+    pub dirty: bool,
+}
+
+/// The files open in the editor, one of them active.
+pub struct BufferManager {
+    pub buffers: Vec<Buffer>,
+    pub active: usize,
+    root_dir: Option<String>,
+}
+
+impl BufferManager {
+    pub fn new(root_dir: Option<String>) -> Self {
+        Self {
+            buffers: Vec::new(),
+            active: 0,
+            root_dir: root_dir.filter(|r| !r.is_empty()),
+        }
+    }
+
+    /// Where `file_name` lives on disk, if the project has a directory.
+    pub fn disk_path(&self, file_name: &str) -> Option<PathBuf> {
+        self.root_dir.as_deref().map(|root| Path::new(root).join(file_name))
+    }
+
+    pub fn find(&self, file_name: &str) -> Option<usize> {
+        self.buffers.iter().position(|b| b.file_name == file_name)
+    }
+
+    pub fn active(&self) -> Option<&Buffer> {
+        self.buffers.get(self.active)
+    }
+
+    pub fn active_mut(&mut self) -> Option<&mut Buffer> {
+        self.buffers.get_mut(self.active)
+    }
+
+    /// Open `file_name` (or switch to it if it is already open) and make it active.
+    ///
+    /// An already open buffer picks up changes made to the file in the VFS meanwhile, such as a rename
+    /// across the project, as an undoable edit.
+    pub fn open(&mut self, file_name: &str, vfs: &Vfs) -> Option<usize> {
+        let content = vfs.read_file(file_name).map(|c| String::from_utf8_lossy(&c).to_string());
+
+        if let Some(index) = self.find(file_name) {
+            let buffer = &mut self.buffers[index];
+            if let Some(content) = content.filter(|c| *c != buffer.text()) {
+                buffer.editor.replace_text(&content);
+                buffer.update_dirty();
+            }
+            self.active = index;
+            return Some(index);
+        }
+
+        let content = content?;
+        // Without a project directory (the demo) the VFS is the only copy
+        let saved_text = match self.disk_path(file_name) {
+            Some(path) => std::fs::read(path).map(|d| String::from_utf8_lossy(&d).to_string()).unwrap_or_default(),
+            None => content.clone(),
+        };
+        let mut editor = Editor::new();
+        editor.open(&content, self.root_dir.as_deref().map(|root| crate::undo::history_path(root, file_name)));
+
+        let mut buffer = Buffer { file_name: file_name.to_string(), editor, saved_text, dirty: false };
+        buffer.update_dirty();
+        self.buffers.push(buffer);
+        self.active = self.buffers.len() - 1;
+        Some(self.active)
+    }
+
+    /// Apply edits made across the project, such as a rename, returning the buffers they changed.
+    ///
+    /// Open buffers take the new text as an undoable edit. Files that are not open are opened in a
+    /// background tab, so their edits are tracked, prompted about on close and saved like any other.
+    pub fn apply_edits(&mut self, edits: Vec<(String, String)>, vfs: &Vfs) -> Vec<usize> {
+        let active = self.active;
+        let mut changed = Vec::new();
+        for (file_name, content) in edits {
+            vfs.write_file(&file_name, content.into_bytes());
+            changed.extend(self.open(&file_name, vfs));
+        }
+        self.active = active.min(self.buffers.len().saturating_sub(1));
+        changed
+    }
+
+    /// Write a buffer to its file under the project root.
+    pub fn save(&mut self, index: usize, vfs: &Vfs) -> Result<(), String> {
+        let Some(buffer) = self.buffers.get(index) else {
+            return Err("no such buffer".to_string());
+        };
+        let Some(path) = self.disk_path(&buffer.file_name) else {
+            return Err(format!("{} is not part of a project on disk", buffer.file_name));
+        };
+
+        let text = buffer.text();
+        crate::io::IoHandler::write_atomic_sync(&path, text.as_bytes())
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))?;
+        vfs.write_file(&buffer.file_name, text.as_bytes().to_vec());
+
+        let buffer = &mut self.buffers[index];
+        buffer.saved_text = text;
+        buffer.dirty = false;
+        if let Err(e) = buffer.editor.save_history() {
+            warn!("Failed to save undo history for {}: {}", buffer.file_name, e);
+        }
+        Ok(())
+    }
+
+    /// Save every modified buffer, stopping at the first failure.
+    pub fn save_all(&mut self, vfs: &Vfs) -> Result<(), String> {
+        for index in 0..self.buffers.len() {
+            if self.buffers[index].dirty {
+                self.save(index, vfs)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Close a buffer, dropping unsaved edits: the VFS goes back to the saved text. The neighbouring tab
+    /// becomes active.
+    pub fn close(&mut self, index: usize, vfs: &Vfs) {
+        if index >= self.buffers.len() {
+            return;
+        }
+        let buffer = self.buffers.remove(index);
+        if let Err(e) = buffer.editor.save_history() {
+            warn!("Failed to save undo history for {}: {}", buffer.file_name, e);
+        }
+        if buffer.dirty {
+            vfs.write_file(&buffer.file_name, buffer.saved_text.into_bytes());
+        }
+        if self.active > index || self.active >= self.buffers.len() {
+            self.active = self.active.saturating_sub(1);
+        }
+    }
+
+    /// Pick up a change made to an open file outside SokuTeX. Buffers with unsaved edits are left alone.
+    /// Returns the index of the reloaded buffer.
+    pub fn reload_from_disk(&mut self, disk_path: &str, vfs: &Vfs) -> Option<usize> {
+        let changed = std::fs::canonicalize(disk_path).ok()?;
+        let index = self.buffers.iter().position(|b| {
+            self.disk_path(&b.file_name).and_then(|p| std::fs::canonicalize(p).ok()).as_ref() == Some(&changed)
+        })?;
+        let text = String::from_utf8_lossy(&std::fs::read(&changed).ok()?).to_string();
+
+        let buffer = &mut self.buffers[index];
+        if buffer.dirty || text == buffer.saved_text {
+            return None;
+        }
+        buffer.editor.replace_text(&text);
+        buffer.saved_text = text.clone();
+        buffer.dirty = false;
+        vfs.write_file(&buffer.file_name, text.into_bytes());
+        Some(index)
+    }
+
+    pub fn dirty_files(&self) -> Vec<String> {
+        self.buffers.iter().filter(|b| b.dirty).map(|b| b.file_name.clone()).collect()
+    }
+
+    /// Write every buffer's undo history, e.g. before exiting.
+    pub fn save_histories(&self) {
+        for buffer in &self.buffers {
+            if let Err(e) = buffer.editor.save_history() {
+                warn!("Failed to save undo history for {}: {}", buffer.file_name, e);
+            }
+        }
+    }
+
+    pub fn tabs(&self) -> Vec<Tab> {
+        self.buffers.iter().map(|b| Tab { file_name: b.file_name.clone(), dirty: b.dirty }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_track_dirty_state_and_save() {
+        let dir = std::env::temp_dir().join(format!("sokutex_buffers_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("chapters")).unwrap();
+        std::fs::write(dir.join("main.tex"), "main").unwrap();
+        std::fs::write(dir.join("chapters/intro.tex"), "intro").unwrap();
+
+        let mut vfs = Vfs::new();
+        vfs.load_directory(dir.to_str().unwrap());
+        let mut buffers = BufferManager::new(vfs.root_dir.clone());
+
+        assert_eq!(buffers.open("main.tex", &vfs), Some(0));
+        assert_eq!(buffers.open("chapters/intro.tex", &vfs), Some(1));
+        assert_eq!(buffers.open("main.tex", &vfs), Some(0));
+        assert!(buffers.dirty_files().is_empty());
+
+        let buffer = buffers.active_mut().unwrap();
+        buffer.editor.replace_text("main edited");
+        buffer.update_dirty();
+        assert_eq!(buffers.dirty_files(), vec!["main.tex".to_string()]);
+        // Undoing back to the saved text is clean again
+        let buffer = buffers.active_mut().unwrap();
+        buffer.editor.undo();
+        buffer.update_dirty();
+        assert!(!buffer.dirty);
+        buffer.editor.redo();
+        buffer.update_dirty();
+
+        buffers.save_all(&vfs).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("main.tex")).unwrap(), "main edited");
+        assert!(buffers.dirty_files().is_empty());
+
+        // External edits reload clean buffers
+        std::fs::write(dir.join("chapters/intro.tex"), "intro v2").unwrap();
+        let path = dir.join("chapters/intro.tex");
+        assert_eq!(buffers.reload_from_disk(path.to_str().unwrap(), &vfs), Some(1));
+        assert_eq!(buffers.buffers[1].text(), "intro v2");
+
+        buffers.close(0, &vfs);
+        assert_eq!(buffers.active().unwrap().file_name, "chapters/intro.tex");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rename_edits_mark_every_file_dirty() {
+        let dir = std::env::temp_dir().join(format!("sokutex_buffers_rename_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.tex"), "\\input{intro}\nSee \\ref{sec:intro}.\n").unwrap();
+        std::fs::write(dir.join("intro.tex"), "\\section{Intro}\\label{sec:intro}\n").unwrap();
+
+        let mut vfs = Vfs::new();
+        vfs.load_directory(dir.to_str().unwrap());
+        let mut buffers = BufferManager::new(vfs.root_dir.clone());
+        buffers.open("main.tex", &vfs);
+
+        let index = crate::symbols::SymbolIndex::from_tree(&crate::dependencies::DependencyScanner::scan("main.tex", &vfs));
+        let reference = index.symbol_at("main.tex", 2, 10).unwrap();
+        let edits = index.rename(reference, "sec:introduction", &vfs).unwrap();
+        assert_eq!(buffers.apply_edits(edits, &vfs).len(), 2);
+
+        // intro.tex was not open: it is now, in the background, and counts as unsaved
+        let mut dirty = buffers.dirty_files();
+        dirty.sort();
+        assert_eq!(dirty, vec!["intro.tex".to_string(), "main.tex".to_string()]);
+        assert_eq!(buffers.active().unwrap().file_name, "main.tex");
+        assert_eq!(std::fs::read_to_string(dir.join("intro.tex")).unwrap(), "\\section{Intro}\\label{sec:intro}\n");
+
+        // The rename is an ordinary edit: undoable, and saved with the rest
+        let main = buffers.active_mut().unwrap();
+        main.editor.undo();
+        main.update_dirty();
+        assert_eq!(buffers.dirty_files(), vec!["intro.tex".to_string()]);
+        buffers.save_all(&vfs).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("intro.tex")).unwrap(), "\\section{Intro}\\label{sec:introduction}\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,106 @@
+use crate::io::STATE_DIR;
+use crate::vfs::Vfs;
+use dashmap::DashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Component, Path, PathBuf};
+
+const AREA: &str = "build";
+
+/// A shadow copy of the project under `<root>/.sokutex/build` that the engines compile in.
+///
+/// Generated changes such as an injected `\includeonly` only ever reach the copy, and the PDF, log,
+/// aux and SyncTeX files are written next to it, so compiling never touches the user's sources.
+pub struct BuildDir {
+    dir: PathBuf,
+    /// Content hashes of the files last written, so unchanged files keep their timestamps for latexmk
+    written: DashMap<String, u64>,
+}
+
+impl BuildDir {
+    /// Projects without a directory of their own (the demo) build under the system temp directory.
+    pub fn for_project(root_dir: Option<&str>) -> Self {
+        let dir = match root_dir.filter(|r| !r.is_empty()) {
+            Some(root) => Path::new(root).join(STATE_DIR).join(AREA),
+            None => std::env::temp_dir().join(format!("sokutex_build_{}", std::process::id())),
+        };
+        Self { dir, written: DashMap::new() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Mirror the VFS into the build directory, with `latex` as the text of `main_file`.
+    /// Returns the path of the root file's copy.
+    pub fn sync(&self, vfs: &Vfs, main_file: &str, latex: &str) -> std::io::Result<PathBuf> {
+        for entry in vfs.get_all_files().iter() {
+            if entry.key() != main_file {
+                self.write_if_changed(entry.key(), entry.value())?;
+            }
+        }
+        self.write_if_changed(main_file, latex.as_bytes())?;
+        Ok(self.dir.join(main_file))
+    }
+
+    /// An output of the root file, e.g. `output("main.tex", "pdf")` for `<build>/main.pdf`.
+    pub fn output(&self, main_file: &str, extension: &str) -> PathBuf {
+        self.dir.join(main_file).with_extension(extension)
+    }
+
+    fn write_if_changed(&self, file_name: &str, content: &[u8]) -> std::io::Result<()> {
+        // Never follow a name out of the build directory
+        if !Path::new(file_name).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return Ok(());
+        }
+        let mut hasher = ahash::AHasher::default();
+        content.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let path = self.dir.join(file_name);
+        if self.written.get(file_name).is_some_and(|h| *h == hash) && path.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, content)?;
+        self.written.insert(file_name.to_string(), hash);
+        Ok(())
+    }
+}
+
+/// A path relative to the project root, with the build directory taken off: engines report the
+/// copies they compiled, but the editor wants the sources.
+pub fn source_path(relative: &Path) -> &Path {
+    relative.strip_prefix(Path::new(STATE_DIR).join(AREA)).unwrap_or(relative)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_writes_copies_only() {
+        let root = std::env::temp_dir().join(format!("sokutex_build_test_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("main.tex"), "original").unwrap();
+
+        let vfs = Vfs::new();
+        vfs.write_file("main.tex", b"original".to_vec());
+        vfs.write_file("chapters/intro.tex", b"intro".to_vec());
+        vfs.write_file("../outside.tex", b"escape".to_vec());
+
+        let build = BuildDir::for_project(Some(root.to_str().unwrap()));
+        let copy = build.sync(&vfs, "main.tex", "\\includeonly{chapters/intro}\noriginal").unwrap();
+        assert_eq!(copy, root.join(".sokutex/build/main.tex"));
+        assert_eq!(std::fs::read_to_string(&copy).unwrap(), "\\includeonly{chapters/intro}\noriginal");
+        assert_eq!(std::fs::read_to_string(build.path().join("chapters/intro.tex")).unwrap(), "intro");
+        assert_eq!(std::fs::read_to_string(root.join("main.tex")).unwrap(), "original");
+        assert!(!root.join(".sokutex/outside.tex").exists());
+        assert_eq!(build.output("main.tex", "synctex.gz"), root.join(".sokutex/build/main.synctex.gz"));
+
+        assert_eq!(source_path(Path::new(".sokutex/build/chapters/intro.tex")), Path::new("chapters/intro.tex"));
+        assert_eq!(source_path(Path::new("chapters/intro.tex")), Path::new("chapters/intro.tex"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often a running tool is checked for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Shared flag a newer compile request sets to stop an outdated build.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A build stopped because a newer one superseded it.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "build cancelled by a newer request")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Run `command` to completion like `Command::output`, unless `token` is cancelled first.
+/// Every line the tool prints is passed to `on_line` as it arrives.
+///
+/// The tool runs in its own process group, so cancelling also stops the engines it started.
+pub fn output(command: &mut Command, token: &CancelToken, on_line: impl Fn(&str) + Sync) -> std::io::Result<Result<Output, Cancelled>> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take().map(|p| Box::new(p) as Box<dyn Read + Send>);
+    let stderr = child.stderr.take().map(|p| Box::new(p) as Box<dyn Read + Send>);
+
+    std::thread::scope(|scope| {
+        // Drain both pipes while waiting so a chatty tool cannot block on a full pipe
+        let drain = |pipe: Option<Box<dyn Read + Send>>| {
+            let on_line = &on_line;
+            scope.spawn(move || {
+                let mut data = Vec::new();
+                if let Some(pipe) = pipe {
+                    let mut reader = BufReader::new(pipe);
+                    let mut line = Vec::new();
+                    while reader.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+                        on_line(String::from_utf8_lossy(&line).trim_end());
+                        data.append(&mut line);
+                    }
+                }
+                data
+            })
+        };
+        let stdout = drain(stdout);
+        let stderr = drain(stderr);
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if token.is_cancelled() {
+                kill_process_group(child.id());
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok(Err(Cancelled));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        Ok(Ok(Output {
+            status,
+            stdout: stdout.join().unwrap_or_default(),
+            stderr: stderr.join().unwrap_or_default(),
+        }))
+    })
+}
+
+/// Kill every process in the group led by `pid`, as started with `process_group(0)`.
+pub fn kill_process_group(pid: u32) {
+    #[cfg(unix)]
+    unsafe {
+        libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = pid;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cancel_stops_a_running_tool() {
+        let token = CancelToken::new();
+        let lines = std::sync::Mutex::new(Vec::new());
+        let finished = output(Command::new("sh").args(["-c", "echo done; echo oops >&2"]), &token, |line| {
+            lines.lock().unwrap().push(line.to_string());
+        }).unwrap().unwrap();
+        assert_eq!(finished.stdout, b"done\n");
+        assert_eq!(finished.stderr, b"oops\n");
+        let mut lines = lines.into_inner().unwrap();
+        lines.sort();
+        assert_eq!(lines, vec!["done", "oops"]);
+
+        let canceller = token.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+        let started = std::time::Instant::now();
+        // The shell's child `sleep` is in the same group and goes down with it
+        let result = output(Command::new("sh").args(["-c", "sleep 5; echo late"]), &token, |_| {}).unwrap();
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,431 @@
+use crate::compiler::Compiler;
+use crate::compiler_daemon::{CompileRequest, CompilerDaemon};
+use crate::config::{CompileBackend, ProjectSettings, TexEngine};
+use crate::dependencies::{DependencyNode, DependencyScanner};
+use crate::magic_comments::MagicComments;
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::vfs::Vfs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+const USAGE: &str = "Usage:
+  sokutex [PATH]                      Open the editor (demo project if PATH is omitted)
+  sokutex compile PATH [OPTIONS]      Compile once and exit
+  sokutex watch PATH [OPTIONS]        Recompile whenever a project file changes
+  sokutex deps PATH                   Print the include/dependency tree
+  sokutex outline PATH                Print the document outline
+  sokutex lsp [PATH]                  Run as a language server over stdio (PATH overrides the client's workspace)
+
+Options:
+  --backend <internal|tectonic|latexmk>   Compile backend (default: tectonic)
+  --engine <pdflatex|xelatex|lualatex>    TeX engine (default: project setting; % !TEX program wins)
+  --draft                                 Compile in draft mode
+  -o, --output <FILE>                     Where to write the PDF (default: <root>.pdf next to the root file)
+  --diagnostics <FILE>                    Where to write diagnostics (default: <output>.diagnostics)";
+
+struct CompileOptions {
+    backend: CompileBackend,
+    engine: Option<TexEngine>,
+    draft: bool,
+    output: Option<PathBuf>,
+    diagnostics: Option<PathBuf>,
+}
+
+/// Load a project from a file or directory path, returning the VFS and the root file name.
+///
+/// A file whose `% !TEX root` points elsewhere opens the project of that root file.
+pub fn load_project(path: &str) -> (Vfs, String) {
+    let mut p = PathBuf::from(path);
+    if p.is_file() {
+        let magic_root = std::fs::read_to_string(&p).ok().and_then(|text| MagicComments::parse(&text).root);
+        let root_path = magic_root.and_then(|root| Some(p.parent()?.join(root)));
+        if let Some(root_path) = root_path.filter(|r| r.is_file()) {
+            p = root_path;
+        }
+    }
+
+    let mut vfs = Vfs::new();
+    vfs.load_directory(&p.to_string_lossy());
+
+    let opened = p.is_file().then(|| p.file_name().unwrap().to_string_lossy().to_string());
+    let setting = ProjectSettings::load(&project_root(&vfs)).root;
+    let main_file = crate::root_file::resolve(&vfs, opened.as_deref(), setting.as_deref());
+    (vfs, main_file)
+}
+
+/// Run a headless subcommand. Returns `None` when `args` should open the editor instead.
+pub async fn run(args: &[String]) -> Option<i32> {
+    let command = args.get(1)?.as_str();
+    if !matches!(command, "compile" | "watch" | "deps" | "outline" | "lsp" | "help" | "--help" | "-h") {
+        return None;
+    }
+    if matches!(command, "help" | "--help" | "-h") {
+        println!("{}", USAGE);
+        return Some(0);
+    }
+    if command == "lsp" {
+        // stdout carries the protocol, so nothing else may print to it
+        return Some(crate::lsp::serve(args.get(2).cloned()).await);
+    }
+
+    let Some(path) = args.get(2).filter(|a| !a.starts_with('-')) else {
+        eprintln!("sokutex {}: missing project path\n\n{}", command, USAGE);
+        return Some(2);
+    };
+    if !Path::new(path).exists() {
+        eprintln!("sokutex {}: {} does not exist", command, path);
+        return Some(2);
+    }
+
+    let options = match parse_options(&args[3..]) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("sokutex {}: {}\n\n{}", command, e, USAGE);
+            return Some(2);
+        }
+    };
+
+    let (vfs, main_file) = load_project(path);
+    let vfs = Arc::new(vfs);
+    // Latexmk is only driven asynchronously through the daemon, which keeps one `latexmk -pvc` for all builds
+    let daemon = (options.backend == CompileBackend::Latexmk && matches!(command, "compile" | "watch")).then(|| {
+        let (tx, rx) = mpsc::channel(1);
+        (tx, tokio::spawn(CompilerDaemon::new(rx, vfs.clone()).run()))
+    });
+    let sender = daemon.as_ref().map(|(tx, _)| tx);
+
+    let code = match command {
+        "compile" => compile(&vfs, &main_file, &options, sender).await,
+        "watch" => watch(&vfs, &main_file, &options, sender).await,
+        "deps" => {
+            print_tree(&DependencyScanner::scan(&main_file, &vfs), 0);
+            0
+        }
+        "outline" => {
+            print_outline(&DependencyScanner::scan(&main_file, &vfs));
+            0
+        }
+        _ => unreachable!(),
+    };
+
+    // The process ends with `exit`, which runs no destructors: let the daemon stop latexmk first
+    if let Some((tx, handle)) = daemon {
+        drop(tx);
+        let _ = handle.await;
+    }
+    Some(code)
+}
+
+fn parse_options(args: &[String]) -> Result<CompileOptions, String> {
+    let mut options = CompileOptions {
+        backend: CompileBackend::Tectonic,
+        engine: None,
+        draft: false,
+        output: None,
+        diagnostics: None,
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--backend" => {
+                options.backend = match iter.next().map(|s| s.as_str()) {
+                    Some("internal") => CompileBackend::Internal,
+                    Some("tectonic") => CompileBackend::Tectonic,
+                    Some("latexmk") => CompileBackend::Latexmk,
+                    Some(other) => return Err(format!("unknown backend '{}'", other)),
+                    None => return Err("--backend needs a value".to_string()),
+                };
+            }
+            "--engine" => {
+                let name = iter.next().ok_or("--engine needs a value")?;
+                options.engine = Some(TexEngine::from_name(name).ok_or_else(|| format!("unknown engine '{}'", name))?);
+            }
+            "--draft" => options.draft = true,
+            "-o" | "--output" => {
+                options.output = Some(iter.next().ok_or("--output needs a value")?.into());
+            }
+            "--diagnostics" => {
+                options.diagnostics = Some(iter.next().ok_or("--diagnostics needs a value")?.into());
+            }
+            other => return Err(format!("unknown option '{}'", other)),
+        }
+    }
+    Ok(options)
+}
+
+/// Compile the project once, write the PDF and diagnostics, and return the exit status.
+///
+/// With a `daemon` the build goes through it, as latexmk builds must; otherwise the compiler runs here.
+async fn compile(vfs: &Arc<Vfs>, main_file: &str, options: &CompileOptions, daemon: Option<&mpsc::Sender<CompileRequest>>) -> i32 {
+    let Some(latex) = vfs.read_file(main_file) else {
+        eprintln!("sokutex: root file {} not found", main_file);
+        return 2;
+    };
+    let latex = String::from_utf8_lossy(&latex).to_string();
+    let setting = options.engine.unwrap_or_else(|| ProjectSettings::load(&project_root(vfs)).engine);
+    let engine = crate::magic_comments::engine_for(setting, main_file, &latex, |path| {
+        vfs.read_file(path).map(|data| String::from_utf8_lossy(&data).to_string())
+    });
+
+    let timer = crate::perf::PerfTimer::start("CLI Compile");
+    let (pdf, diagnostics) = if let Some(daemon) = daemon {
+        let (otx, orx) = oneshot::channel();
+        let request = CompileRequest::Compile {
+            latex,
+            main_file: main_file.to_string(),
+            backend: options.backend,
+            engine,
+            draft: options.draft,
+            focus_mode: false,
+            active_file: Some(main_file.to_string()),
+            response: otx,
+        };
+        if daemon.send(request).await.is_err() {
+            eprintln!("sokutex: compiler daemon is not running");
+            return 1;
+        }
+        match orx.await {
+            Ok(res) => (res.pdf, res.diagnostics),
+            Err(_) => {
+                eprintln!("sokutex: compiler daemon dropped the request");
+                return 1;
+            }
+        }
+    } else {
+        let mut compiler = Compiler::new();
+        compiler.set_backend(options.backend);
+        compiler.set_engine(engine);
+        compiler.active_file = Some(main_file.to_string());
+        compiler.main_file = main_file.to_string();
+        match compiler.compile(&latex, options.draft, false, vfs) {
+            Ok(output) => (output.pdf, output.diagnostics),
+            Err(e) => {
+                eprintln!("sokutex: compilation failed: {}", e);
+                return 1;
+            }
+        }
+    };
+    timer.stop();
+
+    let (output_path, diagnostics_path) = output_paths(vfs, main_file, options);
+
+    for diag in &diagnostics {
+        eprintln!("{}", format_diagnostic(diag));
+    }
+    let report: String = diagnostics.iter().map(|d| format_diagnostic(d) + "\n").collect();
+    if let Err(e) = std::fs::write(&diagnostics_path, report) {
+        eprintln!("sokutex: could not write {}: {}", diagnostics_path.display(), e);
+    }
+
+    let written = match pdf {
+        Some(pdf) => {
+            if let Err(e) = std::fs::write(&output_path, pdf) {
+                eprintln!("sokutex: could not write {}: {}", output_path.display(), e);
+                return 1;
+            }
+            println!("Wrote {}", output_path.display());
+            true
+        }
+        None => {
+            eprintln!("sokutex: no PDF produced");
+            false
+        }
+    };
+    exit_status(written, &diagnostics)
+}
+
+/// 0 only for a clean build: a PDF was written and no errors were reported.
+fn exit_status(pdf_written: bool, diagnostics: &[Diagnostic]) -> i32 {
+    let has_errors = diagnostics.iter().any(|d| d.severity == Severity::Error);
+    if pdf_written && !has_errors { 0 } else { 1 }
+}
+
+/// Compile, then recompile whenever a source file under the project root changes.
+async fn watch(vfs: &Arc<Vfs>, main_file: &str, options: &CompileOptions, daemon: Option<&mpsc::Sender<CompileRequest>>) -> i32 {
+    let root = project_root(vfs);
+    let (file_tx, mut file_rx) = mpsc::channel(64);
+    let mut watcher = match crate::watcher::FileWatcher::new(file_tx) {
+        Ok(w) => w,
+        Err(e) => {
+            eprintln!("sokutex: failed to set up file watcher: {}", e);
+            return 1;
+        }
+    };
+    if let Err(e) = watcher.watch(&root) {
+        eprintln!("sokutex: failed to watch {}: {}", root, e);
+        return 1;
+    }
+
+    compile(vfs, main_file, options, daemon).await;
+    println!("Watching {} for changes...", root);
+
+    let debounce = std::time::Duration::from_millis(150);
+    loop {
+        let path = tokio::select! {
+            event = file_rx.recv() => match event {
+                Some(crate::watcher::FileEvent::Modified(path)) => path,
+                None => break,
+            },
+            // Ctrl-C ends the watch normally, so latexmk is stopped on the way out
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let mut changed = reload_source(vfs, &root, &path);
+
+        // Coalesce the burst of events editors emit on save
+        while let Ok(Some(crate::watcher::FileEvent::Modified(path))) = tokio::time::timeout(debounce, file_rx.recv()).await {
+            changed |= reload_source(vfs, &root, &path);
+        }
+
+        if changed {
+            compile(vfs, main_file, options, daemon).await;
+        }
+    }
+    0
+}
+
+/// Refresh a modified source file in the VFS. Returns false for files that do not affect the build.
+fn reload_source(vfs: &Vfs, root: &str, path: &str) -> bool {
+    let disk_path = Path::new(path);
+    let ext = disk_path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    if !matches!(ext, "tex" | "bib" | "sty" | "cls") {
+        return false;
+    }
+    let Ok(data) = std::fs::read(disk_path) else {
+        return false;
+    };
+
+    let root = std::fs::canonicalize(root).unwrap_or_else(|_| PathBuf::from(root));
+    let absolute = std::fs::canonicalize(disk_path).unwrap_or_else(|_| disk_path.to_path_buf());
+    let relative = absolute.strip_prefix(&root).unwrap_or(&absolute).to_string_lossy().to_string();
+    vfs.write_file(&relative, data);
+    true
+}
+
+fn project_root(vfs: &Vfs) -> String {
+    vfs.root_dir.clone().filter(|r| !r.is_empty()).unwrap_or_else(|| ".".to_string())
+}
+
+fn default_output(vfs: &Vfs, main_file: &str) -> PathBuf {
+    Path::new(&project_root(vfs)).join(main_file).with_extension("pdf")
+}
+
+/// Where to write the PDF and the diagnostics report; the report goes next to the PDF by default.
+fn output_paths(vfs: &Vfs, main_file: &str, options: &CompileOptions) -> (PathBuf, PathBuf) {
+    let output = options.output.clone().unwrap_or_else(|| default_output(vfs, main_file));
+    let diagnostics = options.diagnostics.clone().unwrap_or_else(|| output.with_extension("diagnostics"));
+    (output, diagnostics)
+}
+
+fn format_diagnostic(diag: &Diagnostic) -> String {
+    let severity = match diag.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Info => "info",
+    };
+    let location = diag.location();
+    if location.is_empty() {
+        format!("{}: {}", severity, diag.message)
+    } else {
+        format!("{}: {}: {}", location, severity, diag.message)
+    }
+}
+
+fn print_tree(node: &DependencyNode, depth: usize) {
+    println!("{}{}", "  ".repeat(depth), node.name);
+    for child in &node.children {
+        print_tree(child, depth + 1);
+    }
+}
+
+fn print_outline(node: &DependencyNode) {
+    for item in &node.outline {
+        println!("{}{}  ({}:{})", "  ".repeat(item.level), item.title, item.file_name, item.line);
+    }
+    for child in &node.children {
+        print_outline(child);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::DiagnosticKind;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn diagnostic(severity: Severity, file: Option<&str>, line: Option<usize>) -> Diagnostic {
+        Diagnostic {
+            severity,
+            kind: DiagnosticKind::TexError,
+            file: file.map(str::to_string),
+            line,
+            message: "Undefined control sequence".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let mut vfs = Vfs::new();
+        vfs.root_dir = Some("/work/thesis".to_string());
+
+        let defaults = parse_options(&[]).unwrap();
+        assert_eq!(defaults.backend, CompileBackend::Tectonic);
+        assert_eq!(defaults.engine, None);
+        assert!(!defaults.draft);
+        assert_eq!(output_paths(&vfs, "chapters/main.tex", &defaults), (
+            PathBuf::from("/work/thesis/chapters/main.pdf"),
+            PathBuf::from("/work/thesis/chapters/main.diagnostics"),
+        ));
+
+        let options = parse_options(&args(&["--backend", "latexmk", "--engine", "lualatex", "--draft", "-o", "out/book.pdf"])).unwrap();
+        assert_eq!(options.backend, CompileBackend::Latexmk);
+        assert_eq!(options.engine, Some(TexEngine::LuaLatex));
+        assert!(options.draft);
+        // The report follows the PDF unless it is given its own path
+        assert_eq!(output_paths(&vfs, "main.tex", &options), (PathBuf::from("out/book.pdf"), PathBuf::from("out/book.diagnostics")));
+        let options = parse_options(&args(&["--output", "book.pdf", "--diagnostics", "report.txt"])).unwrap();
+        assert_eq!(output_paths(&vfs, "main.tex", &options).1, PathBuf::from("report.txt"));
+
+        assert_eq!(parse_options(&args(&["--backend", "make"])).err().unwrap(), "unknown backend 'make'");
+        assert_eq!(parse_options(&args(&["--backend"])).err().unwrap(), "--backend needs a value");
+        assert_eq!(parse_options(&args(&["--engine", "context"])).err().unwrap(), "unknown engine 'context'");
+        assert_eq!(parse_options(&args(&["-o"])).err().unwrap(), "--output needs a value");
+        assert_eq!(parse_options(&args(&["--diagnostics"])).err().unwrap(), "--diagnostics needs a value");
+        assert_eq!(parse_options(&args(&["--verbose"])).err().unwrap(), "unknown option '--verbose'");
+    }
+
+    #[test]
+    fn test_format_diagnostic_and_exit_status() {
+        assert_eq!(format_diagnostic(&diagnostic(Severity::Error, Some("main.tex"), Some(12))), "main.tex:12: error: Undefined control sequence");
+        assert_eq!(format_diagnostic(&diagnostic(Severity::Warning, None, Some(3))), "L3: warning: Undefined control sequence");
+        assert_eq!(format_diagnostic(&diagnostic(Severity::Info, None, None)), "info: Undefined control sequence");
+
+        let warning = diagnostic(Severity::Warning, Some("main.tex"), Some(1));
+        let error = diagnostic(Severity::Error, Some("main.tex"), Some(2));
+        assert_eq!(exit_status(true, &[]), 0);
+        assert_eq!(exit_status(true, std::slice::from_ref(&warning)), 0);
+        assert_eq!(exit_status(true, &[warning, error]), 1);
+        assert_eq!(exit_status(false, &[]), 1);
+    }
+
+    #[test]
+    fn test_usage_errors_exit_with_2() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let run = |list: &[&str]| runtime.block_on(run(&args(list)));
+
+        // Anything but a subcommand opens the editor
+        assert_eq!(run(&["sokutex"]), None);
+        assert_eq!(run(&["sokutex", "thesis/main.tex"]), None);
+        assert_eq!(run(&["sokutex", "--help"]), Some(0));
+
+        assert_eq!(run(&["sokutex", "compile"]), Some(2));
+        assert_eq!(run(&["sokutex", "compile", "--draft"]), Some(2));
+        assert_eq!(run(&["sokutex", "compile", "/nonexistent/sokutex/project"]), Some(2));
+        let dir = std::env::temp_dir().to_string_lossy().to_string();
+        assert_eq!(run(&["sokutex", "compile", &dir, "--backend", "make"]), Some(2));
+    }
+}
@@ -8,11 +8,18 @@ use tectonic::status::NoopStatusBackend;
 
 use regex::Regex;
 use crate::vfs::Vfs;
-use crate::config::CompileBackend;
+use crate::build_dir::BuildDir;
+use crate::cancel::CancelToken;
+use crate::progress::{BuildEvent, OutputParser};
+use crate::config::{CompileBackend, TexEngine};
 use crate::bib::BibParser;
+use crate::diagnostics::{Diagnostic, DiagnosticKind, LogParser, Severity};
 use std::hash::{Hash, Hasher};
 use std::collections::HashSet;
+use std::path::Path;
 use log::info;
+#[cfg(feature = "tectonic-backend")]
+use log::error;
 use rayon::prelude::*;
 
 use std::sync::OnceLock;
@@ -25,6 +32,15 @@ static BIBRESOURCE_REGEX: OnceLock<Regex> = OnceLock::new();
 use dashmap::DashMap;
 
 
+#[derive(Debug, Clone)]
+pub struct CompileOutput {
+    /// `None` when the engine failed before producing a PDF.
+    pub pdf: Option<Vec<u8>>,
+    /// SyncTeX data, for engines that hand it over directly instead of writing it next to the PDF.
+    pub synctex: Option<Vec<u8>>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
 #[derive(Debug, Clone)]
 pub struct FileDelta {
     pub path: String,
@@ -38,6 +54,8 @@ pub struct TectonicSessionManager {
     pub session: Option<Box<ProcessingSession>>,
     pub bundle: Option<Box<dyn tectonic::io::Bundle + Send>>,
     pub format_cache_path: Option<std::path::PathBuf>,
+    /// The root file copy the session was created for
+    pub input: Option<std::path::PathBuf>,
 }
 
 #[cfg(feature = "tectonic-backend")]
@@ -47,16 +65,26 @@ impl TectonicSessionManager {
             session: None,
             bundle: None,
             format_cache_path: None,
+            input: None,
         }
     }
 }
 
 pub struct Compiler {
-    cache: DashMap<u64, Vec<u8>>,
+    cache: DashMap<u64, CompileOutput>,
     backend: CompileBackend,
+    engine: TexEngine,
     file_hashes: DashMap<String, u64>,
     bib_cache: DashMap<String, (u64, Vec<String>)>,
     pub active_file: Option<String>,
+    /// The root file the dependency walk starts from
+    pub main_file: String,
+    /// Created on the first build, once the project directory is known
+    build: OnceLock<BuildDir>,
+    /// Set by the daemon when a newer request supersedes the build in progress
+    cancel: CancelToken,
+    /// Where the daemon wants the output and progress of external tools
+    progress: Option<tokio::sync::broadcast::Sender<BuildEvent>>,
     #[cfg(feature = "tectonic-backend")]
     tectonic_manager: std::sync::Mutex<TectonicSessionManager>,
 }
@@ -66,9 +94,14 @@ impl Compiler {
         Self {
             cache: DashMap::new(),
             backend: CompileBackend::Internal,
+            engine: TexEngine::default(),
             file_hashes: DashMap::new(),
             bib_cache: DashMap::new(),
             active_file: None,
+            main_file: "main.tex".to_string(),
+            build: OnceLock::new(),
+            cancel: CancelToken::new(),
+            progress: None,
             #[cfg(feature = "tectonic-backend")]
             tectonic_manager: std::sync::Mutex::new(TectonicSessionManager::new()),
         }
@@ -78,6 +111,33 @@ impl Compiler {
         self.backend = backend;
     }
 
+    pub fn set_engine(&mut self, engine: TexEngine) {
+        self.engine = engine;
+    }
+
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = token;
+    }
+
+    pub fn set_progress(&mut self, progress: tokio::sync::broadcast::Sender<BuildEvent>) {
+        self.progress = Some(progress);
+    }
+
+    /// Publish a line of tool output, and the progress it reports.
+    fn publish_line(&self, parser: &mut OutputParser, line: &str) {
+        if let Some(progress) = &self.progress {
+            if let Some(event) = parser.parse(line) {
+                let _ = progress.send(event);
+            }
+            let _ = progress.send(BuildEvent::Log(line.to_string()));
+        }
+    }
+
+    /// The shadow copy of the project that external engines compile in.
+    pub fn build_dir(&self, vfs: &Vfs) -> &BuildDir {
+        self.build.get_or_init(|| BuildDir::for_project(vfs.root_dir.as_deref()))
+    }
+
     pub fn update_bib_cache(&self, latex: &str, vfs: &Vfs) {
         let bib_re = BIB_REGEX.get_or_init(|| Regex::new(r"\\bibliography\{([^}]+)\}").unwrap());
         let bibresource_re = BIBRESOURCE_REGEX.get_or_init(|| Regex::new(r"\\addbibresource\{([^}]+)\}").unwrap());
@@ -116,7 +176,7 @@ impl Compiler {
         }
     }
 
-    pub fn compile(&self, latex: &str, draft: bool, focus_mode: bool, vfs: &Vfs) -> Result<Vec<u8>, Box<dyn Error>> {
+    pub fn compile(&self, latex: &str, draft: bool, focus_mode: bool, vfs: &Vfs) -> Result<CompileOutput, Box<dyn Error>> {
         let (optimized_latex, _, deltas) = self.optimize_latex(latex, draft, focus_mode, vfs);
 
         for delta in &deltas {
@@ -127,52 +187,83 @@ impl Compiler {
         let mut hasher = ahash::AHasher::default();
         optimized_latex.hash(&mut hasher);
         self.backend.hash(&mut hasher);
+        self.engine.hash(&mut hasher);
+        self.main_file.hash(&mut hasher);
+        // The engine reads every project file from the build directory, so any of them changing is a new build
+        let files = vfs.get_all_files().iter().fold(0u64, |acc, entry| {
+            let mut file_hasher = ahash::AHasher::default();
+            entry.key().hash(&mut file_hasher);
+            entry.value().hash(&mut file_hasher);
+            acc ^ file_hasher.finish()
+        });
+        files.hash(&mut hasher);
         let final_hash = hasher.finish();
         
-        if let Some(cached) = self.cache.get(&final_hash) {
+        // The preview is cheap and reads included files itself, so it is not cached
+        let cacheable = !matches!(self.backend, CompileBackend::Internal | CompileBackend::Shadow);
+        if let Some(cached) = self.cache.get(&final_hash).filter(|_| cacheable) {
             return Ok(cached.clone());
         }
 
         // 4. Execution
         let result = match self.backend {
-            CompileBackend::Internal => self.compile_internal(&optimized_latex),
-            CompileBackend::Shadow => self.compile_shadow(&optimized_latex),
-            CompileBackend::Tectonic => self.compile_tectonic(&optimized_latex),
+            CompileBackend::Internal | CompileBackend::Shadow => self.compile_preview(latex, vfs),
+            CompileBackend::Tectonic => self.compile_tectonic(&optimized_latex, vfs)?,
             CompileBackend::Latexmk => {
                 return Err("Latexmk backend is handled asynchronously by the daemon".into());
             }
-        }?;
+        };
 
-        self.cache.insert(final_hash, result.clone());
+        // Failed builds are not cached so the next attempt re-runs the engine
+        if cacheable && result.pdf.is_some() {
+            self.cache.insert(final_hash, result.clone());
+        }
         Ok(result)
     }
 
-    fn compile_tectonic(&self, latex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    fn compile_tectonic(&self, latex: &str, vfs: &Vfs) -> Result<CompileOutput, Box<dyn Error>> {
+        let input = self.build_dir(vfs).sync(vfs, &self.main_file, latex)?;
+        let mut output = self.run_tectonic(&input, self.build_dir(vfs).path())?;
+        // Tectonic is built on XeTeX, which also covers pdfLaTeX documents, but it has no LuaTeX mode
+        if self.engine == TexEngine::LuaLatex {
+            output.diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                kind: DiagnosticKind::LatexWarning,
+                file: None,
+                line: None,
+                message: "Tectonic typesets with XeTeX; use the Latexmk backend to compile with LuaLaTeX".to_string(),
+            });
+        }
+        Ok(output)
+    }
+
+    /// Run Tectonic on the root file's copy in the build directory; the outputs are written next to it.
+    fn run_tectonic(&self, input: &Path, build_dir: &Path) -> Result<CompileOutput, Box<dyn Error>> {
         #[cfg(feature = "tectonic-backend")]
         {
-            let res = self.compile_tectonic_lib(latex);
+            let res = self.compile_tectonic_lib(input, build_dir);
             if res.is_ok() {
                 return res;
             }
             info!("Tectonic library backend failed or not available, falling back to CLI");
         }
         
-        self.compile_tectonic_cli(latex)
+        self.compile_tectonic_cli(input, build_dir)
     }
 
     #[cfg(feature = "tectonic-backend")]
-    fn compile_tectonic_lib(&self, latex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-        let temp_dir = std::env::temp_dir().join("sokutex_tectonic_lib");
-        if !temp_dir.exists() {
-            std::fs::create_dir_all(&temp_dir)?;
-        }
-        
-        let file_path = temp_dir.join("main.tex");
-        std::fs::write(&file_path, latex)?;
-        
+    fn compile_tectonic_lib(&self, input: &Path, build_dir: &Path) -> Result<CompileOutput, Box<dyn Error>> {
+        let out_dir = input.parent().unwrap_or(build_dir);
+        let input_name = input.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+
         let mut manager = self.tectonic_manager.lock().map_err(|e| format!("Lock error: {}", e))?;
         let mut status = NoopStatusBackend::default();
 
+        // A session is tied to its root file; the bundle it took is loaded again below
+        if manager.input.as_deref() != Some(input) {
+            manager.session = None;
+        }
+
         // 1. Ensure we have the persistent bundle and config
         if manager.bundle.is_none() {
             info!("Loading persistent Tectonic bundle and config...");
@@ -187,19 +278,20 @@ impl Compiler {
             
             if let Some(bundle) = manager.bundle.take() {
                 sb.bundle(bundle)
-                  .primary_input_path(&file_path)
-                  .tex_input_name("main.tex")
+                  .primary_input_path(input)
+                  .tex_input_name(&input_name)
                   .format_name("latex")
                   .format_cache_path(manager.format_cache_path.as_ref().unwrap())
-                  .keep_logs(false)
+                  .keep_logs(true)
                   .keep_intermediates(false)
                   .print_stdout(false)
-                  .output_dir(&temp_dir)
+                  .output_dir(out_dir)
                   .output_format(OutputFormat::Pdf);
                   
                 match sb.create(&mut status) {
                     Ok(sess) => {
                         manager.session = Some(Box::new(sess));
+                        manager.input = Some(input.to_path_buf());
                     },
                     Err(e) => {
                         error!("Failed to create Tectonic session: {}", e);
@@ -210,39 +302,69 @@ impl Compiler {
         }
 
         // 3. Run the persistent session
+        let log_path = input.with_extension("log");
+        let _ = std::fs::remove_file(&log_path);
         if let Some(sess) = manager.session.as_mut() {
-            if let Err(e) = sess.run(&mut status) {
+            let result = sess.run(&mut status);
+            // The library reports nothing while it runs; show its log once it is done
+            if let Ok(log) = std::fs::read(&log_path) {
+                let mut parser = OutputParser::default();
+                for line in String::from_utf8_lossy(&log).lines() {
+                    self.publish_line(&mut parser, line);
+                }
+            }
+            if let Err(e) = result {
                 error!("Tectonic session run failed: {}. Recreating session next time.", e);
                 manager.session = None; // Reset so it recreates next time
-                return Err(format!("Tectonic session failed: {}", e).into());
+
+                // A TeX error still leaves a log behind; only bail out if there is nothing to report
+                let diagnostics = Self::read_log_diagnostics(&log_path, build_dir);
+                if diagnostics.is_empty() {
+                    return Err(format!("Tectonic session failed: {}", e).into());
+                }
+                return Ok(CompileOutput { pdf: None, synctex: None, diagnostics });
             }
         }
 
-        let pdf_path = temp_dir.join("main.pdf");
-        Ok(std::fs::read(pdf_path)?)
+        Ok(CompileOutput {
+            pdf: Some(std::fs::read(input.with_extension("pdf"))?),
+            synctex: None,
+            diagnostics: Self::read_log_diagnostics(&log_path, build_dir),
+        })
     }
 
-    fn compile_tectonic_cli(&self, latex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-        let temp_dir = std::env::temp_dir().join("sokutex_tectonic_cli");
-        if !temp_dir.exists() {
-            std::fs::create_dir_all(&temp_dir)?;
-        }
-        
-        let file_path = temp_dir.join("main.tex");
-        std::fs::write(&file_path, latex)?;
+    fn compile_tectonic_cli(&self, input: &Path, build_dir: &Path) -> Result<CompileOutput, Box<dyn Error>> {
+        let mut command = std::process::Command::new("tectonic");
+        command.arg("-o").arg(input.parent().unwrap_or(build_dir)).arg(input);
+        let parser = std::sync::Mutex::new(OutputParser::default());
+        let output = crate::cancel::output(&mut command, &self.cancel, |line| {
+            self.publish_line(&mut parser.lock().unwrap_or_else(|e| e.into_inner()), line);
+        })??;
 
-        let output = std::process::Command::new("tectonic")
-            .arg("-o")
-            .arg(&temp_dir)
-            .arg(&file_path)
-            .output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut diagnostics = LogParser::parse_tectonic(&stderr);
+        LogParser::relativize(&mut diagnostics, build_dir);
 
         if !output.status.success() {
-            return Err(format!("Tectonic CLI failed: {}", String::from_utf8_lossy(&output.stderr)).into());
+            if diagnostics.is_empty() {
+                return Err(format!("Tectonic CLI failed: {}", stderr).into());
+            }
+            return Ok(CompileOutput { pdf: None, synctex: None, diagnostics });
         }
 
-        let pdf_path = temp_dir.join("main.pdf");
-        Ok(std::fs::read(pdf_path)?)
+        Ok(CompileOutput { pdf: Some(std::fs::read(input.with_extension("pdf"))?), synctex: None, diagnostics })
+    }
+
+    /// Parse the TeX log an engine left in `build_dir`, if any.
+    pub fn read_log_diagnostics(log_path: &std::path::Path, build_dir: &std::path::Path) -> Vec<Diagnostic> {
+        match std::fs::read(log_path) {
+            Ok(bytes) => {
+                let mut diagnostics = LogParser::parse(&String::from_utf8_lossy(&bytes));
+                LogParser::relativize(&mut diagnostics, build_dir);
+                diagnostics
+            }
+            Err(_) => Vec::new(),
+        }
     }
 
     /// Extracted optimization logic for use in external compilation flows (like Latexmk)
@@ -261,7 +383,7 @@ impl Compiler {
 
         // 2. Track changes across the entire dependency tree
         let mut all_known_files = Vec::new();
-        self.collect_all_dependencies("main.tex", vfs, &mut all_known_files, &mut HashSet::new());
+        self.collect_all_dependencies(&self.main_file, vfs, &mut all_known_files, &mut HashSet::new());
 
         // Update BibTeX cache for all dependencies (Parallel)
         self.update_bib_cache(latex, vfs);
@@ -442,63 +564,12 @@ impl Compiler {
         }
     }
 
-    fn compile_internal(&self, latex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-        // Fast Internal Mock Engine (Aesthetic representation)
-        let lines: Vec<String> = latex.lines().take(60).map(|s| s.to_string()).collect();
-        let content_stream = lines.iter().enumerate().map(|(i, line)| {
-            let y_pos = 800 - (i * 12);
-            format!("BT /F1 10 Tf 50 {} Td ({}) Tj ET", y_pos, line.replace("(", "\\(").replace(")", "\\)").replace("\\", "\\\\"))
-        }).collect::<Vec<_>>().join("\n");
-
-        let pdf = format!(
-            "%PDF-1.4\n\
-            1 0 obj <</Type /Catalog /Pages 2 0 R>> endobj\n\
-            2 0 obj <</Type /Pages /Kids [3 0 R] /Count 1>> endobj\n\
-            3 0 obj <</Type /Page /Parent 2 0 R /Resources 4 0 R /MediaBox [0 0 595 842] /Contents 5 0 R>> endobj\n\
-            4 0 obj <</Font <</F1 <</Type /Font /Subtype /Type1 /BaseFont /Helvetica>>>>>> endobj\n\
-            5 0 obj <</Length {}>> stream\n\
-            {}\n\
-            endstream endobj\n\
-            xref\n\
-            0 6\n\
-            0000000000 65535 f\n\
-            trailer <</Size 6 /Root 1 0 R>> startxref 0 %%EOF",
-            content_stream.len(),
-            content_stream
-        );
-
-        Ok(pdf.into_bytes())
-    }
-
-    fn compile_shadow(&self, latex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-        // FAST SHADOW RENDERER
-        // Designed to provide near-zero latency feedback by doing an instant 
-        // visual approximation of the text as the user types,
-        // while the heavier tectonic run executes in the background.
-
-        let lines: Vec<String> = latex.lines().take(60).map(|s| s.to_string()).collect();
-        let content_stream = lines.iter().enumerate().map(|(i, line)| {
-            let y_pos = 800 - (i * 12);
-            format!("BT /F1 10 Tf 50 {} Td ({}) Tj ET", y_pos, line.replace("(", "\\(").replace(")", "\\)").replace("\\", "\\\\"))
-        }).collect::<Vec<_>>().join("\n");
-
-        let pdf = format!(
-            "%PDF-1.4\n\
-            1 0 obj <</Type /Catalog /Pages 2 0 R>> endobj\n\
-            2 0 obj <</Type /Pages /Kids [3 0 R] /Count 1>> endobj\n\
-            3 0 obj <</Type /Page /Parent 2 0 R /Resources 4 0 R /MediaBox [0 0 595 842] /Contents 5 0 R>> endobj\n\
-            4 0 obj <</Font <</F1 <</Type /Font /Subtype /Type1 /BaseFont /Helvetica>>>>>> endobj\n\
-            5 0 obj <</Length {}>> stream\n\
-            {}\n\
-            endstream endobj\n\
-            xref\n\
-            0 6\n\
-            0000000000 65535 f\n\
-            trailer <</Size 6 /Root 1 0 R>> startxref 0 %%EOF",
-            content_stream.len(),
-            content_stream
-        );
-
-        Ok(pdf.into_bytes())
+    /// Typeset with the built-in preview engine, which handles a LaTeX subset in milliseconds.
+    ///
+    /// The source is typeset as written rather than optimized, so SyncTeX lines match the editor.
+    fn compile_preview(&self, latex: &str, vfs: &Vfs) -> CompileOutput {
+        let read = |path: &str| vfs.read_file(path).map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+        let preview = crate::preview::typeset(latex, &self.main_file, &read);
+        CompileOutput { pdf: Some(preview.pdf), synctex: Some(preview.synctex), diagnostics: preview.diagnostics }
     }
 }
@@ -1,25 +1,38 @@
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use crate::latexmk::{LatexmkPvc, LatexmkEvent};
-use crate::compiler::Compiler;
+use crate::cancel::CancelToken;
+use crate::compiler::{CompileOutput, Compiler};
 use crate::vfs::Vfs;
-use crate::config::CompileBackend;
-use std::path::PathBuf;
-use std::sync::Arc;
+use crate::config::{CompileBackend, TexEngine};
+use crate::diagnostics::{Diagnostic, DiagnosticKind, Severity};
+use crate::progress::{BuildEvent, OutputParser};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::fs;
 use log::{info, error};
 use std::hash::Hash;
 
+/// Progress events kept for a subscriber that falls behind; a chatty build can print thousands of lines.
+const PROGRESS_CAPACITY: usize = 1024;
+
 pub struct CompileResult {
-    pub pdf: Vec<u8>,
+    /// `None` when the build failed; the previous PDF should stay on screen.
+    pub pdf: Option<Vec<u8>>,
     pub revision: u64,
     #[allow(dead_code)]
     pub synctex_data: Option<Vec<u8>>,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 pub enum CompileRequest {
     Compile {
+        /// The text of the root file
         latex: String,
+        /// The root file, relative to the project directory
+        main_file: String,
         backend: CompileBackend,
+        engine: TexEngine,
         draft: bool,
         focus_mode: bool,
         active_file: Option<String>,
@@ -31,86 +44,145 @@ pub enum CompileRequest {
     },
 }
 
+/// What a compile request asks the compiler to build.
+struct CompileJob {
+    latex: String,
+    main_file: String,
+    backend: CompileBackend,
+    engine: TexEngine,
+    draft: bool,
+    focus_mode: bool,
+    active_file: Option<String>,
+}
+
+impl CompileJob {
+    fn configure(&self, compiler: &mut Compiler) {
+        compiler.set_backend(self.backend);
+        compiler.set_engine(self.engine);
+        compiler.active_file = self.active_file.clone();
+        compiler.main_file = self.main_file.clone();
+    }
+}
+
+/// A build that ran off the event loop, handed back with the request it answers.
+struct FinishedJob {
+    id: u64,
+    output: Result<CompileOutput, String>,
+    duration: Duration,
+    response: oneshot::Sender<CompileResult>,
+}
+
+/// A latexmk request whose sources were mirrored into the build directory off the event loop.
+struct PreparedLatexmk {
+    id: u64,
+    job: CompileJob,
+    /// The build directory, or why it could not be written
+    build_dir: Result<PathBuf, String>,
+    response: oneshot::Sender<CompileResult>,
+}
+
 pub struct CompilerDaemon {
     receiver: mpsc::Receiver<CompileRequest>,
     latexmk: Option<LatexmkPvc>,
+    /// The root file and engine the running latexmk was started with
+    latexmk_target: Option<(String, TexEngine)>,
+    /// The build directory the running latexmk works in
+    latexmk_dir: PathBuf,
+    /// Set between latexmk starting a run and reporting its result
+    latexmk_building: bool,
+    /// When the latexmk build being waited for was requested
+    latexmk_started: Option<Instant>,
+    latexmk_parser: OutputParser,
+    /// Events of the running latexmk; every restart gets a fresh channel so a killed one cannot answer
     event_rx: mpsc::Receiver<LatexmkEvent>,
     pending_response: Option<oneshot::Sender<CompileResult>>,
-    compiler: Compiler,
+    /// Shared with the build running on the blocking pool
+    compiler: Arc<Mutex<Compiler>>,
     vfs: Arc<Vfs>,
     revision: u64,
     last_pdf_hash: u64,
+    /// The newest compile request; builds for older ones are cancelled and their results dropped
+    job_id: u64,
+    cancel: CancelToken,
+    finished_tx: mpsc::Sender<FinishedJob>,
+    finished_rx: mpsc::Receiver<FinishedJob>,
+    prepared_tx: mpsc::Sender<PreparedLatexmk>,
+    prepared_rx: mpsc::Receiver<PreparedLatexmk>,
+    progress: broadcast::Sender<BuildEvent>,
 }
 
 impl CompilerDaemon {
     pub fn new(receiver: mpsc::Receiver<CompileRequest>, vfs: Arc<Vfs>) -> Self {
-        let (event_tx, event_rx) = mpsc::channel(10);
-        
-        let latexmk = match LatexmkPvc::spawn(PathBuf::from("main.tex"), event_tx) {
-            Ok(pvc) => Some(pvc),
-            Err(e) => {
-                error!("Failed to spawn latexmk: {}. Latexmk backend will be unavailable.", e);
-                None
-            }
-        };
+        // latexmk is started by the first request for it, once the root file is known
+        let (_, event_rx) = mpsc::channel(1);
+        let (finished_tx, finished_rx) = mpsc::channel(4);
+        let (prepared_tx, prepared_rx) = mpsc::channel(4);
+        let (progress, _) = broadcast::channel(PROGRESS_CAPACITY);
 
         Self { 
             receiver, 
-            latexmk, 
+            latexmk: None, 
+            latexmk_target: None,
+            latexmk_dir: PathBuf::new(),
+            latexmk_building: false,
+            latexmk_started: None,
+            latexmk_parser: OutputParser::default(),
             event_rx,
             pending_response: None,
-            compiler: Compiler::new(),
+            compiler: Arc::new(Mutex::new(Compiler::new())),
             vfs,
             revision: 0,
             last_pdf_hash: 0,
+            job_id: 0,
+            cancel: CancelToken::new(),
+            finished_tx,
+            finished_rx,
+            prepared_tx,
+            prepared_rx,
+            progress,
         }
     }
 
+    /// Follow the progress of every build from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<BuildEvent> {
+        self.progress.subscribe()
+    }
+
+    fn publish(&self, event: BuildEvent) {
+        // Nobody listening is fine: the CLI and the language server only want results
+        let _ = self.progress.send(event);
+    }
+
+    /// Tell subscribers how a build ended.
+    fn publish_result(&self, duration: Duration, built: bool, diagnostics: &[Diagnostic]) {
+        self.publish(if built {
+            BuildEvent::Finished { duration }
+        } else {
+            BuildEvent::Failed { duration, diagnostics: diagnostics.to_vec() }
+        });
+    }
+
     pub async fn run(mut self) {
         loop {
             tokio::select! {
-                Some(request) = self.receiver.recv() => {
+                request = self.receiver.recv() => {
+                    // Every sender is gone: nobody is left to ask for builds
+                    let Some(request) = request else { break };
                     match request {
-                        CompileRequest::Compile { latex, mut backend, draft, focus_mode, active_file, response } => {
+                        CompileRequest::Compile { latex, main_file, mut backend, engine, draft, focus_mode, active_file, response } => {
                             if draft {
                                 // Force Internal for near-instant feedback in draft mode
                                 backend = CompileBackend::Internal;
                             }
-                            self.compiler.set_backend(backend);
-                            self.compiler.active_file = active_file;
+                            self.supersede();
+                            self.publish(BuildEvent::Queued);
+                            let job = CompileJob { latex, main_file, backend, engine, draft, focus_mode, active_file };
 
                             if backend == CompileBackend::Latexmk {
-                                if let Some(ref mut latexmk) = self.latexmk {
-                                    // Incremental Optimization: Inject \includeonly if possible
-                                    let (optimized_latex, is_incremental, deltas) = self.compiler.optimize_latex(&latex, draft, focus_mode, &self.vfs);
-                                    
-                                    for delta in &deltas {
-                                        info!("Delta detected for Latexmk: {} ({} -> {})", delta.path, delta.old_hash, delta.new_hash);
-                                    }
-
-                                    if is_incremental {
-                                        info!("Transparent Incremental Compilation: injecting \\includeonly");
-                                    }
-
-                                    // 1. Save optimized content to main.tex
-                                    if let Ok(_) = fs::write("main.tex", optimized_latex).await {
-                                        // 2. Trigger rebuild via persistent handle
-                                        let _ = latexmk.trigger_rebuild().await;
-                                        // 3. Store response channel to send back PDF when build finishes
-                                        self.pending_response = Some(response);
-                                    }
-                                } else {
-                                    // Fallback if latexmk failed to start
-                                    error!("Latexmk requested but not available. Falling back to internal.");
-                                    if let Ok(pdf) = self.compiler.compile(&latex, draft, focus_mode, &self.vfs) {
-                                        self.update_revision_and_send(pdf, None, response);
-                                    }
-                                }
+                                self.start_latexmk(job, response);
                             } else {
                                 // Use Internal or Tectonic
-                                if let Ok(pdf) = self.compiler.compile(&latex, draft, focus_mode, &self.vfs) {
-                                    self.update_revision_and_send(pdf, None, response);
-                                }
+                                self.start_job(job, response);
                             }
                         }
                         CompileRequest::ScanDependencies { main_file, response } => {
@@ -119,49 +191,286 @@ impl CompilerDaemon {
                         }
                     }
                 }
+                Some(finished) = self.finished_rx.recv() => {
+                    // Only the newest request is answered; an outdated build that finished anyway is dropped
+                    if finished.id == self.job_id {
+                        match finished.output {
+                            Ok(output) => {
+                                self.publish_result(finished.duration, output.pdf.is_some(), &output.diagnostics);
+                                self.update_revision_and_send(output.pdf, output.synctex, output.diagnostics, finished.response);
+                            }
+                            Err(e) => {
+                                error!("Compilation failed: {}", e);
+                                self.fail(e, finished.duration, finished.response);
+                            }
+                        }
+                    }
+                }
+                Some(prepared) = self.prepared_rx.recv() => {
+                    if prepared.id == self.job_id {
+                        self.run_latexmk(prepared).await;
+                    }
+                }
                 Some(event) = self.event_rx.recv() => {
                     match event {
                         LatexmkEvent::BuildFinished(success) => {
-                            if success {
-                                if let Some(response) = self.pending_response.take() {
-                                    // 4. Read generated PDF and send back
-                                    if let Ok(pdf_data) = fs::read("main.pdf").await {
-                                        let synctex_data = if let Ok(data) = fs::read("main.synctex.gz").await {
-                                            Some(data)
-                                        } else if let Ok(data) = fs::read("main.synctex").await {
-                                            Some(data)
-                                        } else {
-                                            None
-                                        };
-                                        self.update_revision_and_send(pdf_data, synctex_data, response);
-                                    }
-                                }
+                            self.latexmk_building = false;
+                            let stem = self.latexmk_target.as_ref().map(|(main_file, _)| self.latexmk_dir.join(main_file));
+                            if let (Some(response), Some(stem)) = (self.pending_response.take(), stem) {
+                                // 4. Collect diagnostics from the log, then read generated PDF and send back
+                                let diagnostics = Compiler::read_log_diagnostics(&stem.with_extension("log"), &self.latexmk_dir);
+                                let pdf_data = if success { fs::read(stem.with_extension("pdf")).await.ok() } else { None };
+                                let synctex_data = if pdf_data.is_none() {
+                                    None
+                                } else if let Ok(data) = fs::read(stem.with_extension("synctex.gz")).await {
+                                    Some(data)
+                                } else {
+                                    fs::read(stem.with_extension("synctex")).await.ok()
+                                };
+                                let duration = self.latexmk_started.take().map(|t| t.elapsed()).unwrap_or_default();
+                                self.publish_result(duration, pdf_data.is_some(), &diagnostics);
+                                self.update_revision_and_send(pdf_data, synctex_data, diagnostics, response);
                             }
                         }
                         LatexmkEvent::BuildStarted => {
-                            // Could notify UI that build is in progress
+                            self.latexmk_building = true;
+                        }
+                        LatexmkEvent::Output(line) => {
+                            if let Some(event) = self.latexmk_parser.parse(&line) {
+                                self.publish(event);
+                            }
+                            self.publish(BuildEvent::Log(line));
                         }
                     }
                 }
+                else => break,
+            }
+        }
+        if let Some(latexmk) = self.latexmk.take() {
+            info!("Stopping latexmk");
+            let _ = latexmk.kill().await;
+        }
+    }
+
+    /// A new request makes every build in progress outdated: cancel them and forget their callers.
+    fn supersede(&mut self) {
+        self.cancel.cancel();
+        self.cancel = CancelToken::new();
+        self.job_id += 1;
+        self.pending_response = None;
+    }
+
+    /// Build on the blocking pool, so the daemon keeps taking requests and can cancel this one.
+    fn start_job(&mut self, job: CompileJob, response: oneshot::Sender<CompileResult>) {
+        let (id, token) = (self.job_id, self.cancel.clone());
+        let (compiler, vfs, finished_tx) = (self.compiler.clone(), self.vfs.clone(), self.finished_tx.clone());
+        let progress = self.progress.clone();
+        tokio::task::spawn_blocking(move || {
+            // The previous build gives the compiler up once it sees its own cancellation
+            let mut compiler = compiler.lock().unwrap_or_else(|e| e.into_inner());
+            if token.is_cancelled() {
+                return;
+            }
+            let _ = progress.send(BuildEvent::Started { backend: job.backend, engine: job.engine });
+            let started = Instant::now();
+            job.configure(&mut compiler);
+            compiler.set_cancel_token(token.clone());
+            compiler.set_progress(progress);
+            let output = compiler.compile(&job.latex, job.draft, job.focus_mode, &vfs).map_err(|e| e.to_string());
+            drop(compiler);
+            if !token.is_cancelled() {
+                let _ = finished_tx.blocking_send(FinishedJob { id, output, duration: started.elapsed(), response });
+            }
+        });
+    }
+
+    /// Mirror the sources into the build directory on the blocking pool: the compiler may still be
+    /// busy with a build that cannot be cancelled, and the daemon must keep answering meanwhile.
+    fn start_latexmk(&mut self, job: CompileJob, response: oneshot::Sender<CompileResult>) {
+        let (id, token) = (self.job_id, self.cancel.clone());
+        let (compiler, vfs, prepared_tx) = (self.compiler.clone(), self.vfs.clone(), self.prepared_tx.clone());
+        tokio::task::spawn_blocking(move || {
+            let mut compiler = compiler.lock().unwrap_or_else(|e| e.into_inner());
+            if token.is_cancelled() {
+                return;
+            }
+            job.configure(&mut compiler);
+
+            // Incremental Optimization: Inject \includeonly if possible
+            let (optimized_latex, is_incremental, deltas) = compiler.optimize_latex(&job.latex, job.draft, job.focus_mode, &vfs);
+
+            for delta in &deltas {
+                info!("Delta detected for Latexmk: {} ({} -> {})", delta.path, delta.old_hash, delta.new_hash);
+            }
+
+            if is_incremental {
+                info!("Transparent Incremental Compilation: injecting \\includeonly");
+            }
+
+            // 1. Mirror the project into the build directory with the optimized root; the sources are left alone
+            let build_dir = compiler.build_dir(&vfs);
+            let build_dir = build_dir
+                .sync(&vfs, &job.main_file, &optimized_latex)
+                .map(|_| build_dir.path().to_path_buf())
+                .map_err(|e| e.to_string());
+            drop(compiler);
+            let _ = prepared_tx.blocking_send(PreparedLatexmk { id, job, build_dir, response });
+        });
+    }
+
+    /// Hand the prepared build to the persistent latexmk, which answers through its events.
+    async fn run_latexmk(&mut self, prepared: PreparedLatexmk) {
+        let PreparedLatexmk { job, build_dir, response, .. } = prepared;
+        let build_dir = match build_dir {
+            Ok(build_dir) => build_dir,
+            Err(e) => {
+                error!("Failed to prepare the build directory: {}", e);
+                self.fail(format!("Could not write the build directory: {}", e), Duration::ZERO, response);
+                return;
             }
+        };
+
+        // A run of outdated sources is killed; the restarted latexmk builds the new ones straight away
+        if self.latexmk_building {
+            info!("Stopping the outdated latexmk run");
+            self.latexmk_target = None;
+        }
+        let restarted = self.ensure_latexmk(&job.main_file, job.engine, &build_dir).await;
+        if self.latexmk.is_some() {
+            self.publish(BuildEvent::Started { backend: CompileBackend::Latexmk, engine: job.engine });
+            self.latexmk_started = Some(Instant::now());
+            self.latexmk_parser = OutputParser::default();
+        }
+        if let Some(ref mut latexmk) = self.latexmk {
+            // 2. Trigger rebuild via persistent handle
+            if !restarted {
+                let _ = latexmk.trigger_rebuild().await;
+            }
+            // 3. Store response channel to send back PDF when build finishes
+            self.pending_response = Some(response);
+        } else {
+            // Fallback if latexmk failed to start
+            error!("Latexmk requested but not available. Falling back to internal.");
+            self.start_job(CompileJob { backend: CompileBackend::Internal, ..job }, response);
+        }
+    }
+
+    /// latexmk picks its root file and engine at startup, so (re)start it when either changes.
+    /// Returns whether it was started, in which case it is already building.
+    async fn ensure_latexmk(&mut self, main_file: &str, engine: TexEngine, build_dir: &Path) -> bool {
+        let target = (main_file.to_string(), engine);
+        if self.latexmk_target.as_ref() == Some(&target) {
+            return false;
         }
+        if let Some(latexmk) = self.latexmk.take() {
+            let _ = latexmk.kill().await;
+        }
+        info!("Starting latexmk on {} with {}", main_file, engine.label());
+        let (event_tx, event_rx) = mpsc::channel(64);
+        self.event_rx = event_rx;
+        self.latexmk_building = false;
+        self.latexmk_dir = build_dir.to_path_buf();
+        self.latexmk = match LatexmkPvc::spawn(build_dir, main_file, engine, event_tx) {
+            Ok(pvc) => Some(pvc),
+            Err(e) => {
+                error!("Failed to spawn latexmk: {}. Latexmk backend will be unavailable.", e);
+                None
+            }
+        };
+        self.latexmk_target = Some(target);
+        true
     }
 
-    fn update_revision_and_send(&mut self, pdf: Vec<u8>, synctex: Option<Vec<u8>>, response: oneshot::Sender<CompileResult>) {
-        let mut hasher = ahash::AHasher::default();
-        use std::hash::Hasher;
-        pdf.hash(&mut hasher);
-        let hash = hasher.finish();
+    /// Answer a request that produced no build with a single error.
+    fn fail(&mut self, message: String, duration: Duration, response: oneshot::Sender<CompileResult>) {
+        let diagnostic = Diagnostic {
+            severity: Severity::Error,
+            kind: DiagnosticKind::TexError,
+            file: None,
+            line: None,
+            message,
+        };
+        self.publish_result(duration, false, std::slice::from_ref(&diagnostic));
+        self.update_revision_and_send(None, None, vec![diagnostic], response);
+    }
 
-        if hash != self.last_pdf_hash {
-            self.revision += 1;
-            self.last_pdf_hash = hash;
+    fn update_revision_and_send(&mut self, pdf: Option<Vec<u8>>, synctex: Option<Vec<u8>>, diagnostics: Vec<Diagnostic>, response: oneshot::Sender<CompileResult>) {
+        if let Some(ref pdf) = pdf {
+            let mut hasher = ahash::AHasher::default();
+            use std::hash::Hasher;
+            pdf.hash(&mut hasher);
+            let hash = hasher.finish();
+
+            if hash != self.last_pdf_hash {
+                self.revision += 1;
+                self.last_pdf_hash = hash;
+            }
         }
 
         let _ = response.send(CompileResult { 
             pdf, 
             revision: self.revision,
             synctex_data: synctex,
+            diagnostics,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_daemon_answers_while_a_build_holds_the_compiler() {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let vfs = Arc::new(Vfs::new());
+            vfs.write_file("main.tex", b"\\documentclass{article}\n\\begin{document}\nHi\n\\end{document}\n".to_vec());
+            let (tx, rx) = mpsc::channel(4);
+            let daemon = CompilerDaemon::new(rx, vfs);
+            let compiler = daemon.compiler.clone();
+            let mut progress = daemon.subscribe();
+            tokio::spawn(daemon.run());
+
+            // A build that cannot be cancelled, such as a Tectonic library session, holds on to the compiler
+            let (held_tx, held_rx) = std::sync::mpsc::channel();
+            let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+            std::thread::spawn(move || {
+                let _build = compiler.lock().unwrap();
+                held_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            });
+            held_rx.recv().unwrap();
+            let patience = Duration::from_secs(2);
+
+            let (otx, _orx) = oneshot::channel();
+            tx.send(CompileRequest::Compile {
+                latex: String::new(),
+                main_file: "main.tex".to_string(),
+                backend: CompileBackend::Latexmk,
+                engine: TexEngine::PdfLatex,
+                draft: false,
+                focus_mode: false,
+                active_file: None,
+                response: otx,
+            }).await.unwrap();
+            assert!(matches!(tokio::time::timeout(patience, progress.recv()).await, Ok(Ok(BuildEvent::Queued))));
+
+            let (otx, orx) = oneshot::channel();
+            tx.send(CompileRequest::ScanDependencies { main_file: "main.tex".to_string(), response: otx }).await.unwrap();
+            assert_eq!(tokio::time::timeout(patience, orx).await.unwrap().unwrap().name, "main.tex");
+            drop(release_tx);
+        });
+    }
+
+    #[test]
+    fn test_daemon_stops_when_its_senders_are_gone() {
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let (tx, rx) = mpsc::channel(1);
+            let daemon = tokio::spawn(CompilerDaemon::new(rx, Arc::new(Vfs::new())).run());
+            drop(tx);
+            assert!(tokio::time::timeout(Duration::from_secs(2), daemon).await.is_ok());
         });
     }
 }
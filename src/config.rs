@@ -1,3 +1,6 @@
+use std::path::{Path, PathBuf};
+use serde_json::{json, Value};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CompileBackend {
     Internal,
@@ -7,6 +10,86 @@ pub enum CompileBackend {
     Latexmk,
 }
 
+/// The TeX engine a document is typeset with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[allow(clippy::enum_variant_names)]
+pub enum TexEngine {
+    #[default]
+    PdfLatex,
+    XeLatex,
+    LuaLatex,
+}
+
+impl TexEngine {
+    pub const ALL: [TexEngine; 3] = [TexEngine::PdfLatex, TexEngine::XeLatex, TexEngine::LuaLatex];
+
+    /// The engine for a program name as written in `% !TEX program` comments and on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        // TeXstudio writes commands as `txs:///xelatex`
+        let name = name.trim().trim_start_matches("txs:///").to_ascii_lowercase();
+        match name.as_str() {
+            "pdflatex" | "pdftex" | "latex" | "pdflatexmk" => Some(TexEngine::PdfLatex),
+            "xelatex" | "xetex" | "xelatexmk" => Some(TexEngine::XeLatex),
+            "lualatex" | "luatex" | "lualatexmk" => Some(TexEngine::LuaLatex),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TexEngine::PdfLatex => "pdflatex",
+            TexEngine::XeLatex => "xelatex",
+            TexEngine::LuaLatex => "lualatex",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            TexEngine::PdfLatex => "pdfLaTeX",
+            TexEngine::XeLatex => "XeLaTeX",
+            TexEngine::LuaLatex => "LuaLaTeX",
+        }
+    }
+
+    /// The latexmk switch that selects this engine for PDF output.
+    pub fn latexmk_flag(self) -> &'static str {
+        match self {
+            TexEngine::PdfLatex => "-pdf",
+            TexEngine::XeLatex => "-xelatex",
+            TexEngine::LuaLatex => "-lualatex",
+        }
+    }
+}
+
+/// Settings saved with a project in `<root>/.sokutex/project.json`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProjectSettings {
+    pub engine: TexEngine,
+    /// The root file, relative to the project directory; detected when unset
+    pub root: Option<String>,
+}
+
+impl ProjectSettings {
+    pub fn path(root_dir: &str) -> PathBuf {
+        Path::new(root_dir).join(crate::io::STATE_DIR).join("project.json")
+    }
+
+    /// The saved settings, or the defaults when there are none or they cannot be read.
+    pub fn load(root_dir: &str) -> Self {
+        let value: Option<Value> = std::fs::read(Self::path(root_dir)).ok().and_then(|data| serde_json::from_slice(&data).ok());
+        let Some(value) = value else { return Self::default() };
+        Self {
+            engine: value["engine"].as_str().and_then(TexEngine::from_name).unwrap_or_default(),
+            root: value["root"].as_str().filter(|root| !root.is_empty()).map(|root| root.to_string()),
+        }
+    }
+
+    pub fn save(&self, root_dir: &str) -> std::io::Result<()> {
+        let value = json!({ "engine": self.engine.name(), "root": self.root });
+        crate::io::IoHandler::write_atomic_sync(&Self::path(root_dir), value.to_string().as_bytes())
+    }
+}
+
 pub struct Config {
     pub background_color: [f32; 4],
 }
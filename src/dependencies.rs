@@ -2,6 +2,7 @@ use regex::Regex;
 use dashmap::DashSet;
 use rayon::prelude::*;
 use crate::vfs::Vfs;
+use crate::symbols::{Symbol, SymbolScanner};
 
 #[derive(Debug, Clone)]
 pub struct OutlineItem {
@@ -16,6 +17,7 @@ pub struct DependencyNode {
     pub name: String,
     pub children: Vec<DependencyNode>,
     pub outline: Vec<OutlineItem>,
+    pub symbols: Vec<Symbol>,
 }
 
 use std::sync::OnceLock;
@@ -35,6 +37,7 @@ impl DependencyScanner {
             name: file_name.to_string(),
             children: Vec::new(),
             outline: Vec::new(),
+            symbols: Vec::new(),
         };
 
         if visited.contains(file_name) {
@@ -66,6 +69,7 @@ impl DependencyScanner {
                 }
             }
             node.outline = outline_items;
+            node.symbols = SymbolScanner::scan(file_name, &content);
 
             let re = DEP_REGEX.get_or_init(|| Regex::new(r"\\(?:input|include|bibliography|usepackage)\{([^}]*)\}").unwrap());
             
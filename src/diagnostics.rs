@@ -0,0 +1,357 @@
+use regex::Regex;
+use std::path::Path;
+use std::sync::OnceLock;
+
+static FILE_LINE_ERROR_REGEX: OnceLock<Regex> = OnceLock::new();
+static TECTONIC_REGEX: OnceLock<Regex> = OnceLock::new();
+static WARNING_REGEX: OnceLock<Regex> = OnceLock::new();
+static INPUT_LINE_REGEX: OnceLock<Regex> = OnceLock::new();
+static BAD_BOX_REGEX: OnceLock<Regex> = OnceLock::new();
+static UNDEFINED_REGEX: OnceLock<Regex> = OnceLock::new();
+
+/// TeX wraps its log output at `max_print_line` characters.
+const MAX_PRINT_LINE: usize = 79;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    TexError,
+    LatexWarning,
+    PackageWarning(String),
+    BadBox,
+    UndefinedReference(String),
+    UndefinedCitation(String),
+    UnusedLabel(String),
+    BibSyntax,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn location(&self) -> String {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => format!("{}:{}", file, line),
+            (Some(file), None) => file.clone(),
+            (None, Some(line)) => format!("L{}", line),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+pub struct LogParser;
+
+impl LogParser {
+    /// Parse a TeX `.log` file, tracking the input file stack through `(`/`)` nesting.
+    pub fn parse(log: &str) -> Vec<Diagnostic> {
+        let lines = Self::unwrap_lines(log);
+        let mut diagnostics = Vec::new();
+        let mut file_stack: Vec<Option<String>> = Vec::new();
+
+        let file_line_re = FILE_LINE_ERROR_REGEX.get_or_init(|| Regex::new(r"^(.+\.\w+):(\d+): (.+)$").unwrap());
+        let warning_re = WARNING_REGEX.get_or_init(|| Regex::new(r"^(?:LaTeX(?: (\w+))?|Package (\S+)|Class (\S+)) Warning: (.*)$").unwrap());
+        let input_line_re = INPUT_LINE_REGEX.get_or_init(|| Regex::new(r"on input line (\d+)").unwrap());
+        let bad_box_re = BAD_BOX_REGEX.get_or_init(|| Regex::new(r"^((?:Over|Under)full \\[hv]box .*?)(?: (?:in paragraph|in alignment|detected) at lines? (\d+)(?:--\d+)?)?\s*$").unwrap());
+
+        let mut i = 0;
+        while i < lines.len() {
+            let line = &lines[i];
+            let current_file = file_stack.iter().rev().flatten().next().cloned();
+
+            if let Some(rest) = line.strip_prefix("! ") {
+                // Classic TeX error: the source line follows as `l.<n> <context>`
+                let mut message = rest.trim().to_string();
+                let mut line_num = None;
+                let mut j = i + 1;
+                while j < lines.len() && j <= i + 12 {
+                    let next = &lines[j];
+                    if let Some(num) = Self::parse_context_line(next) {
+                        line_num = Some(num);
+                        break;
+                    }
+                    if next.starts_with("! ") {
+                        break;
+                    }
+                    if j == i + 1 && !next.is_empty() && !next.starts_with("See the") && !next.starts_with("Type ") {
+                        // Errors such as `! LaTeX Error:` continue onto the next line
+                        if !message.ends_with('.') {
+                            message.push(' ');
+                            message.push_str(next.trim());
+                        }
+                    }
+                    j += 1;
+                }
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    kind: DiagnosticKind::TexError,
+                    file: current_file,
+                    line: line_num,
+                    message,
+                });
+                // Skip the help text and both halves of the context line, which echo user source
+                i = if line_num.is_some() { j + 2 } else { i + 1 };
+                continue;
+            }
+
+            if let Some(caps) = file_line_re.captures(line) {
+                // `-file-line-error` style: `./chapter.tex:12: Undefined control sequence.`
+                let file = Self::normalize(&caps[1]);
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Error,
+                    kind: DiagnosticKind::TexError,
+                    file: Some(file),
+                    line: caps[2].parse().ok(),
+                    message: caps[3].trim().to_string(),
+                });
+                let context = (i + 1..lines.len().min(i + 13)).find(|&j| Self::parse_context_line(&lines[j]).is_some());
+                i = context.map_or(i + 1, |j| j + 2);
+                continue;
+            }
+
+            if let Some(caps) = warning_re.captures(line) {
+                let package = caps.get(2).or(caps.get(3)).map(|m| m.as_str().to_string());
+                let prefix = caps.get(1).map(|m| m.as_str().to_string()).or_else(|| package.clone());
+                let mut message = caps[4].trim().to_string();
+
+                // Package warnings continue on lines indented with `(pkg)`, plain ones until the sentence ends
+                let mut j = i + 1;
+                while j < lines.len() && !lines[j].trim().is_empty() {
+                    let next = lines[j].trim_start();
+                    let continuation = match prefix {
+                        Some(ref p) => next.strip_prefix(&format!("({})", p)).map(|s| s.trim()),
+                        None if !message.ends_with('.') => Some(next),
+                        None => None,
+                    };
+                    match continuation {
+                        Some(text) => {
+                            message.push(' ');
+                            message.push_str(text);
+                            j += 1;
+                        }
+                        None => break,
+                    }
+                }
+
+                let line_num = input_line_re.captures(&message).and_then(|c| c[1].parse().ok());
+                let kind = Self::warning_kind(&message, package);
+
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    kind,
+                    file: current_file,
+                    line: line_num,
+                    message,
+                });
+                i = j;
+                continue;
+            }
+
+            if let Some(caps) = bad_box_re.captures(line) {
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Info,
+                    kind: DiagnosticKind::BadBox,
+                    file: current_file,
+                    line: caps.get(2).and_then(|m| m.as_str().parse().ok()),
+                    message: caps[1].trim().to_string(),
+                });
+                // The offending box contents follow until a blank line
+                i += 1;
+                while i < lines.len() && !lines[i].trim().is_empty() {
+                    i += 1;
+                }
+                continue;
+            }
+
+            Self::update_file_stack(line, &mut file_stack);
+            i += 1;
+        }
+
+        diagnostics
+    }
+
+    /// Parse the `error:`/`warning:` lines Tectonic prints on stderr.
+    pub fn parse_tectonic(output: &str) -> Vec<Diagnostic> {
+        let re = TECTONIC_REGEX.get_or_init(|| Regex::new(r"^(error|warning|note): (?:(.+?\.\w+):(\d+): )?(.*)$").unwrap());
+
+        output.lines()
+            .filter_map(|line| re.captures(line.trim_end()))
+            .filter(|caps| &caps[1] != "note")
+            .map(|caps| {
+                let message = caps[4].trim().to_string();
+                let (severity, kind) = if &caps[1] == "error" {
+                    (Severity::Error, DiagnosticKind::TexError)
+                } else if message.starts_with("Overfull") || message.starts_with("Underfull") {
+                    (Severity::Info, DiagnosticKind::BadBox)
+                } else {
+                    (Severity::Warning, Self::warning_kind(&message, None))
+                };
+                Diagnostic {
+                    severity,
+                    kind,
+                    file: caps.get(2).map(|m| Self::normalize(m.as_str())),
+                    line: caps.get(3).and_then(|m| m.as_str().parse().ok()),
+                    message,
+                }
+            })
+            .collect()
+    }
+
+    /// Rewrite file paths reported from a build directory so they match VFS paths.
+    pub fn relativize(diagnostics: &mut [Diagnostic], build_dir: &Path) {
+        for diag in diagnostics.iter_mut() {
+            if let Some(ref file) = diag.file {
+                if let Ok(rel) = Path::new(file).strip_prefix(build_dir) {
+                    diag.file = Some(rel.to_string_lossy().to_string());
+                }
+            }
+        }
+    }
+
+    fn warning_kind(message: &str, package: Option<String>) -> DiagnosticKind {
+        let re = UNDEFINED_REGEX.get_or_init(|| Regex::new(r"(Reference|Citation) [`']([^']+)' on page \d+ undefined").unwrap());
+        match re.captures(message) {
+            Some(caps) if &caps[1] == "Reference" => DiagnosticKind::UndefinedReference(caps[2].to_string()),
+            Some(caps) => DiagnosticKind::UndefinedCitation(caps[2].to_string()),
+            None => match package {
+                Some(pkg) => DiagnosticKind::PackageWarning(pkg),
+                None => DiagnosticKind::LatexWarning,
+            },
+        }
+    }
+
+    fn parse_context_line(line: &str) -> Option<usize> {
+        let rest = line.strip_prefix("l.")?;
+        let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+        digits.parse().ok()
+    }
+
+    fn normalize(path: &str) -> String {
+        path.trim().trim_start_matches("./").to_string()
+    }
+
+    /// Join lines that TeX hard-wrapped at `max_print_line`.
+    fn unwrap_lines(log: &str) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::new();
+        let mut continues = false;
+        for raw in log.lines() {
+            if continues {
+                if let Some(last) = lines.last_mut() {
+                    last.push_str(raw);
+                }
+            } else {
+                lines.push(raw.to_string());
+            }
+            continues = raw.chars().count() == MAX_PRINT_LINE;
+        }
+        lines
+    }
+
+    fn update_file_stack(line: &str, stack: &mut Vec<Option<String>>) {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '(' => {
+                    let token: String = chars[i + 1..].iter()
+                        .take_while(|c| !c.is_whitespace() && **c != '(' && **c != ')')
+                        .collect();
+                    i += token.chars().count();
+                    stack.push(if Self::looks_like_path(&token) { Some(Self::normalize(&token)) } else { None });
+                }
+                ')' => {
+                    stack.pop();
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    fn looks_like_path(token: &str) -> bool {
+        if token.is_empty() || token.starts_with(|c: char| c.is_ascii_digit()) {
+            return false;
+        }
+        match token.rsplit_once('.') {
+            Some((stem, ext)) => !stem.is_empty() && !ext.is_empty() && ext.chars().all(|c| c.is_ascii_alphanumeric()),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_LOG: &str = r#"This is pdfTeX, Version 3.141592653-2.6-1.40.25 (TeX Live 2023) (preloaded format=pdflatex)
+(./main.tex
+LaTeX2e <2022-11-01> patch level 1
+(/usr/share/texlive/texmf-dist/tex/latex/base/article.cls
+Document Class: article 2022/07/02 v1.4n Standard LaTeX document class
+(/usr/share/texlive/texmf-dist/tex/latex/base/size10.clo))
+(./chapters/intro.tex
+! Undefined control sequence.
+l.7 \textbfz
+            {Hello}
+)
+Overfull \hbox (12.0pt too wide) in paragraph at lines 14--15
+[]\OT1/cmr/m/n/10 Some long text
+
+LaTeX Warning: Reference `fig:plot' on page 1 undefined on input line 21.
+
+Package natbib Warning: Citation `einstein' on page 1 undefined on input line 2
+4.
+
+Package hyperref Warning: Token not allowed in a PDF string (Unicode):
+(hyperref)                removing `math shift' on input line 30.
+
+[1] (./main.aux) )
+Output written on main.pdf (1 page, 1234 bytes).
+"#;
+
+    #[test]
+    fn test_parse_log_diagnostics() {
+        let diags = LogParser::parse(SAMPLE_LOG);
+        assert_eq!(diags.len(), 5);
+
+        assert_eq!(diags[0].severity, Severity::Error);
+        assert_eq!(diags[0].file.as_deref(), Some("chapters/intro.tex"));
+        assert_eq!(diags[0].line, Some(7));
+        assert_eq!(diags[0].message, "Undefined control sequence.");
+
+        assert_eq!(diags[1].kind, DiagnosticKind::BadBox);
+        assert_eq!(diags[1].file.as_deref(), Some("main.tex"));
+        assert_eq!(diags[1].line, Some(14));
+
+        assert_eq!(diags[2].kind, DiagnosticKind::UndefinedReference("fig:plot".into()));
+        assert_eq!(diags[2].line, Some(21));
+
+        assert_eq!(diags[3].kind, DiagnosticKind::UndefinedCitation("einstein".into()));
+        assert_eq!(diags[3].line, Some(24));
+
+        assert_eq!(diags[4].kind, DiagnosticKind::PackageWarning("hyperref".into()));
+        assert_eq!(diags[4].line, Some(30));
+    }
+
+    #[test]
+    fn test_parse_tectonic_output() {
+        let stderr = "note: Running TeX ...\nerror: main.tex:5: Undefined control sequence\nwarning: main.tex:9: Overfull \\hbox (3.0pt too wide) in paragraph at lines 9--9\nerror: halted on potentially-recoverable error as specified\n";
+        let diags = LogParser::parse_tectonic(stderr);
+        assert_eq!(diags.len(), 3);
+        assert_eq!(diags[0].line, Some(5));
+        assert_eq!(diags[0].file.as_deref(), Some("main.tex"));
+        assert_eq!(diags[1].severity, Severity::Info);
+        assert_eq!(diags[2].file, None);
+    }
+}
@@ -1,4 +1,15 @@
 use ropey::Rope;
+use ahash::AHashMap;
+use regex::Regex;
+use crate::undo::{Edit, Group, HistoryMove, UndoTree};
+use std::path::PathBuf;
+
+const ESC: char = '\u{1b}';
+const BACKSPACE: char = '\u{8}';
+const CTRL_R: char = '\u{12}';
+const SHIFT_WIDTH: &str = "    ";
+/// Larger counts are clamped, so `9999999999dd` cannot overflow a position.
+const MAX_COUNT: usize = 999_999;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EditorMode {
@@ -7,6 +18,118 @@ pub enum EditorMode {
     Visual,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Outdent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward { big: bool },
+    WordBackward { big: bool },
+    WordEnd { big: bool },
+    ParagraphForward,
+    ParagraphBackward,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    FirstLine,
+    LastLine,
+    FindForward(char),
+    TillForward(char),
+    FindBackward(char),
+    TillBackward(char),
+    RepeatFind,
+    RepeatFindReverse,
+    SearchNext,
+    SearchPrev,
+}
+
+/// `w`, `e` (LaTeX environment), `$` (inline math) and `c` (command argument).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextObject {
+    Word,
+    Environment,
+    InlineMath,
+    Argument,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MotionKind {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Motion(Motion),
+    Object { inner: bool, object: TextObject },
+    /// `dd`, `yy`, `cc`, `>>`: whole lines
+    Line,
+    /// The visual selection
+    Selection,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InsertAt {
+    Cursor,
+    After,
+    LineStart,
+    LineEnd,
+    OpenBelow,
+    OpenAbove,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Move(Motion),
+    Operate(Operator, Target),
+    Insert(InsertAt),
+    Replace(char),
+    Put { before: bool },
+    History(HistoryMove),
+    Repeat,
+    StartVisual,
+    ExitVisual,
+    Select { inner: bool, object: TextObject },
+    StartSearch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Command {
+    register: Option<char>,
+    count: Option<usize>,
+    action: Action,
+}
+
+enum Partial<T> {
+    Incomplete,
+    Invalid,
+    Done(T),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Register {
+    pub text: String,
+    pub linewise: bool,
+}
+
+/// The last buffer change, replayed by `.`
+#[derive(Debug, Clone)]
+struct Change {
+    command: Command,
+    inserted: String,
+}
+
 #[allow(dead_code)]
 pub struct Editor {
     pub buffer: Rope,
@@ -14,8 +137,21 @@ pub struct Editor {
     pub mode: EditorMode,
     pub entries: Vec<crate::bib::BibEntry>,
     pub visual_anchor: Option<usize>,
-    pub history: Vec<(Rope, usize, EditorMode, Option<usize>)>,
-    pub redo_stack: Vec<(Rope, usize, EditorMode, Option<usize>)>,
+    pub undo_tree: UndoTree,
+    /// Where the undo history is persisted between sessions
+    pub history_file: Option<PathBuf>,
+    /// `"` is the unnamed register, `0` the last yank, `a`-`z` named registers
+    pub registers: AHashMap<char, Register>,
+    /// The `/` prompt while it is being typed
+    pub search_input: Option<String>,
+    pub last_search: Option<String>,
+    /// Normal/visual keys typed so far that do not form a complete command yet
+    pending: Vec<char>,
+    last_find: Option<Motion>,
+    last_change: Option<Change>,
+    recording_insert: bool,
+    /// The buffer and cursor before the change being made, set by `snapshot`
+    change_start: Option<(Rope, usize)>,
 }
 
 #[allow(dead_code)]
@@ -27,99 +163,1161 @@ impl Editor {
             mode: EditorMode::Normal,
             entries: Vec::new(),
             visual_anchor: None,
-            history: Vec::new(),
-            redo_stack: Vec::new(),
+            undo_tree: UndoTree::new(),
+            history_file: None,
+            registers: AHashMap::new(),
+            search_input: None,
+            last_search: None,
+            pending: Vec::new(),
+            last_find: None,
+            last_change: None,
+            recording_insert: false,
+            change_start: None,
+        }
+    }
+
+    /// Load `text` as a fresh buffer, restoring its saved undo history if it still matches.
+    pub fn open(&mut self, text: &str, history_file: Option<PathBuf>) {
+        self.buffer = Rope::from_str(text);
+        self.cursor = 0;
+        self.mode = EditorMode::Normal;
+        self.visual_anchor = None;
+        self.pending.clear();
+        self.change_start = None;
+        self.undo_tree = history_file.as_deref()
+            .and_then(|path| UndoTree::load(path, text))
+            .unwrap_or_default();
+        self.history_file = history_file;
+    }
+
+    pub fn save_history(&self) -> std::io::Result<()> {
+        match self.history_file {
+            Some(ref path) => self.undo_tree.save(path, &self.buffer.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Take over text edited elsewhere (the GUI text box), recording the difference as typing.
+    pub fn replace_text(&mut self, text: &str) {
+        let after = Rope::from_str(text);
+        if let Some(edit) = Edit::diff(&self.buffer, &after) {
+            let cursor_after = edit.pos + edit.inserted.chars().count();
+            self.undo_tree.record(edit, self.cursor, cursor_after, Group::Typing);
+            self.cursor = cursor_after;
         }
+        self.buffer = after;
+    }
+
+    /// Undo, redo or travel through the history. Returns the new cursor if anything changed.
+    pub fn apply_history(&mut self, step: HistoryMove) -> Option<usize> {
+        self.commit_change(Group::New);
+        self.undo_tree.seal();
+        let cursor = self.undo_tree.apply(&mut self.buffer, step)?;
+        self.cursor = cursor.min(self.buffer.len_chars());
+        Some(self.cursor)
     }
 
+    /// Mark the start of a change; `commit_change` records everything since as one undo step.
     pub fn snapshot(&mut self) {
-        self.history.push((self.buffer.clone(), self.cursor, self.mode, self.visual_anchor));
-        self.redo_stack.clear();
+        if self.change_start.is_none() {
+            self.change_start = Some((self.buffer.clone(), self.cursor));
+        }
     }
 
-    pub fn undo(&mut self) {
-        if let Some((buf, cur, mode, anchor)) = self.history.pop() {
-            self.redo_stack.push((self.buffer.clone(), self.cursor, self.mode, self.visual_anchor));
-            self.buffer = buf;
-            self.cursor = cur;
-            self.mode = mode;
-            self.visual_anchor = anchor;
+    fn commit_change(&mut self, group: Group) {
+        if let Some((before, cursor)) = self.change_start.take() {
+            if let Some(edit) = Edit::diff(&before, &self.buffer) {
+                self.undo_tree.record(edit, cursor, self.cursor, group);
+            }
         }
     }
 
+    pub fn undo(&mut self) {
+        self.apply_history(HistoryMove::Undo);
+    }
+
     pub fn redo(&mut self) {
-        if let Some((buf, cur, mode, anchor)) = self.redo_stack.pop() {
-            self.history.push((self.buffer.clone(), self.cursor, self.mode, self.visual_anchor));
-            self.buffer = buf;
-            self.cursor = cur;
-            self.mode = mode;
-            self.visual_anchor = anchor;
-        }
+        self.apply_history(HistoryMove::Redo);
     }
 
+    /// Feed one key. Escape is `\u{1b}`, Backspace `\u{8}`, Ctrl-r `\u{12}` and Enter `\n`.
     pub fn handle_key(&mut self, c: char) {
+        if self.search_input.is_some() {
+            self.handle_search_key(c);
+            return;
+        }
         match self.mode {
-            EditorMode::Normal => self.handle_normal_key(c),
+            EditorMode::Normal | EditorMode::Visual => {
+                self.pending.push(c);
+                match Self::parse_command(&self.pending, self.mode == EditorMode::Visual) {
+                    Partial::Incomplete => {}
+                    Partial::Invalid => self.pending.clear(),
+                    Partial::Done(command) => {
+                        self.pending.clear();
+                        self.execute(command, true);
+                        // An insert session continues the step its command started
+                        self.commit_change(Group::New);
+                        if self.mode != EditorMode::Insert {
+                            self.undo_tree.seal();
+                        }
+                    }
+                }
+            }
             EditorMode::Insert => {
-                if c == '\u{1b}' {
-                    // Escape
+                if c == ESC {
                     self.mode = EditorMode::Normal;
+                    self.recording_insert = false;
+                    self.undo_tree.seal();
+                    return;
+                }
+                self.snapshot();
+                if c == BACKSPACE {
+                    self.delete_back();
                 } else {
-                    self.snapshot();
                     self.insert_char(c);
                 }
+                self.commit_change(Group::Continue);
+                if self.recording_insert {
+                    if let Some(ref mut change) = self.last_change {
+                        change.inserted.push(c);
+                    }
+                }
             }
-            EditorMode::Visual => self.handle_visual_key(c),
         }
     }
 
-    fn handle_normal_key(&mut self, c: char) {
+    fn handle_search_key(&mut self, c: char) {
+        let Some(ref mut input) = self.search_input else { return };
         match c {
-            'i' => self.mode = EditorMode::Insert,
-            'v' => {
-                self.mode = EditorMode::Visual;
-                self.visual_anchor = Some(self.cursor);
+            ESC => self.search_input = None,
+            BACKSPACE => {
+                if input.pop().is_none() {
+                    self.search_input = None;
+                }
             }
-            'h' => self.move_left(),
-            'j' => self.move_down(),
-            'k' => self.move_up(),
-            'l' => self.move_right(),
-            'x' => {
-                self.snapshot();
-                self.delete_char();
+            '\n' | '\r' => {
+                let pattern = std::mem::take(input);
+                self.search_input = None;
+                if !pattern.is_empty() {
+                    self.last_search = Some(pattern);
+                }
+                if let Some((pos, _)) = self.motion_target(Motion::SearchNext, None, false) {
+                    self.cursor = pos;
+                }
             }
-            'a' => {
-                self.snapshot();
-                self.move_right();
-                self.mode = EditorMode::Insert;
+            _ => input.push(c),
+        }
+    }
+
+    // ---- Command parsing ----
+
+    /// Parse `["x][count]command[count][motion|text object]`.
+    fn parse_command(keys: &[char], visual: bool) -> Partial<Command> {
+        let mut rest = keys;
+        let mut register = None;
+        if rest.first() == Some(&'"') {
+            match rest.get(1) {
+                None => return Partial::Incomplete,
+                Some(&r) if r == '"' || r == '_' || r.is_ascii_alphanumeric() => register = Some(r),
+                Some(_) => return Partial::Invalid,
             }
-            '0' => self.move_to_line_start(),
-            '$' => self.move_to_line_end(),
-            'u' => self.undo(),
-            'r' => self.redo(),
-            _ => {}
+            rest = &rest[2..];
         }
+
+        let (count, used) = Self::parse_count(rest);
+        rest = &rest[used..];
+        let Some(&key) = rest.first() else { return Partial::Incomplete };
+        let after = &rest[1..];
+
+        let operator = match key {
+            'd' | 'x' if visual => Some(Operator::Delete),
+            'c' | 's' if visual => Some(Operator::Change),
+            'd' => Some(Operator::Delete),
+            'c' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            '>' => Some(Operator::Indent),
+            '<' => Some(Operator::Outdent),
+            _ => None,
+        };
+
+        let done = |action| Partial::Done(Command { register, count, action });
+
+        if let Some(operator) = operator {
+            if visual {
+                return done(Action::Operate(operator, Target::Selection));
+            }
+            let (count2, used) = Self::parse_count(after);
+            let count = match (count, count2) {
+                (Some(a), Some(b)) => Some(a.saturating_mul(b).min(MAX_COUNT)),
+                (a, b) => a.or(b),
+            };
+            let target_keys = &after[used..];
+            let target = match target_keys.first() {
+                None => return Partial::Incomplete,
+                Some(&k) if k == key => Target::Line,
+                Some('i') | Some('a') => match Self::parse_object(&target_keys[1..]) {
+                    Partial::Done(object) => Target::Object { inner: target_keys[0] == 'i', object },
+                    Partial::Incomplete => return Partial::Incomplete,
+                    Partial::Invalid => return Partial::Invalid,
+                },
+                Some(_) => match Self::parse_motion(target_keys) {
+                    Partial::Done(motion) => Target::Motion(motion),
+                    Partial::Incomplete => return Partial::Incomplete,
+                    Partial::Invalid => return Partial::Invalid,
+                },
+            };
+            return Partial::Done(Command { register, count, action: Action::Operate(operator, target) });
+        }
+
+        let action = match key {
+            ESC | 'v' if visual => Action::ExitVisual,
+            'i' | 'a' if visual => match Self::parse_object(after) {
+                Partial::Done(object) => Action::Select { inner: key == 'i', object },
+                Partial::Incomplete => return Partial::Incomplete,
+                Partial::Invalid => return Partial::Invalid,
+            },
+            'y' if visual => Action::Operate(Operator::Yank, Target::Selection),
+            'i' => Action::Insert(InsertAt::Cursor),
+            'a' => Action::Insert(InsertAt::After),
+            'I' => Action::Insert(InsertAt::LineStart),
+            'A' => Action::Insert(InsertAt::LineEnd),
+            'o' => Action::Insert(InsertAt::OpenBelow),
+            'O' => Action::Insert(InsertAt::OpenAbove),
+            'x' => Action::Operate(Operator::Delete, Target::Motion(Motion::Right)),
+            'X' => Action::Operate(Operator::Delete, Target::Motion(Motion::Left)),
+            's' => Action::Operate(Operator::Change, Target::Motion(Motion::Right)),
+            'D' => Action::Operate(Operator::Delete, Target::Motion(Motion::LineEnd)),
+            'C' => Action::Operate(Operator::Change, Target::Motion(Motion::LineEnd)),
+            'Y' => Action::Operate(Operator::Yank, Target::Line),
+            'r' => match after.first() {
+                None => return Partial::Incomplete,
+                Some(&ESC) => return Partial::Invalid,
+                Some(&c) => Action::Replace(c),
+            },
+            'p' => Action::Put { before: false },
+            'P' => Action::Put { before: true },
+            'u' => Action::History(HistoryMove::Undo),
+            CTRL_R => Action::History(HistoryMove::Redo),
+            'g' if after.first() == Some(&'-') => Action::History(HistoryMove::Earlier),
+            'g' if after.first() == Some(&'+') => Action::History(HistoryMove::Later),
+            '.' => Action::Repeat,
+            'v' => Action::StartVisual,
+            '/' => Action::StartSearch,
+            _ => match Self::parse_motion(rest) {
+                Partial::Done(motion) => Action::Move(motion),
+                Partial::Incomplete => return Partial::Incomplete,
+                Partial::Invalid => return Partial::Invalid,
+            },
+        };
+        done(action)
     }
 
-    fn handle_visual_key(&mut self, c: char) {
-        match c {
-            '\u{1b}' => {
-                // Escape
+    /// A count never starts with `0`, which is the line-start motion.
+    fn parse_count(keys: &[char]) -> (Option<usize>, usize) {
+        if !keys.first().is_some_and(|c| ('1'..='9').contains(c)) {
+            return (None, 0);
+        }
+        let digits: String = keys.iter().take_while(|c| c.is_ascii_digit()).collect();
+        // Only digits, so parsing fails on overflow alone
+        (Some(digits.parse().unwrap_or(MAX_COUNT).min(MAX_COUNT)), digits.len())
+    }
+
+    fn parse_motion(keys: &[char]) -> Partial<Motion> {
+        let Some(&key) = keys.first() else { return Partial::Incomplete };
+        let motion = match key {
+            'h' | BACKSPACE => Motion::Left,
+            'l' | ' ' => Motion::Right,
+            'j' => Motion::Down,
+            'k' => Motion::Up,
+            'w' => Motion::WordForward { big: false },
+            'W' => Motion::WordForward { big: true },
+            'b' => Motion::WordBackward { big: false },
+            'B' => Motion::WordBackward { big: true },
+            'e' => Motion::WordEnd { big: false },
+            'E' => Motion::WordEnd { big: true },
+            '}' => Motion::ParagraphForward,
+            '{' => Motion::ParagraphBackward,
+            '0' => Motion::LineStart,
+            '^' => Motion::FirstNonBlank,
+            '$' => Motion::LineEnd,
+            'G' => Motion::LastLine,
+            ';' => Motion::RepeatFind,
+            ',' => Motion::RepeatFindReverse,
+            'n' => Motion::SearchNext,
+            'N' => Motion::SearchPrev,
+            'g' => match keys.get(1) {
+                None => return Partial::Incomplete,
+                Some('g') => Motion::FirstLine,
+                Some(_) => return Partial::Invalid,
+            },
+            'f' | 't' | 'F' | 'T' => match keys.get(1) {
+                None => return Partial::Incomplete,
+                Some(&ESC) => return Partial::Invalid,
+                Some(&c) => match key {
+                    'f' => Motion::FindForward(c),
+                    't' => Motion::TillForward(c),
+                    'F' => Motion::FindBackward(c),
+                    _ => Motion::TillBackward(c),
+                },
+            },
+            _ => return Partial::Invalid,
+        };
+        Partial::Done(motion)
+    }
+
+    fn parse_object(keys: &[char]) -> Partial<TextObject> {
+        match keys.first() {
+            None => Partial::Incomplete,
+            Some('w') => Partial::Done(TextObject::Word),
+            Some('e') => Partial::Done(TextObject::Environment),
+            Some('$') => Partial::Done(TextObject::InlineMath),
+            Some('c') => Partial::Done(TextObject::Argument),
+            Some(_) => Partial::Invalid,
+        }
+    }
+
+    // ---- Command execution ----
+
+    fn execute(&mut self, command: Command, record: bool) {
+        let count = command.count.unwrap_or(1);
+        match command.action {
+            Action::Move(motion) => {
+                if let Some((pos, _)) = self.motion_target(motion, command.count, false) {
+                    self.remember_find(motion);
+                    self.cursor = pos;
+                }
+                if self.mode == EditorMode::Normal {
+                    self.clamp_cursor();
+                }
+            }
+            Action::Operate(operator, target) => {
+                let Some((start, end, linewise)) = self.target_range(operator, target, command.count) else {
+                    return;
+                };
+                if let Target::Motion(motion) = target {
+                    self.remember_find(motion);
+                }
+                if operator != Operator::Yank {
+                    self.snapshot();
+                }
+                self.apply_operator(operator, start, end, linewise, command.register);
+                if self.mode == EditorMode::Visual {
+                    self.mode = EditorMode::Normal;
+                    self.visual_anchor = None;
+                }
+                if record && operator != Operator::Yank && target != Target::Selection {
+                    self.record_change(command);
+                }
+            }
+            Action::Insert(at) => {
+                self.snapshot();
+                self.start_insert(at);
+                if record {
+                    self.record_change(command);
+                }
+            }
+            Action::Replace(c) => {
+                let line_end = self.line_content_end(self.line_of(self.cursor));
+                if self.cursor + count > line_end {
+                    return;
+                }
+                self.snapshot();
+                self.buffer.remove(self.cursor..self.cursor + count);
+                self.buffer.insert(self.cursor, &c.to_string().repeat(count));
+                self.cursor += count - 1;
+                if record {
+                    self.record_change(command);
+                }
+            }
+            Action::Put { before } => {
+                let register = command.register.unwrap_or('"');
+                let Some(content) = self.registers.get(&register).cloned() else { return };
+                self.snapshot();
+                self.put(&content, before, count);
+                if record {
+                    self.record_change(command);
+                }
+            }
+            Action::History(step) => {
+                for _ in 0..count {
+                    self.apply_history(step);
+                }
                 self.mode = EditorMode::Normal;
                 self.visual_anchor = None;
+                self.clamp_cursor();
             }
-            'h' => self.move_left(),
-            'j' => self.move_down(),
-            'k' => self.move_up(),
-            'l' => self.move_right(),
-            'd' | 'x' => {
-                self.snapshot();
-                self.delete_selection();
+            Action::Repeat => self.repeat_change(command.count),
+            Action::StartVisual => {
+                self.mode = EditorMode::Visual;
+                self.visual_anchor = Some(self.cursor);
+            }
+            Action::ExitVisual => {
                 self.mode = EditorMode::Normal;
                 self.visual_anchor = None;
+                self.clamp_cursor();
+            }
+            Action::Select { inner, object } => {
+                if let Some((start, end)) = self.text_object(object, inner) {
+                    if end > start {
+                        self.visual_anchor = Some(start);
+                        self.cursor = end - 1;
+                    }
+                }
+            }
+            Action::StartSearch => self.search_input = Some(String::new()),
+        }
+    }
+
+    fn remember_find(&mut self, motion: Motion) {
+        if matches!(motion, Motion::FindForward(_) | Motion::TillForward(_) | Motion::FindBackward(_) | Motion::TillBackward(_)) {
+            self.last_find = Some(motion);
+        }
+    }
+
+    fn record_change(&mut self, command: Command) {
+        self.last_change = Some(Change { command, inserted: String::new() });
+        self.recording_insert = self.mode == EditorMode::Insert;
+    }
+
+    /// `.`: replay the last change, with a new count if one was given.
+    fn repeat_change(&mut self, count: Option<usize>) {
+        let Some(change) = self.last_change.clone() else { return };
+        let mut command = change.command;
+        if count.is_some() {
+            command.count = count;
+        }
+        self.execute(command, false);
+        if self.mode == EditorMode::Insert {
+            for c in change.inserted.chars() {
+                if c == BACKSPACE {
+                    self.delete_back();
+                } else {
+                    self.insert_char(c);
+                }
+            }
+            self.mode = EditorMode::Normal;
+            self.clamp_cursor();
+        }
+    }
+
+    fn start_insert(&mut self, at: InsertAt) {
+        let line = self.line_of(self.cursor);
+        let line_end = self.line_content_end(line);
+        self.cursor = match at {
+            InsertAt::Cursor => self.cursor,
+            InsertAt::After => (self.cursor + 1).min(line_end),
+            InsertAt::LineStart => self.first_non_blank(line),
+            InsertAt::LineEnd => line_end,
+            InsertAt::OpenBelow => {
+                self.buffer.insert_char(line_end, '\n');
+                line_end + 1
+            }
+            InsertAt::OpenAbove => {
+                let start = self.buffer.line_to_char(line);
+                self.buffer.insert_char(start, '\n');
+                start
+            }
+        };
+        self.mode = EditorMode::Insert;
+    }
+
+    /// Resolve an operator target to a `start..end` char range and whether it is linewise.
+    fn target_range(&self, operator: Operator, target: Target, count: Option<usize>) -> Option<(usize, usize, bool)> {
+        let len = self.buffer.len_chars();
+        match target {
+            Target::Line => {
+                let line = self.line_of(self.cursor);
+                let last = (line + count.unwrap_or(1) - 1).min(self.last_line());
+                Some((self.buffer.line_to_char(line), self.line_content_end(last), true))
+            }
+            Target::Selection => {
+                let anchor = self.visual_anchor.unwrap_or(self.cursor);
+                Some((anchor.min(self.cursor), (anchor.max(self.cursor) + 1).min(len), false))
+            }
+            Target::Object { inner, object } => self.text_object(object, inner).map(|(start, end)| (start, end, false)),
+            Target::Motion(motion) => {
+                // `cw` on a word behaves like `ce`
+                let on_word = self.char_at(self.cursor).is_some_and(|c| !c.is_whitespace());
+                let motion = match motion {
+                    Motion::WordForward { big } if operator == Operator::Change && on_word => Motion::WordEnd { big },
+                    other => other,
+                };
+                let (target, kind) = self.motion_target(motion, count, true)?;
+                let start = self.cursor.min(target);
+                let mut end = self.cursor.max(target);
+                match kind {
+                    MotionKind::Linewise => return Some((start, end, true)),
+                    MotionKind::Inclusive => end = (end + 1).min(len),
+                    MotionKind::Exclusive => {}
+                }
+                // `dw` on the last word of a line stops at the line end
+                if matches!(motion, Motion::WordForward { .. }) && self.line_of(end) > self.line_of(start) {
+                    while end > start && self.char_at(end - 1).is_some_and(char::is_whitespace) {
+                        end -= 1;
+                    }
+                }
+                Some((start, end, false))
+            }
+        }
+    }
+
+    fn apply_operator(&mut self, operator: Operator, start: usize, end: usize, linewise: bool, register: Option<char>) {
+        let (start, end) = if linewise { self.expand_to_lines(start, end) } else { (start, end) };
+        match operator {
+            Operator::Yank => {
+                let text = self.register_text(start, end, linewise);
+                self.write_register(register, text, linewise, true);
+                if !linewise || self.line_of(start) < self.line_of(self.cursor) {
+                    self.cursor = start;
+                }
+            }
+            Operator::Delete => {
+                let text = self.register_text(start, end, linewise);
+                self.write_register(register, text, linewise, false);
+                let (start, end) = if linewise { self.with_trailing_line_break(start, end) } else { (start, end) };
+                self.buffer.remove(start..end);
+                self.cursor = start.min(self.buffer.len_chars());
+                if linewise {
+                    self.cursor = self.first_non_blank(self.line_of(self.cursor).min(self.last_line()));
+                }
+                self.clamp_cursor();
+            }
+            Operator::Change => {
+                let text = self.register_text(start, end, linewise);
+                self.write_register(register, text, linewise, false);
+                // Linewise changes keep an empty line to type into
+                let end = if linewise && self.char_at(end.saturating_sub(1)) == Some('\n') && end > start { end - 1 } else { end };
+                self.buffer.remove(start..end);
+                self.cursor = start;
+                self.mode = EditorMode::Insert;
+            }
+            Operator::Indent | Operator::Outdent => {
+                let first = self.line_of(start);
+                let last = self.line_of(end.saturating_sub(1).max(start));
+                for line in (first..=last).rev() {
+                    let line_start = self.buffer.line_to_char(line);
+                    if operator == Operator::Indent {
+                        if self.line_content_end(line) > line_start {
+                            self.buffer.insert(line_start, SHIFT_WIDTH);
+                        }
+                    } else {
+                        let mut remove = 0;
+                        while remove < SHIFT_WIDTH.len() && self.char_at(line_start + remove) == Some(' ') {
+                            remove += 1;
+                        }
+                        if remove == 0 && self.char_at(line_start) == Some('\t') {
+                            remove = 1;
+                        }
+                        self.buffer.remove(line_start..line_start + remove);
+                    }
+                }
+                self.cursor = self.first_non_blank(first);
+            }
+        }
+    }
+
+    /// Whole lines covering `start..end`, including the final line break.
+    fn expand_to_lines(&self, start: usize, end: usize) -> (usize, usize) {
+        let first = self.line_of(start);
+        let last = self.line_of(end.saturating_sub(1).max(start));
+        let line_end = self.line_content_end(last);
+        let end = if self.char_at(line_end) == Some('\n') { line_end + 1 } else { line_end };
+        (self.buffer.line_to_char(first), end)
+    }
+
+    /// Deleting the last line also takes the line break before it.
+    fn with_trailing_line_break(&self, start: usize, end: usize) -> (usize, usize) {
+        if end == self.buffer.len_chars() && start > 0 && self.char_at(end.saturating_sub(1)) != Some('\n') {
+            (start - 1, end)
+        } else {
+            (start, end)
+        }
+    }
+
+    fn register_text(&self, start: usize, end: usize, linewise: bool) -> String {
+        let mut text = self.buffer.slice(start..end).to_string();
+        if linewise && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text
+    }
+
+    fn write_register(&mut self, register: Option<char>, text: String, linewise: bool, yank: bool) {
+        let content = Register { text, linewise };
+        match register {
+            Some('_') => return,
+            Some(r) if r.is_ascii_uppercase() => {
+                let entry = self.registers.entry(r.to_ascii_lowercase()).or_default();
+                entry.text.push_str(&content.text);
+                entry.linewise |= content.linewise;
+            }
+            Some(r) if r != '"' => {
+                self.registers.insert(r, content.clone());
             }
             _ => {}
         }
+        if yank && register.is_none() {
+            self.registers.insert('0', content.clone());
+        }
+        self.registers.insert('"', content);
+    }
+
+    fn put(&mut self, content: &Register, before: bool, count: usize) {
+        let text = content.text.repeat(count);
+        let line = self.line_of(self.cursor);
+        if content.linewise {
+            let at = if before {
+                self.buffer.line_to_char(line)
+            } else {
+                let line_end = self.line_content_end(line);
+                if self.char_at(line_end) == Some('\n') {
+                    line_end + 1
+                } else {
+                    // Last line without a line break: open one first
+                    self.buffer.insert_char(line_end, '\n');
+                    let text = text.strip_suffix('\n').unwrap_or(&text);
+                    self.buffer.insert(line_end + 1, text);
+                    self.cursor = self.first_non_blank(line + 1);
+                    return;
+                }
+            };
+            self.buffer.insert(at, &text);
+            self.cursor = self.first_non_blank(self.line_of(at));
+        } else {
+            let line_end = self.line_content_end(line);
+            let at = if before { self.cursor } else { (self.cursor + 1).min(line_end) };
+            self.buffer.insert(at, &text);
+            self.cursor = at + text.chars().count().saturating_sub(1);
+        }
+    }
+
+    // ---- Motions ----
+
+    fn motion_target(&self, motion: Motion, count: Option<usize>, for_operator: bool) -> Option<(usize, MotionKind)> {
+        let n = count.unwrap_or(1).max(1);
+        let len = self.buffer.len_chars();
+        let pos = self.cursor.min(len);
+        let line = self.line_of(pos);
+        let line_start = self.buffer.line_to_char(line);
+        let line_end = self.line_content_end(line);
+
+        let target = match motion {
+            Motion::Left => (pos.saturating_sub(n).max(line_start), MotionKind::Exclusive),
+            Motion::Right => {
+                let limit = if for_operator { line_end } else { line_end.saturating_sub(1).max(line_start) };
+                ((pos + n).min(limit), MotionKind::Exclusive)
+            }
+            Motion::Up | Motion::Down => {
+                let target_line = if motion == Motion::Up { line.checked_sub(n)? } else { line.saturating_add(n) };
+                if target_line > self.last_line() {
+                    return None;
+                }
+                let start = self.buffer.line_to_char(target_line);
+                let max_col = self.line_content_end(target_line) - start;
+                (start + (pos - line_start).min(max_col), MotionKind::Linewise)
+            }
+            Motion::WordForward { big } => {
+                let mut p = pos;
+                for _ in 0..n {
+                    p = self.next_word_start(p, big);
+                }
+                (p, MotionKind::Exclusive)
+            }
+            Motion::WordEnd { big } => {
+                let mut p = pos;
+                for _ in 0..n {
+                    p = self.next_word_end(p, big);
+                }
+                (p, MotionKind::Inclusive)
+            }
+            Motion::WordBackward { big } => {
+                let mut p = pos;
+                for _ in 0..n {
+                    p = self.prev_word_start(p, big);
+                }
+                (p, MotionKind::Exclusive)
+            }
+            Motion::ParagraphForward => {
+                let mut l = line;
+                for _ in 0..n {
+                    l = self.next_paragraph(l);
+                }
+                let p = if l > self.last_line() { len } else { self.buffer.line_to_char(l) };
+                (p, MotionKind::Exclusive)
+            }
+            Motion::ParagraphBackward => {
+                let mut l = line;
+                for _ in 0..n {
+                    l = self.prev_paragraph(l);
+                }
+                (self.buffer.line_to_char(l), MotionKind::Exclusive)
+            }
+            Motion::LineStart => (line_start, MotionKind::Exclusive),
+            Motion::FirstNonBlank => (self.first_non_blank(line), MotionKind::Exclusive),
+            Motion::LineEnd => {
+                let target_line = line.saturating_add(n - 1).min(self.last_line());
+                let start = self.buffer.line_to_char(target_line);
+                let end = self.line_content_end(target_line);
+                if for_operator {
+                    (end, MotionKind::Exclusive)
+                } else {
+                    (end.saturating_sub(1).max(start), MotionKind::Inclusive)
+                }
+            }
+            Motion::FirstLine | Motion::LastLine => {
+                let default = if motion == Motion::FirstLine { 0 } else { self.last_line() };
+                let target_line = count.map_or(default, |c| c.saturating_sub(1)).min(self.last_line());
+                (self.first_non_blank(target_line), MotionKind::Linewise)
+            }
+            Motion::FindForward(c) | Motion::TillForward(c) => {
+                let mut p = pos;
+                for _ in 0..n {
+                    p = (p + 1..line_end).find(|&i| self.char_at(i) == Some(c))?;
+                }
+                let p = if matches!(motion, Motion::TillForward(_)) { p - 1 } else { p };
+                (p, MotionKind::Inclusive)
+            }
+            Motion::FindBackward(c) | Motion::TillBackward(c) => {
+                let mut p = pos;
+                for _ in 0..n {
+                    p = (line_start..p).rev().find(|&i| self.char_at(i) == Some(c))?;
+                }
+                let p = if matches!(motion, Motion::TillBackward(_)) { p + 1 } else { p };
+                (p, MotionKind::Exclusive)
+            }
+            Motion::RepeatFind | Motion::RepeatFindReverse => {
+                let find = self.last_find?;
+                let find = if motion == Motion::RepeatFind {
+                    find
+                } else {
+                    match find {
+                        Motion::FindForward(c) => Motion::FindBackward(c),
+                        Motion::TillForward(c) => Motion::TillBackward(c),
+                        Motion::FindBackward(c) => Motion::FindForward(c),
+                        Motion::TillBackward(c) => Motion::TillForward(c),
+                        other => other,
+                    }
+                };
+                return self.motion_target(find, count, for_operator);
+            }
+            Motion::SearchNext | Motion::SearchPrev => {
+                let pattern = self.last_search.as_deref()?;
+                let mut p = pos;
+                for _ in 0..n {
+                    p = self.search(pattern, p, motion == Motion::SearchNext)?;
+                }
+                (p, MotionKind::Exclusive)
+            }
+        };
+        Some(target)
+    }
+
+    /// Next match of `pattern` after (or before) `from`, wrapping around the buffer.
+    fn search(&self, pattern: &str, from: usize, forward: bool) -> Option<usize> {
+        let re = Regex::new(pattern).or_else(|_| Regex::new(&regex::escape(pattern))).ok()?;
+        let text = self.buffer.to_string();
+        let from = self.buffer.char_to_byte(from.min(self.buffer.len_chars()));
+        let starts: Vec<usize> = re.find_iter(&text).map(|m| m.start()).collect();
+        let found = if forward {
+            starts.iter().find(|&&s| s > from).or(starts.first())
+        } else {
+            starts.iter().rev().find(|&&s| s < from).or(starts.last())
+        };
+        found.map(|&byte| self.buffer.byte_to_char(byte))
+    }
+
+    fn next_word_start(&self, pos: usize, big: bool) -> usize {
+        let len = self.buffer.len_chars();
+        let mut p = pos;
+        if let Some(class) = self.class_at(p, big).filter(|&c| c != 0) {
+            while p < len && self.class_at(p, big) == Some(class) {
+                p += 1;
+            }
+        }
+        while p < len && self.class_at(p, big) == Some(0) {
+            // An empty line counts as a word
+            if self.char_at(p) == Some('\n') && self.char_at(p + 1) == Some('\n') && p >= pos {
+                return p + 1;
+            }
+            p += 1;
+        }
+        p
+    }
+
+    fn next_word_end(&self, pos: usize, big: bool) -> usize {
+        let len = self.buffer.len_chars();
+        let mut p = pos + 1;
+        while p < len && self.class_at(p, big) == Some(0) {
+            p += 1;
+        }
+        if p >= len {
+            return len.saturating_sub(1).max(pos.min(len));
+        }
+        let class = self.class_at(p, big);
+        while p + 1 < len && self.class_at(p + 1, big) == class {
+            p += 1;
+        }
+        p
+    }
+
+    fn prev_word_start(&self, pos: usize, big: bool) -> usize {
+        if pos == 0 {
+            return 0;
+        }
+        let mut p = pos - 1;
+        while p > 0 && self.class_at(p, big) == Some(0) {
+            p -= 1;
+        }
+        let class = self.class_at(p, big);
+        while p > 0 && self.class_at(p - 1, big) == class {
+            p -= 1;
+        }
+        p
+    }
+
+    /// The blank line after the paragraph at `line`, or one past the last line.
+    fn next_paragraph(&self, line: usize) -> usize {
+        let last = self.last_line();
+        let mut l = line;
+        while l <= last && self.is_blank_line(l) {
+            l += 1;
+        }
+        while l <= last && !self.is_blank_line(l) {
+            l += 1;
+        }
+        l
+    }
+
+    fn prev_paragraph(&self, line: usize) -> usize {
+        let mut l = line;
+        while l > 0 && self.is_blank_line(l) {
+            l -= 1;
+        }
+        while l > 0 && !self.is_blank_line(l) {
+            l -= 1;
+        }
+        l
+    }
+
+    // ---- Text objects ----
+
+    /// The `start..end` range of a text object around the cursor.
+    fn text_object(&self, object: TextObject, inner: bool) -> Option<(usize, usize)> {
+        match object {
+            TextObject::Word => self.word_object(inner),
+            TextObject::Environment => self.environment_object(inner),
+            TextObject::InlineMath => self.math_object(inner),
+            TextObject::Argument => self.argument_object(inner),
+        }
+    }
+
+    fn word_object(&self, inner: bool) -> Option<(usize, usize)> {
+        let len = self.buffer.len_chars();
+        let class = self.class_at(self.cursor, false)?;
+        let same = |i: usize| self.class_at(i, false) == Some(class) && self.char_at(i) != Some('\n');
+        let mut start = self.cursor;
+        while start > 0 && same(start - 1) {
+            start -= 1;
+        }
+        let mut end = self.cursor;
+        while end < len && same(end) {
+            end += 1;
+        }
+        if !inner {
+            let blank = |i: usize| self.char_at(i).is_some_and(|c| c == ' ' || c == '\t');
+            if end < len && blank(end) {
+                while end < len && blank(end) {
+                    end += 1;
+                }
+            } else {
+                while start > 0 && blank(start - 1) {
+                    start -= 1;
+                }
+            }
+        }
+        Some((start, end))
+    }
+
+    /// `ae` spans `\begin{env}` through `\end{env}`; `ie` is the body between them.
+    fn environment_object(&self, inner: bool) -> Option<(usize, usize)> {
+        static ENV_REGEX: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+        static ARGS_REGEX: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+        let env_re = ENV_REGEX.get_or_init(|| Regex::new(r"\\(begin|end)\s*\{([^}]*)\}").unwrap());
+
+        let text = self.buffer.to_string();
+        let cursor = self.buffer.char_to_byte(self.cursor.min(self.buffer.len_chars()));
+        let mut stack: Vec<(&str, usize, usize)> = Vec::new();
+        let mut best: Option<(usize, usize, usize, usize)> = None;
+        for cap in env_re.captures_iter(&text) {
+            let whole = cap.get(0).unwrap();
+            let name = cap.get(2).unwrap().as_str();
+            if &cap[1] == "begin" {
+                stack.push((name, whole.start(), whole.end()));
+            } else if let Some(i) = stack.iter().rposition(|(n, _, _)| *n == name) {
+                let (_, begin_start, begin_end) = stack[i];
+                stack.truncate(i);
+                let contains = begin_start <= cursor && cursor < whole.end();
+                // Environments close innermost first, so the first enclosing pair wins
+                if contains && best.is_none() {
+                    best = Some((begin_start, begin_end, whole.start(), whole.end()));
+                }
+            }
+        }
+        let (begin_start, begin_end, end_start, end_end) = best?;
+
+        let (start, end) = if inner {
+            // Skip the rest of the \begin line when it only holds arguments
+            let args_re = ARGS_REGEX.get_or_init(|| Regex::new(r"^(?:\s*(?:\[[^\]\n]*\]|\{[^}\n]*\}))*[ \t]*\n").unwrap());
+            let start = args_re.find(&text[begin_end..]).map_or(begin_end, |m| begin_end + m.end());
+            let end_line_start = text[..end_start].rfind('\n').map_or(0, |i| i + 1);
+            let end = if text[end_line_start..end_start].trim().is_empty() { end_line_start } else { end_start };
+            (start.min(end), end)
+        } else {
+            (begin_start, end_end)
+        };
+        Some((self.buffer.byte_to_char(start), self.buffer.byte_to_char(end)))
+    }
+
+    /// `a$` includes the `$`/`\(` delimiters, `i$` only the math inside.
+    fn math_object(&self, inner: bool) -> Option<(usize, usize)> {
+        let len = self.buffer.len_chars();
+        let mut pairs = Vec::new();
+        let mut dollar: Option<usize> = None;
+        let mut paren: Option<usize> = None;
+        let mut i = 0;
+        while i < len {
+            match self.char_at(i) {
+                Some('\\') => {
+                    match self.char_at(i + 1) {
+                        Some('(') => paren = Some(i),
+                        Some(')') => {
+                            if let Some(open) = paren.take() {
+                                pairs.push((open, i + 2, 2));
+                            }
+                        }
+                        _ => {}
+                    }
+                    i += 2;
+                    continue;
+                }
+                // $$...$$ is display math, not an inline formula
+                Some('$') if self.char_at(i + 1) == Some('$') => {
+                    i += 2;
+                    continue;
+                }
+                Some('$') => match dollar.take() {
+                    Some(open) => pairs.push((open, i + 1, 1)),
+                    None => dollar = Some(i),
+                },
+                _ => {}
+            }
+            i += 1;
+        }
+
+        let (start, end, delimiter) = pairs.into_iter()
+            .filter(|&(start, end, _)| start <= self.cursor && self.cursor < end)
+            .min_by_key(|&(start, end, _)| end - start)?;
+        Some(if inner { (start + delimiter, end - delimiter) } else { (start, end) })
+    }
+
+    /// `ic` is the argument under the cursor; `ac` the whole command with all its arguments.
+    fn argument_object(&self, inner: bool) -> Option<(usize, usize)> {
+        // On a command name, use its first argument
+        let (command_start, open) = match self.command_name_at(self.cursor) {
+            Some((start, name_end)) => (start, self.skip_optional_args(name_end).filter(|&p| self.char_at(p) == Some('{'))?),
+            None => {
+                let mut open = self.enclosing_brace(self.cursor)?;
+                loop {
+                    if let Some(start) = self.command_before_group(open) {
+                        break (start, open);
+                    }
+                    open = self.enclosing_brace(open.checked_sub(1)?)?;
+                }
+            }
+        };
+        let close = self.matching_close(open)?;
+        if inner {
+            return Some((open + 1, close));
+        }
+
+        // Extend over every argument group directly following the command
+        let mut end = close + 1;
+        while let Some(c @ ('{' | '[')) = self.char_at(end) {
+            let next = if c == '{' { self.matching_close(end) } else { (end..self.buffer.len_chars()).find(|&i| self.char_at(i) == Some(']')) };
+            match next {
+                Some(close) => end = close + 1,
+                None => break,
+            }
+        }
+        Some((command_start, end))
+    }
+
+    /// `(backslash, end of name)` when `pos` is on a `\name` control sequence.
+    fn command_name_at(&self, pos: usize) -> Option<(usize, usize)> {
+        let is_letter = |i: usize| self.char_at(i).is_some_and(|c| c.is_ascii_alphabetic() || c == '@');
+        let mut start = pos;
+        if self.char_at(pos) != Some('\\') {
+            if !is_letter(pos) {
+                return None;
+            }
+            while start > 0 && is_letter(start - 1) {
+                start -= 1;
+            }
+            start = start.checked_sub(1).filter(|&s| self.char_at(s) == Some('\\'))?;
+        }
+        let mut end = start + 1;
+        while is_letter(end) {
+            end += 1;
+        }
+        (end > start + 1).then_some((start, end))
+    }
+
+    fn skip_optional_args(&self, mut pos: usize) -> Option<usize> {
+        while self.char_at(pos) == Some('[') {
+            pos = (pos..self.buffer.len_chars()).find(|&i| self.char_at(i) == Some(']'))? + 1;
+        }
+        Some(pos)
+    }
+
+    /// The command whose argument list contains the group opening at `open`.
+    fn command_before_group(&self, open: usize) -> Option<usize> {
+        let mut p = open;
+        loop {
+            let prev = p.checked_sub(1)?;
+            match self.char_at(prev)? {
+                '}' => p = self.matching_open(prev)?,
+                ']' => p = (0..prev).rev().find(|&i| self.char_at(i) == Some('['))?,
+                c if c.is_ascii_alphabetic() || c == '@' => return self.command_name_at(prev).map(|(start, _)| start),
+                _ => return None,
+            }
+        }
+    }
+
+    fn is_escaped(&self, pos: usize) -> bool {
+        let mut backslashes = 0;
+        while pos > backslashes && self.char_at(pos - backslashes - 1) == Some('\\') {
+            backslashes += 1;
+        }
+        backslashes % 2 == 1
+    }
+
+    /// The `{` of the innermost brace group containing `pos`.
+    fn enclosing_brace(&self, pos: usize) -> Option<usize> {
+        let mut depth = 0;
+        let mut p = pos.min(self.buffer.len_chars().checked_sub(1)?);
+        loop {
+            match self.char_at(p) {
+                Some('}') if p != pos && !self.is_escaped(p) => depth += 1,
+                Some('{') if !self.is_escaped(p) => {
+                    if depth == 0 {
+                        return Some(p);
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            p = p.checked_sub(1)?;
+        }
+    }
+
+    fn matching_close(&self, open: usize) -> Option<usize> {
+        let mut depth = 0;
+        for p in open + 1..self.buffer.len_chars() {
+            match self.char_at(p) {
+                Some('{') if !self.is_escaped(p) => depth += 1,
+                Some('}') if !self.is_escaped(p) => {
+                    if depth == 0 {
+                        return Some(p);
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn matching_open(&self, close: usize) -> Option<usize> {
+        let mut depth = 0;
+        for p in (0..close).rev() {
+            match self.char_at(p) {
+                Some('}') if !self.is_escaped(p) => depth += 1,
+                Some('{') if !self.is_escaped(p) => {
+                    if depth == 0 {
+                        return Some(p);
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // ---- Buffer helpers ----
+
+    fn char_at(&self, pos: usize) -> Option<char> {
+        (pos < self.buffer.len_chars()).then(|| self.buffer.char(pos))
+    }
+
+    /// 0 for whitespace, 1 for word characters, 2 for punctuation (`big` words: 0 or 1).
+    fn class_at(&self, pos: usize, big: bool) -> Option<u8> {
+        let c = self.char_at(pos)?;
+        Some(if c.is_whitespace() {
+            0
+        } else if big || c.is_alphanumeric() || c == '_' {
+            1
+        } else {
+            2
+        })
+    }
+
+    fn line_of(&self, pos: usize) -> usize {
+        self.buffer.char_to_line(pos.min(self.buffer.len_chars()))
+    }
+
+    /// The index of the last line that holds text (a trailing newline does not start a new one).
+    fn last_line(&self) -> usize {
+        let lines = self.buffer.len_lines();
+        if lines > 1 && self.buffer.line(lines - 1).len_chars() == 0 {
+            lines - 2
+        } else {
+            lines - 1
+        }
+    }
+
+    /// Position of the line break ending `line`, or the end of the buffer.
+    fn line_content_end(&self, line: usize) -> usize {
+        let start = self.buffer.line_to_char(line);
+        let len = self.buffer.line(line).len_chars();
+        if len > 0 && self.buffer.char(start + len - 1) == '\n' {
+            start + len - 1
+        } else {
+            start + len
+        }
+    }
+
+    fn first_non_blank(&self, line: usize) -> usize {
+        let mut p = self.buffer.line_to_char(line);
+        let end = self.line_content_end(line);
+        while p < end && self.char_at(p).is_some_and(|c| c == ' ' || c == '\t') {
+            p += 1;
+        }
+        p
+    }
+
+    fn is_blank_line(&self, line: usize) -> bool {
+        self.buffer.line(line).chars().all(char::is_whitespace)
+    }
+
+    /// Normal mode keeps the cursor on a character, never on the line break.
+    fn clamp_cursor(&mut self) {
+        let line = self.line_of(self.cursor);
+        let start = self.buffer.line_to_char(line);
+        let end = self.line_content_end(line);
+        if self.cursor >= end && end > start {
+            self.cursor = end - 1;
+        }
     }
 
     pub fn insert_char(&mut self, c: char) {
@@ -140,17 +1338,6 @@ impl Editor {
         }
     }
 
-    fn delete_selection(&mut self) {
-        if let Some(anchor) = self.visual_anchor {
-            let start = anchor.min(self.cursor);
-            let end = anchor.max(self.cursor);
-            if start < end {
-                self.buffer.remove(start..end);
-                self.cursor = start;
-            }
-        }
-    }
-
     pub fn move_left(&mut self) {
         if self.cursor > 0 {
             self.cursor -= 1;
@@ -194,22 +1381,6 @@ impl Editor {
         }
     }
 
-    fn move_to_line_start(&mut self) {
-        let line_idx = self.buffer.char_to_line(self.cursor);
-        self.cursor = self.buffer.line_to_char(line_idx);
-    }
-
-    fn move_to_line_end(&mut self) {
-        let line_idx = self.buffer.char_to_line(self.cursor);
-        let line_start = self.buffer.line_to_char(line_idx);
-        let line_len = self.buffer.line(line_idx).len_chars();
-        if line_len > 0 && self.buffer.char(line_start + line_len - 1) == '\n' {
-            self.cursor = line_start + line_len - 1;
-        } else {
-            self.cursor = line_start + line_len;
-        }
-    }
-
     pub fn get_text(&self) -> String {
         self.buffer.to_string()
     }
@@ -246,6 +1417,9 @@ impl Editor {
                     self.cursor += 1;
                 }
                 
+                self.commit_change(Group::New);
+                self.undo_tree.seal();
+
                 // Reset cursor to start of snippet so jump logic finds the first placeholder
                 self.cursor = snippet_start;
             }
@@ -322,4 +1496,161 @@ mod tests {
         editor.handle_key('x'); // delete 'l'
         assert_eq!(editor.get_text(), "helo");
     }
+
+    fn editor_with(text: &str) -> Editor {
+        let mut editor = Editor::new();
+        editor.buffer = Rope::from_str(text);
+        editor
+    }
+
+    fn keys(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            editor.handle_key(c);
+        }
+    }
+
+    #[test]
+    fn test_vim_counts_and_operators() {
+        let mut editor = editor_with("one two three four five\n");
+        keys(&mut editor, "2dw");
+        assert_eq!(editor.get_text(), "three four five\n");
+        keys(&mut editor, "cwTHREE\u{1b}");
+        assert_eq!(editor.get_text(), "THREE four five\n");
+        keys(&mut editor, "w.");
+        assert_eq!(editor.get_text(), "THREE THREE five\n");
+        keys(&mut editor, "0d$");
+        assert_eq!(editor.get_text(), "\n");
+
+        let mut editor = editor_with("a\nb\nc\nd\n");
+        keys(&mut editor, "2dd");
+        assert_eq!(editor.get_text(), "c\nd\n");
+        keys(&mut editor, ">>");
+        assert_eq!(editor.get_text(), "    c\nd\n");
+        keys(&mut editor, "j.");
+        assert_eq!(editor.get_text(), "    c\n    d\n");
+        keys(&mut editor, "gg<j");
+        assert_eq!(editor.get_text(), "c\nd\n");
+
+        // `r` replaces a character and Ctrl-r redoes
+        keys(&mut editor, "rx");
+        assert_eq!(editor.get_text(), "x\nd\n");
+        keys(&mut editor, "u");
+        assert_eq!(editor.get_text(), "c\nd\n");
+        keys(&mut editor, "\u{12}");
+        assert_eq!(editor.get_text(), "x\nd\n");
+    }
+
+    #[test]
+    fn test_vim_huge_counts() {
+        let mut editor = editor_with("one two\nthree\nfour\n");
+        keys(&mut editor, "99999999999999999999j");
+        assert_eq!(editor.cursor, 0);
+        keys(&mut editor, "99999999999999999999$");
+        assert_eq!(editor.cursor, editor.buffer.len_chars() - 2);
+        keys(&mut editor, "gg9999999999x");
+        assert_eq!(editor.get_text(), "\nthree\nfour\n");
+        keys(&mut editor, "9999999999d9999999999d");
+        assert_eq!(editor.get_text(), "");
+    }
+
+    #[test]
+    fn test_vim_undo_groups() {
+        let mut editor = editor_with("");
+        keys(&mut editor, "ione two\u{1b}");
+        keys(&mut editor, "othree\u{1b}");
+        assert_eq!(editor.get_text(), "one two\nthree");
+        // Each insert session is a single step
+        keys(&mut editor, "u");
+        assert_eq!(editor.get_text(), "one two");
+        keys(&mut editor, "ggdw");
+        assert_eq!(editor.get_text(), "two");
+        // The undone insert is still reachable on the other branch
+        keys(&mut editor, "g-");
+        assert_eq!(editor.get_text(), "one two\nthree");
+        keys(&mut editor, "g-");
+        assert_eq!(editor.get_text(), "one two");
+        keys(&mut editor, "g+g+");
+        assert_eq!(editor.get_text(), "two");
+    }
+
+    #[test]
+    fn test_vim_motions_and_search() {
+        let mut editor = editor_with("first para\nstill first\n\nsecond para\n");
+        keys(&mut editor, "}");
+        assert_eq!(editor.cursor, 23);
+        keys(&mut editor, "G");
+        assert_eq!(editor.cursor, 24);
+        keys(&mut editor, "{");
+        assert_eq!(editor.cursor, 23);
+        keys(&mut editor, "gg$");
+        assert_eq!(editor.cursor, 9);
+        keys(&mut editor, "0fa");
+        assert_eq!(editor.cursor, 7);
+        keys(&mut editor, ";");
+        assert_eq!(editor.cursor, 9);
+        keys(&mut editor, "0dtp");
+        assert_eq!(editor.get_text(), "para\nstill first\n\nsecond para\n");
+
+        keys(&mut editor, "/para\n");
+        assert_eq!(editor.cursor, 25);
+        keys(&mut editor, "n");
+        assert_eq!(editor.cursor, 0);
+        keys(&mut editor, "N");
+        assert_eq!(editor.cursor, 25);
+        keys(&mut editor, "eb");
+        assert_eq!(editor.cursor, 25);
+    }
+
+    #[test]
+    fn test_vim_registers() {
+        let mut editor = editor_with("alpha\nbeta\n");
+        keys(&mut editor, "\"ayyjp");
+        assert_eq!(editor.get_text(), "alpha\nbeta\nalpha\n");
+        keys(&mut editor, "ggdw\"aP");
+        assert_eq!(editor.get_text(), "alpha\n\nbeta\nalpha\n");
+        keys(&mut editor, "jjp");
+        assert_eq!(editor.get_text(), "alpha\n\nbalphaeta\nalpha\n");
+        assert_eq!(editor.registers[&'a'], Register { text: "alpha\n".to_string(), linewise: true });
+
+        keys(&mut editor, "G\"Ayy");
+        assert_eq!(editor.registers[&'a'].text, "alpha\nalpha\n");
+        keys(&mut editor, "\"_dd");
+        assert_eq!(editor.registers[&'"'].text, "alpha\n");
+        keys(&mut editor, "yiw");
+        assert_eq!(editor.registers[&'0'].text, "balphaeta");
+    }
+
+    #[test]
+    fn test_vim_latex_text_objects() {
+        let text = "\\begin{figure}[h]\n  \\caption{A $x^2$ plot}\n  \\begin{center}\n    body\n  \\end{center}\n\\end{figure}\n";
+        let mut editor = editor_with(text);
+        editor.cursor = text.find("body").unwrap();
+        keys(&mut editor, "die");
+        assert_eq!(editor.get_text(), text.replace("    body\n", ""));
+
+        let mut editor = editor_with(text);
+        editor.cursor = text.find("body").unwrap();
+        keys(&mut editor, "dae");
+        assert_eq!(editor.get_text(), text.replace("\\begin{center}\n    body\n  \\end{center}", ""));
+
+        let mut editor = editor_with(text);
+        editor.cursor = text.find("^2").unwrap();
+        keys(&mut editor, "ci$y\u{1b}");
+        assert!(editor.get_text().contains("A $y$ plot"));
+        keys(&mut editor, "da$");
+        assert!(editor.get_text().contains("A  plot"));
+
+        let mut editor = editor_with(text);
+        editor.cursor = text.find("plot").unwrap();
+        keys(&mut editor, "cicNew\u{1b}");
+        assert!(editor.get_text().contains("\\caption{New}"));
+        keys(&mut editor, "dac");
+        assert!(editor.get_text().starts_with("\\begin{figure}[h]\n  \n"));
+
+        let mut editor = editor_with("see \\(a + b\\) here\n");
+        editor.cursor = 8;
+        keys(&mut editor, "vi$d");
+        assert_eq!(editor.get_text(), "see \\(\\) here\n");
+        assert_eq!(editor.mode, EditorMode::Normal);
+    }
 }
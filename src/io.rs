@@ -1,10 +1,42 @@
-use tokio::fs;
+use std::path::{Path, PathBuf};
 
+/// Per-project state (undo history, recovery copies) lives here, next to the sources.
+pub const STATE_DIR: &str = ".sokutex";
 
 pub struct IoHandler;
 
 impl IoHandler {
-    pub async fn auto_save(content: String, path: &str) -> Result<(), std::io::Error> {
-        fs::write(path, content).await
+    /// Write `content` to `path` atomically: a crash leaves either the old file or the new one, never half of it.
+    pub fn write_atomic_sync(path: &Path, content: &[u8]) -> Result<(), std::io::Error> {
+        use std::io::Write;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)?;
+        let tmp = temp_path(path);
+
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp, path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })
     }
 }
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+/// Where project state of kind `area` for `file_name` is kept, e.g. `<root>/.sokutex/undo/chapters%2Fintro.tex`.
+///
+/// The file name is escaped so nested files map to one flat directory and can be mapped back.
+pub fn state_path(root_dir: &str, area: &str, file_name: &str) -> PathBuf {
+    let escaped = file_name.replace('%', "%25").replace(['/', '\\'], "%2F");
+    Path::new(root_dir).join(STATE_DIR).join(area).join(escaped)
+}
+
+/// The project file name a `state_path` entry was created for.
+pub fn state_file_name(entry: &str) -> String {
+    entry.replace("%2F", "/").replace("%25", "%")
+}
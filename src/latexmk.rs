@@ -1,13 +1,16 @@
 use std::process::Stdio;
 use tokio::process::{Child, Command};
-use tokio::io::{BufReader, AsyncBufReadExt, AsyncWriteExt};
-use std::path::PathBuf;
+use tokio::io::{AsyncRead, BufReader, AsyncBufReadExt, AsyncWriteExt};
+use std::path::Path;
 use tokio::sync::mpsc;
 use log::info;
+use crate::config::TexEngine;
 
 pub enum LatexmkEvent {
     BuildStarted,
     BuildFinished(bool), // true if success
+    /// A line latexmk or the engine printed
+    Output(String),
 }
 
 pub struct LatexmkPvc {
@@ -17,14 +20,21 @@ pub struct LatexmkPvc {
 }
 
 impl LatexmkPvc {
-    pub fn spawn(main_file: PathBuf, event_tx: mpsc::Sender<LatexmkEvent>) -> Result<Self, std::io::Error> {
-        let mut child = Command::new("latexmk")
+    /// Watch `main_file`, relative to the project directory `dir`; the outputs are written next to it.
+    pub fn spawn(dir: &Path, main_file: &str, engine: TexEngine, event_tx: mpsc::Sender<LatexmkEvent>) -> Result<Self, std::io::Error> {
+        let source = dir.join(main_file);
+        let mut command = Command::new("latexmk");
+        // Its own process group, so killing it also stops the engine run in progress
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command
+            .current_dir(source.parent().unwrap_or(dir))
             .arg("-pvc")
-            .arg("-pdf")
+            .arg(engine.latexmk_flag())
             .arg("-interaction=nonstopmode")
             // Use -view=none to prevent latexmk from opening a PDF viewer
             .arg("-view=none")
-            .arg(main_file)
+            .arg(source.file_name().unwrap_or(source.as_os_str()))
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -32,33 +42,56 @@ impl LatexmkPvc {
 
         let stdin = child.stdin.take().expect("failed to open stdin");
         let stdout = child.stdout.take().expect("failed to open stdout");
-        
-        // Spawn a task to monitor stdout
-        tokio::spawn(async move {
-            let mut reader = BufReader::new(stdout).lines();
-            while let Ok(Some(line)) = reader.next_line().await {
-                info!("latexmk: {}", line);
-                if line.contains("Latexmk: All targets") && line.contains("are up-to-date") {
-                    let _ = event_tx.send(LatexmkEvent::BuildFinished(true)).await;
-                } else if line.contains("Latexmk: Run number") {
-                    let _ = event_tx.send(LatexmkEvent::BuildStarted).await;
-                } else if line.contains("Errors during processing") {
-                    let _ = event_tx.send(LatexmkEvent::BuildFinished(false)).await;
-                }
-            }
-        });
+        let stderr = child.stderr.take().expect("failed to open stderr");
+
+        // latexmk reports on stderr and the engine on stdout; monitor both
+        tokio::spawn(Self::monitor(stdout, event_tx.clone()));
+        tokio::spawn(Self::monitor(stderr, event_tx));
 
         Ok(Self { child, stdin })
     }
 
+    async fn monitor(pipe: impl AsyncRead + Unpin, event_tx: mpsc::Sender<LatexmkEvent>) {
+        let mut reader = BufReader::new(pipe).lines();
+        while let Ok(Some(line)) = reader.next_line().await {
+            info!("latexmk: {}", line);
+            let event = if line.contains("Latexmk: All targets") && line.contains("are up-to-date") {
+                Some(LatexmkEvent::BuildFinished(true))
+            } else if line.contains("Latexmk: Run number") {
+                Some(LatexmkEvent::BuildStarted)
+            } else if line.contains("Errors during processing") {
+                Some(LatexmkEvent::BuildFinished(false))
+            } else {
+                None
+            };
+            let _ = event_tx.send(LatexmkEvent::Output(line)).await;
+            if let Some(event) = event {
+                let _ = event_tx.send(event).await;
+            }
+        }
+    }
+
     pub async fn trigger_rebuild(&mut self) -> tokio::io::Result<()> {
         self.stdin.write_all(b"\n").await?;
         self.stdin.flush().await?;
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub async fn kill(mut self) -> tokio::io::Result<()> {
+        if let Some(pid) = self.child.id() {
+            crate::cancel::kill_process_group(pid);
+        }
         self.child.kill().await
     }
 }
+
+/// latexmk runs in a process group of its own, out of reach of the terminal's Ctrl-C,
+/// so it must never outlive its handle.
+impl Drop for LatexmkPvc {
+    fn drop(&mut self) {
+        if let Some(pid) = self.child.id() {
+            crate::cancel::kill_process_group(pid);
+            let _ = self.child.start_kill();
+        }
+    }
+}
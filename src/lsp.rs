@@ -0,0 +1,805 @@
+use crate::autocomplete::AutocompleteEngine;
+use crate::bib::BibParser;
+use crate::build_dir::BuildDir;
+use crate::compiler_daemon::{CompileRequest, CompileResult, CompilerDaemon};
+use crate::config::{CompileBackend, ProjectSettings, TexEngine};
+use crate::dependencies::{DependencyNode, DependencyScanner, OutlineItem};
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::symbols::{Symbol, SymbolIndex};
+use crate::synctex::SyncTex;
+use crate::vfs::Vfs;
+use log::{error, info};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// Custom request mapping a source position to a PDF location through SyncTeX.
+/// Params: `TextDocumentPositionParams`. Result: `{ page, x, y, width, height, depth }` or null.
+pub const FORWARD_SEARCH: &str = "sokutex/forwardSearch";
+
+const COMPILE_DEBOUNCE: Duration = Duration::from_millis(300);
+
+// JSON-RPC / LSP error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_NOT_INITIALIZED: i64 = -32002;
+const REQUEST_FAILED: i64 = -32803;
+
+static CITE_REGEX: OnceLock<Regex> = OnceLock::new();
+
+struct Server {
+    vfs: Arc<Vfs>,
+    root: PathBuf,
+    main_file: String,
+    backend: CompileBackend,
+    /// The project's engine; `% !TEX program` comments override it per compile
+    engine: TexEngine,
+    autocomplete: AutocompleteEngine,
+    compile_tx: mpsc::Sender<CompileRequest>,
+    result_tx: mpsc::Sender<CompileResult>,
+    synctex: Option<SyncTex>,
+    /// URIs that currently have diagnostics published, so they can be cleared
+    published: HashSet<String>,
+    /// When the next debounced compile is due
+    compile_at: Option<Instant>,
+    active_file: Option<String>,
+    shutdown: bool,
+    /// Where responses and notifications go: stdout, or a pipe in tests
+    output: Box<dyn AsyncWrite + Unpin + Send>,
+}
+
+/// A frame from the client: a message, or why its body could not be used.
+enum Frame {
+    Message(Value),
+    Invalid(String),
+}
+
+/// Serve the Language Server Protocol over stdin/stdout until the client exits.
+/// `path` overrides the workspace root the client sends in `initialize`.
+pub async fn serve(path: Option<String>) -> i32 {
+    let (msg_tx, mut msg_rx) = mpsc::channel(64);
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(tokio::io::stdin());
+        loop {
+            match read_message(&mut reader).await {
+                Ok(Some(frame)) => {
+                    if msg_tx.send(frame).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("LSP: failed to read message: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut output: Box<dyn AsyncWrite + Unpin + Send> = Box::new(tokio::io::stdout());
+
+    // The project is only known once the client has told us its workspace
+    let initialize = loop {
+        match msg_rx.recv().await {
+            Some(Frame::Message(msg)) if msg["method"] == "initialize" => break msg,
+            Some(Frame::Message(msg)) if msg["method"] == "exit" => return 1,
+            Some(Frame::Message(msg)) => {
+                if let (Some(id), Some(_)) = (msg.get("id"), msg.get("method")) {
+                    let error = json!({ "code": SERVER_NOT_INITIALIZED, "message": "server not initialized" });
+                    let _ = write_message(&mut output, &json!({ "jsonrpc": "2.0", "id": id, "error": error })).await;
+                }
+            }
+            Some(Frame::Invalid(e)) => {
+                error!("LSP: unreadable message: {}", e);
+                let error = json!({ "code": PARSE_ERROR, "message": format!("parse error: {}", e) });
+                let _ = write_message(&mut output, &json!({ "jsonrpc": "2.0", "id": Value::Null, "error": error })).await;
+            }
+            None => return 1,
+        }
+    };
+
+    let params = &initialize["params"];
+    let project = path
+        .or_else(|| params["rootUri"].as_str().and_then(uri_to_path).map(|p| p.to_string_lossy().to_string()))
+        .or_else(|| params["rootPath"].as_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| ".".to_string());
+    let backend = match params["initializationOptions"]["backend"].as_str() {
+        Some("internal") => CompileBackend::Internal,
+        Some("latexmk") => CompileBackend::Latexmk,
+        _ => CompileBackend::Tectonic,
+    };
+
+    let (vfs, main_file) = crate::cli::load_project(&project);
+    let root = vfs.root_dir.clone().filter(|r| !r.is_empty()).unwrap_or_else(|| ".".to_string());
+    let root = std::fs::canonicalize(&root).unwrap_or_else(|_| PathBuf::from(root));
+    let engine = params["initializationOptions"]["engine"]
+        .as_str()
+        .and_then(TexEngine::from_name)
+        .unwrap_or_else(|| ProjectSettings::load(&root.to_string_lossy()).engine);
+    let vfs = Arc::new(vfs);
+    info!("LSP: serving {} (root file {})", root.display(), main_file);
+
+    let (compile_tx, compile_rx) = mpsc::channel(10);
+    tokio::spawn(CompilerDaemon::new(compile_rx, vfs.clone()).run());
+    let (result_tx, mut result_rx) = mpsc::channel(4);
+
+    let mut server = Server {
+        vfs,
+        root,
+        main_file,
+        backend,
+        engine,
+        autocomplete: AutocompleteEngine::new(),
+        compile_tx,
+        result_tx,
+        synctex: None,
+        published: HashSet::new(),
+        compile_at: None,
+        active_file: None,
+        shutdown: false,
+        output,
+    };
+
+    let capabilities = json!({
+        "capabilities": {
+            "textDocumentSync": { "openClose": true, "change": 1, "save": { "includeText": false } },
+            "documentSymbolProvider": true,
+            "definitionProvider": true,
+            "referencesProvider": true,
+            "renameProvider": true,
+            "completionProvider": { "triggerCharacters": ["\\", "{", ","] },
+            "experimental": { "forwardSearchProvider": FORWARD_SEARCH },
+        },
+        "serverInfo": { "name": "sokutex", "version": env!("CARGO_PKG_VERSION") },
+    });
+    server.respond(initialize["id"].clone(), Ok(capabilities)).await;
+
+    loop {
+        let compile_at = server.compile_at;
+        tokio::select! {
+            msg = msg_rx.recv() => match msg {
+                Some(Frame::Message(msg)) => {
+                    if let Some(code) = server.handle(msg).await {
+                        return code;
+                    }
+                }
+                // A broken message is answered, and the session goes on
+                Some(Frame::Invalid(e)) => {
+                    error!("LSP: unreadable message: {}", e);
+                    server.respond(Value::Null, Err((PARSE_ERROR, format!("parse error: {}", e)))).await;
+                }
+                // Client went away without the exit notification
+                None => return if server.shutdown { 0 } else { 1 },
+            },
+            Some(result) = result_rx.recv() => server.finish_compile(result).await,
+            _ = tokio::time::sleep_until(compile_at.unwrap_or_else(Instant::now)), if compile_at.is_some() => {
+                server.compile_at = None;
+                server.start_compile().await;
+            }
+        }
+    }
+}
+
+impl Server {
+    /// Handle one incoming message. Returns the exit code once the client sends `exit`.
+    async fn handle(&mut self, msg: Value) -> Option<i32> {
+        // Responses to server-initiated requests carry no method; we never send any
+        let method = msg["method"].as_str()?.to_string();
+        let params = &msg["params"];
+
+        if let Some(id) = msg.get("id") {
+            let result = match method.as_str() {
+                "shutdown" => {
+                    self.shutdown = true;
+                    Ok(Value::Null)
+                }
+                "textDocument/documentSymbol" => self.document_symbols(params),
+                "textDocument/completion" => self.completion(params),
+                "textDocument/definition" => self.definition(params),
+                "textDocument/references" => self.references(params),
+                "textDocument/rename" => self.rename(params),
+                FORWARD_SEARCH => self.forward_search(params),
+                _ => Err((METHOD_NOT_FOUND, format!("unhandled method {}", method))),
+            };
+            self.respond(id.clone(), result).await;
+            return None;
+        }
+
+        match method.as_str() {
+            "textDocument/didOpen" => {
+                let doc = &params["textDocument"];
+                if let (Some(file), Some(text)) = (self.document_file(&doc["uri"]), doc["text"].as_str()) {
+                    self.vfs.write_file(&file, text.as_bytes().to_vec());
+                    self.schedule_compile(file, Duration::ZERO);
+                }
+            }
+            "textDocument/didChange" => {
+                // Full document sync: the last change carries the whole buffer
+                let text = params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str());
+                if let (Some(file), Some(text)) = (self.document_file(&params["textDocument"]["uri"]), text) {
+                    self.vfs.write_file(&file, text.as_bytes().to_vec());
+                    self.schedule_compile(file, COMPILE_DEBOUNCE);
+                }
+            }
+            "textDocument/didSave" => {
+                if let Some(file) = self.document_file(&params["textDocument"]["uri"]) {
+                    self.schedule_compile(file, Duration::ZERO);
+                }
+            }
+            "textDocument/didClose" => {
+                // Unsaved edits are discarded by the client, so the disk copy is authoritative again
+                if let Some(file) = self.document_file(&params["textDocument"]["uri"]) {
+                    if let Ok(data) = std::fs::read(self.root.join(&file)) {
+                        if self.vfs.read_file(&file).as_ref() != Some(&data) {
+                            self.vfs.write_file(&file, data);
+                            self.compile_at = Some(Instant::now());
+                        }
+                    }
+                    // Its diagnostics came from those edits; the next build reports the disk copy's
+                    let uri = path_to_uri(&self.root.join(&file));
+                    if self.published.remove(&uri) {
+                        self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] })).await;
+                    }
+                }
+            }
+            "exit" => return Some(if self.shutdown { 0 } else { 1 }),
+            _ => {}
+        }
+        None
+    }
+
+    fn schedule_compile(&mut self, file: String, delay: Duration) {
+        self.active_file = Some(file);
+        self.compile_at = Some(Instant::now() + delay);
+    }
+
+    async fn start_compile(&mut self) {
+        let Some(latex) = self.vfs.read_file(&self.main_file) else {
+            error!("LSP: root file {} not found", self.main_file);
+            return;
+        };
+        let latex = String::from_utf8_lossy(&latex).to_string();
+        let vfs = &self.vfs;
+        let engine = crate::magic_comments::engine_for(self.engine, &self.main_file, &latex, |path| {
+            vfs.read_file(path).map(|data| String::from_utf8_lossy(&data).to_string())
+        });
+        let (otx, orx) = oneshot::channel();
+        let request = CompileRequest::Compile {
+            latex,
+            main_file: self.main_file.clone(),
+            backend: self.backend,
+            engine,
+            draft: false,
+            focus_mode: false,
+            active_file: self.active_file.clone(),
+            response: otx,
+        };
+        if self.compile_tx.send(request).await.is_err() {
+            error!("LSP: compiler daemon is not running");
+            return;
+        }
+        let result_tx = self.result_tx.clone();
+        tokio::spawn(async move {
+            if let Ok(result) = orx.await {
+                let _ = result_tx.send(result).await;
+            }
+        });
+    }
+
+    async fn finish_compile(&mut self, result: CompileResult) {
+        if result.pdf.is_some() {
+            let mut stx = SyncTex::new();
+            let loaded = match result.synctex_data {
+                Some(ref data) => stx.load_from_bytes(data).is_ok(),
+                None => {
+                    let build = BuildDir::for_project(Some(&self.root.to_string_lossy()));
+                    stx.load(build.output(&self.main_file, "synctex.gz")).is_ok() || stx.load(build.output(&self.main_file, "synctex")).is_ok()
+                }
+            };
+            self.synctex = loaded.then_some(stx);
+        }
+        self.publish_diagnostics(&result.diagnostics).await;
+    }
+
+    async fn publish_diagnostics(&mut self, diagnostics: &[Diagnostic]) {
+        // Label warnings from the index, unless the TeX log already reported the same thing
+        let mut diagnostics = diagnostics.to_vec();
+        for warning in self.symbol_index().diagnostics() {
+            if !diagnostics.iter().any(|d| d.kind == warning.kind) {
+                diagnostics.push(warning);
+            }
+        }
+
+        let mut by_uri: HashMap<String, Vec<Value>> = HashMap::new();
+        for diag in &diagnostics {
+            let file = diag.file.clone().unwrap_or_else(|| self.main_file.clone());
+            let line = diag.line.unwrap_or(1).saturating_sub(1);
+            let severity = match diag.severity {
+                Severity::Error => 1,
+                Severity::Warning => 2,
+                Severity::Info => 3,
+            };
+            by_uri.entry(path_to_uri(&self.root.join(&file))).or_default().push(json!({
+                "range": self.line_range(&file, line),
+                "severity": severity,
+                "source": "sokutex",
+                "message": diag.message,
+            }));
+        }
+
+        // Clear files that were clean in this build
+        let stale: Vec<String> = self.published.iter().filter(|uri| !by_uri.contains_key(*uri)).cloned().collect();
+        for uri in stale {
+            by_uri.insert(uri, Vec::new());
+        }
+
+        self.published.clear();
+        for (uri, diagnostics) in by_uri {
+            if !diagnostics.is_empty() {
+                self.published.insert(uri.clone());
+            }
+            self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics })).await;
+        }
+    }
+
+    fn document_symbols(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let file = self.document_file(&params["textDocument"]["uri"]).ok_or((INVALID_PARAMS, "document outside the project".to_string()))?;
+
+        // Scan from the root so the outline matches what the editor shows; fall back to
+        // the file itself when it is not part of the include tree
+        let mut items = Vec::new();
+        collect_outline(&DependencyScanner::scan(&self.main_file, &self.vfs), &file, &mut items);
+        if items.is_empty() {
+            collect_outline(&DependencyScanner::scan(&file, &self.vfs), &file, &mut items);
+        }
+        Ok(Value::Array(self.nest_symbols(&file, &items)))
+    }
+
+    fn nest_symbols(&self, file: &str, items: &[OutlineItem]) -> Vec<Value> {
+        let mut symbols = Vec::new();
+        let mut i = 0;
+        while i < items.len() {
+            let item = &items[i];
+            let end = items[i + 1..].iter().position(|next| next.level <= item.level).map_or(items.len(), |p| i + 1 + p);
+            let range = self.line_range(file, item.line.saturating_sub(1));
+            symbols.push(json!({
+                "name": item.title,
+                "kind": 2, // SymbolKind.Module
+                "range": range,
+                "selectionRange": range,
+                "children": self.nest_symbols(file, &items[i + 1..end]),
+            }));
+            i = end;
+        }
+        symbols
+    }
+
+    fn completion(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let file = self.document_file(&params["textDocument"]["uri"]).ok_or((INVALID_PARAMS, "document outside the project".to_string()))?;
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+
+        let text = self.line_text(&file, line).unwrap_or_default();
+        let prefix = &text[..utf16_to_byte(&text, character)];
+        let mut items = Vec::new();
+
+        let cite_re = CITE_REGEX.get_or_init(|| Regex::new(r"\\[a-zA-Z]*cite[a-zA-Z]*\*?(?:\[[^\]]*\])*\{([^}]*)$").unwrap());
+        if let Some(cap) = cite_re.captures(prefix) {
+            // Complete the key after the last comma of a multi-key citation
+            let keys = &cap[1];
+            let partial = keys.rsplit(',').next().unwrap_or("").trim_start();
+            let range = edit_range(line, character, partial);
+            for entry in self.bib_entries() {
+                if !entry.key.starts_with(partial) {
+                    continue;
+                }
+                let documentation = [entry.plain("author"), entry.plain("year")].into_iter().flatten().collect::<Vec<_>>().join(", ");
+                items.push(json!({
+                    "label": entry.key,
+                    "kind": 18, // CompletionItemKind.Reference
+                    "detail": entry.plain("title").unwrap_or_default(),
+                    "documentation": documentation,
+                    "textEdit": { "range": range, "newText": entry.key },
+                }));
+            }
+        } else if let Some(start) = prefix.rfind('\\') {
+            let command = &prefix[start..];
+            if command[1..].chars().all(|c| c.is_ascii_alphabetic()) {
+                let range = edit_range(line, character, command);
+                for suggestion in self.autocomplete.suggest(command) {
+                    items.push(json!({
+                        "label": suggestion,
+                        "kind": 3, // CompletionItemKind.Function
+                        "textEdit": { "range": range, "newText": suggestion },
+                    }));
+                }
+            }
+        }
+
+        Ok(json!({ "isIncomplete": false, "items": items }))
+    }
+
+    fn definition(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (index, file, line, column) = self.index_at(params)?;
+        let definition = match index.symbol_at(&file, line, column) {
+            Some(symbol) => index.definition(symbol),
+            None => self.line_text(&file, line - 1).and_then(|text| crate::symbols::command_at(&text, column).and_then(|c| index.command_definition(c))),
+        };
+        Ok(definition.map_or(Value::Null, |d| self.symbol_location(d)))
+    }
+
+    fn references(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (index, file, line, column) = self.index_at(params)?;
+        let Some(symbol) = index.symbol_at(&file, line, column) else {
+            return Ok(Value::Null);
+        };
+        let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+        let locations: Vec<Value> = index.references(symbol).into_iter()
+            .filter(|s| include_declaration || !s.kind.is_definition())
+            .map(|s| self.symbol_location(s))
+            .collect();
+        Ok(Value::Array(locations))
+    }
+
+    fn rename(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let (index, file, line, column) = self.index_at(params)?;
+        let new_name = params["newName"].as_str().unwrap_or_default().trim();
+        let symbol = index.symbol_at(&file, line, column).ok_or((REQUEST_FAILED, "no label or citation key at this position".to_string()))?;
+
+        // Validates the name and that the index matches the buffers before we hand out edits
+        index.rename(symbol, new_name, &self.vfs).map_err(|e| (REQUEST_FAILED, e))?;
+
+        let mut changes: HashMap<String, Vec<Value>> = HashMap::new();
+        for s in index.references(symbol) {
+            let location = self.symbol_location(s);
+            changes.entry(location["uri"].as_str().unwrap_or_default().to_string()).or_default().push(json!({
+                "range": location["range"],
+                "newText": new_name,
+            }));
+        }
+        Ok(json!({ "changes": changes }))
+    }
+
+    fn forward_search(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let file = self.document_file(&params["textDocument"]["uri"]).ok_or((INVALID_PARAMS, "document outside the project".to_string()))?;
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as u32 + 1;
+
+        let Some(ref stx) = self.synctex else {
+            return Ok(Value::Null);
+        };
+        let Some(tag) = stx.tag_for_file(&file, self.root.to_str()) else {
+            return Ok(Value::Null);
+        };
+        Ok(stx.forward_sync(line, tag).map_or(Value::Null, |node| json!({
+            "page": node.page,
+            "x": node.x,
+            "y": node.y,
+            "width": node.width,
+            "height": node.height,
+            "depth": node.depth,
+        })))
+    }
+
+    fn symbol_index(&self) -> SymbolIndex {
+        SymbolIndex::from_tree(&DependencyScanner::scan(&self.main_file, &self.vfs))
+    }
+
+    /// A fresh index plus the document position as a 1-based line and byte column.
+    fn index_at(&self, params: &Value) -> Result<(SymbolIndex, String, usize, usize), (i64, String)> {
+        let file = self.document_file(&params["textDocument"]["uri"]).ok_or((INVALID_PARAMS, "document outside the project".to_string()))?;
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+        let column = self.line_text(&file, line).map_or(0, |text| utf16_to_byte(&text, character));
+        Ok((self.symbol_index(), file, line + 1, column))
+    }
+
+    fn symbol_location(&self, symbol: &Symbol) -> Value {
+        let line = symbol.line.saturating_sub(1);
+        let text = self.line_text(&symbol.file_name, line).unwrap_or_default();
+        let start = text.get(..symbol.column).map_or(0, |t| t.encode_utf16().count());
+        let end = start + symbol.name.encode_utf16().count();
+        json!({
+            "uri": path_to_uri(&self.root.join(&symbol.file_name)),
+            "range": {
+                "start": { "line": line, "character": start },
+                "end": { "line": line, "character": end },
+            },
+        })
+    }
+
+    fn bib_entries(&self) -> Vec<crate::bib::BibEntry> {
+        let files = self.vfs.get_all_files();
+        let mut entries = Vec::new();
+        for file in files.iter().filter(|f| f.key().ends_with(".bib")) {
+            entries.append(&mut BibParser::parse(&String::from_utf8_lossy(file.value())));
+        }
+        entries
+    }
+
+    /// Map a document URI to its VFS path, relative to the project root.
+    fn document_file(&self, uri: &Value) -> Option<String> {
+        let path = uri_to_path(uri.as_str()?)?;
+        let path = std::fs::canonicalize(&path).unwrap_or(path);
+        let relative = path.strip_prefix(&self.root).ok()?;
+        Some(relative.to_string_lossy().replace('\\', "/"))
+    }
+
+    fn line_text(&self, file: &str, line: usize) -> Option<String> {
+        let content = self.vfs.read_file(file)?;
+        let content = String::from_utf8_lossy(&content);
+        content.lines().nth(line).map(|l| l.to_string())
+    }
+
+    /// A range covering a whole source line, in UTF-16 code units as LSP expects.
+    fn line_range(&self, file: &str, line: usize) -> Value {
+        let end = self.line_text(file, line).map_or(0, |text| text.encode_utf16().count());
+        json!({
+            "start": { "line": line, "character": 0 },
+            "end": { "line": line, "character": end },
+        })
+    }
+
+    async fn respond(&mut self, id: Value, result: Result<Value, (i64, String)>) {
+        let msg = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+        };
+        if let Err(e) = write_message(&mut self.output, &msg).await {
+            error!("LSP: failed to write response: {}", e);
+        }
+    }
+
+    async fn notify(&mut self, method: &str, params: Value) {
+        let msg = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        if let Err(e) = write_message(&mut self.output, &msg).await {
+            error!("LSP: failed to write notification: {}", e);
+        }
+    }
+}
+
+fn collect_outline(node: &DependencyNode, file: &str, items: &mut Vec<OutlineItem>) {
+    items.extend(node.outline.iter().filter(|item| item.file_name == file).cloned());
+    for child in &node.children {
+        collect_outline(child, file, items);
+    }
+}
+
+/// The range replaced by a completion: `typed` immediately before the cursor.
+fn edit_range(line: usize, character: usize, typed: &str) -> Value {
+    let start = character.saturating_sub(typed.encode_utf16().count());
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": character },
+    })
+}
+
+/// Convert an LSP UTF-16 column into a byte offset within `line`, clamped to its length.
+fn utf16_to_byte(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let mut length = None;
+    let mut headers = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if headers {
+                break;
+            }
+            continue;
+        }
+        headers = true;
+        // Not only at the start: the body of a frame without a length runs into the next header
+        if let Some((_, value)) = header.split_once("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let Some(length) = length else {
+        return Ok(Some(Frame::Invalid("missing Content-Length header".to_string())));
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Some(match serde_json::from_slice(&body) {
+        Ok(msg) => Frame::Message(msg),
+        Err(e) => Frame::Invalid(e.to_string()),
+    }))
+}
+
+async fn write_message<W: AsyncWriteExt + Unpin>(writer: &mut W, msg: &Value) -> io::Result<()> {
+    let body = msg.to_string();
+    writer.write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes()).await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.flush().await
+}
+
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    let decoded = String::from_utf8(decoded).ok()?;
+
+    // file:///C:/dir on Windows
+    #[cfg(target_os = "windows")]
+    let decoded = decoded.trim_start_matches('/').to_string();
+
+    Some(PathBuf::from(decoded))
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' | b':' => uri.push(byte as char),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(future)
+    }
+
+    /// A server over a project on disk, with its output readable from the returned pipe.
+    fn test_server(name: &str, files: &[(&str, &str)]) -> (Server, BufReader<DuplexStream>) {
+        let dir = std::env::temp_dir().join(format!("sokutex_lsp_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let vfs = Vfs::new();
+        for (file, content) in files {
+            std::fs::write(dir.join(file), content).unwrap();
+            vfs.write_file(file, content.as_bytes().to_vec());
+        }
+        let (output, client) = tokio::io::duplex(1 << 16);
+        // Nothing compiles here: both channels are closed
+        let (compile_tx, _) = mpsc::channel(1);
+        let (result_tx, _) = mpsc::channel(1);
+        let server = Server {
+            vfs: Arc::new(vfs),
+            root: std::fs::canonicalize(&dir).unwrap(),
+            main_file: "main.tex".to_string(),
+            backend: CompileBackend::Tectonic,
+            engine: TexEngine::PdfLatex,
+            autocomplete: AutocompleteEngine::new(),
+            compile_tx,
+            result_tx,
+            synctex: None,
+            published: HashSet::new(),
+            compile_at: None,
+            active_file: None,
+            shutdown: false,
+            output: Box::new(output),
+        };
+        (server, BufReader::new(client))
+    }
+
+    fn position(server: &Server, file: &str, line: usize, character: usize) -> Value {
+        json!({
+            "textDocument": { "uri": path_to_uri(&server.root.join(file)) },
+            "position": { "line": line, "character": character },
+        })
+    }
+
+    const MAIN: &str = "\\documentclass{article}\n\\begin{document}\n\\input{intro}\nSee \\ref{sec:intro} and \\cite{kn}.\n\\bibliography{refs}\n\\end{document}\n";
+    const INTRO: &str = "\\section{Intro}\\label{sec:intro}\n";
+    const REFS: &str = "@book{knuth1984, author = {Donald Knuth}, title = {The TeXbook}, year = 1984}\n";
+
+    #[test]
+    fn test_message_framing() {
+        block_on(async {
+            let mut written = Vec::new();
+            write_message(&mut written, &json!({ "jsonrpc": "2.0", "method": "initialized" })).await.unwrap();
+            written.extend_from_slice(b"Content-Length: 8\r\n\r\n{\"id\": 1");
+            // Without a length the body cannot be skipped, but the next frame is still found
+            written.extend_from_slice(b"Content-Type: application/json\r\n\r\n{}");
+            write_message(&mut written, &json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" })).await.unwrap();
+
+            let mut reader = BufReader::new(written.as_slice());
+            let Some(Frame::Message(first)) = read_message(&mut reader).await.unwrap() else { panic!("expected a message") };
+            assert_eq!(first["method"], "initialized");
+            let Some(Frame::Invalid(e)) = read_message(&mut reader).await.unwrap() else { panic!("expected bad JSON") };
+            assert!(e.contains("EOF"), "{}", e);
+            let Some(Frame::Invalid(e)) = read_message(&mut reader).await.unwrap() else { panic!("expected a missing length") };
+            assert_eq!(e, "missing Content-Length header");
+            let Some(Frame::Message(last)) = read_message(&mut reader).await.unwrap() else { panic!("expected a message") };
+            assert_eq!(last["id"], 2);
+            assert!(read_message(&mut reader).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_completion_definition_and_rename() {
+        let (server, _client) = test_server("requests", &[("main.tex", MAIN), ("intro.tex", INTRO), ("refs.bib", REFS)]);
+        let see = MAIN.lines().nth(3).unwrap();
+
+        let completion = server.completion(&position(&server, "main.tex", 3, see.find("kn}").unwrap() + 2)).unwrap();
+        assert_eq!(completion["items"][0]["label"], "knuth1984");
+        assert_eq!(completion["items"][0]["detail"], "The TeXbook");
+        assert_eq!(completion["items"][0]["textEdit"]["range"]["start"]["character"], see.find("kn}").unwrap());
+
+        let at_ref = position(&server, "main.tex", 3, see.find("sec:intro").unwrap() + 1);
+        let definition = server.definition(&at_ref).unwrap();
+        assert_eq!(definition["uri"], path_to_uri(&server.root.join("intro.tex")));
+        assert_eq!(definition["range"]["start"], json!({ "line": 0, "character": 22 }));
+
+        let mut rename = at_ref.clone();
+        rename["newName"] = json!("sec:introduction");
+        let changes = &server.rename(&rename).unwrap()["changes"];
+        assert_eq!(changes.as_object().unwrap().len(), 2);
+        assert_eq!(changes[path_to_uri(&server.root.join("main.tex"))][0]["newText"], "sec:introduction");
+        rename["newName"] = json!("has space");
+        assert_eq!(server.rename(&rename).unwrap_err().0, REQUEST_FAILED);
+    }
+
+    #[test]
+    fn test_did_close_clears_diagnostics() {
+        let (mut server, mut client) = test_server("close", &[("main.tex", MAIN), ("intro.tex", INTRO)]);
+        let uri = path_to_uri(&server.root.join("intro.tex"));
+        block_on(async {
+            server.vfs.write_file("intro.tex", b"\\section{Intro\n".to_vec());
+            server.published.insert(uri.clone());
+            let close = json!({ "jsonrpc": "2.0", "method": "textDocument/didClose", "params": { "textDocument": { "uri": uri } } });
+            assert!(server.handle(close).await.is_none());
+
+            let Some(Frame::Message(msg)) = read_message(&mut client).await.unwrap() else { panic!("expected a notification") };
+            assert_eq!(msg["method"], "textDocument/publishDiagnostics");
+            assert_eq!(msg["params"], json!({ "uri": uri, "diagnostics": [] }));
+        });
+        assert!(server.published.is_empty());
+        // The discarded edits are gone and the disk copy is rebuilt
+        assert_eq!(server.vfs.read_file("intro.tex").unwrap(), INTRO.as_bytes());
+        assert!(server.compile_at.is_some());
+    }
+
+    #[test]
+    fn test_uri_round_trip() {
+        let path = Path::new("/home/user/My Thesis/chapter 1.tex");
+        let uri = path_to_uri(path);
+        assert_eq!(uri, "file:///home/user/My%20Thesis/chapter%201.tex");
+        assert_eq!(uri_to_path(&uri).unwrap(), path);
+        assert_eq!(utf16_to_byte("\\cite{é😀x", 8), "\\cite{é😀".len());
+    }
+}
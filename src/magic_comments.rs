@@ -0,0 +1,100 @@
+use crate::config::TexEngine;
+
+/// TeXShop only looks this far into a file for magic comments, and so do we.
+const MAGIC_LINES: usize = 20;
+
+/// TeXShop/TeXstudio-style `% !TEX key = value` comments at the top of a file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MagicComments {
+    /// `% !TEX program = lualatex` (or `TS-program`)
+    pub program: Option<TexEngine>,
+    /// `% !TEX root = ../main.tex`, as written
+    pub root: Option<String>,
+}
+
+impl MagicComments {
+    pub fn parse(text: &str) -> Self {
+        let mut magic = Self::default();
+        for line in text.lines().take(MAGIC_LINES) {
+            let Some(comment) = line.trim_start().strip_prefix('%') else { continue };
+            let comment = comment.trim_start();
+            if !comment.get(..4).is_some_and(|head| head.eq_ignore_ascii_case("!tex")) {
+                continue;
+            }
+            let Some((key, value)) = comment[4..].split_once('=') else { continue };
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "program" | "ts-program" => magic.program = magic.program.or_else(|| TexEngine::from_name(value)),
+                "root" if !value.is_empty() => {
+                    magic.root.get_or_insert_with(|| value.to_string());
+                }
+                _ => {}
+            }
+        }
+        magic
+    }
+}
+
+/// A path written relative to `file`, as a project path: `../main.tex` from `chapters/intro.tex` is `main.tex`.
+pub fn resolve_relative(file: &str, target: &str) -> String {
+    let target = target.replace('\\', "/");
+    let mut parts: Vec<&str> = file.split('/').collect();
+    parts.pop();
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// The engine to typeset `file` with: its own `program` comment, then that of the root it names, then the project setting.
+pub fn engine_for(setting: TexEngine, file: &str, text: &str, read: impl Fn(&str) -> Option<String>) -> TexEngine {
+    let magic = MagicComments::parse(text);
+    if let Some(program) = magic.program {
+        return program;
+    }
+    magic.root
+        .map(|root| resolve_relative(file, &root))
+        .filter(|root| root != file)
+        .and_then(|root| read(&root))
+        .and_then(|root_text| MagicComments::parse(&root_text).program)
+        .unwrap_or(setting)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_magic_comments() {
+        let text = "%!TEX TS-program = xelatex\n% !TeX root = ../main.tex\n% !TEX program = lualatex\n\\documentclass{article}";
+        let magic = MagicComments::parse(text);
+        assert_eq!(magic.program, Some(TexEngine::XeLatex));
+        assert_eq!(magic.root.as_deref(), Some("../main.tex"));
+
+        assert_eq!(MagicComments::parse("% !TEX program = txs:///lualatex").program, Some(TexEngine::LuaLatex));
+        assert_eq!(MagicComments::parse("% !TEX program = context").program, None);
+        // Only the top of the file counts
+        let late = format!("{}% !TEX program = xelatex\n", "\n".repeat(MAGIC_LINES));
+        assert_eq!(MagicComments::parse(&late), MagicComments::default());
+    }
+
+    #[test]
+    fn test_engine_follows_the_root_file() {
+        assert_eq!(resolve_relative("chapters/intro.tex", "../main.tex"), "main.tex");
+        assert_eq!(resolve_relative("main.tex", "./sub/./root.tex"), "sub/root.tex");
+
+        let read = |path: &str| (path == "main.tex").then(|| "% !TEX program = lualatex\n".to_string());
+        let chapter = "% !TEX root = ../main.tex\n\\section{Intro}";
+        assert_eq!(engine_for(TexEngine::PdfLatex, "chapters/intro.tex", chapter, read), TexEngine::LuaLatex);
+        assert_eq!(engine_for(TexEngine::XeLatex, "chapters/intro.tex", "\\section{Intro}", read), TexEngine::XeLatex);
+        // The file's own comment wins over its root's
+        let own = "% !TEX program = pdflatex\n% !TEX root = ../main.tex\n";
+        assert_eq!(engine_for(TexEngine::XeLatex, "chapters/intro.tex", own, read), TexEngine::PdfLatex);
+    }
+}
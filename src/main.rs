@@ -4,11 +4,13 @@ use winit::{
     keyboard::{Key, NamedKey},
     window::WindowBuilder,
 };
+#[cfg(target_os = "macos")]
 use winit::platform::macos::WindowBuilderExtMacOS;
 
 mod compiler;
 mod renderer;
 mod editor;
+mod buffers;
 mod pdf_renderer;
 mod palette;
 mod vfs;
@@ -24,57 +26,161 @@ mod autocomplete;
 mod watcher;
 mod latexmk;
 mod dependencies;
+mod symbols;
+mod diagnostics;
+mod cli;
+mod lsp;
+mod magic_comments;
+mod root_file;
+mod build_dir;
+mod cancel;
+mod progress;
+mod undo;
+mod recovery;
+mod viewer;
+mod pdf_text;
+mod pdf_diff;
+mod pdf_writer;
+mod preview;
+mod preview_math;
+
+
+use pdf_renderer::{OutlineItem, PageChanges, PageLink, PdfRenderer, RenderedTile};
+
+/// Finished tiles waiting for the next frame to upload them.
+const TILE_CHANNEL: usize = 64;
+/// Width of the page thumbnails in the navigation panel, in pixels.
+const THUMBNAIL_WIDTH: u32 = 120;
+/// Width both builds of a page are rendered at for the diff view, in pixels.
+const DIFF_WIDTH: u32 = 900;
+
+/// Measure every page of a new PDF for the viewer layout, and find the pages that differ from the previous build.
+fn layout_pdf(
+    pdf_renderer: std::sync::Arc<PdfRenderer>,
+    pdf_data: std::sync::Arc<Vec<u8>>,
+    revision: u64,
+    previous: Option<(std::sync::Arc<Vec<u8>>, u64)>,
+    tx: tokio::sync::mpsc::Sender<(u64, Vec<(f32, f32)>, Option<PageChanges>)>,
+) {
+    tokio::task::spawn_blocking(move || {
+        if let Ok(sizes) = pdf_renderer.page_sizes(&pdf_data, revision) {
+            let changes = previous.and_then(|(old, old_revision)| {
+                pdf_renderer.compare_revisions((&old, old_revision), (&pdf_data, revision)).ok()
+            });
+            let _ = tx.blocking_send((revision, sizes, changes));
+        }
+    });
+}
 
+/// Pull the text layer out of every page, for searching and selecting in the viewer.
+fn extract_pdf_text(
+    pdf_renderer: std::sync::Arc<PdfRenderer>,
+    pdf_data: std::sync::Arc<Vec<u8>>,
+    revision: u64,
+    tx: tokio::sync::mpsc::Sender<(u64, Vec<pdf_text::PageText>)>,
+) {
+    tokio::task::spawn_blocking(move || {
+        let Ok(pages) = pdf_renderer.page_sizes(&pdf_data, revision) else { return };
+        let texts = (0..pages.len())
+            .map(|page| pdf_renderer.page_text(&pdf_data, revision, page as i32).unwrap_or_default())
+            .collect();
+        let _ = tx.blocking_send((revision, texts));
+    });
+}
 
-use pdf_renderer::PdfRenderer;
+/// Read the outline and every page's links, for navigating the viewer.
+fn extract_pdf_navigation(
+    pdf_renderer: std::sync::Arc<PdfRenderer>,
+    pdf_data: std::sync::Arc<Vec<u8>>,
+    revision: u64,
+    tx: tokio::sync::mpsc::Sender<(u64, Vec<OutlineItem>, Vec<Vec<PageLink>>)>,
+) {
+    tokio::task::spawn_blocking(move || {
+        let Ok(pages) = pdf_renderer.page_sizes(&pdf_data, revision) else { return };
+        let outline = pdf_renderer.outline(&pdf_data, revision).unwrap_or_default();
+        let links = (0..pages.len())
+            .map(|page| pdf_renderer.page_links(&pdf_data, revision, page as i32).unwrap_or_default())
+            .collect();
+        let _ = tx.blocking_send((revision, outline, links));
+    });
+}
 
-fn render_pdf(
+/// Render a thumbnail of every page, sending each as it is done.
+fn render_thumbnails(
     pdf_renderer: std::sync::Arc<PdfRenderer>,
     pdf_data: std::sync::Arc<Vec<u8>>,
     revision: u64,
-    page: i32,
-    width: u16,
-    height: u16,
-    tx: Option<tokio::sync::mpsc::Sender<(u32, u32, std::sync::Arc<Vec<u8>>, f32, f32)>>,
+    tx: tokio::sync::mpsc::Sender<(u64, usize, egui::ColorImage)>,
 ) {
     tokio::task::spawn_blocking(move || {
-        let timer = perf::PerfTimer::start("PDF Render (Async)");
-        if let Ok((pixels, pw, ph)) = pdf_renderer.render_page(&pdf_data, revision, page, width, height) {
-            if let Some(tx) = tx {
-                let _ = tx.blocking_send((width as u32, height as u32, pixels, pw, ph));
+        let Ok(pages) = pdf_renderer.page_sizes(&pdf_data, revision) else { return };
+        for page in 0..pages.len() {
+            if let Ok(image) = pdf_renderer.render_page_image(&pdf_data, revision, page as i32, THUMBNAIL_WIDTH) {
+                if tx.blocking_send((revision, page, image)).is_err() {
+                    return;
+                }
             }
         }
-        timer.stop();
     });
 }
 
+/// Render a page of the current build and the page it replaced in the previous build, and mark where they differ.
+fn diff_pdf_page(
+    pdf_renderer: std::sync::Arc<PdfRenderer>,
+    previous: (std::sync::Arc<Vec<u8>>, u64),
+    current: (std::sync::Arc<Vec<u8>>, u64),
+    (page, old_page): (usize, Option<usize>),
+    tx: tokio::sync::mpsc::Sender<(u64, usize, pdf_diff::PageDiff)>,
+) {
+    tokio::task::spawn_blocking(move || {
+        let old = old_page.and_then(|old_page| pdf_renderer.render_page_image(&previous.0, previous.1, old_page as i32, DIFF_WIDTH).ok());
+        let new = pdf_renderer.render_page_image(&current.0, current.1, page as i32, DIFF_WIDTH).ok();
+        let _ = tx.blocking_send((current.1, page, pdf_diff::PageDiff::new(old, new)));
+    });
+}
 
+/// macOS draws the content under a transparent title bar; elsewhere we keep native decorations.
+#[cfg(target_os = "macos")]
+fn build_window(event_loop: &EventLoop<()>) -> winit::window::Window {
+    WindowBuilder::new()
+        .with_title("SokuTeX")
+        .with_transparent(true)
+        .with_fullsize_content_view(true)
+        .with_titlebar_transparent(true)
+        .with_title_hidden(true)
+        .build(event_loop)
+        .unwrap()
+}
 
+#[cfg(not(target_os = "macos"))]
+fn build_window(event_loop: &EventLoop<()>) -> winit::window::Window {
+    WindowBuilder::new()
+        .with_title("SokuTeX")
+        .build(event_loop)
+        .unwrap()
+}
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
+
+    let args: Vec<String> = std::env::args().collect();
+
+    // Headless subcommands never open a window
+    if let Some(code) = cli::run(&args).await {
+        std::process::exit(code);
+    }
     
     // Initialize VFS — load from CLI arg or use built-in demo
-    let mut vfs_inner = vfs::Vfs::new();
-    let args: Vec<String> = std::env::args().collect();
+    let vfs_inner;
     let main_file_name;
     
     if args.len() > 1 {
         // Load real files from disk
-        let path = &args[1];
-        vfs_inner.load_directory(path);
-        
-        // Determine the main file name
-        let p = std::path::Path::new(path);
-        if p.is_file() {
-            main_file_name = p.file_name().unwrap().to_string_lossy().to_string();
-        } else {
-            // Look for main.tex in the directory
-            main_file_name = "main.tex".to_string();
-        }
+        (vfs_inner, main_file_name) = cli::load_project(&args[1]);
     } else {
         // Built-in demo content
+        vfs_inner = vfs::Vfs::new();
         main_file_name = "main.tex".to_string();
         vfs_inner.write_file("main.tex", b"\\documentclass{article}\n\\usepackage{amsmath}\n\n\\title{SokuTeX Demo}\n\\author{SokuTeX}\n\\date{\\today}\n\n\\begin{document}\n\\maketitle\n\n\\section{Welcome}\nWelcome to SokuTeX! Start typing LaTeX here.\n\nHere is some math: $E = mc^2$\n\n\\[\n    \\int_0^\\infty e^{-x^2}\\, dx = \\frac{\\sqrt{\\pi}}{2}\n\\]\n\n\\section{Features}\n\\begin{itemize}\n    \\item Real-time preview\n    \\item Syntax highlighting\n    \\item Auto-compile on keystroke\n    \\item Multi-file project support\n\\end{itemize}\n\n\\end{document}\n".to_vec());
         vfs_inner.write_file("references.bib", b"@article{einstein1905,\n  author = {Einstein, Albert},\n  title = {On the Electrodynamics of Moving Bodies},\n  journal = {Annalen der Physik},\n  year = {1905}\n}\n@book{knuth1984,\n  author = {Knuth, Donald E.},\n  title = {The TeXbook},\n  year = {1984},\n  publisher = {Addison-Wesley}\n}".to_vec());
@@ -85,14 +191,14 @@ async fn main() {
     let (compile_tx, compile_rx) = tokio::sync::mpsc::channel(10);
     let (result_tx, mut result_rx) = tokio::sync::mpsc::channel::<(compiler_daemon::CompileResult, crate::dependencies::DependencyNode)>(1);
     let daemon = compiler_daemon::CompilerDaemon::new(compile_rx, vfs.clone());
+    let mut progress_rx = daemon.subscribe();
     tokio::spawn(daemon.run());
 
     // Compile Debouncer
-    let (debounce_tx, mut debounce_rx) = tokio::sync::mpsc::channel::<(String, crate::config::CompileBackend, bool, bool, Option<String>)>(10);
+    let (debounce_tx, mut debounce_rx) = tokio::sync::mpsc::channel::<(String, crate::config::CompileBackend, crate::config::TexEngine, bool, bool, Option<String>, String)>(10);
     let compile_tx_clone = compile_tx.clone();
     let result_tx_clone = result_tx.clone();
     let vfs_clone = vfs.clone();
-    let mfn_debounce = main_file_name.clone();
     tokio::spawn(async move {
         let mut last_req = None;
         let mut last_compile_time: Option<tokio::time::Instant> = None;
@@ -100,16 +206,22 @@ async fn main() {
         let sleep = tokio::time::sleep(sleep_duration);
         tokio::pin!(sleep);
         
-        let trigger_compile = |r: (String, crate::config::CompileBackend, bool, bool, Option<String>), vfs: std::sync::Arc<crate::vfs::Vfs>, ctx: tokio::sync::mpsc::Sender<crate::compiler_daemon::CompileRequest>, rtx: tokio::sync::mpsc::Sender<(crate::compiler_daemon::CompileResult, crate::dependencies::DependencyNode)>, mfn: String| {
-            let (text, backend, draft, focus_mode, active_file) = r;
+        let trigger_compile = |r: (String, crate::config::CompileBackend, crate::config::TexEngine, bool, bool, Option<String>, String), vfs: std::sync::Arc<crate::vfs::Vfs>, ctx: tokio::sync::mpsc::Sender<crate::compiler_daemon::CompileRequest>, rtx: tokio::sync::mpsc::Sender<(crate::compiler_daemon::CompileResult, crate::dependencies::DependencyNode)>| {
+            let (text, backend, engine, draft, focus_mode, active_file, main_file) = r;
             tokio::spawn(async move {
-                let write_path = active_file.clone().unwrap_or_else(|| mfn.clone());
+                let write_path = active_file.clone().unwrap_or_else(|| main_file.clone());
                 vfs.write_file(&write_path, text.as_bytes().to_vec());
-                let dep_tree = crate::dependencies::DependencyScanner::scan(&mfn, &vfs);
+                let read = |path: &str| vfs.read_file(path).map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+                let engine = crate::magic_comments::engine_for(engine, &write_path, &text, read);
+                // The edited file may be a chapter; the build always starts from the root
+                let latex = read(&main_file).unwrap_or(text);
+                let dep_tree = crate::dependencies::DependencyScanner::scan(&main_file, &vfs);
                 let (otx, orx) = tokio::sync::oneshot::channel();
                 if ctx.send(crate::compiler_daemon::CompileRequest::Compile { 
-                    latex: text, 
+                    latex, 
+                    main_file,
                     backend, 
+                    engine,
                     draft,
                     focus_mode,
                     active_file,
@@ -131,7 +243,7 @@ async fn main() {
                             if last_compile_time.map_or(true, |t| now.duration_since(t) >= sleep_duration) {
                                 // Leading-edge: trigger immediately
                                 last_compile_time = Some(now);
-                                trigger_compile(r, vfs_clone.clone(), compile_tx_clone.clone(), result_tx_clone.clone(), mfn_debounce.clone());
+                                trigger_compile(r, vfs_clone.clone(), compile_tx_clone.clone(), result_tx_clone.clone());
                                 last_req = None; 
                                 sleep.as_mut().reset(now + sleep_duration);
                             } else {
@@ -146,7 +258,7 @@ async fn main() {
                 () = &mut sleep => {
                     if let Some(r) = last_req.take() {
                         last_compile_time = Some(tokio::time::Instant::now());
-                        trigger_compile(r, vfs_clone.clone(), compile_tx_clone.clone(), result_tx_clone.clone(), mfn_debounce.clone());
+                        trigger_compile(r, vfs_clone.clone(), compile_tx_clone.clone(), result_tx_clone.clone());
                     }
                 }
             }
@@ -154,14 +266,7 @@ async fn main() {
     });
 
     let event_loop = EventLoop::new().unwrap();
-    let window = WindowBuilder::new()
-        .with_title("SokuTeX")
-        .with_transparent(true)
-        .with_fullsize_content_view(true)
-        .with_titlebar_transparent(true)
-        .with_title_hidden(true)
-        .build(&event_loop)
-        .unwrap();
+    let window = build_window(&event_loop);
 
     let mut state = renderer::State::new(&window).await;
 
@@ -181,15 +286,26 @@ async fn main() {
         std::sync::Arc::new(vec![])
     };
     let mut current_pdf_revision = 0u64;
-
-    // PDF Render Channel
-    let (pdf_tx, mut pdf_rx) = tokio::sync::mpsc::channel::<(u32, u32, std::sync::Arc<Vec<u8>>, f32, f32)>(2);
+    // The build before the current one, kept for the diff view
+    let mut previous_pdf: Option<(std::sync::Arc<Vec<u8>>, u64)> = None;
+
+    // PDF Render Channels: page layouts and rasterized tiles
+    let (layout_tx, mut layout_rx) = tokio::sync::mpsc::channel::<(u64, Vec<(f32, f32)>, Option<PageChanges>)>(2);
+    let (tile_tx, mut tile_rx) = tokio::sync::mpsc::channel::<RenderedTile>(TILE_CHANNEL);
+    let (text_tx, mut text_rx) = tokio::sync::mpsc::channel::<(u64, Vec<pdf_text::PageText>)>(2);
+    let mut pdf_text_revision = None;
+    let (nav_tx, mut nav_rx) = tokio::sync::mpsc::channel::<(u64, Vec<OutlineItem>, Vec<Vec<PageLink>>)>(2);
+    let (thumb_tx, mut thumb_rx) = tokio::sync::mpsc::channel::<(u64, usize, egui::ColorImage)>(16);
+    let mut thumbnail_revision = None;
+    let (diff_tx, mut diff_rx) = tokio::sync::mpsc::channel::<(u64, usize, pdf_diff::PageDiff)>(2);
+    let tile_workers = std::thread::available_parallelism().map_or(2, |n| (n.get() / 2).clamp(1, 4));
+    pdf_renderer.start_tile_workers(tile_workers, tile_tx);
 
 
     // Dependency render channel
     let (dep_tx, mut dep_rx) = tokio::sync::mpsc::channel::<crate::dependencies::DependencyNode>(10);
 
-    render_pdf(pdf_renderer.clone(), current_pdf_data.clone(), current_pdf_revision, 0, state.size.width as u16, state.size.height as u16, Some(pdf_tx.clone()));
+    layout_pdf(pdf_renderer.clone(), current_pdf_data.clone(), current_pdf_revision, None, layout_tx.clone());
     let mut palette = palette::CommandPalette::new();
 
     let mut gui = ui::Gui::new();
@@ -201,17 +317,30 @@ async fn main() {
     for entry in vfs.get_all_files().iter() {
         if entry.key().ends_with(".bib") {
             if let Ok(content) = String::from_utf8(entry.value().clone()) {
-                bib_contents.push(content);
+                bib_contents.push((entry.key().clone(), content));
             }
         }
     }
     gui.refresh_bibliography(bib_contents);
+    if let Some(root) = vfs.root_dir.as_deref().filter(|r| !r.is_empty()) {
+        let settings = config::ProjectSettings::load(root);
+        gui.tex_engine = settings.engine;
+        gui.root_setting = settings.root;
+    }
 
-    let mut editor = editor::Editor::new();
+    let mut saved_root_setting = gui.root_setting.clone();
+
+    let mut buffers = buffers::BufferManager::new(vfs.root_dir.clone());
+    // The demo project has no directory to keep recovery copies in
+    let mut recovery = vfs.root_dir.clone().filter(|r| !r.is_empty()).map(|root| recovery::RecoveryStore::new(&root));
+    if let Some(ref store) = recovery {
+        gui.recoveries = store.scan();
+    }
+    gui.root_file = main_file_name.clone();
     gui.active_file_path = main_file_name.clone();
-    if let Some(content) = vfs.read_file(&main_file_name) {
-        gui.ui_text = String::from_utf8_lossy(&content).to_string();
-        editor.buffer = ropey::Rope::from_str(&gui.ui_text);
+    if buffers.open(&main_file_name, &vfs).is_some() {
+        gui.ui_text = buffers.active().map(|b| b.text()).unwrap_or_default();
+        gui.tabs = buffers.tabs();
         
         let dtx = dep_tx.clone();
         let rtx = compile_tx.clone();
@@ -239,7 +368,13 @@ async fn main() {
                 window_id,
             } if window_id == window.id() => {
                 match event {
-                    WindowEvent::CloseRequested => target.exit(),
+                    WindowEvent::CloseRequested => {
+                        if buffers.dirty_files().is_empty() {
+                            target.exit();
+                        } else {
+                            gui.quit_prompt = true;
+                        }
+                    }
                     WindowEvent::KeyboardInput {
                         event: KeyEvent {
                             state: ElementState::Pressed,
@@ -251,7 +386,13 @@ async fn main() {
                         let consumed = state.handle_event(&window, &event).consumed;
                         if !consumed {
                             match logical_key {
-                                Key::Named(NamedKey::Escape) => target.exit(),
+                                Key::Named(NamedKey::Escape) if !gui.quit_prompt && gui.close_prompt.is_none() => {
+                                    if buffers.dirty_files().is_empty() {
+                                        target.exit();
+                                    } else {
+                                        gui.quit_prompt = true;
+                                    }
+                                }
                                 Key::Character(c) if c == "p" && (modifiers.state().super_key() || modifiers.state().control_key()) => {
                                     palette.toggle();
                                 }
@@ -261,46 +402,100 @@ async fn main() {
                     }
                     WindowEvent::Resized(physical_size) => {
                         state.resize(*physical_size);
-                        render_pdf(pdf_renderer.clone(), current_pdf_data.clone(), current_pdf_revision, 0, state.size.width as u16, state.size.height as u16, Some(pdf_tx.clone()));
                     }
                     WindowEvent::ScaleFactorChanged { .. } => {}
                     WindowEvent::ModifiersChanged(new_modifiers) => {
                         modifiers = *new_modifiers;
                     }
                     WindowEvent::RedrawRequested => {
-                        // Check for PDF render results
-                        if let Ok((w, h, pixels, pw, ph)) = pdf_rx.try_recv() {
-                            state.update_texture(w, h, &pixels);
-                            gui.pdf_page_size = egui::vec2(pw, ph);
+                        // Check for PDF layouts and render results
+                        while let Ok((revision, sizes, changes)) = layout_rx.try_recv() {
+                            if revision == current_pdf_revision {
+                                gui.set_pdf_pages(sizes.into_iter().map(|(w, h)| egui::vec2(w, h)).collect());
+                                // Unchanged pages keep their tiles; the old build stays on screen until then
+                                if let Some(changes) = changes {
+                                    state.carry_tiles_forward(revision, &changes);
+                                    gui.show_pdf_changes(&changes.changed);
+                                    if changes.previous != revision {
+                                        gui.set_pdf_diff_changes(changes);
+                                    }
+                                }
+                                pdf_renderer.set_tile_source(current_pdf_data.clone(), revision);
+                                extract_pdf_navigation(pdf_renderer.clone(), current_pdf_data.clone(), revision, nav_tx.clone());
+                            }
+                        }
+                        while let Ok((revision, outline, links)) = nav_rx.try_recv() {
+                            if revision == current_pdf_revision {
+                                gui.set_pdf_navigation(outline, links);
+                            }
+                        }
+                        while let Ok((revision, page, image)) = thumb_rx.try_recv() {
+                            if revision == current_pdf_revision {
+                                gui.set_pdf_thumbnail(&state.egui_ctx, page, image);
+                            }
+                        }
+                        while let Ok(tile) = tile_rx.try_recv() {
+                            if tile.key.revision == current_pdf_revision {
+                                state.upload_tile(&tile);
+                            }
                         }
 
+                        while let Ok((revision, page, diff)) = diff_rx.try_recv() {
+                            if revision == current_pdf_revision {
+                                gui.set_pdf_diff(&state.egui_ctx, page, diff);
+                            }
+                        }
 
-
-                        let pdf_texture_id = state.pdf_texture_id;
+                        while let Ok((revision, texts)) = text_rx.try_recv() {
+                            if revision == current_pdf_revision {
+                                gui.set_pdf_text(texts);
+                            }
+                        }
 
                         // Handle Backward Sync: Update internal editor state before drawing
-                        if let Some(line) = gui.sync_to_editor_request {
-                            editor.move_to_line(line);
+                        if let (Some(line), None) = (gui.sync_to_editor_request, &gui.file_change_request) {
+                            if let Some(buffer) = buffers.active_mut() {
+                                buffer.editor.move_to_line(line);
+                            }
                         }
 
                         
-                        // Update GPU Uniforms for PDF Transform
-                        let zoom = gui.pdf_zoom;
-                        let pan = gui.pdf_pan;
-                        
-                        // Simple 2D transform matrix: [zoom, 0, 0, pan.x], [0, zoom, 0, pan.y], ...
+                        // Page quads are given in window pixels
+                        let (width, height) = (state.size.width as f32, state.size.height as f32);
                         let transform = [
-                            [zoom, 0.0, 0.0, 0.0],
-                            [0.0, zoom, 0.0, 0.0],
+                            [2.0 / width, 0.0, 0.0, 0.0],
+                            [0.0, -2.0 / height, 0.0, 0.0],
                             [0.0, 0.0, 1.0, 0.0],
-                            [pan.x, pan.y, 0.0, 1.0],
+                            [-1.0, 1.0, 0.0, 1.0],
                         ];
                         state.update_uniforms(transform);
 
                         let render_res = state.render(&window, |ctx| {
-                            gui.draw(ctx, pdf_texture_id);
+                            gui.draw(ctx);
+                            gui.pdf_scene.clone()
                         });
 
+                        // The text layer is only extracted once search or selection needs it
+                        if gui.pdf_text_wanted && pdf_text_revision != Some(current_pdf_revision) {
+                            pdf_text_revision = Some(current_pdf_revision);
+                            extract_pdf_text(pdf_renderer.clone(), current_pdf_data.clone(), current_pdf_revision, text_tx.clone());
+                        }
+
+                        if gui.show_pdf_nav && thumbnail_revision != Some(current_pdf_revision) && !gui.pdf_scene.pages.is_empty() {
+                            thumbnail_revision = Some(current_pdf_revision);
+                            render_thumbnails(pdf_renderer.clone(), current_pdf_data.clone(), current_pdf_revision, thumb_tx.clone());
+                        }
+
+                        if let Some(pages) = gui.pdf_diff_wanted.take() {
+                            if let Some(previous) = previous_pdf.clone() {
+                                diff_pdf_page(pdf_renderer.clone(), previous, (current_pdf_data.clone(), current_pdf_revision), pages, diff_tx.clone());
+                            }
+                        }
+
+                        // Queue tiles around the view; the workers draft them first and sharpen them when idle
+                        let (viewport, tiles) = state.tile_requests(&gui.pdf_scene, current_pdf_revision, state.egui_ctx.pixels_per_point());
+                        pdf_renderer.prioritize_tiles(viewport, tiles);
+
                         // Check for Auto-Compile requests from GUI
                         if gui.compile_requested {
                             gui.compile_requested = false;
@@ -308,83 +503,117 @@ async fn main() {
                             
                             let text = gui.ui_text.clone();
                             let backend = gui.compile_backend;
+                            let engine = gui.tex_engine;
                             let draft = gui.draft_mode;
                             let focus_mode = gui.focus_mode;
                             let active_file = Some(gui.active_file_path.clone());
+                            let main_file = gui.root_file.clone();
 
                             let tx = debounce_tx.clone();
                             tokio::spawn(async move {
-                                let _ = tx.send((text, backend, draft, focus_mode, active_file)).await;
+                                let _ = tx.send((text, backend, engine, draft, focus_mode, active_file, main_file)).await;
                             });
                         }
 
+                        if std::mem::take(&mut gui.settings_changed) {
+                            if let Some(root) = vfs.root_dir.as_deref().filter(|r| !r.is_empty()) {
+                                let settings = config::ProjectSettings { engine: gui.tex_engine, root: gui.root_setting.clone() };
+                                if let Err(e) = settings.save(root) {
+                                    log::error!("Could not save project settings: {}", e);
+                                }
+                            }
+
+                            // The file opened on the command line stays the root until another is chosen
+                            let root_changed = gui.root_setting != saved_root_setting;
+                            saved_root_setting = gui.root_setting.clone();
+                            let root_file = root_file::resolve(&vfs, None, gui.root_setting.as_deref());
+                            if root_changed && root_file != gui.root_file {
+                                log::info!("Root file is now {}", root_file);
+                                gui.root_file = root_file.clone();
+                                gui.compile_requested = true;
+
+                                let dtx = dep_tx.clone();
+                                let rtx = compile_tx.clone();
+                                tokio::spawn(async move {
+                                    let (otx, orx) = tokio::sync::oneshot::channel();
+                                    let _ = rtx.send(crate::compiler_daemon::CompileRequest::ScanDependencies {
+                                        main_file: root_file,
+                                        response: otx,
+                                    }).await;
+                                    if let Ok(tree) = orx.await {
+                                        let _ = dtx.send(tree).await;
+                                    }
+                                });
+                            }
+                        }
+
                         // Check for compilation results and updated dependency tree
                         if let Ok(dep_tree) = dep_rx.try_recv() {
-                            gui.dependency_tree = Some(dep_tree);
+                            gui.set_dependency_tree(dep_tree);
+                        }
+
+                        // Live build progress for the top bar and the build log
+                        loop {
+                            match progress_rx.try_recv() {
+                                Ok(event) => gui.apply_build_event(event),
+                                Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => continue,
+                                Err(_) => break,
+                            }
                         }
 
                         if let Ok((res, dep_tree)) = result_rx.try_recv() {
-                            gui.dependency_tree = Some(dep_tree);
-                            current_pdf_revision = res.revision;
-                            current_pdf_data = std::sync::Arc::new(res.pdf);
-                            render_pdf(pdf_renderer.clone(), current_pdf_data.clone(), current_pdf_revision, 0, state.size.width as u16, state.size.height as u16, Some(pdf_tx.clone()));
+                            gui.set_dependency_tree(dep_tree);
+                            gui.set_diagnostics(res.diagnostics);
+
+                            // Failed builds keep the last good PDF on screen
+                            if let Some(pdf) = res.pdf {
+                                let previous = Some((current_pdf_data.clone(), current_pdf_revision));
+                                if res.revision != current_pdf_revision {
+                                    previous_pdf = previous.clone();
+                                }
+                                current_pdf_revision = res.revision;
+                                current_pdf_data = std::sync::Arc::new(pdf);
+                                layout_pdf(pdf_renderer.clone(), current_pdf_data.clone(), current_pdf_revision, previous, layout_tx.clone());
                             
-                            // Load SyncTeX if available
-                            let mut stx = crate::synctex::SyncTex::new();
-                            let mut loaded = false;
+                                // Load SyncTeX if available
+                                let mut stx = crate::synctex::SyncTex::new();
+                                let mut loaded = false;
                             
-                            if let Some(ref data) = res.synctex_data {
-                                use std::io::Cursor;
-                                use flate2::read::GzDecoder;
-                                
-                                // Try Gz first if it looks like one, or just try both
-                                let cursor = Cursor::new(data);
-                                let mut decoder = GzDecoder::new(cursor);
-                                let mut decoded_data = Vec::new();
-                                use std::io::Read;
-                                if decoder.read_to_end(&mut decoded_data).is_ok() {
-                                    if stx.load_from_reader(Cursor::new(decoded_data)).is_ok() {
-                                        loaded = true;
-                                    }
-                                } else {
-                                    if stx.load_from_reader(Cursor::new(data)).is_ok() {
+                                if let Some(ref data) = res.synctex_data {
+                                    loaded = stx.load_from_bytes(data).is_ok();
+                                }
+                            
+                                if !loaded {
+                                    // Fallback to disk if not in result
+                                    let build = build_dir::BuildDir::for_project(vfs.root_dir.as_deref());
+                                    if stx.load(build.output(&gui.root_file, "synctex.gz")).is_ok() || stx.load(build.output(&gui.root_file, "synctex")).is_ok() {
                                         loaded = true;
                                     }
                                 }
-                            }
                             
-                            if !loaded {
-                                // Fallback to disk if not in result
-                                if stx.load("main.synctex.gz").is_ok() || stx.load("main.synctex").is_ok() {
-                                    loaded = true;
+                                if loaded {
+                                    gui.synctex = Some(stx);
                                 }
                             }
-                            
-                            if loaded {
-                                gui.synctex = Some(stx);
-                            }
-
-                            // Lazy pre-render adjacent pages
-                            for i in 1..5 {
-                                render_pdf(pdf_renderer.clone(), current_pdf_data.clone(), current_pdf_revision, i, state.size.width as u16, state.size.height as u16, None);
-                            }
                         }
 
 
                         // Check for external file changes
                         if let Ok(crate::watcher::FileEvent::Modified(path)) = file_rx.try_recv() {
-                            if path.contains("main.tex") {
-                                if let Some(content) = vfs.read_file("main.tex") {
-                                    gui.ui_text = String::from_utf8_lossy(&content).to_string();
+                            // Open buffers without unsaved edits follow the file on disk
+                            if let Some(index) = buffers.reload_from_disk(&path, &vfs) {
+                                if index == buffers.active {
+                                    gui.ui_text = buffers.buffers[index].text();
                                 }
-                            } else if path.ends_with(".bib") {
+                            }
+                            if path.ends_with(".bib") {
                                 if let Some(_content_bytes) = vfs.read_file(&path) {
                                     // Refresh bibliography from all .bib files in VFS
                                     let mut bib_contents = Vec::new();
                                     for entry in vfs.get_all_files().iter() {
                                         if entry.key().ends_with(".bib") {
                                             if let Ok(content) = String::from_utf8(entry.value().clone()) {
-                                                bib_contents.push(content);
+                                                bib_contents.push((entry.key().clone(), content));
                                             }
                                         }
                                     }
@@ -394,47 +623,55 @@ async fn main() {
                         }
 
 
-                        // Handle file change request from GUI
-                        if let Some(new_file) = gui.file_change_request.take() {
-                            // Save current text to VFS first
-                            vfs.write_file(&gui.active_file_path, gui.ui_text.as_bytes().to_vec());
-                            
-                            if let Some(content) = vfs.read_file(&new_file) {
-                                gui.active_file_path = new_file;
-                                gui.ui_text = String::from_utf8_lossy(&content).to_string();
-                                editor.buffer = ropey::Rope::from_str(&gui.ui_text);
-                                gui.last_compile_text = gui.ui_text.clone();
-                                gui.prev_ui_text = gui.ui_text.clone();
-                                
-                                let dtx = dep_tx.clone();
-                                let rtx = compile_tx.clone();
-                                tokio::spawn(async move {
-                                    let (otx, orx) = tokio::sync::oneshot::channel();
-                                    let _ = rtx.send(crate::compiler_daemon::CompileRequest::ScanDependencies {
-                                        main_file: "main.tex".to_string(),
-                                        response: otx,
-                                    }).await;
-                                    if let Ok(tree) = orx.await {
-                                        let _ = dtx.send(tree).await;
+                        // A rename edits files across the project; each one becomes a modified buffer
+                        let mut renamed = false;
+                        if let Some(edits) = gui.rename_edits.take() {
+                            let active = buffers.active;
+                            for index in buffers.apply_edits(edits, &vfs) {
+                                let buffer = &buffers.buffers[index];
+                                if index != active && buffer.dirty {
+                                    if let Some(ref mut store) = recovery {
+                                        store.note_change(&buffer.file_name, &buffer.text());
                                     }
-                                });
+                                }
+                            }
+                            if let Some(buffer) = buffers.active() {
+                                gui.ui_text = buffer.text();
+                                renamed = true;
+                            }
+                        }
+
+                        // Undo/redo go through the editor's history instead of the text box's own
+                        let mut history_moved = false;
+                        if let (Some(step), Some(buffer)) = (gui.history_request.take(), buffers.active_mut()) {
+                            if let Some(cursor) = buffer.editor.apply_history(step) {
+                                gui.ui_text = buffer.text();
+                                gui.cursor_override = Some(cursor);
+                                history_moved = true;
                             }
                         }
 
-                        // Sync back to editor and update VFS if changed
+                        // Sync back to the active buffer and update VFS if changed
                         let current_text = gui.ui_text.clone();
-                        if editor.get_text() != current_text {
-                            editor.buffer = ropey::Rope::from_str(&current_text);
+                        let buffer_text = buffers.active().map(|b| b.text());
+                        if history_moved || renamed || buffer_text.is_some_and(|t| t != current_text) {
+                            if let Some(buffer) = buffers.active_mut() {
+                                if !history_moved && !renamed {
+                                    buffer.editor.replace_text(&current_text);
+                                }
+                                buffer.update_dirty();
+                            }
                             
                             // Update VFS and request async dependency scan
                             vfs.write_file(&gui.active_file_path, current_text.as_bytes().to_vec());
                             
                             let dtx = dep_tx.clone();
                             let rtx = compile_tx.clone();
+                            let main_file = gui.root_file.clone();
                             tokio::spawn(async move {
                                 let (otx, orx) = tokio::sync::oneshot::channel();
                                 let _ = rtx.send(crate::compiler_daemon::CompileRequest::ScanDependencies {
-                                    main_file: "main.tex".to_string(),
+                                    main_file,
                                     response: otx,
                                 }).await;
                                 if let Ok(tree) = orx.await {
@@ -447,17 +684,112 @@ async fn main() {
                             for entry in vfs.get_all_files().iter() {
                                 if entry.key().ends_with(".bib") {
                                     if let Ok(content) = String::from_utf8(entry.value().clone()) {
-                                        bib_contents.push(content);
+                                        bib_contents.push((entry.key().clone(), content));
                                     }
                                 }
                             }
                             gui.refresh_bibliography(bib_contents);
 
-                            tokio::spawn(async move {
-                                let _ = io::IoHandler::auto_save(current_text, "autosave.tex").await;
-                            });
+                            // Keep a recovery copy of unsaved text, dropping it once the buffer is clean again
+                            if let (Some(store), Some(buffer)) = (recovery.as_mut(), buffers.active()) {
+                                if buffer.dirty {
+                                    store.note_change(&buffer.file_name, &current_text);
+                                } else {
+                                    store.discard(&buffer.file_name);
+                                }
+                            }
+                        }
+
+                        if let Some(ref mut store) = recovery {
+                            // Written in place rather than spawned, so a save or close that follows can discard them
+                            for (path, text) in store.take_due() {
+                                if let Err(e) = io::IoHandler::write_atomic_sync(&path, text.as_bytes()) {
+                                    log::error!("Failed to write recovery copy {}: {}", path.display(), e);
+                                }
+                            }
+                        }
+
+                        // Restoring puts the recovered text into the VFS and opens it as a modified buffer
+                        if let Some((found, restore)) = gui.recovery_request.take() {
+                            if restore {
+                                vfs.write_file(&found.file_name, found.recovered.into_bytes());
+                                gui.file_change_request = Some(found.file_name);
+                            } else if let Some(ref mut store) = recovery {
+                                store.discard(&found.file_name);
+                            }
+                        }
+
+                        if std::mem::take(&mut gui.save_request) {
+                            gui.buffer_error = buffers.save(buffers.active, &vfs).err();
+                            if gui.buffer_error.is_none() {
+                                if let (Some(store), Some(buffer)) = (recovery.as_mut(), buffers.active()) {
+                                    store.discard(&buffer.file_name);
+                                }
+                            }
                         }
 
+                        // Closing a tab, after the unsaved-changes prompt if it had edits
+                        if let Some((file, save)) = gui.close_request.take() {
+                            if let Some(index) = buffers.find(&file) {
+                                match if save { buffers.save(index, &vfs) } else { Ok(()) } {
+                                    Ok(()) => {
+                                        buffers.close(index, &vfs);
+                                        if let Some(ref mut store) = recovery {
+                                            store.discard(&file);
+                                        }
+                                        gui.close_prompt = None;
+                                        gui.buffer_error = None;
+                                        if file == gui.active_file_path {
+                                            gui.file_change_request = buffers.active().map(|b| b.file_name.clone());
+                                        }
+                                    }
+                                    Err(e) => gui.buffer_error = Some(e),
+                                }
+                            }
+                        }
+
+                        if let Some(save) = gui.quit_request.take() {
+                            match if save { buffers.save_all(&vfs) } else { Ok(()) } {
+                                Ok(()) => {
+                                    // Saved or deliberately thrown away: nothing left to recover
+                                    if let Some(ref mut store) = recovery {
+                                        for tab in buffers.tabs() {
+                                            store.discard(&tab.file_name);
+                                        }
+                                    }
+                                    target.exit();
+                                }
+                                Err(e) => gui.buffer_error = Some(e),
+                            }
+                        }
+
+                        // Handle file change request from GUI
+                        if let Some(new_file) = gui.file_change_request.take() {
+                            // Open it in a tab, or switch to the tab it already has
+                            if buffers.open(&new_file, &vfs).is_some() {
+                                gui.ui_text = buffers.active().map(|b| b.text()).unwrap_or_default();
+                                gui.active_file_path = new_file;
+                                gui.last_compile_text = gui.ui_text.clone();
+                                gui.prev_ui_text = gui.ui_text.clone();
+                                
+                                let dtx = dep_tx.clone();
+                                let rtx = compile_tx.clone();
+                                let main_file = gui.root_file.clone();
+                                tokio::spawn(async move {
+                                    let (otx, orx) = tokio::sync::oneshot::channel();
+                                    let _ = rtx.send(crate::compiler_daemon::CompileRequest::ScanDependencies {
+                                        main_file,
+                                        response: otx,
+                                    }).await;
+                                    if let Ok(tree) = orx.await {
+                                        let _ = dtx.send(tree).await;
+                                    }
+                                });
+                            }
+                        }
+
+                        gui.tabs = buffers.tabs();
+
 
                         match render_res {
                             Ok(_) => {}
@@ -474,6 +806,9 @@ async fn main() {
             Event::AboutToWait => {
                 window.request_redraw();
             }
+            Event::LoopExiting => {
+                buffers.save_histories();
+            }
             _ => {}
         }
     }).unwrap();
@@ -0,0 +1,126 @@
+use egui::{Color32, ColorImage};
+use crate::pdf_renderer::PageChanges;
+
+/// How far the luminance of a pixel has to move before it counts as changed, out of 255.
+const DIFF_THRESHOLD: i32 = 40;
+pub const ADDED: Color32 = Color32::from_rgb(40, 170, 90);
+pub const REMOVED: Color32 = Color32::from_rgb(220, 70, 70);
+
+/// How the diff view shows the two builds of a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffMode {
+    /// One image: ink that appeared in one colour, ink that went away in another
+    Overlay,
+    SideBySide,
+    /// The new page over the old one, uncovered up to a slider
+    Swipe,
+}
+
+/// A page of the previous and the current build, rendered at the same width, and where they differ.
+pub struct PageDiff {
+    pub old: ColorImage,
+    pub new: ColorImage,
+    pub overlay: ColorImage,
+    /// Pixels that differ
+    pub changed: usize,
+}
+
+impl PageDiff {
+    /// Compare two renders pixel by pixel from their top left corners; a missing page is blank paper.
+    pub fn new(old: Option<ColorImage>, new: Option<ColorImage>) -> Self {
+        let size = [
+            old.as_ref().map_or(0, |i| i.size[0]).max(new.as_ref().map_or(0, |i| i.size[0])),
+            old.as_ref().map_or(0, |i| i.size[1]).max(new.as_ref().map_or(0, |i| i.size[1])),
+        ];
+        let old = old.unwrap_or_else(|| ColorImage::new(size, Color32::WHITE));
+        let new = new.unwrap_or_else(|| ColorImage::new(size, Color32::WHITE));
+
+        let mut overlay = ColorImage::new(size, Color32::WHITE);
+        let mut changed = 0;
+        for y in 0..size[1] {
+            for x in 0..size[0] {
+                let (before, after) = (pixel(&old, x, y), pixel(&new, x, y));
+                let delta = luminance(after) - luminance(before);
+                overlay.pixels[y * size[0] + x] = if delta < -DIFF_THRESHOLD {
+                    changed += 1;
+                    ADDED
+                } else if delta > DIFF_THRESHOLD {
+                    changed += 1;
+                    REMOVED
+                } else {
+                    // Unchanged content is faded so the changes stand out
+                    Color32::from_rgb(fade(after.r()), fade(after.g()), fade(after.b()))
+                };
+            }
+        }
+        Self { old, new, overlay, changed }
+    }
+}
+
+fn pixel(image: &ColorImage, x: usize, y: usize) -> Color32 {
+    if x < image.size[0] && y < image.size[1] {
+        image.pixels[y * image.size[0] + x]
+    } else {
+        Color32::WHITE
+    }
+}
+
+fn luminance(c: Color32) -> i32 {
+    (c.r() as i32 * 54 + c.g() as i32 * 183 + c.b() as i32 * 19) / 256
+}
+
+fn fade(channel: u8) -> u8 {
+    255 - (255 - channel) / 4
+}
+
+/// The page of the previous build that a changed page replaced, if it was not a new page.
+///
+/// Changed pages sit between carried ones, so count on from the last carried page before it.
+pub fn previous_page(changes: &PageChanges, page: usize) -> Option<usize> {
+    let before = changes.carried.iter().filter(|(_, new)| *new < page).max_by_key(|(_, new)| *new);
+    let candidate = match before {
+        Some((old, new)) => old + (page - new),
+        None => page,
+    };
+    let limit = changes.carried.iter().filter(|(_, new)| *new > page).map(|(old, _)| *old).min();
+    let taken = changes.carried.iter().any(|(old, _)| *old == candidate);
+    (!taken && limit.map_or(true, |limit| candidate < limit)).then_some(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_diff_marks_added_and_removed_ink() {
+        let mut old = ColorImage::new([3, 1], Color32::WHITE);
+        old.pixels[0] = Color32::BLACK;
+        let mut new = ColorImage::new([3, 2], Color32::WHITE);
+        new.pixels[1] = Color32::BLACK;
+        new.pixels[2] = Color32::from_gray(240);
+
+        let diff = PageDiff::new(Some(old), Some(new));
+        assert_eq!(diff.overlay.size, [3, 2]);
+        assert_eq!(&diff.overlay.pixels[..2], &[REMOVED, ADDED]);
+        // A faint change stays under the threshold
+        assert_eq!(diff.overlay.pixels[2], Color32::from_gray(252));
+        assert_eq!(diff.changed, 2);
+
+        let diff = PageDiff::new(None, Some(ColorImage::new([2, 2], Color32::BLACK)));
+        assert_eq!(diff.changed, 4);
+        assert_eq!(diff.old.size, [2, 2]);
+    }
+
+    #[test]
+    fn test_previous_page_skips_inserted_pages() {
+        // Page 1 was edited, and a page inserted after it pushed the rest down
+        let changes = PageChanges { previous: 1, changed: vec![1, 2], carried: vec![(0, 0), (2, 3), (3, 4)] };
+        assert_eq!(previous_page(&changes, 1), Some(1));
+        assert_eq!(previous_page(&changes, 2), None);
+
+        // The first page changed, and a page was appended
+        let changes = PageChanges { previous: 1, changed: vec![0, 2], carried: vec![(1, 1)] };
+        assert_eq!(previous_page(&changes, 0), Some(0));
+        assert_eq!(previous_page(&changes, 2), Some(2));
+    }
+}
@@ -1,12 +1,19 @@
 use std::collections::VecDeque;
-use mupdf::{Document, Colorspace, Matrix, DisplayList};
+use mupdf::{Document, Colorspace, Matrix, DisplayList, Device, Pixmap, TextPageOptions};
+use mupdf::pdf::{PdfDocument, PdfObject};
+use std::hash::Hasher;
 use std::error::Error;
 use dashmap::DashMap;
-use std::sync::Arc;
-use ahash::RandomState;
-use log::info;
+use std::sync::{Arc, Condvar};
+use ahash::{AHashMap, RandomState};
 use lru::LruCache;
 use std::num::NonZeroUsize;
+
+/// Side of a square tile in device pixels at `Quality::Standard`.
+pub const TILE_SIZE: u32 = 256;
+/// Zoom levels per doubling of the tile grid's resolution.
+const LEVELS_PER_OCTAVE: f32 = 4.0;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Rect {
     pub x: f32,
@@ -15,17 +22,127 @@ pub struct Rect {
     pub height: f32,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Resolution of a tile relative to its grid: drafts are quick and blurry, high quality is supersampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Quality {
     Draft,
     Standard,
     HighQuality,
 }
 
+impl Quality {
+    /// Rasterized pixels per grid pixel.
+    fn scale(self) -> f32 {
+        match self {
+            Quality::Draft => 0.25,
+            Quality::Standard => 1.0,
+            Quality::HighQuality => 2.0,
+        }
+    }
+}
+
+/// One tile of a page's grid at a zoom level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    pub revision: u64,
+    pub page: usize,
+    /// The grid has `2^(level / 4)` pixels per PDF point
+    pub level: i32,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl TileKey {
+    /// The level closest to a zoom, so small zoom changes keep using the same tiles.
+    pub fn level_for(pixels_per_point: f32) -> i32 {
+        (pixels_per_point.max(1e-3).log2() * LEVELS_PER_OCTAVE).round().clamp(-16.0, 24.0) as i32
+    }
+
+    /// Grid pixels per PDF point at a level.
+    pub fn scale(level: i32) -> f32 {
+        (level as f32 / LEVELS_PER_OCTAVE).exp2()
+    }
+
+    /// Columns and rows of tiles covering a page of this size in points.
+    pub fn grid(page_size: (f32, f32), level: i32) -> (u16, u16) {
+        let scale = Self::scale(level);
+        let tiles = |points: f32| (points * scale / TILE_SIZE as f32).ceil().max(1.0) as u16;
+        (tiles(page_size.0), tiles(page_size.1))
+    }
+}
+
+/// A finished tile in BGRA and the part of its page it covers, in PDF points.
+pub struct RenderedTile {
+    pub key: TileKey,
+    pub quality: Quality,
+    pub width: u32,
+    pub height: u32,
+    pub rect: Rect,
+    pub pixels: Arc<Vec<u8>>,
+}
+
+/// Where a link in the PDF goes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkTarget {
+    /// A page of this document, 0-based
+    Page(usize),
+    Uri(String),
+}
+
+/// A link annotation: where it sits on the page in page points and where it goes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageLink {
+    pub rect: egui::Rect,
+    pub target: LinkTarget,
+}
+
+/// An entry of the document outline (the PDF bookmarks).
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutlineItem {
+    pub title: String,
+    pub page: Option<usize>,
+    /// Height of the destination on its page, in page points from the top
+    pub y: f32,
+    pub children: Vec<OutlineItem>,
+}
+
+impl OutlineItem {
+    fn from_mupdf(outline: mupdf::Outline) -> Self {
+        Self {
+            title: outline.title,
+            page: outline.page.map(|p| p as usize),
+            y: if outline.y.is_finite() { outline.y.max(0.0) } else { 0.0 },
+            children: outline.down.into_iter().map(Self::from_mupdf).collect(),
+        }
+    }
+}
+
+/// How the pages of a new build relate to the previous one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PageChanges {
+    pub previous: u64,
+    /// Pages (0-based) that look different or are new
+    pub changed: Vec<usize>,
+    /// Unchanged pages as (page in the previous build, page now)
+    pub carried: Vec<(usize, usize)>,
+}
+
+/// A tile the viewer wants, where it is on screen (in pixels) and the quality it already has.
+#[derive(Debug, Clone, Copy)]
+pub struct TileRequest {
+    pub key: TileKey,
+    pub rect: Rect,
+    pub have: Option<Quality>,
+}
+
 pub struct TileRenderQueue {
-    pub visible_tiles: VecDeque<(u16, u16)>, // Render immediately
-    pub adjacent_tiles: VecDeque<(u16, u16)>, // Next priority
-    pub offscreen_tiles: VecDeque<(u16, u16)>, // Low priority
+    pub visible_tiles: VecDeque<TileRequest>, // Render immediately
+    pub adjacent_tiles: VecDeque<TileRequest>, // Next priority
+    pub offscreen_tiles: VecDeque<TileRequest>, // Low priority
+    /// Tiles handed to a worker, until the viewer reports having them at that quality
+    in_flight: AHashMap<TileKey, Quality>,
+    /// The build tiles are rendered from; tiles of other builds wait
+    source: Option<(u64, Arc<Vec<u8>>)>,
 }
 
 impl TileRenderQueue {
@@ -34,32 +151,53 @@ impl TileRenderQueue {
             visible_tiles: VecDeque::new(),
             adjacent_tiles: VecDeque::new(),
             offscreen_tiles: VecDeque::new(),
+            in_flight: AHashMap::new(),
+            source: None,
         }
     }
 
-    pub fn prioritize_tiles(&mut self, viewport: Rect, all_tiles: Vec<(u16, u16)>) {
+    pub fn prioritize_tiles(&mut self, viewport: Rect, all_tiles: Vec<TileRequest>) {
         self.visible_tiles.clear();
         self.adjacent_tiles.clear();
         self.offscreen_tiles.clear();
 
-        for (tx, ty) in all_tiles {
-            let tile_rect = Rect {
-                x: tx as f32 * 256.0,
-                y: ty as f32 * 256.0,
-                width: 256.0,
-                height: 256.0,
-            };
+        // Jobs stay claimed until the viewer has the result, or stops asking for the tile
+        let wanted: AHashMap<TileKey, Option<Quality>> = all_tiles.iter().map(|t| (t.key, t.have)).collect();
+        self.in_flight.retain(|key, quality| wanted.get(key).is_some_and(|have| *have < Some(*quality)));
 
-            if self.intersects(&viewport, &tile_rect) {
-                self.visible_tiles.push_back((tx, ty));
-            } else if self.is_adjacent(&viewport, &tile_rect) {
-                self.adjacent_tiles.push_back((tx, ty));
+        for tile in all_tiles {
+            if self.intersects(&viewport, &tile.rect) {
+                self.visible_tiles.push_back(tile);
+            } else if self.is_adjacent(&viewport, &tile.rect) {
+                self.adjacent_tiles.push_back(tile);
             } else {
-                self.offscreen_tiles.push_back((tx, ty));
+                self.offscreen_tiles.push_back(tile);
             }
         }
     }
 
+    /// The most urgent tile of the source build that still needs work, claimed for the caller.
+    pub fn next_job(&mut self) -> Option<(TileKey, Quality)> {
+        let revision = self.source.as_ref()?.0;
+        // Everything near the view gets a draft first and sharpens while idle; offscreen tiles only get drafts
+        let passes = [
+            (Quality::Draft, &self.visible_tiles),
+            (Quality::Draft, &self.adjacent_tiles),
+            (Quality::Draft, &self.offscreen_tiles),
+            (Quality::Standard, &self.visible_tiles),
+            (Quality::Standard, &self.adjacent_tiles),
+            (Quality::HighQuality, &self.visible_tiles),
+        ];
+        let job = passes.into_iter().find_map(|(quality, tiles)| {
+            tiles.iter()
+                .filter(|tile| tile.key.revision == revision)
+                .find(|tile| tile.have.max(self.in_flight.get(&tile.key).copied()) < Some(quality))
+                .map(|tile| (tile.key, quality))
+        })?;
+        self.in_flight.insert(job.0, job.1);
+        Some(job)
+    }
+
     fn intersects(&self, r1: &Rect, r2: &Rect) -> bool {
         r1.x < r2.x + r2.width &&
         r1.x + r1.width > r2.x &&
@@ -68,7 +206,7 @@ impl TileRenderQueue {
     }
 
     fn is_adjacent(&self, r1: &Rect, r2: &Rect) -> bool {
-        let margin = 256.0;
+        let margin = TILE_SIZE as f32;
         let expanded_r1 = Rect {
             x: r1.x - margin,
             y: r1.y - margin,
@@ -84,68 +222,144 @@ use std::sync::Mutex;
 struct SendDocument(Document);
 unsafe impl Send for SendDocument {}
 
-#[allow(dead_code)]
-struct SendDisplayList(DisplayList);
+/// A page's recorded drawing commands and its size in points.
+struct SendDisplayList {
+    list: DisplayList,
+    size: (f32, f32),
+}
 unsafe impl Send for SendDisplayList {}
+// Display lists are immutable once recorded, and mupdf runs them from several threads at once
+unsafe impl Sync for SendDisplayList {}
 
 pub struct PdfRenderer {
-    // Cache for rendered pixmaps: (revision, page, width, height)
-    cache: Arc<Mutex<LruCache<(u64, i32, u16, u16), Arc<Vec<u8>>>>>,
+    // Cache for rendered tiles, so tiles the viewer evicted come back without rasterizing
+    cache: Arc<Mutex<LruCache<(TileKey, Quality), Arc<Vec<u8>>>>>,
     // Cache for interpreted display lists to avoid re-parsing the page
-    #[allow(dead_code)]
     dl_cache: DashMap<(u64, i32), Arc<SendDisplayList>, RandomState>,
     doc_cache: DashMap<u64, Arc<Mutex<SendDocument>>, RandomState>,
+    // Per-page fingerprints of recent builds, to tell which pages a rebuild changed
+    fingerprints: DashMap<u64, Arc<Vec<u64>>, RandomState>,
     pub render_queue: Mutex<TileRenderQueue>,
+    /// Wakes the tile workers when the queue changes
+    queue_changed: Condvar,
 }
 
 impl PdfRenderer {
     pub fn new() -> Result<Self, Box<dyn Error>> {
         Ok(Self {
-            cache: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(100).unwrap()))),
+            cache: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(512).unwrap()))),
             dl_cache: DashMap::with_hasher(RandomState::new()),
             doc_cache: DashMap::with_hasher(RandomState::new()),
+            fingerprints: DashMap::with_hasher(RandomState::new()),
             render_queue: Mutex::new(TileRenderQueue::new()),
+            queue_changed: Condvar::new(),
         })
     }
 
-    pub fn prioritize_tiles(&self, viewport: Rect, all_tiles: Vec<(u16, u16)>) {
+    /// Render tiles from a new build, dropping the documents and display lists of older ones.
+    pub fn set_tile_source(&self, pdf_data: Arc<Vec<u8>>, revision: u64) {
+        self.dl_cache.retain(|(rev, _), _| *rev == revision);
+        self.doc_cache.retain(|rev, _| *rev == revision);
+        self.render_queue.lock().unwrap().source = Some((revision, pdf_data));
+        self.queue_changed.notify_all();
+    }
+
+    pub fn prioritize_tiles(&self, viewport: Rect, all_tiles: Vec<TileRequest>) {
         if let Ok(mut queue) = self.render_queue.lock() {
             queue.prioritize_tiles(viewport, all_tiles);
         }
+        self.queue_changed.notify_all();
     }
 
-    pub fn render_tiles_at_quality(&self, viewport: Rect, quality: Quality) {
-        // Implementation of progressive rendering based on quality
-        match quality {
-            Quality::Draft => {
-                // First pass: Draft quality (fast)
-                info!("Rendering Draft quality tiles for viewport: {:?}", viewport);
-                // Implementation: low-res rasterization or using cached low-res tiles
-            }
-            Quality::Standard => {
-                // Then: Standard quality (background task)
-                info!("Rendering Standard quality tiles for viewport: {:?}", viewport);
-            }
-            Quality::HighQuality => {
-                // Finally: High quality (idle time)
-                info!("Rendering High quality tiles for viewport: {:?}", viewport);
+    /// Start `workers` threads draining the tile queue; they stop once `tx` is closed.
+    pub fn start_tile_workers(self: &Arc<Self>, workers: usize, tx: tokio::sync::mpsc::Sender<RenderedTile>) {
+        for _ in 0..workers {
+            let renderer = self.clone();
+            let tx = tx.clone();
+            std::thread::spawn(move || renderer.progressive_render(tx));
+        }
+    }
+
+    /// A worker's loop: take the most urgent job, rasterize it, hand it to the viewer.
+    pub fn progressive_render(&self, tx: tokio::sync::mpsc::Sender<RenderedTile>) {
+        loop {
+            let (key, quality, pdf_data) = {
+                let mut queue = self.render_queue.lock().unwrap();
+                loop {
+                    if let Some((key, quality)) = queue.next_job() {
+                        let pdf_data = queue.source.as_ref().map(|(_, data)| data.clone()).unwrap_or_default();
+                        break (key, quality, pdf_data);
+                    }
+                    queue = self.queue_changed.wait(queue).unwrap();
+                }
+            };
+            match self.render_tile(&pdf_data, key.revision, key, quality) {
+                Ok(tile) => {
+                    if tx.blocking_send(tile).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => log::warn!("Failed to render tile {:?}: {}", key, e),
             }
         }
     }
 
-    pub fn progressive_render(&self, viewport: Rect) {
-        // First pass: Draft quality (fast)
-        self.render_tiles_at_quality(viewport, Quality::Draft);
+    /// Rasterize one tile from the page's display list.
+    pub fn render_tile(&self, pdf_data: &[u8], revision: u64, key: TileKey, quality: Quality) -> Result<RenderedTile, Box<dyn Error>> {
+        let page = self.display_list(pdf_data, revision, key.page as i32)?;
+        let scale = TileKey::scale(key.level) * quality.scale();
+        let tile = (TILE_SIZE as f32 * quality.scale()) as u32;
 
-        // Then: Standard quality (background task)
-        // In this implementation, we log the intent as requested by the pipeline diagram
-        self.render_tiles_at_quality(viewport, Quality::Standard);
+        // Edge tiles stop at the page
+        let (x0, y0) = (key.x as u32 * tile, key.y as u32 * tile);
+        let width = (x0 + tile).min((page.size.0 * scale).ceil() as u32).saturating_sub(x0).max(1);
+        let height = (y0 + tile).min((page.size.1 * scale).ceil() as u32).saturating_sub(y0).max(1);
 
-        // Finally: High quality (idle time)
-        self.render_tiles_at_quality(viewport, Quality::HighQuality);
-    }
+        let cached = self.cache.lock().unwrap().get(&(key, quality)).cloned();
+        let pixels = match cached {
+            Some(pixels) => pixels,
+            None => {
+                let mut pixmap = Pixmap::new(&Colorspace::device_rgb(), x0 as i32, y0 as i32, width as i32, height as i32, false)?;
+                pixmap.clear_with(255)?;
+                {
+                    let device = Device::from_pixmap(&pixmap)?;
+                    let area = mupdf::Rect::new(x0 as f32, y0 as f32, (x0 + width) as f32, (y0 + height) as f32);
+                    page.list.run(&device, &Matrix::new_scale(scale, scale), area)?;
+                }
+                let pixels = Arc::new(self.convert_to_bgra(pixmap.samples(), width as u16, height as u16));
+                self.cache.lock().unwrap().put((key, quality), pixels.clone());
+                pixels
+            }
+        };
 
+        let rect = Rect {
+            x: x0 as f32 / scale,
+            y: y0 as f32 / scale,
+            width: width as f32 / scale,
+            height: height as f32 / scale,
+        };
+        // High quality is supersampled and shrunk back to the grid
+        let (pixels, width, height) = if quality == Quality::HighQuality {
+            let (half, w, h) = halve_bgra(&pixels, width, height);
+            (Arc::new(half), w, h)
+        } else {
+            (pixels, width, height)
+        };
+        Ok(RenderedTile { key, quality, width, height, rect, pixels })
+    }
 
+    fn display_list(&self, pdf_data: &[u8], revision: u64, page_index: i32) -> Result<Arc<SendDisplayList>, Box<dyn Error>> {
+        if let Some(list) = self.dl_cache.get(&(revision, page_index)) {
+            return Ok(list.value().clone());
+        }
+        let document_arc = self.get_document(pdf_data, revision)?;
+        let document = document_arc.lock().map_err(|_| "Mutex poisoned")?;
+        let page = document.0.load_page(page_index)?;
+        let bounds = page.bounds()?;
+        let list = Arc::new(SendDisplayList { list: page.to_display_list(false)?, size: (bounds.width(), bounds.height()) });
+        self.dl_cache.insert((revision, page_index), list.clone());
+        Ok(list)
+    }
 
     fn get_document(&self, pdf_data: &[u8], revision: u64) -> Result<Arc<Mutex<SendDocument>>, Box<dyn Error>> {
         if let Some(doc) = self.doc_cache.get(&revision) {
@@ -157,6 +371,112 @@ impl PdfRenderer {
         }
     }
 
+    /// A hash per page of what it draws, cached per revision.
+    pub fn page_fingerprints(&self, pdf_data: &[u8], revision: u64) -> Result<Arc<Vec<u64>>, Box<dyn Error>> {
+        if let Some(prints) = self.fingerprints.get(&revision) {
+            return Ok(prints.value().clone());
+        }
+        let document = PdfDocument::from_bytes(pdf_data)?;
+        let mut prints = Vec::new();
+        for index in 0..document.page_count()? {
+            prints.push(page_fingerprint(&document.find_page(index)?)?);
+        }
+        let prints = Arc::new(prints);
+        self.fingerprints.insert(revision, prints.clone());
+        Ok(prints)
+    }
+
+    /// Compare a new build with the previous one and carry the rendered tiles of unchanged pages over to it.
+    pub fn compare_revisions(&self, previous: (&[u8], u64), current: (&[u8], u64)) -> Result<PageChanges, Box<dyn Error>> {
+        let old = self.page_fingerprints(previous.0, previous.1)?;
+        let new = self.page_fingerprints(current.0, current.1)?;
+        self.fingerprints.retain(|rev, _| *rev == previous.1 || *rev == current.1);
+
+        let mut changes = match_pages(&old, &new);
+        changes.previous = previous.1;
+
+        let moves: AHashMap<usize, usize> = changes.carried.iter().copied().collect();
+        let mut cache = self.cache.lock().unwrap();
+        let carried: Vec<_> = cache.iter()
+            .filter(|((key, _), _)| key.revision == previous.1)
+            .filter_map(|((key, quality), pixels)| {
+                let page = *moves.get(&key.page)?;
+                Some(((TileKey { revision: current.1, page, ..*key }, *quality), pixels.clone()))
+            })
+            .collect();
+        for (key, pixels) in carried {
+            cache.put(key, pixels);
+        }
+        Ok(changes)
+    }
+
+    /// The structured text of a page: its characters in reading order with their boxes in page points.
+    pub fn page_text(&self, pdf_data: &[u8], revision: u64, page_index: i32) -> Result<crate::pdf_text::PageText, Box<dyn Error>> {
+        use crate::pdf_text::{PageChar, PageText};
+
+        let document_arc = self.get_document(pdf_data, revision)?;
+        let document = document_arc.lock().map_err(|_| "Mutex poisoned")?;
+        let text_page = document.0.load_page(page_index)?.to_text_page(TextPageOptions::empty())?;
+        let mut lines = Vec::new();
+        for block in text_page.blocks() {
+            for line in block.lines() {
+                lines.push(line.chars().filter_map(|ch| {
+                    let quad = ch.quad();
+                    let rect = egui::Rect::from_points(&[
+                        egui::pos2(quad.ul.x, quad.ul.y),
+                        egui::pos2(quad.ur.x, quad.ur.y),
+                        egui::pos2(quad.ll.x, quad.ll.y),
+                        egui::pos2(quad.lr.x, quad.lr.y),
+                    ]);
+                    Some(PageChar { c: ch.char()?, rect })
+                }).collect());
+            }
+        }
+        Ok(PageText::from_lines(lines))
+    }
+
+    /// The link annotations of a page: internal destinations and URIs.
+    pub fn page_links(&self, pdf_data: &[u8], revision: u64, page_index: i32) -> Result<Vec<PageLink>, Box<dyn Error>> {
+        let document_arc = self.get_document(pdf_data, revision)?;
+        let document = document_arc.lock().map_err(|_| "Mutex poisoned")?;
+        let page = document.0.load_page(page_index)?;
+        let mut links = Vec::new();
+        for link in page.links()? {
+            let external = link.uri.contains("://") || link.uri.starts_with("mailto:");
+            let target = if external { LinkTarget::Uri(link.uri) } else { LinkTarget::Page(link.page as usize) };
+            let rect = egui::Rect::from_min_max(egui::pos2(link.bounds.x0, link.bounds.y0), egui::pos2(link.bounds.x1, link.bounds.y1));
+            links.push(PageLink { rect, target });
+        }
+        Ok(links)
+    }
+
+    /// The document outline, as `hyperref` writes it from the sectioning commands.
+    pub fn outline(&self, pdf_data: &[u8], revision: u64) -> Result<Vec<OutlineItem>, Box<dyn Error>> {
+        let document_arc = self.get_document(pdf_data, revision)?;
+        let document = document_arc.lock().map_err(|_| "Mutex poisoned")?;
+        Ok(document.0.outlines()?.into_iter().map(OutlineItem::from_mupdf).collect())
+    }
+
+    /// A whole page scaled to `width` pixels across, for thumbnails and the diff view.
+    pub fn render_page_image(&self, pdf_data: &[u8], revision: u64, page_index: i32, width: u32) -> Result<egui::ColorImage, Box<dyn Error>> {
+        let page = self.display_list(pdf_data, revision, page_index)?;
+        let scale = width as f32 / page.size.0;
+        let pixmap = page.list.to_pixmap(&Matrix::new_scale(scale, scale), &Colorspace::device_rgb(), false)?;
+        Ok(egui::ColorImage::from_rgb([pixmap.width() as usize, pixmap.height() as usize], pixmap.samples()))
+    }
+
+    /// The size of every page in PDF points, which the viewer lays out before anything is rendered.
+    pub fn page_sizes(&self, pdf_data: &[u8], revision: u64) -> Result<Vec<(f32, f32)>, Box<dyn Error>> {
+        let document_arc = self.get_document(pdf_data, revision)?;
+        let document = document_arc.lock().map_err(|_| "Mutex poisoned")?;
+        let mut sizes = Vec::new();
+        for index in 0..document.0.page_count()? {
+            let bounds = document.0.load_page(index)?.bounds()?;
+            sizes.push((bounds.width(), bounds.height()));
+        }
+        Ok(sizes)
+    }
+
     fn convert_to_bgra(&self, samples: &[u8], width: u16, height: u16) -> Vec<u8> {
         let mut bgra_samples = vec![255u8; width as usize * height as usize * 4];
         bgra_samples.chunks_exact_mut(4)
@@ -168,41 +488,203 @@ impl PdfRenderer {
             });
         bgra_samples
     }
+}
 
-    pub fn render_page(&self, pdf_data: &[u8], revision: u64, page_index: i32, width: u16, height: u16) -> Result<(Arc<Vec<u8>>, f32, f32), Box<dyn Error>> {
-        // Create a unique key for this render
-        let key = (revision, page_index, width, height);
-        
-        let document_arc = self.get_document(pdf_data, revision)?;
+/// Pair each new page with an old page that draws the same thing, preferring the one at the same number.
+fn match_pages(old: &[u64], new: &[u64]) -> PageChanges {
+    let mut unused: AHashMap<u64, VecDeque<usize>> = AHashMap::new();
+    for (page, print) in old.iter().enumerate() {
+        unused.entry(*print).or_default().push_back(page);
+    }
 
+    let mut changes = PageChanges::default();
+    for (page, print) in new.iter().enumerate() {
+        let Some(candidates) = unused.get_mut(print) else {
+            changes.changed.push(page);
+            continue;
+        };
+        let from = match candidates.iter().position(|&p| p == page) {
+            Some(same) => candidates.remove(same),
+            None => candidates.pop_front(),
+        };
+        match from {
+            Some(from) => changes.carried.push((from, page)),
+            None => changes.changed.push(page),
+        }
+    }
+    changes
+}
 
-        // We need to load the page anyway to get bounds if not cached? 
-        // Actually, let's keep it simple.
-        let document = document_arc.lock().map_err(|_| "Mutex poisoned")?;
-        let page = document.0.load_page(page_index)?;
-        let bounds = page.bounds()?;
-        let pw = bounds.width();
-        let ph = bounds.height();
-
-        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
-            return Ok((cached.clone(), pw, ph));
-        }
-
-        let scale_x = width as f32 / pw;
-        let scale_y = height as f32 / ph;
-        
-        let matrix = Matrix::new_scale(scale_x, scale_y);
-        let colorspace = Colorspace::device_rgb();
-        
-        // Argument 'false, false' for show_extras and show_annotations (depends on mupdf-rs version)
-        let pixmap = page.to_pixmap(&matrix, &colorspace, false, false)?;
-        let samples = pixmap.samples();
-        
-        // Convert to BGRA
-        let bgra_samples = self.convert_to_bgra(samples, width, height);
-        
-        let arc_samples = Arc::new(bgra_samples);
-        self.cache.lock().unwrap().put(key, arc_samples.clone());
-        Ok((arc_samples, pw, ph))
+/// Nesting beyond which resources are not followed, as a guard against reference cycles.
+const FINGERPRINT_DEPTH: usize = 12;
+
+/// Hash a page's content streams, boxes and the resources they draw with.
+fn page_fingerprint(page: &PdfObject) -> Result<u64, Box<dyn Error>> {
+    let mut hasher = ahash::AHasher::default();
+    for key in ["Contents", "Resources", "MediaBox", "CropBox", "Rotate"] {
+        hasher.write(key.as_bytes());
+        if let Some(value) = page.get_dict(key)? {
+            hash_object(&value, &mut hasher, 0)?;
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// Hash an object by value: references are followed, so renumbered objects hash the same.
+fn hash_object(object: &PdfObject, hasher: &mut ahash::AHasher, depth: usize) -> Result<(), Box<dyn Error>> {
+    if depth > FINGERPRINT_DEPTH {
+        return Ok(());
+    }
+    let resolved;
+    let object = if object.is_indirect()? {
+        match object.resolve()? {
+            Some(target) => {
+                resolved = target;
+                &resolved
+            }
+            None => return Ok(()),
+        }
+    } else {
+        object
+    };
+
+    if object.is_name()? {
+        let name = object.as_name()?;
+        // Font subsets are retagged as other pages use new glyphs; the glyphs this page draws stay the same
+        let subset_tag = name.len() > 7 && name[6] == b'+' && name[..6].iter().all(u8::is_ascii_uppercase);
+        hasher.write(if subset_tag { &name[7..] } else { name });
+    } else if object.is_int()? {
+        hasher.write_i32(object.as_int()?);
+    } else if object.is_real()? {
+        hasher.write_u32(object.as_float()?.to_bits());
+    } else if object.is_bool()? {
+        hasher.write_u8(object.as_bool()? as u8);
+    } else if object.is_string()? {
+        hasher.write(object.as_bytes()?);
+    } else if object.is_array()? {
+        for index in 0..object.len()? {
+            if let Some(item) = object.get_array(index as i32)? {
+                hash_object(&item, hasher, depth + 1)?;
+            }
+        }
+    } else if object.is_dict()? {
+        for index in 0..object.dict_len()? {
+            let (Some(key), Some(value)) = (object.get_dict_key(index as i32)?, object.get_dict_val(index as i32)?) else {
+                continue;
+            };
+            let key_name = key.as_name()?;
+            // Parents lead back up the page tree; embedded font programs grow with every glyph the document uses
+            if matches!(key_name, b"Parent" | b"P" | b"FontFile" | b"FontFile2" | b"FontFile3") {
+                continue;
+            }
+            hasher.write(key_name);
+            hash_object(&value, hasher, depth + 1)?;
+        }
+        if object.is_stream()? {
+            hasher.write(&object.read_stream()?);
+        }
+    }
+    Ok(())
+}
+
+/// Average 2x2 blocks of BGRA pixels; an odd last row or column averages with itself.
+fn halve_bgra(pixels: &[u8], width: u32, height: u32) -> (Vec<u8>, u32, u32) {
+    let (w, h) = (width.div_ceil(2), height.div_ceil(2));
+    let mut out = vec![0u8; (w * h * 4) as usize];
+    for y in 0..h {
+        for x in 0..w {
+            let (x0, y0) = (x * 2, y * 2);
+            let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+            for c in 0..4 {
+                let at = |px: u32, py: u32| pixels[((py * width + px) * 4 + c) as usize] as u32;
+                let sum = at(x0, y0) + at(x1, y0) + at(x0, y1) + at(x1, y1);
+                out[((y * w + x) * 4 + c) as usize] = ((sum + 2) / 4) as u8;
+            }
+        }
+    }
+    (out, w, h)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(x: u16, screen_x: f32, have: Option<Quality>) -> TileRequest {
+        TileRequest {
+            key: TileKey { revision: 1, page: 0, level: 0, x, y: 0 },
+            rect: Rect { x: screen_x, y: 0.0, width: 256.0, height: 256.0 },
+            have,
+        }
+    }
+
+    #[test]
+    fn test_tile_queue_drafts_before_refining() {
+        let viewport = Rect { x: 0.0, y: 0.0, width: 512.0, height: 512.0 };
+        let mut queue = TileRenderQueue::new();
+        queue.source = Some((1, Arc::new(Vec::new())));
+        queue.prioritize_tiles(viewport, vec![
+            request(0, 2000.0, None),
+            request(1, 600.0, None),
+            request(2, 0.0, Some(Quality::Draft)),
+            request(3, 256.0, None),
+        ]);
+
+        // Drafts for visible, adjacent, then offscreen tiles, before anything is refined
+        assert_eq!(queue.next_job().map(|(key, q)| (key.x, q)), Some((3, Quality::Draft)));
+        assert_eq!(queue.next_job().map(|(key, q)| (key.x, q)), Some((1, Quality::Draft)));
+        assert_eq!(queue.next_job().map(|(key, q)| (key.x, q)), Some((0, Quality::Draft)));
+        assert_eq!(queue.next_job().map(|(key, q)| (key.x, q)), Some((2, Quality::Standard)));
+
+        // Claimed jobs stay claimed until the viewer reports the result
+        queue.prioritize_tiles(viewport, vec![request(2, 0.0, Some(Quality::Draft)), request(3, 256.0, Some(Quality::Draft))]);
+        assert_eq!(queue.next_job().map(|(key, q)| (key.x, q)), Some((3, Quality::Standard)));
+        queue.prioritize_tiles(viewport, vec![request(2, 0.0, Some(Quality::Standard)), request(3, 256.0, Some(Quality::Standard))]);
+        assert_eq!(queue.next_job().map(|(key, q)| (key.x, q)), Some((2, Quality::HighQuality)));
+        assert_eq!(queue.next_job().map(|(key, q)| (key.x, q)), Some((3, Quality::HighQuality)));
+        assert_eq!(queue.next_job(), None);
+
+        // Tiles of a build that is not the source yet wait for it
+        let mut next = request(0, 0.0, None);
+        next.key.revision = 2;
+        queue.prioritize_tiles(viewport, vec![next]);
+        assert_eq!(queue.next_job(), None);
+        queue.source = Some((2, Arc::new(Vec::new())));
+        assert_eq!(queue.next_job().map(|(key, q)| (key.revision, q)), Some((2, Quality::Draft)));
+    }
+
+    #[test]
+    fn test_tile_levels_and_grid() {
+        assert_eq!(TileKey::level_for(1.0), 0);
+        assert_eq!(TileKey::level_for(2.0), 4);
+        assert_eq!(TileKey::level_for(1.1), 1);
+        assert!((TileKey::scale(4) - 2.0).abs() < 1e-6);
+        // A letter page at 2 pixels per point is 1224 x 1584 pixels
+        assert_eq!(TileKey::grid((612.0, 792.0), 4), (5, 7));
+        assert_eq!(TileKey::grid((612.0, 792.0), -16), (1, 1));
+    }
+
+    #[test]
+    fn test_match_pages_carries_unchanged_and_shifted_pages() {
+        // Page 1 was edited, and a page inserted after it pushed the rest down
+        let changes = match_pages(&[10, 11, 12, 13], &[10, 21, 99, 12, 13]);
+        assert_eq!(changes.changed, vec![1, 2]);
+        assert_eq!(changes.carried, vec![(0, 0), (2, 3), (3, 4)]);
+
+        // Identical pages pair up at the same number first
+        let changes = match_pages(&[7, 7, 7], &[7, 7]);
+        assert_eq!(changes.carried, vec![(0, 0), (1, 1)]);
+        assert!(changes.changed.is_empty());
+    }
+
+    #[test]
+    fn test_halve_bgra_averages_blocks() {
+        // 3x2: the odd last column only averages its own rows
+        let pixels = [
+            0, 0, 0, 255, 100, 100, 100, 255, 10, 20, 30, 40,
+            200, 200, 200, 255, 100, 100, 100, 255, 30, 40, 50, 60,
+        ];
+        let (half, width, height) = halve_bgra(&pixels, 3, 2);
+        assert_eq!((width, height), (2, 1));
+        assert_eq!(half, vec![100, 100, 100, 255, 20, 30, 40, 50]);
     }
 }
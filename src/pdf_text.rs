@@ -0,0 +1,186 @@
+use egui::{Pos2, Rect};
+use std::ops::Range;
+
+/// A character of a page's text layer and its box in page points.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageChar {
+    pub c: char,
+    pub rect: Rect,
+}
+
+/// The text of a page in reading order. Every line ends in a `'\n'` with an empty box at the end of the line.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PageText {
+    pub chars: Vec<PageChar>,
+}
+
+/// A search hit: characters `start..end` of a page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextHit {
+    pub page: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl PageText {
+    pub fn from_lines(lines: Vec<Vec<PageChar>>) -> Self {
+        let mut chars = Vec::new();
+        for line in lines.into_iter().filter(|l| !l.is_empty()) {
+            let last = line[line.len() - 1].rect;
+            chars.extend(line);
+            chars.push(PageChar { c: '\n', rect: Rect::from_min_max(last.right_top(), last.right_bottom()) });
+        }
+        Self { chars }
+    }
+
+    pub fn text(&self, range: Range<usize>) -> String {
+        self.chars[range.start.min(self.chars.len())..range.end.min(self.chars.len())].iter().map(|c| c.c).collect()
+    }
+
+    /// Every match of `needle`, ignoring case; any run of whitespace or line breaks matches any other.
+    pub fn find_all(&self, needle: &str) -> Vec<Range<usize>> {
+        let needle = fold(needle.trim().chars().enumerate()).into_iter().map(|(c, _)| c).collect::<Vec<_>>();
+        if needle.is_empty() {
+            return Vec::new();
+        }
+        let hay = fold(self.chars.iter().map(|c| c.c).enumerate());
+        let mut hits = Vec::new();
+        let mut i = 0;
+        while i + needle.len() <= hay.len() {
+            if hay[i..i + needle.len()].iter().map(|(c, _)| *c).eq(needle.iter().copied()) {
+                hits.push(hay[i].1..hay[i + needle.len() - 1].1 + 1);
+                i += needle.len();
+            } else {
+                i += 1;
+            }
+        }
+        hits
+    }
+
+    /// One box per line the range covers.
+    pub fn rects(&self, range: Range<usize>) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = Vec::new();
+        let mut line_start = true;
+        for ch in &self.chars[range.start.min(self.chars.len())..range.end.min(self.chars.len())] {
+            if ch.c == '\n' {
+                line_start = true;
+                continue;
+            }
+            match rects.last_mut() {
+                Some(rect) if !line_start => *rect = rect.union(ch.rect),
+                _ => rects.push(ch.rect),
+            }
+            line_start = false;
+        }
+        rects
+    }
+
+    /// Whether a point is on a character, where a drag selects instead of panning.
+    pub fn hit_test(&self, pos: Pos2) -> bool {
+        self.chars.iter().any(|c| c.c != '\n' && c.rect.contains(pos))
+    }
+
+    /// The gap between characters closest to a point, as the index of the character after it.
+    pub fn boundary_at(&self, pos: Pos2) -> usize {
+        let nearest = self.chars.iter().enumerate()
+            .min_by(|(_, a), (_, b)| a.rect.distance_sq_to_pos(pos).total_cmp(&b.rect.distance_sq_to_pos(pos)));
+        match nearest {
+            Some((i, ch)) if ch.c != '\n' && pos.x > ch.rect.center().x => i + 1,
+            Some((i, _)) => i,
+            None => 0,
+        }
+    }
+}
+
+/// Lowercase characters with whitespace runs folded to one space, each with the index it came from.
+fn fold(chars: impl Iterator<Item = (usize, char)>) -> Vec<(char, usize)> {
+    let mut folded: Vec<(char, usize)> = Vec::new();
+    for (i, c) in chars {
+        if c.is_whitespace() {
+            if folded.last().is_some_and(|(last, _)| *last != ' ') {
+                folded.push((' ', i));
+            }
+        } else {
+            folded.push((c.to_lowercase().next().unwrap_or(c), i));
+        }
+    }
+    folded
+}
+
+/// Every match of `needle` across the document, in page order.
+pub fn search(pages: &[PageText], needle: &str) -> Vec<TextHit> {
+    pages.iter().enumerate()
+        .flat_map(|(page, text)| text.find_all(needle).into_iter().map(move |r| TextHit { page, start: r.start, end: r.end }))
+        .collect()
+}
+
+/// The characters selected between two (page, boundary) ends of a drag, per page.
+pub fn selection_ranges(pages: &[PageText], anchor: (usize, usize), head: (usize, usize)) -> Vec<(usize, Range<usize>)> {
+    let (from, to) = if anchor <= head { (anchor, head) } else { (head, anchor) };
+    (from.0..=to.0.min(pages.len().saturating_sub(1)))
+        .map(|page| {
+            let start = if page == from.0 { from.1 } else { 0 };
+            let end = if page == to.0 { to.1 } else { pages[page].chars.len() };
+            (page, start..end.max(start))
+        })
+        .filter(|(_, range)| !range.is_empty())
+        .collect()
+}
+
+/// The selected text, pages joined by line breaks.
+pub fn selected_text(pages: &[PageText], anchor: (usize, usize), head: (usize, usize)) -> String {
+    selection_ranges(pages, anchor, head).into_iter()
+        .map(|(page, range)| pages[page].text(range).trim_end_matches('\n').to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::pos2;
+
+    /// Monospaced lines: every character is 6 x 10 points, lines 12 points apart.
+    fn page(lines: &[&str]) -> PageText {
+        PageText::from_lines(lines.iter().enumerate().map(|(row, line)| {
+            line.chars().enumerate().map(|(col, c)| PageChar {
+                c,
+                rect: Rect::from_min_size(pos2(col as f32 * 6.0, row as f32 * 12.0), egui::vec2(6.0, 10.0)),
+            }).collect()
+        }).collect())
+    }
+
+    #[test]
+    fn test_find_all_ignores_case_and_line_breaks() {
+        let text = page(&["The quick brown", "fox jumps over the", "lazy dog"]);
+        assert_eq!(text.find_all("the"), vec![0..3, 31..34]);
+        // "brown" ends one line and "fox" starts the next
+        let hits = text.find_all("Brown  fox");
+        assert_eq!(hits, vec![10..19]);
+        assert_eq!(text.text(hits[0].clone()), "brown\nfox");
+        assert_eq!(text.rects(hits[0].clone()), vec![
+            Rect::from_min_max(pos2(60.0, 0.0), pos2(90.0, 10.0)),
+            Rect::from_min_max(pos2(0.0, 12.0), pos2(18.0, 22.0)),
+        ]);
+        assert!(text.find_all("  ").is_empty());
+
+        let pages = vec![text.clone(), page(&["the end"])];
+        assert_eq!(search(&pages, "THE").last(), Some(&TextHit { page: 1, start: 0, end: 3 }));
+    }
+
+    #[test]
+    fn test_selection_within_and_across_pages() {
+        let pages = vec![page(&["alpha beta", "gamma"]), page(&["delta"])];
+        assert!(pages[0].hit_test(pos2(7.0, 5.0)));
+        assert!(!pages[0].hit_test(pos2(7.0, 11.0)));
+
+        // From the middle of "alpha" to after "beta", dragged backwards
+        let start = pages[0].boundary_at(pos2(13.0, 5.0));
+        let end = pages[0].boundary_at(pos2(100.0, 5.0));
+        assert_eq!((start, end), (2, 10));
+        assert_eq!(selected_text(&pages, (0, end), (0, start)), "pha beta");
+
+        assert_eq!(selected_text(&pages, (0, 6), (1, 3)), "beta\ngamma\ndel");
+        assert_eq!(selection_ranges(&pages, (0, 6), (1, 3)), vec![(0, 6..17), (1, 0..3)]);
+    }
+}
@@ -0,0 +1,340 @@
+use std::io::Write;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+/// TeX scaled points per PDF big point, the unit SyncTeX coordinates are written in.
+const SP_PER_BP: f32 = 65781.76;
+
+/// The standard 14 fonts the preview uses; every PDF viewer has them built in, so nothing is embedded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Font {
+    Roman,
+    Bold,
+    Italic,
+    BoldItalic,
+    Mono,
+    /// Greek and mathematical symbols, addressed by their codes in the font's own encoding
+    Symbol,
+}
+
+const FONTS: [Font; 6] = [Font::Roman, Font::Bold, Font::Italic, Font::BoldItalic, Font::Mono, Font::Symbol];
+
+/// Advance widths in thousandths of the font size for `' '..='~'`, from the Adobe font metrics.
+const TIMES_ROMAN: [u16; 95] = [
+    250, 333, 408, 500, 500, 833, 778, 180, 333, 333, 500, 564, 250, 333, 250, 278,
+    500, 500, 500, 500, 500, 500, 500, 500, 500, 500, 278, 278, 564, 564, 564, 444,
+    921, 722, 667, 667, 722, 611, 556, 722, 722, 333, 389, 722, 611, 889, 722, 722,
+    556, 722, 667, 556, 611, 722, 722, 944, 722, 722, 611, 333, 278, 333, 469, 500,
+    333, 444, 500, 444, 500, 444, 333, 500, 500, 278, 278, 500, 278, 778, 500, 500,
+    500, 500, 333, 389, 278, 500, 500, 722, 500, 500, 444, 480, 200, 480, 541,
+];
+const TIMES_BOLD: [u16; 95] = [
+    250, 333, 555, 500, 500, 1000, 833, 278, 333, 333, 500, 570, 250, 333, 250, 278,
+    500, 500, 500, 500, 500, 500, 500, 500, 500, 500, 333, 333, 570, 570, 570, 500,
+    930, 722, 667, 722, 722, 667, 611, 778, 778, 389, 500, 778, 667, 944, 722, 778,
+    611, 778, 722, 556, 667, 722, 722, 1000, 722, 722, 667, 333, 278, 333, 581, 500,
+    333, 500, 556, 444, 556, 444, 333, 500, 556, 278, 333, 556, 278, 833, 556, 500,
+    556, 556, 444, 389, 333, 556, 500, 722, 500, 500, 444, 394, 220, 394, 520,
+];
+const TIMES_ITALIC: [u16; 95] = [
+    250, 333, 420, 500, 500, 833, 778, 214, 333, 333, 500, 675, 250, 333, 250, 278,
+    500, 500, 500, 500, 500, 500, 500, 500, 500, 500, 333, 333, 675, 675, 675, 500,
+    920, 611, 611, 667, 722, 611, 611, 722, 722, 333, 444, 667, 556, 833, 667, 722,
+    611, 722, 611, 500, 556, 722, 611, 833, 611, 556, 556, 389, 278, 389, 422, 500,
+    333, 500, 500, 444, 500, 444, 278, 500, 500, 278, 278, 444, 278, 722, 500, 500,
+    500, 500, 389, 389, 278, 500, 444, 667, 444, 444, 389, 400, 275, 400, 541,
+];
+const TIMES_BOLD_ITALIC: [u16; 95] = [
+    250, 389, 555, 500, 500, 833, 778, 278, 333, 333, 500, 570, 250, 333, 250, 278,
+    500, 500, 500, 500, 500, 500, 500, 500, 500, 500, 333, 333, 570, 570, 570, 500,
+    832, 667, 667, 667, 722, 667, 667, 722, 778, 389, 500, 667, 611, 889, 722, 722,
+    611, 722, 667, 556, 611, 722, 667, 889, 667, 611, 611, 333, 278, 333, 570, 500,
+    333, 500, 500, 444, 500, 444, 333, 500, 556, 278, 278, 500, 278, 778, 556, 500,
+    500, 500, 389, 389, 278, 556, 444, 667, 500, 444, 389, 348, 220, 348, 570,
+];
+
+impl Font {
+    fn resource(self) -> &'static str {
+        match self {
+            Font::Roman => "F1",
+            Font::Bold => "F2",
+            Font::Italic => "F3",
+            Font::BoldItalic => "F4",
+            Font::Mono => "F5",
+            Font::Symbol => "F6",
+        }
+    }
+
+    fn base_font(self) -> &'static str {
+        match self {
+            Font::Roman => "Times-Roman",
+            Font::Bold => "Times-Bold",
+            Font::Italic => "Times-Italic",
+            Font::BoldItalic => "Times-BoldItalic",
+            Font::Mono => "Courier",
+            Font::Symbol => "Symbol",
+        }
+    }
+
+    pub fn styled(bold: bool, italic: bool) -> Self {
+        match (bold, italic) {
+            (false, false) => Font::Roman,
+            (true, false) => Font::Bold,
+            (false, true) => Font::Italic,
+            (true, true) => Font::BoldItalic,
+        }
+    }
+
+    /// The advance of a character at a size, in points.
+    pub fn width(self, c: char, size: f32) -> f32 {
+        let table = match self {
+            Font::Mono => return 0.6 * size,
+            Font::Symbol => return crate::preview_math::symbol_width(c as u32 as u8) as f32 / 1000.0 * size,
+            Font::Roman => &TIMES_ROMAN,
+            Font::Bold => &TIMES_BOLD,
+            Font::Italic => &TIMES_ITALIC,
+            Font::BoldItalic => &TIMES_BOLD_ITALIC,
+        };
+        let units = match c {
+            ' '..='~' => table[c as usize - 32],
+            '\u{a0}' => 250,
+            '‘' | '’' => 333,
+            '“' | '”' if self == Font::Roman => 444,
+            '“' | '”' => 500,
+            '–' => 500,
+            '—' => 1000,
+            '•' => 350,
+            '…' => 1000,
+            // Accented letters are about as wide as the letters they are built on
+            c if c.is_uppercase() => 722,
+            _ => 500,
+        };
+        units as f32 / 1000.0 * size
+    }
+
+    pub fn text_width(self, text: &str, size: f32) -> f32 {
+        text.chars().map(|c| self.width(c, size)).sum()
+    }
+}
+
+/// A character as a byte of WinAnsiEncoding, which the text fonts are set up with.
+fn win_ansi(c: char) -> u8 {
+    match c {
+        ' '..='~' | '\u{a0}'..='\u{ff}' => c as u32 as u8,
+        '‘' => 0x91,
+        '’' => 0x92,
+        '“' => 0x93,
+        '”' => 0x94,
+        '•' => 0x95,
+        '–' => 0x96,
+        '—' => 0x97,
+        '…' => 0x85,
+        _ => b'?',
+    }
+}
+
+/// Something drawn on a page. Coordinates are in points from the top left corner of the page.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mark {
+    /// `y` is the baseline
+    Text { x: f32, y: f32, font: Font, size: f32, text: String },
+    /// A filled rectangle, `y` its top
+    Rule { x: f32, y: f32, width: f32, height: f32 },
+    /// An outlined grey rectangle, `y` its top
+    Frame { x: f32, y: f32, width: f32, height: f32 },
+}
+
+impl Mark {
+    pub fn translated(mut self, dx: f32, dy: f32) -> Self {
+        match &mut self {
+            Mark::Text { x, y, .. } | Mark::Rule { x, y, .. } | Mark::Frame { x, y, .. } => {
+                *x += dx;
+                *y += dy;
+            }
+        }
+        self
+    }
+}
+
+/// Output made from one source line, for SyncTeX. `y` is the baseline, from the top of the page.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceBox {
+    pub tag: u32,
+    pub line: u32,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub depth: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Page {
+    pub marks: Vec<Mark>,
+    pub boxes: Vec<SourceBox>,
+}
+
+/// A PDF string literal in the encoding of a font.
+fn pdf_string(text: &str, font: Font) -> String {
+    let mut out = String::from("(");
+    for c in text.chars() {
+        let byte = if font == Font::Symbol { c as u32 as u8 } else { win_ansi(c) };
+        match byte {
+            b'(' | b')' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    out.push(')');
+    out
+}
+
+/// The drawing operators of a page, with the y axis flipped to PDF's bottom left origin.
+fn content_stream(page: &Page, height: f32) -> String {
+    let mut ops = String::new();
+    for mark in &page.marks {
+        match mark {
+            Mark::Text { x, y, font, size, text } => {
+                ops.push_str(&format!("BT /{} {:.2} Tf 1 0 0 1 {:.2} {:.2} Tm {} Tj ET\n", font.resource(), size, x, height - y, pdf_string(text, *font)));
+            }
+            Mark::Rule { x, y, width, height: h } => {
+                ops.push_str(&format!("{:.2} {:.2} {:.2} {:.2} re f\n", x, height - y - h, width, h));
+            }
+            Mark::Frame { x, y, width, height: h } => {
+                ops.push_str(&format!("q 0.6 G 0.5 w {:.2} {:.2} {:.2} {:.2} re S Q\n", x, height - y - h, width, h));
+            }
+        }
+    }
+    ops
+}
+
+/// Objects appended in order, remembering where each starts for the cross-reference table.
+struct PdfFile {
+    bytes: Vec<u8>,
+    offsets: Vec<usize>,
+}
+
+impl PdfFile {
+    fn object(&mut self, body: &[u8]) {
+        self.offsets.push(self.bytes.len());
+        self.bytes.extend_from_slice(format!("{} 0 obj\n", self.offsets.len()).as_bytes());
+        self.bytes.extend_from_slice(body);
+        self.bytes.extend_from_slice(b"\nendobj\n");
+    }
+}
+
+/// Write pages of the given size as a PDF with a valid cross-reference table.
+pub fn write_pdf(pages: &[Page], (width, height): (f32, f32), title: Option<&str>) -> Vec<u8> {
+    let mut file = PdfFile { bytes: b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec(), offsets: Vec::new() };
+
+    // 1: catalog, 2: page tree, 3..: fonts, then the info dictionary, then a page and its contents per page
+    let first_page = 3 + FONTS.len() + 1;
+    file.object(b"<< /Type /Catalog /Pages 2 0 R >>");
+    let kids: Vec<String> = (0..pages.len()).map(|i| format!("{} 0 R", first_page + 2 * i)).collect();
+    file.object(format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), pages.len()).as_bytes());
+    for font in FONTS {
+        let encoding = if font == Font::Symbol { "" } else { " /Encoding /WinAnsiEncoding" };
+        file.object(format!("<< /Type /Font /Subtype /Type1 /BaseFont /{}{} >>", font.base_font(), encoding).as_bytes());
+    }
+    let info = match title {
+        Some(title) => format!("<< /Producer (SokuTeX preview) /Title {} >>", pdf_string(title, Font::Roman)),
+        None => "<< /Producer (SokuTeX preview) >>".to_string(),
+    };
+    file.object(info.as_bytes());
+
+    let fonts: Vec<String> = FONTS.iter().enumerate().map(|(i, font)| format!("/{} {} 0 R", font.resource(), 3 + i)).collect();
+    for (i, page) in pages.iter().enumerate() {
+        let contents = first_page + 2 * i + 1;
+        file.object(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /Font << {} >> >> /Contents {} 0 R >>",
+            width, height, fonts.join(" "), contents,
+        ).as_bytes());
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        let _ = encoder.write_all(content_stream(page, height).as_bytes());
+        let stream = encoder.finish().unwrap_or_default();
+        let mut body = format!("<< /Length {} /Filter /FlateDecode >>\nstream\n", stream.len()).into_bytes();
+        body.extend_from_slice(&stream);
+        body.extend_from_slice(b"\nendstream");
+        file.object(&body);
+    }
+
+    let xref = file.bytes.len();
+    let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", file.offsets.len() + 1);
+    for offset in &file.offsets {
+        table.push_str(&format!("{:010} 00000 n \n", offset));
+    }
+    table.push_str(&format!("trailer\n<< /Size {} /Root 1 0 R /Info {} 0 R >>\nstartxref\n{}\n%%EOF\n", file.offsets.len() + 1, 3 + FONTS.len(), xref));
+    file.bytes.extend_from_slice(table.as_bytes());
+    file.bytes
+}
+
+/// Write the source boxes of every page as plain SyncTeX, with coordinates in scaled points.
+pub fn write_synctex(pages: &[Page], inputs: &[(u32, String)]) -> Vec<u8> {
+    let sp = |points: f32| (points * SP_PER_BP).round() as i64;
+    let mut out = String::from("SyncTeX Version:1\n");
+    for (tag, path) in inputs {
+        out.push_str(&format!("Input:{}:{}\n", tag, path));
+    }
+    out.push_str("Output:pdf\nMagnification:1000\nUnit:1\nX Offset:0\nY Offset:0\nContent:\n");
+    for (i, page) in pages.iter().enumerate() {
+        out.push_str(&format!("{{{}\n", i + 1));
+        for b in &page.boxes {
+            out.push_str(&format!("({},{}:{},{}:{},{},{}\n)\n", b.tag, b.line, sp(b.x), sp(b.y), sp(b.width), sp(b.height), sp(b.depth)));
+        }
+        out.push_str(&format!("}}{}\n", i + 1));
+    }
+    out.push_str("Postamble:\n");
+    out.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xref_offsets_point_at_objects() {
+        let page = Page {
+            marks: vec![Mark::Text { x: 72.0, y: 100.0, font: Font::Roman, size: 11.0, text: "Caf\u{e9} (1)".to_string() }],
+            boxes: Vec::new(),
+        };
+        let pdf = write_pdf(&[page.clone(), page], (595.0, 842.0), Some("Test"));
+
+        // The content streams are compressed, so search the bytes rather than text
+        let find = |needle: &[u8]| pdf.windows(needle.len()).rposition(|w| w == needle).unwrap();
+        let tail = String::from_utf8(pdf[find(b"startxref\n")..].to_vec()).unwrap();
+        let startxref: usize = tail.lines().nth(1).unwrap().parse().unwrap();
+        let table = String::from_utf8(pdf[startxref..].to_vec()).unwrap();
+        // Catalog, page tree, six fonts, info, and a page and its contents for each page
+        assert!(table.starts_with("xref\n0 14\n"));
+        let offsets: Vec<usize> = table.lines().skip(3).take(13).map(|l| l[..10].parse().unwrap()).collect();
+        for (i, offset) in offsets.iter().enumerate() {
+            assert!(pdf[*offset..].starts_with(format!("{} 0 obj", i + 1).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_strings_and_widths() {
+        assert_eq!(pdf_string("a(b)\\ \u{e9}\u{2014}", Font::Roman), "(a\\(b\\)\\\\ \\351\\227)");
+        assert_eq!(pdf_string("\u{b4}", Font::Symbol), "(\\264)");
+        assert!((Font::Roman.text_width("Wit", 10.0) - 15.0).abs() < 0.01);
+        assert!((Font::Mono.text_width("abc", 10.0) - 18.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_synctex_round_trips_through_the_parser() {
+        let page = Page {
+            marks: Vec::new(),
+            boxes: vec![SourceBox { tag: 1, line: 7, x: 90.0, y: 120.0, width: 200.0, height: 8.0, depth: 2.0 }],
+        };
+        let mut synctex = crate::synctex::SyncTex::new();
+        synctex.load_from_bytes(&write_synctex(&[page], &[(1, "main.tex".to_string())])).unwrap();
+        let node = synctex.forward_sync(7, 1).unwrap();
+        assert_eq!(node.page, 1);
+        assert!((node.x - 90.0).abs() < 0.01 && (node.y - 120.0).abs() < 0.01 && (node.width - 200.0).abs() < 0.01);
+        assert_eq!(synctex.backward_sync(1, 150.0, 118.0).map(|n| n.line), Some(7));
+    }
+}
@@ -0,0 +1,2325 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bib::{BibEntry, BibParser};
+use crate::diagnostics::{Diagnostic, DiagnosticKind, Severity};
+use crate::pdf_writer::{self, Font, Mark, Page, SourceBox};
+use crate::preview_math::{self, MathBox};
+
+/// A4, in points.
+const PAGE_SIZE: (f32, f32) = (595.28, 841.89);
+const MARGIN: f32 = 90.0;
+const TEXT_WIDTH: f32 = PAGE_SIZE.0 - 2.0 * MARGIN;
+const TEXT_BOTTOM: f32 = PAGE_SIZE.1 - MARGIN;
+const BODY_SIZE: f32 = 11.0;
+/// Baseline to baseline distance as a multiple of the font size
+const LEADING: f32 = 1.236;
+const PARINDENT: f32 = 15.0;
+const LIST_INDENT: f32 = 20.0;
+
+const MATH_ENVIRONMENTS: &[&str] = &[
+    "equation", "equation*", "align", "align*", "gather", "gather*", "multline", "multline*",
+    "eqnarray", "eqnarray*", "displaymath", "flalign", "flalign*", "math",
+];
+const VERBATIM_ENVIRONMENTS: &[&str] = &["verbatim", "verbatim*", "lstlisting", "minted", "Verbatim", "comment"];
+const THEOREMS: &[(&str, &str)] = &[
+    ("theorem", "Theorem"), ("lemma", "Lemma"), ("proposition", "Proposition"), ("corollary", "Corollary"),
+    ("definition", "Definition"), ("remark", "Remark"), ("example", "Example"), ("conjecture", "Conjecture"),
+];
+/// Commands whose arguments only matter to a real engine, with how many they take.
+const IGNORED: &[(&str, usize)] = &[
+    ("documentclass", 1), ("usepackage", 1), ("RequirePackage", 1), ("bibliographystyle", 1), ("pagestyle", 1),
+    ("thispagestyle", 1), ("pagenumbering", 1), ("setlength", 2), ("addtolength", 2), ("setcounter", 2),
+    ("addtocounter", 2), ("hypersetup", 1), ("geometry", 1), ("graphicspath", 1), ("color", 1), ("vphantom", 1),
+    ("numberwithin", 2), ("setcitestyle", 1), ("captionsetup", 1), ("definecolor", 3), ("linespread", 1),
+    ("addcontentsline", 3), ("index", 1), ("pageref", 1), ("phantom", 1), ("newenvironment", 3),
+];
+
+/// The output of the preview typesetter.
+pub struct Preview {
+    pub pdf: Vec<u8>,
+    pub synctex: Vec<u8>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Typeset a document with the preview engine, reading `\input` files and bibliographies through `read`.
+///
+/// The preview covers sectioning, paragraphs, emphasis, lists, tables, math and cross references,
+/// well enough for a draft to look like the document while the real engine runs.
+pub fn typeset(source: &str, file_name: &str, read: &dyn Fn(&str) -> Option<String>) -> Preview {
+    let (document, pages) = lay_out(source, file_name, read);
+    let title = document.title.as_ref().map(|t| plain_text(t));
+    let mut diagnostics = document.diagnostics.clone();
+    diagnostics.extend(layout_diagnostics(&document));
+    Preview {
+        pdf: pdf_writer::write_pdf(&pages, PAGE_SIZE, title.as_deref()),
+        synctex: pdf_writer::write_synctex(&pages, &document.inputs),
+        diagnostics,
+    }
+}
+
+fn lay_out(source: &str, file_name: &str, read: &dyn Fn(&str) -> Option<String>) -> (Document, Vec<Page>) {
+    let mut parser = Parser::new(source, file_name, read);
+    parser.run();
+    let document = parser.finish();
+
+    // Contents need the pages of headings, so lay out once more when there is a table of contents
+    let mut layout = Layout::new(&document, Vec::new());
+    layout.run();
+    if document.blocks.iter().any(|b| matches!(b, Block::Contents)) {
+        let headings = std::mem::take(&mut layout.headings);
+        layout = Layout::new(&document, headings);
+        layout.run();
+    }
+    let pages = layout.finish();
+    (document, pages)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Source {
+    tag: u32,
+    line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Text(String),
+    Space,
+    Par,
+    Command(String),
+    Open,
+    Close,
+    Align,
+    Math { source: String, display: bool, env: Option<String> },
+    /// `\verb` text, or the body of a verbatim environment
+    Verbatim { text: String, block: bool },
+    Begin(String),
+    End(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    tok: Tok,
+    source: Source,
+}
+
+/// Blank out comments, keeping every line so positions still match the file.
+fn strip_comments(source: &str) -> String {
+    source.lines().map(|line| {
+        let mut backslashes = 0;
+        for (i, c) in line.char_indices() {
+            if c == '%' && backslashes % 2 == 0 {
+                return &line[..i];
+            }
+            backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+        }
+        line
+    }).collect::<Vec<_>>().join("\n")
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    args: usize,
+    /// The default of an optional first argument
+    default: Option<String>,
+    body: String,
+}
+
+/// A balanced `{...}` at `pos`, returning its contents and the position after it.
+fn braced(chars: &[char], pos: usize) -> Option<(String, usize)> {
+    if chars.get(pos) != Some(&'{') {
+        return None;
+    }
+    let mut depth = 0;
+    let mut i = pos;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((chars[pos + 1..i].iter().collect(), i + 1));
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+fn skip_blanks(chars: &[char], mut pos: usize) -> usize {
+    while chars.get(pos).is_some_and(|c| c.is_whitespace()) {
+        pos += 1;
+    }
+    pos
+}
+
+/// A control sequence name at `pos` (just after the backslash).
+fn control_name(chars: &[char], pos: usize) -> (String, usize) {
+    let mut end = pos;
+    while chars.get(end).is_some_and(|c| c.is_ascii_alphabetic() || *c == '@') {
+        end += 1;
+    }
+    if end == pos && pos < chars.len() {
+        end += 1;
+    }
+    (chars[pos..end].iter().collect(), end)
+}
+
+/// One macro argument: a group, a control sequence or a single character.
+fn macro_argument(chars: &[char], pos: usize) -> Option<(String, usize)> {
+    let pos = skip_blanks(chars, pos);
+    match chars.get(pos)? {
+        '{' => braced(chars, pos),
+        '\\' => {
+            let (name, end) = control_name(chars, pos + 1);
+            Some((format!("\\{}", name), end))
+        }
+        c => Some((c.to_string(), pos + 1)),
+    }
+}
+
+/// Read a `\newcommand`-like definition after the command name, returning the macro and where it ends.
+fn definition(chars: &[char], command: &str, pos: usize) -> Option<(String, Macro, usize)> {
+    let pos = skip_blanks(chars, pos);
+    let (name, mut pos) = match chars.get(pos)? {
+        '{' => {
+            let (inner, end) = braced(chars, pos)?;
+            (inner.trim().trim_start_matches('\\').to_string(), end)
+        }
+        '\\' => control_name(chars, pos + 1),
+        _ => return None,
+    };
+    if command == "def" {
+        // `\def\name#1#2{body}`
+        let mut args = 0;
+        while chars.get(pos) == Some(&'#') {
+            args += 1;
+            pos += 2;
+        }
+        let (body, end) = braced(chars, skip_blanks(chars, pos))?;
+        return Some((name, Macro { args, default: None, body }, end));
+    }
+    if command == "DeclareMathOperator" {
+        let pos = skip_blanks(chars, pos);
+        let pos = if chars.get(pos) == Some(&'*') { pos + 1 } else { pos };
+        let (text, end) = braced(chars, skip_blanks(chars, pos))?;
+        return Some((name, Macro { args: 0, default: None, body: format!("\\operatorname{{{}}}", text) }, end));
+    }
+    let mut args = 0;
+    let mut default = None;
+    pos = skip_blanks(chars, pos);
+    if chars.get(pos) == Some(&'[') {
+        let close = chars[pos..].iter().position(|c| *c == ']')? + pos;
+        args = chars[pos + 1..close].iter().collect::<String>().trim().parse().unwrap_or(0);
+        pos = skip_blanks(chars, close + 1);
+        if chars.get(pos) == Some(&'[') {
+            let close = chars[pos..].iter().position(|c| *c == ']')? + pos;
+            default = Some(chars[pos + 1..close].iter().collect());
+            pos = close + 1;
+        }
+    }
+    let (body, end) = braced(chars, skip_blanks(chars, pos))?;
+    Some((name, Macro { args, default, body }, end))
+}
+
+/// Record macro definitions and expand their uses, keeping the line count of the source.
+fn expand_macros(source: &str, macros: &mut HashMap<String, Macro>) -> String {
+    let mut chars: Vec<char> = source.chars().collect();
+    let mut pos = 0;
+    // Bounds runaway recursion such as `\def\a{\a}`
+    let mut budget = 20_000;
+    while pos < chars.len() {
+        if chars[pos] != '\\' {
+            pos += 1;
+            continue;
+        }
+        let (name, after) = control_name(&chars, pos + 1);
+        let replacement = match name.as_str() {
+            "newcommand" | "renewcommand" | "providecommand" | "def" | "DeclareMathOperator" | "newcommand*" => {
+                let after = if chars.get(after) == Some(&'*') { after + 1 } else { after };
+                match definition(&chars, &name, after) {
+                    Some((defined, definition, end)) => {
+                        if name != "providecommand" || !macros.contains_key(&defined) {
+                            macros.insert(defined, definition);
+                        }
+                        Some((String::new(), end))
+                    }
+                    None => None,
+                }
+            }
+            _ if budget > 0 && macros.contains_key(&name) => {
+                budget -= 1;
+                let definition = &macros[&name];
+                let mut end = after;
+                let mut values = Vec::new();
+                let mut complete = true;
+                for i in 0..definition.args {
+                    if i == 0 && definition.default.is_some() {
+                        let start = skip_blanks(&chars, end);
+                        if chars.get(start) == Some(&'[') {
+                            if let Some(close) = chars[start..].iter().position(|c| *c == ']') {
+                                values.push(chars[start + 1..start + close].iter().collect());
+                                end = start + close + 1;
+                                continue;
+                            }
+                        }
+                        values.push(definition.default.clone().unwrap_or_default());
+                        continue;
+                    }
+                    match macro_argument(&chars, end) {
+                        Some((value, next)) => {
+                            values.push(value);
+                            end = next;
+                        }
+                        None => complete = false,
+                    }
+                }
+                complete.then(|| {
+                    let mut body = definition.body.clone();
+                    for (i, value) in values.iter().enumerate().rev() {
+                        body = body.replace(&format!("#{}", i + 1), value);
+                    }
+                    (body.replace('\n', " "), end)
+                })
+            }
+            _ => None,
+        };
+        match replacement {
+            Some((text, end)) => {
+                // Lines the use spanned come back after it so everything below stays put
+                let newlines = chars[pos..end].iter().filter(|c| **c == '\n').count();
+                let mut text: Vec<char> = text.chars().collect();
+                text.extend(std::iter::repeat_n('\n', newlines));
+                chars.splice(pos..end, text);
+            }
+            None => pos = after,
+        }
+    }
+    chars.into_iter().collect()
+}
+
+/// Split prepared source into tokens, capturing math and verbatim text raw.
+fn tokenize(source: &str, tag: u32) -> Vec<Token> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut line = 1u32;
+    let mut pos = 0;
+    let count_lines = |from: usize, to: usize| chars[from..to.min(chars.len())].iter().filter(|c| **c == '\n').count() as u32;
+    let find = |from: usize, needle: &str| -> Option<usize> {
+        let needle: Vec<char> = needle.chars().collect();
+        (from..chars.len()).find(|&i| chars[i..].starts_with(&needle))
+    };
+
+    while pos < chars.len() {
+        let source = Source { tag, line };
+        let c = chars[pos];
+        let push = |tokens: &mut Vec<Token>, tok: Tok| tokens.push(Token { tok, source });
+        match c {
+            '\\' => {
+                let (name, after) = control_name(&chars, pos + 1);
+                let name = if name.chars().all(|c| c.is_ascii_alphabetic()) && chars.get(after) == Some(&'*') { format!("{}*", name) } else { name };
+                let mut after = after + usize::from(name.ends_with('*') && name.len() > 1);
+                match name.as_str() {
+                    "[" | "(" => {
+                        let close = if name == "[" { "\\]" } else { "\\)" };
+                        let end = find(after, close).unwrap_or(chars.len());
+                        let math: String = chars[after..end].iter().collect();
+                        push(&mut tokens, Tok::Math { source: math, display: name == "[", env: None });
+                        line += count_lines(pos, end);
+                        pos = (end + 2).min(chars.len());
+                        continue;
+                    }
+                    "begin" | "end" => {
+                        let Some((env, end)) = braced(&chars, skip_blanks(&chars, after)) else {
+                            push(&mut tokens, Tok::Command(name));
+                            pos = after;
+                            continue;
+                        };
+                        let env = env.trim().to_string();
+                        if name == "end" {
+                            push(&mut tokens, Tok::End(env));
+                            pos = end;
+                            continue;
+                        }
+                        let is_math = MATH_ENVIRONMENTS.contains(&env.as_str());
+                        if is_math || VERBATIM_ENVIRONMENTS.contains(&env.as_str()) {
+                            let close = format!("\\end{{{}}}", env);
+                            let mut start = end;
+                            if !is_math {
+                                // Listing options, and the rest of the `\begin` line
+                                if chars.get(start) == Some(&'[') {
+                                    start = find(start, "]").map_or(start, |i| i + 1);
+                                }
+                                if env == "minted" {
+                                    start = braced(&chars, start).map_or(start, |(_, e)| e);
+                                }
+                                if let Some(newline) = (start..chars.len()).find(|&i| chars[i] == '\n') {
+                                    if chars[start..newline].iter().all(|c| c.is_whitespace()) {
+                                        start = newline + 1;
+                                    }
+                                }
+                            }
+                            let stop = find(start, &close).unwrap_or(chars.len());
+                            let body: String = chars[start..stop].iter().collect();
+                            let tok = if is_math {
+                                Tok::Math { source: body, display: env != "math", env: Some(env.clone()) }
+                            } else if env == "comment" {
+                                Tok::Space
+                            } else {
+                                Tok::Verbatim { text: body.trim_end_matches(['\n', ' ']).to_string(), block: true }
+                            };
+                            push(&mut tokens, tok);
+                            line += count_lines(pos, stop);
+                            pos = (stop + close.chars().count()).min(chars.len());
+                        } else {
+                            push(&mut tokens, Tok::Begin(env));
+                            pos = end;
+                        }
+                        continue;
+                    }
+                    "verb" | "verb*" => {
+                        if let Some(&delimiter) = chars.get(after) {
+                            let end = (after + 1..chars.len()).find(|&i| chars[i] == delimiter || chars[i] == '\n').unwrap_or(chars.len());
+                            push(&mut tokens, Tok::Verbatim { text: chars[after + 1..end].iter().collect(), block: false });
+                            pos = (end + 1).min(chars.len());
+                            continue;
+                        }
+                    }
+                    "%" | "&" | "$" | "#" | "_" | "{" | "}" => {
+                        push(&mut tokens, Tok::Text(name.clone()));
+                        pos = after;
+                        continue;
+                    }
+                    _ => {}
+                }
+                // Spaces after a control word are not part of the text
+                if name.chars().next().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    while chars.get(after).is_some_and(|c| *c == ' ' || *c == '\t') {
+                        after += 1;
+                    }
+                }
+                push(&mut tokens, Tok::Command(name));
+                pos = after;
+            }
+            '$' => {
+                let display = chars.get(pos + 1) == Some(&'$');
+                let start = pos + if display { 2 } else { 1 };
+                let end = if display {
+                    find(start, "$$").unwrap_or(chars.len())
+                } else {
+                    (start..chars.len()).find(|&i| chars[i] == '$' && chars[i - 1] != '\\').unwrap_or(chars.len())
+                };
+                push(&mut tokens, Tok::Math { source: chars[start..end].iter().collect(), display, env: None });
+                line += count_lines(pos, end);
+                pos = (end + if display { 2 } else { 1 }).min(chars.len());
+            }
+            '{' | '}' | '&' | '[' | ']' => {
+                let tok = match c {
+                    '{' => Tok::Open,
+                    '}' => Tok::Close,
+                    '&' => Tok::Align,
+                    c => Tok::Text(c.to_string()),
+                };
+                push(&mut tokens, tok);
+                pos += 1;
+            }
+            '~' => {
+                push(&mut tokens, Tok::Text("\u{a0}".to_string()));
+                pos += 1;
+            }
+            c if c.is_whitespace() => {
+                let start = pos;
+                while chars.get(pos).is_some_and(|c| c.is_whitespace()) {
+                    pos += 1;
+                }
+                let newlines = count_lines(start, pos);
+                let tok = if newlines >= 2 { Tok::Par } else { Tok::Space };
+                // A paragraph break wins over the spaces around it
+                match tokens.last_mut() {
+                    Some(last) if last.tok == Tok::Space || last.tok == Tok::Par => {
+                        if tok == Tok::Par {
+                            last.tok = Tok::Par;
+                        }
+                    }
+                    _ => push(&mut tokens, tok),
+                }
+                line += newlines;
+            }
+            _ => {
+                let start = pos;
+                while chars.get(pos).is_some_and(|c| !c.is_whitespace() && !"\\${}&[]~".contains(*c)) {
+                    pos += 1;
+                }
+                push(&mut tokens, Tok::Text(chars[start..pos].iter().collect()));
+            }
+        }
+    }
+    tokens
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    mono: bool,
+    caps: bool,
+    scale: f32,
+    /// Raised above the baseline, in ems, for footnote marks
+    raise: f32,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Self { bold: false, italic: false, mono: false, caps: false, scale: 1.0, raise: 0.0 }
+    }
+}
+
+impl Style {
+    fn font(&self) -> Font {
+        if self.mono { Font::Mono } else { Font::styled(self.bold, self.italic) }
+    }
+
+    fn size(&self) -> f32 {
+        BODY_SIZE * self.scale
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Inline {
+    Text(String, Style, Source),
+    Space,
+    /// Explicit horizontal space, in points
+    Glue(f32),
+    Math(String, Style, Source),
+    Ref(String, Style, Source),
+    Cite(Vec<String>, Option<String>, Style, Source),
+    /// `\\`
+    Break,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Justify,
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Paragraph {
+    inlines: Vec<Inline>,
+    indent: f32,
+    right_indent: f32,
+    first_indent: f32,
+    /// A list label, hung in the margin
+    marker: Option<Vec<Inline>>,
+    align: Align,
+    space_before: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    cells: Vec<Vec<Inline>>,
+    rule_above: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Block {
+    Paragraph(Paragraph),
+    Heading { level: usize, number: Option<String>, text: Vec<Inline>, source: Source },
+    /// Display math rows with their equation numbers
+    Display(Vec<(String, Option<String>, Source)>),
+    Graphic { name: String, width: f32, height: f32, source: Source },
+    Table { rows: Vec<Row>, align: Vec<Align>, rule_below: bool },
+    Verbatim { text: String, source: Source },
+    Contents,
+    Skip(f32),
+    PageBreak,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListKind {
+    Itemize,
+    Enumerate,
+    Description,
+}
+
+/// An open environment and what it does to the paragraphs inside it.
+#[derive(Debug, Clone)]
+struct Frame {
+    name: String,
+    indent: f32,
+    right_indent: f32,
+    align: Option<Align>,
+    list: Option<(ListKind, usize)>,
+    /// Depth of the style stack when the environment opened
+    styles: usize,
+}
+
+struct TableBuilder {
+    align: Vec<Align>,
+    rows: Vec<Row>,
+    rule_pending: bool,
+}
+
+/// What the parser hands to layout.
+struct Document {
+    blocks: Vec<Block>,
+    labels: HashMap<String, String>,
+    citations: HashMap<String, usize>,
+    footnotes: Vec<Vec<Inline>>,
+    title: Option<Vec<Inline>>,
+    inputs: Vec<(u32, String)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+struct Parser<'a> {
+    tokens: VecDeque<Token>,
+    read: &'a dyn Fn(&str) -> Option<String>,
+    macros: HashMap<String, Macro>,
+    inputs: Vec<(u32, String)>,
+    diagnostics: Vec<Diagnostic>,
+
+    in_body: bool,
+    blocks: Vec<Block>,
+    paragraph: Option<Paragraph>,
+    /// Inline buffers for arguments being read, innermost last
+    captures: Vec<Vec<Inline>>,
+    styles: Vec<Style>,
+    frames: Vec<Frame>,
+    table: Option<TableBuilder>,
+    pending_marker: Option<Vec<Inline>>,
+    /// The next paragraph starts without an indent
+    no_indent: bool,
+    /// Text after display math continues the paragraph before it
+    after_display: bool,
+
+    has_chapters: bool,
+    sections: [u32; 4],
+    equation: u32,
+    floats: HashMap<&'static str, u32>,
+    theorems: HashMap<String, String>,
+    theorem_counts: HashMap<String, u32>,
+    /// What `\label` refers to at this point
+    current_label: String,
+    labels: HashMap<String, String>,
+    citations: HashMap<String, usize>,
+    cited: Vec<String>,
+    cite_all: bool,
+    bib_files: Vec<String>,
+    bibliography_at: Option<usize>,
+    footnotes: Vec<Vec<Inline>>,
+    title: Option<Vec<Inline>>,
+    author: Option<Vec<Inline>>,
+    date: Option<Vec<Inline>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &str, file_name: &str, read: &'a dyn Fn(&str) -> Option<String>) -> Self {
+        let mut macros = HashMap::new();
+        let tokens: VecDeque<Token> = tokenize(&expand_macros(&strip_comments(source), &mut macros), 1).into();
+        let has_document = tokens.iter().any(|t| t.tok == Tok::Begin("document".to_string()));
+        let has_chapters = tokens.iter().any(|t| matches!(&t.tok, Tok::Command(c) if c == "chapter" || c == "chapter*"));
+        Self {
+            tokens,
+            read,
+            macros,
+            inputs: vec![(1, file_name.to_string())],
+            diagnostics: Vec::new(),
+            in_body: !has_document,
+            blocks: Vec::new(),
+            paragraph: None,
+            captures: Vec::new(),
+            styles: vec![Style::default()],
+            frames: Vec::new(),
+            table: None,
+            pending_marker: None,
+            no_indent: true,
+            after_display: false,
+            has_chapters,
+            sections: [0; 4],
+            equation: 0,
+            floats: HashMap::new(),
+            theorems: THEOREMS.iter().map(|(env, title)| (env.to_string(), title.to_string())).collect(),
+            theorem_counts: HashMap::new(),
+            current_label: String::new(),
+            labels: HashMap::new(),
+            citations: HashMap::new(),
+            cited: Vec::new(),
+            cite_all: false,
+            bib_files: Vec::new(),
+            bibliography_at: None,
+            footnotes: Vec::new(),
+            title: None,
+            author: None,
+            date: None,
+        }
+    }
+
+    fn style(&self) -> Style {
+        *self.styles.last().unwrap_or(&Style::default())
+    }
+
+    fn style_mut(&mut self) -> &mut Style {
+        if self.styles.is_empty() {
+            self.styles.push(Style::default());
+        }
+        self.styles.last_mut().unwrap()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.tokens.pop_front()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.tokens.front().map(|t| &t.tok), Some(Tok::Space)) {
+            self.tokens.pop_front();
+        }
+    }
+
+    /// The text of a `{...}` argument, or of the next token.
+    fn raw_argument(&mut self) -> Option<String> {
+        self.skip_spaces();
+        match self.next()?.tok {
+            Tok::Open => {
+                let mut text = String::new();
+                let mut depth = 1;
+                while let Some(token) = self.next() {
+                    match token.tok {
+                        Tok::Open => {
+                            depth += 1;
+                            text.push('{');
+                        }
+                        Tok::Close => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                            text.push('}');
+                        }
+                        tok => text.push_str(&raw_text(&tok)),
+                    }
+                }
+                Some(text.trim().to_string())
+            }
+            tok => Some(raw_text(&tok)),
+        }
+    }
+
+    /// The text of an optional `[...]` argument.
+    fn optional_argument(&mut self) -> Option<String> {
+        let mut ahead = 0;
+        while matches!(self.tokens.get(ahead).map(|t| &t.tok), Some(Tok::Space)) {
+            ahead += 1;
+        }
+        if self.tokens.get(ahead).map(|t| &t.tok) != Some(&Tok::Text("[".to_string())) {
+            return None;
+        }
+        self.tokens.drain(..=ahead);
+        let mut text = String::new();
+        let mut depth = 0;
+        while let Some(token) = self.next() {
+            match token.tok {
+                Tok::Text(t) if t == "]" && depth == 0 => break,
+                Tok::Open => {
+                    depth += 1;
+                    text.push('{');
+                }
+                Tok::Close => {
+                    depth -= 1;
+                    text.push('}');
+                }
+                tok => text.push_str(&raw_text(&tok)),
+            }
+        }
+        Some(text.trim().to_string())
+    }
+
+    /// Typeset an argument into its own inline buffer, in the current style changed by `restyle`.
+    fn inline_argument(&mut self, restyle: impl FnOnce(&mut Style)) -> Vec<Inline> {
+        self.captures.push(Vec::new());
+        self.styled_argument(restyle);
+        let mut inlines = self.captures.pop().unwrap_or_default();
+        trim_spaces(&mut inlines);
+        inlines
+    }
+
+    /// Typeset the next group, or the next token, in a changed style.
+    fn styled_argument(&mut self, restyle: impl FnOnce(&mut Style)) {
+        self.skip_spaces();
+        let mut style = self.style();
+        restyle(&mut style);
+        let depth = self.styles.len();
+        self.styles.push(style);
+        match self.next() {
+            Some(Token { tok: Tok::Open, .. }) => {
+                while self.styles.len() > depth {
+                    let Some(token) = self.next() else { break };
+                    self.step(token);
+                }
+            }
+            Some(token) => self.step(token),
+            None => {}
+        }
+        self.styles.truncate(depth.max(1));
+    }
+
+    fn push(&mut self, inline: Inline) {
+        if let Some(capture) = self.captures.last_mut() {
+            if !(inline == Inline::Space && matches!(capture.last(), None | Some(Inline::Space))) {
+                capture.push(inline);
+            }
+            return;
+        }
+        if let Some(table) = &mut self.table {
+            let row = table.rows.last_mut().expect("tables start with a row");
+            let cell = row.cells.last_mut().expect("rows start with a cell");
+            if !(inline == Inline::Space && matches!(cell.last(), None | Some(Inline::Space))) {
+                cell.push(inline);
+            }
+            return;
+        }
+        if !self.in_body {
+            return;
+        }
+        if self.paragraph.is_none() {
+            if inline == Inline::Space {
+                return;
+            }
+            self.start_paragraph();
+        }
+        let paragraph = self.paragraph.as_mut().expect("just started");
+        if !(inline == Inline::Space && matches!(paragraph.inlines.last(), None | Some(Inline::Space))) {
+            paragraph.inlines.push(inline);
+        }
+    }
+
+    fn start_paragraph(&mut self) {
+        let indent = self.frames.iter().map(|f| f.indent).sum();
+        let right_indent = self.frames.iter().map(|f| f.right_indent).sum();
+        let align = self.frames.iter().rev().find_map(|f| f.align).unwrap_or(Align::Justify);
+        let marker = self.pending_marker.take();
+        let in_list = self.frames.iter().any(|f| f.list.is_some());
+        let first_indent = if self.no_indent || self.after_display || in_list || align != Align::Justify { 0.0 } else { PARINDENT };
+        let space_before = if marker.is_some() { 2.0 } else { 0.0 };
+        self.no_indent = false;
+        self.after_display = false;
+        self.paragraph = Some(Paragraph { inlines: Vec::new(), indent, right_indent, first_indent, marker, align, space_before });
+    }
+
+    fn flush(&mut self) {
+        if let Some(mut paragraph) = self.paragraph.take() {
+            trim_spaces(&mut paragraph.inlines);
+            if !paragraph.inlines.is_empty() || paragraph.marker.is_some() {
+                self.blocks.push(Block::Paragraph(paragraph));
+            }
+        }
+    }
+
+    fn block(&mut self, block: Block) {
+        if self.in_body && self.captures.is_empty() {
+            self.flush();
+            self.blocks.push(block);
+        }
+    }
+
+    fn run(&mut self) {
+        while let Some(token) = self.next() {
+            self.step(token);
+        }
+        self.flush();
+    }
+
+    fn step(&mut self, token: Token) {
+        let source = token.source;
+        match token.tok {
+            Tok::Text(text) => {
+                let style = self.style();
+                self.push(Inline::Text(text, style, source));
+            }
+            Tok::Space => self.push(Inline::Space),
+            Tok::Par => {
+                if self.captures.is_empty() && self.table.is_none() {
+                    self.flush();
+                    self.after_display = false;
+                } else {
+                    self.push(Inline::Space);
+                }
+            }
+            Tok::Open => {
+                let style = self.style();
+                self.styles.push(style);
+            }
+            Tok::Close => {
+                if self.styles.len() > 1 {
+                    self.styles.pop();
+                }
+            }
+            Tok::Align => {
+                if let Some(table) = &mut self.table {
+                    if let Some(row) = table.rows.last_mut() {
+                        row.cells.push(Vec::new());
+                    }
+                } else {
+                    self.push(Inline::Space);
+                }
+            }
+            Tok::Math { source: math, display, env } => self.math(math, display, env, source),
+            Tok::Verbatim { text, block } => {
+                if block {
+                    self.block(Block::Verbatim { text, source });
+                } else {
+                    let style = Style { mono: true, ..self.style() };
+                    self.push(Inline::Text(text, style, source));
+                }
+            }
+            Tok::Begin(env) => self.begin(env, source),
+            Tok::End(env) => self.end(&env),
+            Tok::Command(name) => self.command(&name, source),
+        }
+    }
+
+    fn math(&mut self, math: String, display: bool, env: Option<String>, source: Source) {
+        if !self.in_body {
+            return;
+        }
+        if !display {
+            let style = self.style();
+            self.push(Inline::Math(math, style, source));
+            return;
+        }
+        let numbered = env.as_deref().is_some_and(|e| !e.ends_with('*') && e != "displaymath");
+        // Each `\\` starts a row; `equation` and `multline` get one number for the whole display
+        let whole = matches!(env.as_deref(), Some("equation") | Some("multline"));
+        let mut rows = Vec::new();
+        let mut line = source.line;
+        let pieces = split_rows(&math);
+        let count = pieces.len();
+        for (i, row) in pieces.into_iter().enumerate() {
+            let row_source = Source { tag: source.tag, line };
+            line += row.matches('\n').count() as u32;
+            if row.trim().is_empty() {
+                continue;
+            }
+            let suppressed = row.contains("\\nonumber") || row.contains("\\notag");
+            let number = if numbered && !suppressed && (!whole || i + 1 == count) {
+                self.equation += 1;
+                let number = self.equation.to_string();
+                self.current_label = number.clone();
+                Some(number)
+            } else {
+                None
+            };
+            for label in labels_in(&row) {
+                self.labels.insert(label, self.current_label.clone());
+            }
+            rows.push((row, number, row_source));
+        }
+        self.block(Block::Display(rows));
+        self.after_display = true;
+    }
+
+    fn begin(&mut self, env: String, source: Source) {
+        if env == "document" {
+            self.in_body = true;
+            return;
+        }
+        let mut frame = Frame { name: env.clone(), indent: 0.0, right_indent: 0.0, align: None, list: None, styles: self.styles.len() };
+        let depth = self.frames.iter().filter(|f| f.list.is_some()).count();
+        match env.as_str() {
+            "itemize" | "enumerate" | "description" => {
+                self.flush();
+                let kind = match env.as_str() {
+                    "itemize" => ListKind::Itemize,
+                    "enumerate" => ListKind::Enumerate,
+                    _ => ListKind::Description,
+                };
+                frame.indent = LIST_INDENT;
+                frame.list = Some((kind, depth));
+                self.frames.push(frame);
+                self.list_counters_push();
+                return;
+            }
+            "center" | "flushleft" | "flushright" | "figure" | "figure*" | "table" | "table*" => {
+                self.flush();
+                frame.align = Some(match env.as_str() {
+                    "flushleft" => Align::Left,
+                    "flushright" => Align::Right,
+                    _ => Align::Center,
+                });
+                if env.starts_with("figure") || env.starts_with("table") {
+                    self.optional_argument();
+                    self.block(Block::Skip(8.0));
+                }
+            }
+            "quote" | "quotation" | "verse" => {
+                self.flush();
+                frame.indent = 25.0;
+                frame.right_indent = 25.0;
+                self.block(Block::Skip(4.0));
+            }
+            "abstract" => {
+                self.flush();
+                frame.indent = 30.0;
+                frame.right_indent = 30.0;
+                let heading = Inline::Text("Abstract".to_string(), Style { bold: true, scale: 0.9, ..Style::default() }, source);
+                self.blocks.push(Block::Paragraph(Paragraph {
+                    inlines: vec![heading], indent: 0.0, right_indent: 0.0, first_indent: 0.0, marker: None, align: Align::Center, space_before: 10.0,
+                }));
+                self.styles.push(Style { scale: 0.9, ..self.style() });
+            }
+            "tabular" | "tabular*" | "tabularx" | "array" => {
+                if env != "tabular" {
+                    self.raw_argument();
+                }
+                self.optional_argument();
+                let spec = self.raw_argument().unwrap_or_default();
+                self.flush();
+                self.table = Some(TableBuilder { align: column_alignment(&spec), rows: vec![Row { cells: vec![Vec::new()], rule_above: false }], rule_pending: false });
+            }
+            "thebibliography" => {
+                self.raw_argument();
+                self.block(Block::Heading { level: 1, number: None, text: vec![Inline::Text("References".to_string(), Style::default(), source)], source });
+                frame.indent = LIST_INDENT + 5.0;
+                frame.list = Some((ListKind::Enumerate, depth));
+            }
+            "minipage" => {
+                self.optional_argument();
+                self.raw_argument();
+            }
+            "proof" => {
+                self.flush();
+                let title = self.optional_argument().unwrap_or_else(|| "Proof".to_string());
+                self.no_indent = true;
+                self.push(Inline::Text(format!("{}.", title), Style { italic: true, ..self.style() }, source));
+                self.push(Inline::Space);
+            }
+            env if self.theorems.contains_key(env) => {
+                self.flush();
+                let title = self.theorems[env].clone();
+                let count = self.theorem_counts.entry(title.clone()).or_insert(0);
+                *count += 1;
+                let number = count.to_string();
+                self.current_label = number.clone();
+                let note = self.optional_argument();
+                self.block(Block::Skip(4.0));
+                self.no_indent = true;
+                let bold = Style { bold: true, ..self.style() };
+                self.push(Inline::Text(format!("{} {}", title, number), bold, source));
+                if let Some(note) = note {
+                    self.push(Inline::Space);
+                    self.push(Inline::Text(format!("({})", note), Style::default(), source));
+                }
+                self.push(Inline::Text(".".to_string(), bold, source));
+                self.push(Inline::Space);
+                let italic = !matches!(env, "definition" | "remark" | "example");
+                self.styles.push(Style { italic, ..self.style() });
+            }
+            _ => {}
+        }
+        self.frames.push(frame);
+    }
+
+    fn end(&mut self, env: &str) {
+        if env == "document" {
+            self.flush();
+            self.in_body = false;
+            return;
+        }
+        if matches!(env, "tabular" | "tabular*" | "tabularx" | "array") {
+            if let Some(mut table) = self.table.take() {
+                // The row after the last `\\` is usually empty
+                if table.rows.last().is_some_and(|r| r.cells.iter().all(|c| c.iter().all(|i| *i == Inline::Space))) {
+                    table.rows.pop();
+                }
+                let rule_below = table.rule_pending;
+                self.block(Block::Table { rows: table.rows, align: table.align, rule_below });
+            }
+            return;
+        }
+        let Some(index) = self.frames.iter().rposition(|f| f.name == env) else { return };
+        self.flush();
+        let frame = self.frames.remove(index);
+        self.styles.truncate(frame.styles.max(1));
+        if frame.list.is_some() {
+            self.list_counters_pop();
+            self.pending_marker = None;
+        }
+        if matches!(env, "figure" | "figure*" | "table" | "table*" | "quote" | "quotation" | "verse") || self.theorems.contains_key(env) {
+            self.block(Block::Skip(6.0));
+        }
+        self.no_indent = !matches!(env, "abstract");
+    }
+
+    fn list_counters_push(&mut self) {
+        self.theorem_counts.insert(format!("\u{0}list{}", self.frames.len()), 0);
+    }
+
+    fn list_counters_pop(&mut self) {
+        self.theorem_counts.remove(&format!("\u{0}list{}", self.frames.len() + 1));
+    }
+
+    fn item(&mut self, source: Source) {
+        let label = self.optional_argument();
+        self.flush();
+        let frames = self.frames.len();
+        let Some((kind, depth)) = self.frames.iter().rev().find_map(|f| f.list) else { return };
+        let style = self.style();
+        let marker = match (kind, label) {
+            (ListKind::Description, Some(label)) => {
+                // Description labels sit at the start of the line, in bold
+                self.no_indent = true;
+                self.push(Inline::Text(label, Style { bold: true, ..style }, source));
+                self.push(Inline::Space);
+                return;
+            }
+            (_, Some(label)) => label,
+            (ListKind::Itemize, None) => ["\u{2022}", "\u{2013}", "*", "\u{b7}"][depth.min(3)].to_string(),
+            (ListKind::Enumerate, None) => {
+                let counter = self.theorem_counts.entry(format!("\u{0}list{}", frames)).or_insert(0);
+                *counter += 1;
+                let n = *counter;
+                let label = match depth {
+                    0 => format!("{}.", n),
+                    1 => format!("({})", char::from(b'a' + ((n - 1) % 26) as u8)),
+                    2 => format!("{}.", roman(n)),
+                    _ => format!("{}.", char::from(b'A' + ((n - 1) % 26) as u8)),
+                };
+                self.current_label = label.trim_end_matches('.').trim_matches(['(', ')']).to_string();
+                label
+            }
+            (ListKind::Description, None) => String::new(),
+        };
+        self.pending_marker = Some(vec![Inline::Text(marker, Style { bold: false, italic: false, ..style }, source)]);
+        self.start_paragraph();
+    }
+
+    fn heading(&mut self, name: &str, source: Source) {
+        let starred = name.ends_with('*');
+        let name = name.trim_end_matches('*');
+        let level = match name {
+            "part" | "chapter" => 0,
+            "section" => 1,
+            "subsection" => 2,
+            "subsubsection" => 3,
+            _ => 4,
+        };
+        self.optional_argument();
+        let text = self.inline_argument(|_| {});
+        if level == 4 {
+            // Run-in heading at the start of the next paragraph
+            self.flush();
+            self.block(Block::Skip(6.0));
+            self.no_indent = true;
+            for inline in restyled(text, |s| s.bold = true) {
+                self.push(inline);
+            }
+            self.push(Inline::Glue(BODY_SIZE));
+            return;
+        }
+        let number = (!starred).then(|| {
+            self.sections[level] += 1;
+            for deeper in &mut self.sections[level + 1..] {
+                *deeper = 0;
+            }
+            let first = if self.has_chapters { 0 } else { 1 };
+            let number = self.sections[first..=level.max(first)].iter().map(|n| n.to_string()).collect::<Vec<_>>().join(".");
+            if level == 0 {
+                self.equation = 0;
+            }
+            self.current_label = number.clone();
+            number
+        });
+        if level == 0 && self.has_chapters {
+            self.block(Block::PageBreak);
+        }
+        self.block(Block::Heading { level, number, text, source });
+        self.no_indent = true;
+    }
+
+    fn command(&mut self, name: &str, source: Source) {
+        if let Some((_, args)) = IGNORED.iter().find(|(n, _)| *n == name) {
+            self.optional_argument();
+            for _ in 0..*args {
+                self.raw_argument();
+            }
+            return;
+        }
+        let style = self.style();
+        match name {
+            "part" | "part*" | "chapter" | "chapter*" | "section" | "section*" | "subsection" | "subsection*"
+            | "subsubsection" | "subsubsection*" | "paragraph" | "paragraph*" | "subparagraph" => self.heading(name, source),
+            "title" => {
+                self.optional_argument();
+                self.title = Some(self.inline_argument(|_| {}));
+            }
+            "author" => self.author = Some(self.inline_argument(|_| {})),
+            "date" => self.date = Some(self.inline_argument(|_| {})),
+            "maketitle" => self.make_title(source),
+            "and" => {
+                self.push(Inline::Glue(2.0 * BODY_SIZE));
+            }
+            "thanks" => {
+                self.inline_argument(|_| {});
+            }
+            "textbf" | "mathbf" => self.styled_argument(|s| s.bold = true),
+            "textit" | "textsl" | "mathit" => self.styled_argument(|s| s.italic = true),
+            "emph" => self.styled_argument(|s| s.italic = !s.italic),
+            "texttt" | "url" | "path" => self.styled_argument(|s| s.mono = true),
+            "textsc" => self.styled_argument(|s| s.caps = true),
+            "textrm" | "textsf" | "textnormal" | "textup" | "textmd" | "mbox" | "fbox" | "underline" | "text" | "hbox" => self.styled_argument(|_| {}),
+            "bfseries" | "bf" => self.style_mut().bold = true,
+            "itshape" | "it" | "slshape" | "sl" | "em" => self.style_mut().italic = true,
+            "ttfamily" | "tt" => self.style_mut().mono = true,
+            "scshape" => self.style_mut().caps = true,
+            "normalfont" | "rmfamily" | "upshape" | "mdseries" => {
+                let scale = style.scale;
+                *self.style_mut() = Style { scale, ..Style::default() };
+            }
+            "tiny" | "scriptsize" | "footnotesize" | "small" | "normalsize" | "large" | "Large" | "LARGE" | "huge" | "Huge" => {
+                self.style_mut().scale = font_scale(name);
+            }
+            "textcolor" | "colorbox" => {
+                self.raw_argument();
+                self.styled_argument(|_| {});
+            }
+            "href" => {
+                self.raw_argument();
+                self.styled_argument(|_| {});
+            }
+            "hyperref" => {
+                self.optional_argument();
+                self.styled_argument(|_| {});
+            }
+            "footnote" => {
+                self.optional_argument();
+                let text = self.inline_argument(|s| *s = Style::default());
+                self.footnotes.push(text);
+                let mark = Style { scale: style.scale * 0.7, raise: 0.5, ..style };
+                self.push(Inline::Text(self.footnotes.len().to_string(), mark, source));
+            }
+            "item" => self.item(source),
+            "bibitem" => {
+                let label = self.optional_argument();
+                let key = self.raw_argument().unwrap_or_default();
+                self.flush();
+                let number = self.citations.len() + 1;
+                self.citations.entry(key).or_insert(number);
+                let marker = format!("[{}]", label.unwrap_or_else(|| number.to_string()));
+                self.pending_marker = Some(vec![Inline::Text(marker, Style::default(), source)]);
+                self.start_paragraph();
+            }
+            "label" => {
+                let label = self.raw_argument().unwrap_or_default();
+                self.labels.insert(label, self.current_label.clone());
+            }
+            "ref" | "eqref" | "autoref" | "cref" | "Cref" => {
+                let label = self.raw_argument().unwrap_or_default();
+                let reference = Inline::Ref(label, style, source);
+                if name == "eqref" {
+                    self.push(Inline::Text("(".to_string(), style, source));
+                    self.push(reference);
+                    self.push(Inline::Text(")".to_string(), style, source));
+                } else {
+                    self.push(reference);
+                }
+            }
+            "cite" | "citep" | "citet" | "parencite" | "autocite" | "textcite" | "nocite" => {
+                let mut note = self.optional_argument();
+                if let Some(second) = self.optional_argument() {
+                    note = Some(second).filter(|s| !s.is_empty());
+                }
+                let keys: Vec<String> = self.raw_argument().unwrap_or_default().split(',').map(|k| k.trim().to_string()).filter(|k| !k.is_empty()).collect();
+                for key in &keys {
+                    if key == "*" {
+                        self.cite_all = true;
+                    } else if !self.cited.contains(key) {
+                        self.cited.push(key.clone());
+                    }
+                }
+                if name != "nocite" {
+                    self.push(Inline::Cite(keys, note, style, source));
+                }
+            }
+            "bibliography" | "addbibresource" => {
+                let files = self.raw_argument().unwrap_or_default();
+                for file in files.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+                    let file = if file.ends_with(".bib") { file.to_string() } else { format!("{}.bib", file) };
+                    self.bib_files.push(file);
+                }
+                if name == "bibliography" {
+                    self.print_bibliography(source);
+                }
+            }
+            "printbibliography" => {
+                self.optional_argument();
+                self.print_bibliography(source);
+            }
+            "input" | "include" | "subfile" => {
+                let file = self.raw_argument().unwrap_or_default();
+                self.input(&file, name == "include", source);
+            }
+            "includegraphics" => {
+                let options = self.optional_argument().unwrap_or_default();
+                let file = self.raw_argument().unwrap_or_default();
+                let (width, height) = graphic_size(&options);
+                self.block(Block::Graphic { name: file, width, height, source });
+            }
+            "caption" => {
+                self.optional_argument();
+                let kind = self.frames.iter().rev().find_map(|f| match f.name.trim_end_matches('*') {
+                    "figure" => Some(("figure", "Figure")),
+                    "table" => Some(("table", "Table")),
+                    _ => None,
+                });
+                let text = self.inline_argument(|_| {});
+                let (counter, title) = kind.unwrap_or(("figure", "Figure"));
+                let count = self.floats.entry(counter).or_insert(0);
+                *count += 1;
+                self.current_label = count.to_string();
+                let mut inlines = vec![Inline::Text(format!("{} {}:", title, count), Style { bold: true, ..Style::default() }, source), Inline::Space];
+                inlines.extend(text);
+                self.block(Block::Skip(4.0));
+                self.blocks.push(Block::Paragraph(Paragraph {
+                    inlines, indent: 20.0, right_indent: 20.0, first_indent: 0.0, marker: None, align: Align::Center, space_before: 2.0,
+                }));
+            }
+            "newtheorem" | "newtheorem*" => {
+                let env = self.raw_argument().unwrap_or_default();
+                self.optional_argument();
+                let title = self.raw_argument().unwrap_or_default();
+                self.optional_argument();
+                self.theorems.insert(env, title);
+            }
+            "tableofcontents" => self.block(Block::Contents),
+            "newpage" | "clearpage" | "cleardoublepage" | "pagebreak" => self.block(Block::PageBreak),
+            "vspace" | "vspace*" => {
+                let length = self.raw_argument().and_then(|l| length(&l)).unwrap_or(0.0);
+                self.block(Block::Skip(length));
+            }
+            "bigskip" => self.block(Block::Skip(12.0)),
+            "medskip" => self.block(Block::Skip(6.0)),
+            "smallskip" => self.block(Block::Skip(3.0)),
+            "hspace" | "hspace*" => {
+                let length = self.raw_argument().and_then(|l| length(&l)).unwrap_or(0.0);
+                self.push(Inline::Glue(length));
+            }
+            "quad" => self.push(Inline::Glue(style.size())),
+            "qquad" => self.push(Inline::Glue(2.0 * style.size())),
+            "," | "thinspace" => self.push(Inline::Glue(style.size() / 6.0)),
+            " " | "space" => self.push(Inline::Space),
+            "hfill" | "hfil" => self.push(Inline::Glue(2.0 * style.size())),
+            "noindent" => self.no_indent = true,
+            "par" => self.step(Token { tok: Tok::Par, source }),
+            "\\" | "newline" | "linebreak" | "tabularnewline" | "cr" => {
+                self.optional_argument();
+                if let Some(table) = &mut self.table {
+                    let rule_above = std::mem::take(&mut table.rule_pending);
+                    table.rows.push(Row { cells: vec![Vec::new()], rule_above });
+                } else {
+                    self.push(Inline::Break);
+                }
+            }
+            "hline" | "toprule" | "midrule" | "bottomrule" | "cline" | "cmidrule" => {
+                if matches!(name, "cline" | "cmidrule") {
+                    self.optional_argument();
+                    self.raw_argument();
+                }
+                if let Some(table) = &mut self.table {
+                    match table.rows.last_mut() {
+                        Some(row) if row.cells.iter().all(|c| c.is_empty()) => row.rule_above = true,
+                        _ => table.rule_pending = true,
+                    }
+                    table.rule_pending = table.rule_pending || table.rows.last().is_some_and(|r| r.cells.iter().any(|c| !c.is_empty()));
+                }
+            }
+            "multicolumn" => {
+                self.raw_argument();
+                self.raw_argument();
+                self.styled_argument(|_| {});
+            }
+            "today" => self.push(Inline::Text(today(), style, source)),
+            "LaTeX" => self.push(Inline::Text("LaTeX".to_string(), style, source)),
+            "TeX" => self.push(Inline::Text("TeX".to_string(), style, source)),
+            "ldots" | "dots" | "textellipsis" => self.push(Inline::Text("\u{2026}".to_string(), style, source)),
+            "textbackslash" => self.push(Inline::Text("\\".to_string(), style, source)),
+            "textendash" => self.push(Inline::Text("\u{2013}".to_string(), style, source)),
+            "textemdash" => self.push(Inline::Text("\u{2014}".to_string(), style, source)),
+            "textbullet" => self.push(Inline::Text("\u{2022}".to_string(), style, source)),
+            "S" => self.push(Inline::Text("\u{a7}".to_string(), style, source)),
+            "copyright" | "textcopyright" => self.push(Inline::Text("\u{a9}".to_string(), style, source)),
+            "ss" => self.push(Inline::Text("\u{df}".to_string(), style, source)),
+            "'" | "`" | "^" | "\"" | "~" | "c" | "=" | "." => {
+                let base = self.raw_argument().unwrap_or_default();
+                let mut chars = base.chars();
+                let text = match chars.next() {
+                    Some(c) => format!("{}{}", accented(name, c), chars.as_str()),
+                    None => String::new(),
+                };
+                self.push(Inline::Text(text, style, source));
+            }
+            "-" | "/" | "@" | "protect" | "centering" | "raggedright" | "raggedleft" | "null" | "relax" | "indent" => {
+                match name {
+                    "centering" => self.center_group(Align::Center),
+                    "raggedright" => self.center_group(Align::Left),
+                    "raggedleft" => self.center_group(Align::Right),
+                    _ => {}
+                }
+            }
+            // Anything else is dropped, and its arguments read as text so nothing goes missing
+            _ => {}
+        }
+    }
+
+    /// `\centering` and friends: align the rest of the enclosing environment.
+    fn center_group(&mut self, align: Align) {
+        if let Some(frame) = self.frames.last_mut() {
+            frame.align = Some(align);
+        }
+        if let Some(paragraph) = &mut self.paragraph {
+            paragraph.align = align;
+            paragraph.first_indent = 0.0;
+        }
+    }
+
+    fn make_title(&mut self, source: Source) {
+        let date = self.date.clone().unwrap_or_else(|| vec![Inline::Text(today(), Style::default(), source)]);
+        let parts = [(self.title.clone(), 1.55, 0.0), (self.author.clone(), 1.1, 14.0), (Some(date), 1.1, 8.0)];
+        for (inlines, scale, space_before) in parts {
+            let Some(inlines) = inlines.filter(|i| !i.is_empty()) else { continue };
+            self.block(Block::Paragraph(Paragraph {
+                inlines: restyled(inlines, |s| s.scale *= scale),
+                indent: 0.0, right_indent: 0.0, first_indent: 0.0, marker: None, align: Align::Center, space_before,
+            }));
+        }
+        self.block(Block::Skip(16.0));
+        self.no_indent = true;
+    }
+
+    fn print_bibliography(&mut self, source: Source) {
+        if self.in_body && self.bibliography_at.is_none() {
+            self.flush();
+            self.block(Block::Heading { level: 1, number: None, text: vec![Inline::Text("References".to_string(), Style::default(), source)], source });
+            self.bibliography_at = Some(self.blocks.len());
+        }
+    }
+
+    /// Splice the tokens of an `\input` file in front of the rest, under a new SyncTeX tag.
+    fn input(&mut self, file: &str, page_break: bool, source: Source) {
+        let path = if file.ends_with(".tex") { file.to_string() } else { format!("{}.tex", file) };
+        let Some(text) = (self.read)(&path).or_else(|| (self.read)(file)) else {
+            self.diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                kind: DiagnosticKind::LatexWarning,
+                file: self.file_name(source.tag),
+                line: Some(source.line as usize),
+                message: format!("File `{}' not found", path),
+            });
+            return;
+        };
+        // Cycles through `\input` would never end
+        if self.inputs.iter().any(|(_, p)| *p == path) || self.inputs.len() > 256 {
+            return;
+        }
+        let tag = self.inputs.len() as u32 + 1;
+        self.inputs.push((tag, path));
+        let mut tokens = tokenize(&expand_macros(&strip_comments(&text), &mut self.macros), tag);
+        if page_break {
+            tokens.insert(0, Token { tok: Tok::Command("clearpage".to_string()), source });
+            tokens.push(Token { tok: Tok::Command("clearpage".to_string()), source });
+        }
+        for token in tokens.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+    }
+
+    fn file_name(&self, tag: u32) -> Option<String> {
+        self.inputs.iter().find(|(t, _)| *t == tag).map(|(_, p)| p.clone())
+    }
+
+    /// Number citations and add entries from `.bib` files where the bibliography was asked for.
+    fn finish(mut self) -> Document {
+        self.flush();
+        if let Some(at) = self.bibliography_at {
+            let mut entries: Vec<BibEntry> = Vec::new();
+            for file in &self.bib_files {
+                if let Some(text) = (self.read)(file) {
+                    entries.extend(BibParser::parse(&text));
+                }
+            }
+            let mut keys: Vec<String> = self.cited.iter().filter(|k| entries.iter().any(|e| e.key == **k)).cloned().collect();
+            if self.cite_all {
+                keys.extend(entries.iter().map(|e| e.key.clone()).filter(|k| !self.cited.contains(k)));
+            }
+            let source = Source { tag: 1, line: 1 };
+            let mut items = Vec::new();
+            for key in keys {
+                let Some(entry) = entries.iter().find(|e| e.key == key) else { continue };
+                let number = self.citations.len() + 1;
+                self.citations.entry(key).or_insert(number);
+                items.push(Block::Paragraph(Paragraph {
+                    inlines: bib_inlines(entry, source),
+                    indent: LIST_INDENT + 5.0,
+                    right_indent: 0.0,
+                    first_indent: 0.0,
+                    marker: Some(vec![Inline::Text(format!("[{}]", number), Style::default(), source)]),
+                    align: Align::Left,
+                    space_before: 2.0,
+                }));
+            }
+            self.blocks.splice(at..at, items);
+        }
+        Document {
+            blocks: self.blocks,
+            labels: self.labels,
+            citations: self.citations,
+            footnotes: self.footnotes,
+            title: self.title,
+            inputs: self.inputs,
+            diagnostics: self.diagnostics,
+        }
+    }
+}
+
+/// Source text for a token, for arguments read raw.
+fn raw_text(tok: &Tok) -> String {
+    match tok {
+        Tok::Text(t) => t.clone(),
+        Tok::Space | Tok::Par => " ".to_string(),
+        Tok::Command(c) => format!("\\{}", c),
+        Tok::Open => "{".to_string(),
+        Tok::Close => "}".to_string(),
+        Tok::Align => "&".to_string(),
+        Tok::Math { source, .. } => format!("${}$", source),
+        Tok::Verbatim { text, .. } => text.clone(),
+        Tok::Begin(env) => format!("\\begin{{{}}}", env),
+        Tok::End(env) => format!("\\end{{{}}}", env),
+    }
+}
+
+fn trim_spaces(inlines: &mut Vec<Inline>) {
+    while inlines.last() == Some(&Inline::Space) {
+        inlines.pop();
+    }
+    while inlines.first() == Some(&Inline::Space) {
+        inlines.remove(0);
+    }
+}
+
+fn restyled(inlines: Vec<Inline>, restyle: impl Fn(&mut Style)) -> Vec<Inline> {
+    inlines.into_iter().map(|mut inline| {
+        match &mut inline {
+            Inline::Text(_, style, _) | Inline::Math(_, style, _) | Inline::Ref(_, style, _) | Inline::Cite(_, _, style, _) => restyle(style),
+            Inline::Space | Inline::Glue(_) | Inline::Break => {}
+        }
+        inline
+    }).collect()
+}
+
+fn plain_text(inlines: &[Inline]) -> String {
+    inlines.iter().map(|inline| match inline {
+        Inline::Text(text, ..) | Inline::Math(text, ..) => text.as_str(),
+        Inline::Space | Inline::Glue(_) => " ",
+        _ => "",
+    }).collect()
+}
+
+/// Rows of a display, split at `\\` outside braces.
+fn split_rows(math: &str) -> Vec<String> {
+    let mut rows = vec![String::new()];
+    let mut depth = 0;
+    let mut chars = math.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '\\' if chars.peek() == Some(&'\\') && depth == 0 => {
+                chars.next();
+                rows.push(String::new());
+                continue;
+            }
+            '\\' => {
+                rows.last_mut().unwrap().push(c);
+                if let Some(next) = chars.next() {
+                    rows.last_mut().unwrap().push(next);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        rows.last_mut().unwrap().push(c);
+    }
+    rows
+}
+
+fn labels_in(math: &str) -> Vec<String> {
+    math.match_indices("\\label{").filter_map(|(i, m)| {
+        let rest = &math[i + m.len()..];
+        rest.find('}').map(|end| rest[..end].trim().to_string())
+    }).collect()
+}
+
+fn column_alignment(spec: &str) -> Vec<Align> {
+    let mut align = Vec::new();
+    let mut depth = 0;
+    for c in spec.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            'l' if depth == 0 => align.push(Align::Left),
+            'c' if depth == 0 => align.push(Align::Center),
+            'r' if depth == 0 => align.push(Align::Right),
+            'p' | 'm' | 'b' | 'X' if depth == 0 => align.push(Align::Left),
+            _ => {}
+        }
+    }
+    align
+}
+
+/// A TeX length in points, such as `2cm`, `0.5\textwidth` or `1em`.
+fn length(spec: &str) -> Option<f32> {
+    let spec = spec.trim().trim_end_matches('*');
+    let split = spec.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+' || c == ' ')).unwrap_or(spec.len());
+    let number = spec[..split].replace(' ', "");
+    let number: f32 = match number.as_str() {
+        "" | "+" => 1.0,
+        "-" => -1.0,
+        n => n.parse().ok()?,
+    };
+    let unit = match spec[split..].trim() {
+        "pt" => 1.0,
+        "bp" => 1.0,
+        "mm" => 72.0 / 25.4,
+        "cm" => 72.0 / 2.54,
+        "in" => 72.0,
+        "em" => BODY_SIZE,
+        "ex" => BODY_SIZE * 0.45,
+        "pc" => 12.0,
+        "\\textwidth" | "\\linewidth" | "\\columnwidth" | "\\hsize" => TEXT_WIDTH,
+        "\\textheight" => TEXT_BOTTOM - MARGIN,
+        "\\paperwidth" => PAGE_SIZE.0,
+        "\\paperheight" => PAGE_SIZE.1,
+        "\\baselineskip" => BODY_SIZE * LEADING,
+        "\\fill" | "\\stretch" => 0.0,
+        _ => return None,
+    };
+    Some(number * unit)
+}
+
+/// The size of an `\includegraphics` placeholder from its options; images are taken as 4:3 when unknown.
+fn graphic_size(options: &str) -> (f32, f32) {
+    let mut width = None;
+    let mut height = None;
+    let mut scale = None;
+    for option in options.split(',') {
+        let Some((key, value)) = option.split_once('=') else { continue };
+        match key.trim() {
+            "width" => width = length(value),
+            "height" => height = length(value),
+            "scale" => scale = value.trim().parse::<f32>().ok(),
+            _ => {}
+        }
+    }
+    let (width, height) = match (width, height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, w * 0.75),
+        (None, Some(h)) => (h / 0.75, h),
+        (None, None) => {
+            let w = 0.6 * TEXT_WIDTH * scale.unwrap_or(1.0);
+            (w, w * 0.75)
+        }
+    };
+    (width.clamp(10.0, TEXT_WIDTH), height.clamp(10.0, TEXT_BOTTOM - MARGIN - 40.0))
+}
+
+fn font_scale(command: &str) -> f32 {
+    match command {
+        "tiny" => 0.5,
+        "scriptsize" => 0.7,
+        "footnotesize" => 0.8,
+        "small" => 0.9,
+        "large" => 1.09,
+        "Large" => 1.31,
+        "LARGE" => 1.57,
+        "huge" => 1.88,
+        "Huge" => 2.25,
+        _ => 1.0,
+    }
+}
+
+fn roman(mut n: u32) -> String {
+    let mut out = String::new();
+    for (value, digits) in [(10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i")] {
+        while n >= value {
+            out.push_str(digits);
+            n -= value;
+        }
+    }
+    out
+}
+
+/// A letter with an accent command applied, when Latin-1 has it.
+fn accented(command: &str, c: char) -> char {
+    let (from, to) = match command {
+        "'" => ("aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+        "`" => ("aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+        "^" => ("aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+        "\"" => ("aeiouyAEIOU", "äëïöüÿÄËÏÖÜ"),
+        "~" => ("anoANO", "ãñõÃÑÕ"),
+        "c" => ("cC", "çÇ"),
+        _ => ("", ""),
+    };
+    from.chars().position(|f| f == c).and_then(|i| to.chars().nth(i)).unwrap_or(c)
+}
+
+/// Today's date the way `\today` prints it.
+fn today() -> String {
+    const MONTHS: [&str; 12] = ["January", "February", "March", "April", "May", "June", "July", "August", "September", "October", "November", "December"];
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() / 86_400) as i64;
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{} {}, {}", MONTHS[month as usize - 1], day, year)
+}
+
+/// A `.bib` entry as a reference list item: authors, title, venue and year.
+fn bib_inlines(entry: &BibEntry, source: Source) -> Vec<Inline> {
+    let mut inlines = Vec::new();
+    let mut sentence = |text: Option<String>, style: Style| {
+        if let Some(text) = text.filter(|t| !t.trim().is_empty()) {
+            for (i, word) in text.split_whitespace().enumerate() {
+                if i > 0 || !inlines.is_empty() {
+                    inlines.push(Inline::Space);
+                }
+                inlines.push(Inline::Text(word.to_string(), style, source));
+            }
+            inlines.push(Inline::Text(".".to_string(), Style::default(), source));
+        }
+    };
+    let authors = entry.plain("author").map(|a| a.split(" and ").map(str::trim).collect::<Vec<_>>().join(", "));
+    sentence(authors, Style::default());
+    sentence(entry.plain("title"), Style { italic: true, ..Style::default() });
+    sentence(entry.venue(), Style::default());
+    sentence(entry.plain("year").or_else(|| entry.plain("date")), Style::default());
+    inlines
+}
+
+/// Straight quotes and dashes typed the TeX way, as the characters they stand for.
+fn ligatures(text: &str) -> String {
+    text.replace("---", "\u{2014}")
+        .replace("--", "\u{2013}")
+        .replace("``", "\u{201c}")
+        .replace("''", "\u{201d}")
+        .replace('`', "\u{2018}")
+        .replace('\'', "\u{2019}")
+}
+
+#[derive(Debug, Clone)]
+enum PieceKind {
+    Text { text: String, font: Font, size: f32 },
+    Math(MathBox),
+    Blank,
+}
+
+/// Something of fixed width on a line.
+#[derive(Debug, Clone)]
+struct Piece {
+    kind: PieceKind,
+    width: f32,
+    ascent: f32,
+    depth: f32,
+    /// Moves the piece down from the baseline, negative for up
+    shift: f32,
+    source: Option<Source>,
+}
+
+/// Pieces between break opportunities, and the space that follows them.
+#[derive(Debug, Clone, Default)]
+struct Word {
+    pieces: Vec<Piece>,
+    width: f32,
+    space: f32,
+    /// A line break is forced after this word
+    forced: bool,
+}
+
+struct Layout<'a> {
+    document: &'a Document,
+    pages: Vec<Page>,
+    page: Page,
+    y: f32,
+    /// Headings with the page they landed on, for the table of contents
+    headings: Vec<(usize, Option<String>, Vec<Inline>, usize)>,
+    previous_headings: Vec<(usize, Option<String>, Vec<Inline>, usize)>,
+}
+
+impl<'a> Layout<'a> {
+    fn new(document: &'a Document, previous_headings: Vec<(usize, Option<String>, Vec<Inline>, usize)>) -> Self {
+        Self { document, pages: Vec::new(), page: Page::default(), y: MARGIN, headings: Vec::new(), previous_headings }
+    }
+
+    fn run(&mut self) {
+        for block in &self.document.blocks {
+            self.block(block);
+        }
+        if !self.document.footnotes.is_empty() {
+            self.footnotes();
+        }
+    }
+
+    fn finish(mut self) -> Vec<Page> {
+        if !self.page.marks.is_empty() || self.pages.is_empty() {
+            self.new_page();
+        }
+        self.pages
+    }
+
+    fn new_page(&mut self) {
+        let number = (self.pages.len() + 1).to_string();
+        let width = Font::Roman.text_width(&number, 10.0);
+        self.page.marks.push(Mark::Text { x: (PAGE_SIZE.0 - width) / 2.0, y: PAGE_SIZE.1 - 50.0, font: Font::Roman, size: 10.0, text: number });
+        self.pages.push(std::mem::take(&mut self.page));
+        self.y = MARGIN;
+    }
+
+    fn at_top(&self) -> bool {
+        self.y <= MARGIN
+    }
+
+    /// Start a new page unless `height` more fits on this one.
+    fn reserve(&mut self, height: f32) {
+        if self.y + height > TEXT_BOTTOM && !self.at_top() {
+            self.new_page();
+        }
+    }
+
+    fn skip(&mut self, space: f32) {
+        if !self.at_top() {
+            self.y += space;
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        match block {
+            Block::Paragraph(paragraph) => {
+                self.paragraph(paragraph);
+            }
+            Block::Heading { level, number, text, source } => {
+                self.heading(*level, number.as_deref(), text, *source);
+                self.headings.push((*level, number.clone(), text.clone(), self.pages.len() + 1));
+            }
+            Block::Display(rows) => self.display(rows),
+            Block::Graphic { name, width, height, source } => self.graphic(name, *width, *height, *source),
+            Block::Table { rows, align, rule_below } => self.table(rows, align, *rule_below),
+            Block::Verbatim { text, source } => self.verbatim(text, *source),
+            Block::Contents => self.contents(),
+            Block::Skip(space) => self.skip(*space),
+            Block::PageBreak => {
+                if !self.at_top() || !self.page.marks.is_empty() {
+                    self.new_page();
+                }
+            }
+        }
+    }
+
+    fn heading(&mut self, level: usize, number: Option<&str>, text: &[Inline], source: Source) {
+        let (scale, before, after) = match level {
+            0 => (1.9, 24.0, 14.0),
+            1 => (1.31, 16.0, 8.0),
+            2 => (1.09, 12.0, 6.0),
+            _ => (1.0, 10.0, 4.0),
+        };
+        self.skip(before);
+        // Keep a heading with a few lines of what follows
+        self.reserve(scale * BODY_SIZE * LEADING + 3.0 * BODY_SIZE * LEADING);
+        let style = Style { bold: true, scale, ..Style::default() };
+        let mut inlines = Vec::new();
+        if let Some(number) = number {
+            inlines.push(Inline::Text(number.to_string(), style, source));
+            inlines.push(Inline::Glue(scale * BODY_SIZE));
+        }
+        inlines.extend(restyled(text.to_vec(), |s| {
+            s.bold = true;
+            s.scale *= scale;
+        }));
+        self.paragraph(&Paragraph { inlines, indent: 0.0, right_indent: 0.0, first_indent: 0.0, marker: None, align: Align::Left, space_before: 0.0 });
+        self.y += after;
+    }
+
+    fn contents(&mut self) {
+        let source = Source { tag: 1, line: 1 };
+        self.heading(1, None, &[Inline::Text("Contents".to_string(), Style::default(), source)], source);
+        let entries = self.previous_headings.clone();
+        for (level, number, text, page) in entries.iter().filter(|h| h.0 <= 2) {
+            let style = Style { bold: *level <= 1, ..Style::default() };
+            let mut inlines = Vec::new();
+            if let Some(number) = number {
+                inlines.push(Inline::Text(number.clone(), style, source));
+                inlines.push(Inline::Glue(8.0));
+            }
+            inlines.extend(restyled(text.clone(), |s| s.bold = style.bold));
+            let indent = 15.0 * level.saturating_sub(1) as f32;
+            let baseline = self.paragraph(&Paragraph {
+                inlines, indent, right_indent: 30.0, first_indent: 0.0, marker: None, align: Align::Left, space_before: if *level <= 1 { 4.0 } else { 0.0 },
+            });
+            let page = page.to_string();
+            let font = style.font();
+            let x = MARGIN + TEXT_WIDTH - font.text_width(&page, BODY_SIZE);
+            self.page.marks.push(Mark::Text { x, y: baseline, font, size: BODY_SIZE, text: page });
+        }
+        self.y += 8.0;
+    }
+
+    /// Break inline content into words with measured pieces.
+    fn words(&self, inlines: &[Inline]) -> Vec<Word> {
+        let mut words: Vec<Word> = vec![Word::default()];
+        for inline in inlines {
+            match inline {
+                Inline::Space => {
+                    let word = words.last_mut().expect("never empty");
+                    if !word.pieces.is_empty() || word.space > 0.0 {
+                        let space = word.pieces.last().map_or(BODY_SIZE * 0.25, |p| match &p.kind {
+                            PieceKind::Text { font, size, .. } => font.width(' ', *size),
+                            _ => BODY_SIZE * 0.25,
+                        });
+                        word.space = word.space.max(space);
+                        words.push(Word::default());
+                    }
+                }
+                Inline::Glue(width) => {
+                    let word = words.last_mut().expect("never empty");
+                    word.space += width;
+                    words.push(Word::default());
+                }
+                Inline::Break => {
+                    words.last_mut().expect("never empty").forced = true;
+                    words.push(Word::default());
+                }
+                Inline::Text(text, style, source) => {
+                    let text = if style.mono { text.clone() } else { ligatures(text) };
+                    self.text_piece(words.last_mut().expect("never empty"), &text, style, *source);
+                }
+                Inline::Math(math, style, source) => {
+                    let laid = preview_math::layout(math, style.size(), false);
+                    let word = words.last_mut().expect("never empty");
+                    word.width += laid.width;
+                    word.pieces.push(Piece { width: laid.width, ascent: laid.height, depth: laid.depth, shift: 0.0, source: Some(*source), kind: PieceKind::Math(laid) });
+                }
+                Inline::Ref(label, style, source) => {
+                    let text = self.document.labels.get(label).cloned().unwrap_or_else(|| "??".to_string());
+                    self.text_piece(words.last_mut().expect("never empty"), &text, style, *source);
+                }
+                Inline::Cite(keys, note, style, source) => {
+                    let mut parts: Vec<String> = keys.iter().map(|k| self.document.citations.get(k).map_or("?".to_string(), |n| n.to_string())).collect();
+                    if let Some(note) = note {
+                        parts.push(note.clone());
+                    }
+                    self.text_piece(words.last_mut().expect("never empty"), &format!("[{}]", parts.join(", ")), style, *source);
+                }
+            }
+        }
+        words.retain(|w| !w.pieces.is_empty() || w.space > 0.0 || w.forced);
+        words
+    }
+
+    fn text_piece(&self, word: &mut Word, text: &str, style: &Style, source: Source) {
+        let size = style.size();
+        let (text, size) = if style.caps { (text.to_uppercase(), size * 0.85) } else { (text.to_string(), size) };
+        let font = style.font();
+        let width = font.text_width(&text, size);
+        word.width += width;
+        word.pieces.push(Piece {
+            kind: PieceKind::Text { text, font, size },
+            width,
+            ascent: size * LEADING * 0.75,
+            depth: size * LEADING * 0.25,
+            shift: -style.raise * size / 0.7,
+            source: Some(source),
+        });
+    }
+
+    /// Set a paragraph line by line, returning the baseline of its last line.
+    fn paragraph(&mut self, paragraph: &Paragraph) -> f32 {
+        self.skip(paragraph.space_before);
+        let words = self.words(&paragraph.inlines);
+        let left = MARGIN + paragraph.indent;
+        let available = TEXT_WIDTH - paragraph.indent - paragraph.right_indent;
+
+        // Greedy line breaking
+        let mut lines: Vec<(usize, usize)> = Vec::new();
+        let mut start = 0;
+        let mut width = paragraph.first_indent;
+        for (i, word) in words.iter().enumerate() {
+            let gap = if i > start { words[i - 1].space } else { 0.0 };
+            if i > start && width + gap + word.width > available {
+                lines.push((start, i));
+                start = i;
+                width = word.width;
+            } else {
+                width += gap + word.width;
+            }
+            if word.forced {
+                lines.push((start, i + 1));
+                start = i + 1;
+                width = 0.0;
+            }
+        }
+        if start < words.len() || lines.is_empty() {
+            lines.push((start, words.len()));
+        }
+
+        let mut baseline = self.y;
+        for (n, &(start, end)) in lines.iter().enumerate() {
+            let line = &words[start..end];
+            let first = n == 0;
+            let indent = if first { paragraph.first_indent } else { 0.0 };
+            let pieces = line.iter().flat_map(|w| &w.pieces);
+            let ascent = pieces.clone().map(|p| p.ascent - p.shift).fold(BODY_SIZE * LEADING * 0.75, f32::max);
+            let depth = pieces.map(|p| p.depth + p.shift).fold(BODY_SIZE * LEADING * 0.25, f32::max);
+            self.reserve(ascent + depth);
+            baseline = self.y + ascent;
+
+            let natural: f32 = line.iter().map(|w| w.width).sum::<f32>() + line.iter().take(line.len().saturating_sub(1)).map(|w| w.space).sum::<f32>();
+            let room = available - indent - natural;
+            let last = n + 1 == lines.len() || line.last().is_some_and(|w| w.forced);
+            let (offset, stretch) = match paragraph.align {
+                Align::Justify if !last && line.len() > 1 && natural > 0.6 * available => (0.0, room / (line.len() - 1) as f32),
+                Align::Center => (room / 2.0, 0.0),
+                Align::Right => (room, 0.0),
+                _ => (0.0, 0.0),
+            };
+
+            let mut placed = Vec::new();
+            if first {
+                if let Some(marker) = &paragraph.marker {
+                    let marker_words = self.words(marker);
+                    let marker_width: f32 = marker_words.iter().map(|w| w.width + w.space).sum();
+                    let mut x = left - marker_width - 6.0;
+                    for piece in marker_words.iter().flat_map(|w| &w.pieces) {
+                        placed.push((x, piece.clone()));
+                        x += piece.width;
+                    }
+                }
+            }
+            let mut x = left + indent + offset;
+            for word in line {
+                for piece in &word.pieces {
+                    placed.push((x, piece.clone()));
+                    x += piece.width;
+                }
+                x += word.space + stretch;
+            }
+            self.place(&placed, baseline, ascent, depth);
+            self.y = baseline + depth;
+        }
+        baseline
+    }
+
+    /// Draw positioned pieces on a baseline and record which source lines they came from.
+    fn place(&mut self, placed: &[(f32, Piece)], baseline: f32, ascent: f32, depth: f32) {
+        let mut run: Option<(Source, f32, f32)> = None;
+        for (x, piece) in placed {
+            match &piece.kind {
+                PieceKind::Text { text, font, size } => {
+                    self.page.marks.push(Mark::Text { x: *x, y: baseline + piece.shift, font: *font, size: *size, text: text.clone() });
+                }
+                PieceKind::Math(laid) => {
+                    self.page.marks.extend(laid.marks.iter().map(|m| m.clone().translated(*x, baseline)));
+                }
+                PieceKind::Blank => {}
+            }
+            let Some(source) = piece.source else { continue };
+            match &mut run {
+                Some((current, _, end)) if *current == source => *end = x + piece.width,
+                _ => {
+                    if let Some((source, start, end)) = run.take() {
+                        self.source_box(source, start, end - start, baseline, ascent, depth);
+                    }
+                    run = Some((source, *x, x + piece.width));
+                }
+            }
+        }
+        if let Some((source, start, end)) = run {
+            self.source_box(source, start, end - start, baseline, ascent, depth);
+        }
+    }
+
+    fn source_box(&mut self, source: Source, x: f32, width: f32, y: f32, height: f32, depth: f32) {
+        self.page.boxes.push(SourceBox { tag: source.tag, line: source.line, x, y, width, height, depth });
+    }
+
+    fn display(&mut self, rows: &[(String, Option<String>, Source)]) {
+        self.y += 6.0;
+        for (math, number, source) in rows {
+            let laid = preview_math::layout(math, BODY_SIZE, true);
+            self.reserve(laid.height + laid.depth);
+            let baseline = self.y + laid.height.max(BODY_SIZE * 0.75);
+            let x = MARGIN + (TEXT_WIDTH - laid.width).max(0.0) / 2.0;
+            let (width, height, depth) = (laid.width, laid.height, laid.depth);
+            self.page.marks.extend(laid.marks.into_iter().map(|m| m.translated(x, baseline)));
+            if let Some(number) = number {
+                let text = format!("({})", number);
+                let nx = MARGIN + TEXT_WIDTH - Font::Roman.text_width(&text, BODY_SIZE);
+                self.page.marks.push(Mark::Text { x: nx, y: baseline, font: Font::Roman, size: BODY_SIZE, text });
+            }
+            self.source_box(*source, x, width, baseline, height, depth);
+            self.y = baseline + depth.max(BODY_SIZE * 0.25) + 4.0;
+        }
+        self.y += 4.0;
+    }
+
+    fn graphic(&mut self, name: &str, width: f32, height: f32, source: Source) {
+        self.reserve(height + 4.0);
+        self.y += 2.0;
+        let x = MARGIN + (TEXT_WIDTH - width) / 2.0;
+        self.page.marks.push(Mark::Frame { x, y: self.y, width, height });
+        let label = name.rsplit('/').next().unwrap_or(name);
+        let size = 9.0;
+        let label_width = Font::Mono.text_width(label, size);
+        if label_width < width {
+            self.page.marks.push(Mark::Text { x: x + (width - label_width) / 2.0, y: self.y + height / 2.0 + size / 3.0, font: Font::Mono, size, text: label.to_string() });
+        }
+        self.source_box(source, x, width, self.y + height, height, 0.0);
+        self.y += height + 4.0;
+    }
+
+    fn table(&mut self, rows: &[Row], align: &[Align], rule_below: bool) {
+        const PAD: f32 = 6.0;
+        let cells: Vec<Vec<Word>> = rows.iter().map(|row| row.cells.iter().map(|cell| {
+            // Cells are set on one line
+            let words = self.words(cell);
+            let mut merged = Word::default();
+            for (i, word) in words.iter().enumerate() {
+                for piece in &word.pieces {
+                    merged.pieces.push(piece.clone());
+                }
+                merged.width += word.width;
+                if i + 1 < words.len() {
+                    merged.pieces.push(Piece { kind: PieceKind::Blank, width: word.space, ascent: 0.0, depth: 0.0, shift: 0.0, source: None });
+                    merged.width += word.space;
+                }
+            }
+            merged
+        }).collect()).collect();
+        let columns = cells.iter().map(Vec::len).max().unwrap_or(0).max(align.len());
+        let mut widths = vec![0.0f32; columns];
+        for row in &cells {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.width);
+            }
+        }
+        let total: f32 = widths.iter().map(|w| w + 2.0 * PAD).sum();
+        let left = MARGIN + (TEXT_WIDTH - total).max(0.0) / 2.0;
+
+        self.y += 4.0;
+        for (row, words) in rows.iter().zip(&cells) {
+            let ascent = BODY_SIZE * LEADING * 0.75 + 2.0;
+            let depth = BODY_SIZE * LEADING * 0.25 + 2.0;
+            self.reserve(ascent + depth);
+            if row.rule_above {
+                self.page.marks.push(Mark::Rule { x: left, y: self.y, width: total, height: 0.4 });
+            }
+            let baseline = self.y + ascent;
+            let mut x = left;
+            let mut placed = Vec::new();
+            for (i, width) in widths.iter().enumerate() {
+                if let Some(cell) = words.get(i) {
+                    let room = width - cell.width;
+                    let offset = match align.get(i).copied().unwrap_or(Align::Left) {
+                        Align::Center => room / 2.0,
+                        Align::Right => room,
+                        _ => 0.0,
+                    };
+                    let mut cx = x + PAD + offset;
+                    for piece in &cell.pieces {
+                        placed.push((cx, piece.clone()));
+                        cx += piece.width;
+                    }
+                }
+                x += width + 2.0 * PAD;
+            }
+            self.place(&placed, baseline, ascent, depth);
+            self.y = baseline + depth;
+        }
+        if rule_below {
+            self.page.marks.push(Mark::Rule { x: left, y: self.y, width: total, height: 0.4 });
+        }
+        self.y += 4.0;
+    }
+
+    fn verbatim(&mut self, text: &str, source: Source) {
+        let size = BODY_SIZE * 0.9;
+        let leading = size * LEADING;
+        self.y += 3.0;
+        for (i, line) in text.lines().enumerate() {
+            self.reserve(leading);
+            let baseline = self.y + leading * 0.75;
+            let line_source = Source { tag: source.tag, line: source.line + 1 + i as u32 };
+            if !line.trim().is_empty() {
+                let text = line.replace('\t', "    ");
+                let width = Font::Mono.text_width(&text, size);
+                self.page.marks.push(Mark::Text { x: MARGIN, y: baseline, font: Font::Mono, size, text });
+                self.source_box(line_source, MARGIN, width, baseline, leading * 0.75, leading * 0.25);
+            }
+            self.y += leading;
+        }
+        self.y += 3.0;
+    }
+
+    /// Footnotes are set as endnotes, under a short rule after the text.
+    fn footnotes(&mut self) {
+        self.skip(10.0);
+        self.reserve(3.0 * BODY_SIZE);
+        self.page.marks.push(Mark::Rule { x: MARGIN, y: self.y, width: TEXT_WIDTH * 0.3, height: 0.4 });
+        self.y += 4.0;
+        for (i, note) in self.document.footnotes.iter().enumerate() {
+            let small = Style { scale: 0.8, ..Style::default() };
+            let source = note.iter().find_map(|inline| match inline {
+                Inline::Text(_, _, source) | Inline::Math(_, _, source) => Some(*source),
+                _ => None,
+            }).unwrap_or(Source { tag: 1, line: 1 });
+            let mut inlines = vec![Inline::Text((i + 1).to_string(), Style { scale: 0.56, raise: 0.5, ..Style::default() }, source)];
+            inlines.extend(restyled(note.clone(), |s| s.scale *= small.scale));
+            self.paragraph(&Paragraph { inlines, indent: 0.0, right_indent: 0.0, first_indent: 10.0, marker: None, align: Align::Justify, space_before: 1.0 });
+        }
+    }
+}
+
+/// Warnings for references and citations that do not resolve, where they appear.
+fn layout_diagnostics(document: &Document) -> Vec<Diagnostic> {
+    let file = |tag: u32| document.inputs.iter().find(|(t, _)| *t == tag).map(|(_, p)| p.clone());
+    let mut diagnostics = Vec::new();
+    let mut check = |inline: &Inline| match inline {
+        Inline::Ref(label, _, source) if !document.labels.contains_key(label) => diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            kind: DiagnosticKind::UndefinedReference(label.clone()),
+            file: file(source.tag),
+            line: Some(source.line as usize),
+            message: format!("Reference `{}' on page 1 undefined", label),
+        }),
+        Inline::Cite(keys, _, _, source) => {
+            for key in keys.iter().filter(|k| !document.citations.contains_key(*k)) {
+                diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    kind: DiagnosticKind::UndefinedCitation(key.clone()),
+                    file: file(source.tag),
+                    line: Some(source.line as usize),
+                    message: format!("Citation `{}' on page 1 undefined", key),
+                });
+            }
+        }
+        _ => {}
+    };
+    for block in &document.blocks {
+        match block {
+            Block::Paragraph(paragraph) => paragraph.inlines.iter().for_each(&mut check),
+            Block::Heading { text, .. } => text.iter().for_each(&mut check),
+            Block::Table { rows, .. } => rows.iter().flat_map(|r| &r.cells).flatten().for_each(&mut check),
+            _ => {}
+        }
+    }
+    document.footnotes.iter().flatten().for_each(&mut check);
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synctex::SyncTex;
+
+    fn no_files(_: &str) -> Option<String> {
+        None
+    }
+
+    /// The text drawn on each page in drawing order, with a space wherever there is a gap.
+    fn page_text(pages: &[Page]) -> Vec<String> {
+        pages.iter().map(|page| {
+            let mut text = String::new();
+            let mut end: Option<(f32, f32)> = None;
+            for mark in &page.marks {
+                if let Mark::Text { x, y, font, size, text: piece } = mark {
+                    if end.is_some_and(|(ex, ey)| (ey - y).abs() > 0.01 || *x > ex + 0.5) {
+                        text.push(' ');
+                    }
+                    text.push_str(piece);
+                    end = Some((x + font.text_width(piece, *size), *y));
+                }
+            }
+            text
+        }).collect()
+    }
+
+    #[test]
+    fn test_macros_expand_and_keep_lines() {
+        let mut macros = HashMap::new();
+        let source = "\\newcommand{\\R}{\\mathbb{R}}\n\\newcommand\\pair[2]{(#1, #2)}\n$x \\in \\R$ and \\pair{a}\n{b}\nend";
+        let expanded = expand_macros(source, &mut macros);
+        assert_eq!(expanded.lines().count(), source.lines().count());
+        assert!(expanded.contains("$x \\in \\mathbb{R}$"));
+        assert!(expanded.contains("(a, b)"));
+        assert_eq!(expanded.lines().last(), Some("end"));
+
+        // Recursive definitions stop instead of hanging
+        assert!(expand_macros("\\def\\loop{\\loop x}\\loop", &mut HashMap::new()).ends_with(" x"));
+    }
+
+    #[test]
+    fn test_numbers_references_and_citations() {
+        let source = "\\documentclass{article}\n\\begin{document}\n\\section{Intro}\\label{sec:intro}\nSee Section~\\ref{sec:intro} and \\eqref{eq:one} \\cite{knuth}.\n\\begin{equation}\\label{eq:one}\na^2 + b^2 = c^2\n\\end{equation}\n\\ref{missing}\n\\begin{thebibliography}{9}\n\\bibitem{knuth} D. Knuth.\n\\end{thebibliography}\n\\end{document}\n";
+        let (document, pages) = lay_out(source, "main.tex", &no_files);
+        assert_eq!(document.labels.get("sec:intro").map(String::as_str), Some("1"));
+        assert_eq!(document.labels.get("eq:one").map(String::as_str), Some("1"));
+        assert_eq!(document.citations.get("knuth"), Some(&1));
+        let text = &page_text(&pages)[0];
+        assert!(text.starts_with("1 Intro See Section\u{a0}1 and (1) [1]."), "{}", text);
+        assert!(text.contains("(1)") && text.contains("[1] D. Knuth."));
+
+        let diagnostics = typeset(source, "main.tex", &no_files).diagnostics;
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::UndefinedReference("missing".to_string()));
+        assert_eq!(diagnostics[0].line, Some(8));
+    }
+
+    #[test]
+    fn test_lists_inputs_and_source_mapping() {
+        let read = |path: &str| (path == "chapter.tex").then(|| "Included text.\n\n\\begin{enumerate}\n\\item First\n\\item Second\n\\end{enumerate}\n".to_string());
+        let source = "\\begin{document}\nHello \\textbf{world}.\n\\input{chapter}\n\\input{missing}\n\\end{document}";
+        let preview = typeset(source, "main.tex", &read);
+        assert_eq!(preview.diagnostics.len(), 1);
+        assert_eq!(preview.diagnostics[0].line, Some(4));
+        let (_, pages) = lay_out(source, "main.tex", &read);
+        assert!(page_text(&pages)[0].starts_with("Hello world. Included text. 1. First 2. Second"));
+
+        let mut synctex = SyncTex::new();
+        synctex.load_from_bytes(&preview.synctex).unwrap();
+        let hello = synctex.forward_sync(2, 1).unwrap();
+        let second = synctex.forward_sync(5, 2).unwrap();
+        assert_eq!(hello.page, 1);
+        assert!(second.y > hello.y);
+        assert_eq!(synctex.backward_sync(1, second.x + 2.0, second.y - 2.0).map(|n| n.line), Some(5));
+    }
+
+    #[test]
+    fn test_long_paragraphs_break_into_lines_and_pages() {
+        let paragraph = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore. ".repeat(12);
+        let source = format!("\\begin{{document}}\n{}\\end{{document}}\n", format!("{}\n\n", paragraph).repeat(12));
+        let (_, pages) = lay_out(&source, "main.tex", &no_files);
+        assert!(pages.len() > 1);
+        for mark in &pages[0].marks {
+            if let Mark::Text { x, y, font, size, text } = mark {
+                assert!(*x >= MARGIN && x + font.text_width(text, *size) <= MARGIN + TEXT_WIDTH + 0.01, "{} at {}", text, x);
+                assert!(*y <= TEXT_BOTTOM || text == "1");
+            }
+        }
+    }
+}
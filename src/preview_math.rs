@@ -0,0 +1,701 @@
+use crate::pdf_writer::{Font, Mark};
+
+/// How an atom spaces itself from its neighbours, after TeX's atom classes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Ord,
+    /// Big operators and named functions
+    Op,
+    Bin,
+    Rel,
+    Open,
+    Close,
+    Punct,
+}
+
+/// Commands drawn from the Symbol font: name, code in the font, width in thousandths of an em, class.
+const SYMBOLS: &[(&str, u8, u16, Class)] = &[
+    ("alpha", b'a', 631, Class::Ord), ("beta", b'b', 549, Class::Ord), ("gamma", b'g', 411, Class::Ord),
+    ("delta", b'd', 494, Class::Ord), ("epsilon", b'e', 439, Class::Ord), ("varepsilon", b'e', 439, Class::Ord),
+    ("zeta", b'z', 494, Class::Ord), ("eta", b'h', 603, Class::Ord), ("theta", b'q', 521, Class::Ord),
+    ("vartheta", b'J', 631, Class::Ord), ("iota", b'i', 329, Class::Ord), ("kappa", b'k', 549, Class::Ord),
+    ("lambda", b'l', 549, Class::Ord), ("mu", b'm', 576, Class::Ord), ("nu", b'n', 521, Class::Ord),
+    ("xi", b'x', 493, Class::Ord), ("pi", b'p', 549, Class::Ord), ("varpi", b'v', 713, Class::Ord),
+    ("rho", b'r', 549, Class::Ord), ("sigma", b's', 603, Class::Ord), ("varsigma", b'V', 439, Class::Ord),
+    ("tau", b't', 439, Class::Ord), ("upsilon", b'u', 576, Class::Ord), ("phi", b'f', 521, Class::Ord),
+    ("varphi", b'j', 603, Class::Ord), ("chi", b'c', 549, Class::Ord), ("psi", b'y', 686, Class::Ord),
+    ("omega", b'w', 686, Class::Ord),
+    ("Gamma", b'G', 603, Class::Ord), ("Delta", b'D', 612, Class::Ord), ("Theta", b'Q', 741, Class::Ord),
+    ("Lambda", b'L', 686, Class::Ord), ("Xi", b'X', 645, Class::Ord), ("Pi", b'P', 768, Class::Ord),
+    ("Sigma", b'S', 592, Class::Ord), ("Upsilon", 0xA1, 620, Class::Ord), ("Phi", b'F', 763, Class::Ord),
+    ("Psi", b'Y', 795, Class::Ord), ("Omega", b'W', 768, Class::Ord),
+    ("pm", 0xB1, 549, Class::Bin), ("mp", 0xB1, 549, Class::Bin), ("times", 0xB4, 549, Class::Bin),
+    ("div", 0xB8, 549, Class::Bin), ("cdot", 0xD7, 250, Class::Bin), ("circ", 0xB0, 400, Class::Bin),
+    ("bullet", 0xB7, 460, Class::Bin), ("oplus", 0xC5, 768, Class::Bin), ("otimes", 0xC4, 768, Class::Bin),
+    ("cup", 0xC8, 768, Class::Bin), ("cap", 0xC7, 768, Class::Bin), ("wedge", 0xD9, 603, Class::Bin),
+    ("land", 0xD9, 603, Class::Bin), ("vee", 0xDA, 603, Class::Bin), ("lor", 0xDA, 603, Class::Bin),
+    ("leq", 0xA3, 549, Class::Rel), ("le", 0xA3, 549, Class::Rel), ("geq", 0xB3, 549, Class::Rel),
+    ("ge", 0xB3, 549, Class::Rel), ("neq", 0xB9, 549, Class::Rel), ("ne", 0xB9, 549, Class::Rel),
+    ("approx", 0xBB, 549, Class::Rel), ("equiv", 0xBA, 549, Class::Rel), ("sim", b'~', 549, Class::Rel),
+    ("cong", b'@', 549, Class::Rel), ("simeq", b'@', 549, Class::Rel), ("propto", 0xB5, 713, Class::Rel),
+    ("in", 0xCE, 713, Class::Rel), ("notin", 0xCF, 713, Class::Rel), ("ni", b'\'', 439, Class::Rel),
+    ("subset", 0xCC, 713, Class::Rel), ("subseteq", 0xCD, 713, Class::Rel), ("supset", 0xC9, 713, Class::Rel),
+    ("supseteq", 0xCA, 713, Class::Rel), ("perp", b'^', 658, Class::Rel), ("mid", b'|', 200, Class::Rel),
+    ("to", 0xAE, 987, Class::Rel), ("rightarrow", 0xAE, 987, Class::Rel), ("mapsto", 0xAE, 987, Class::Rel),
+    ("leftarrow", 0xAC, 987, Class::Rel), ("gets", 0xAC, 987, Class::Rel), ("leftrightarrow", 0xAB, 1042, Class::Rel),
+    ("Rightarrow", 0xDE, 987, Class::Rel), ("implies", 0xDE, 987, Class::Rel), ("Leftarrow", 0xDC, 987, Class::Rel),
+    ("Leftrightarrow", 0xDB, 1042, Class::Rel), ("iff", 0xDB, 1042, Class::Rel), ("uparrow", 0xAD, 603, Class::Rel),
+    ("downarrow", 0xAF, 603, Class::Rel),
+    ("infty", 0xA5, 713, Class::Ord), ("partial", 0xB6, 494, Class::Ord), ("nabla", 0xD1, 713, Class::Ord),
+    ("forall", b'"', 713, Class::Ord), ("exists", b'$', 549, Class::Ord), ("emptyset", 0xC6, 823, Class::Ord),
+    ("varnothing", 0xC6, 823, Class::Ord), ("neg", 0xD8, 713, Class::Ord), ("lnot", 0xD8, 713, Class::Ord),
+    ("prime", 0xA2, 247, Class::Ord), ("angle", 0xD0, 768, Class::Ord), ("aleph", 0xC0, 823, Class::Ord),
+    ("Re", 0xC2, 795, Class::Ord), ("Im", 0xC1, 686, Class::Ord), ("wp", 0xC3, 987, Class::Ord),
+    ("ldots", 0xBC, 1000, Class::Ord), ("cdots", 0xBC, 1000, Class::Ord), ("dots", 0xBC, 1000, Class::Ord),
+    ("langle", 0xE1, 329, Class::Open), ("rangle", 0xF1, 329, Class::Close),
+    ("sum", 0xE5, 713, Class::Op), ("prod", 0xD5, 823, Class::Op), ("int", 0xF2, 274, Class::Op),
+    ("oint", 0xF2, 274, Class::Op), ("bigcup", 0xC8, 768, Class::Op), ("bigcap", 0xC7, 768, Class::Op),
+    ("minus", b'-', 549, Class::Bin), ("radical", 0xD6, 549, Class::Ord),
+];
+
+/// Operators typeset upright as their names.
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "log", "ln", "lg", "exp", "lim", "liminf", "limsup", "sup", "inf", "max", "min", "det", "dim",
+    "ker", "gcd", "deg", "arg", "hom", "Pr",
+];
+
+/// The width of a Symbol font code, in thousandths of an em.
+pub fn symbol_width(code: u8) -> u16 {
+    SYMBOLS.iter().find(|s| s.1 == code).map_or(549, |s| s.2)
+}
+
+fn symbol(name: &str) -> Option<(u8, Class)> {
+    SYMBOLS.iter().find(|s| s.0 == name).map(|s| (s.1, s.3))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Accent {
+    Bar,
+    Hat,
+    Tilde,
+    Vec,
+    Dot,
+    DoubleDot,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Atom {
+    Glyph { text: String, font: Font, class: Class },
+    Group(Vec<Atom>),
+    Frac(Vec<Atom>, Vec<Atom>),
+    Sqrt(Vec<Atom>),
+    Scripts { base: Box<Atom>, sup: Option<Vec<Atom>>, sub: Option<Vec<Atom>> },
+    Accent(Accent, Vec<Atom>),
+    /// `\left ... \right`: the delimiters grow with the body
+    Fenced(Option<Box<Atom>>, Vec<Atom>, Option<Box<Atom>>),
+    /// Space in ems
+    Space(f32),
+}
+
+impl Atom {
+    fn class(&self) -> Class {
+        match self {
+            Atom::Glyph { class, .. } => *class,
+            Atom::Scripts { base, .. } => base.class(),
+            _ => Class::Ord,
+        }
+    }
+
+    fn glyph(text: impl Into<String>, font: Font, class: Class) -> Self {
+        Atom::Glyph { text: text.into(), font, class }
+    }
+}
+
+/// Recursive descent over math source, which has already had macros expanded.
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    source: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self { chars: source.chars().collect(), pos: 0, source }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// The name after a backslash: letters, or a single other character.
+    fn command(&mut self) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            self.pos += 1;
+        }
+        if self.pos == start {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos.min(self.chars.len())].iter().collect()
+    }
+
+    /// A braced group as raw text, for `\text` and friends.
+    fn raw_group(&mut self) -> String {
+        self.skip_spaces();
+        if self.peek() != Some('{') {
+            return self.peek().map(|c| { self.pos += 1; c.to_string() }).unwrap_or_default();
+        }
+        self.pos += 1;
+        let start = self.pos;
+        let mut depth = 1;
+        while let Some(c) = self.peek() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                '\\' => self.pos += 1,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        let text = self.chars[start..self.pos.min(self.chars.len())].iter().collect();
+        self.pos += 1;
+        text
+    }
+
+    fn skip_optional(&mut self) {
+        self.skip_spaces();
+        if self.peek() == Some('[') {
+            while let Some(c) = self.peek() {
+                self.pos += 1;
+                if c == ']' {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// One argument: a group, a command or a character.
+    fn argument(&mut self) -> Vec<Atom> {
+        self.skip_spaces();
+        if self.peek() == Some('{') {
+            self.pos += 1;
+            return self.list(false);
+        }
+        self.atom(false).into_iter().collect()
+    }
+
+    /// Atoms up to a closing brace (consumed), `\right` (left for the caller) or the end.
+    fn list(&mut self, in_fence: bool) -> Vec<Atom> {
+        let mut atoms: Vec<Atom> = Vec::new();
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                None => break,
+                Some('}') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(c @ ('^' | '_')) => {
+                    self.pos += 1;
+                    let script = self.argument();
+                    let base = atoms.pop().unwrap_or(Atom::Group(Vec::new()));
+                    let (base, mut sup, mut sub) = match base {
+                        Atom::Scripts { base, sup, sub } => (base, sup, sub),
+                        other => (Box::new(other), None, None),
+                    };
+                    if c == '^' { sup = Some(script) } else { sub = Some(script) }
+                    atoms.push(Atom::Scripts { base, sup, sub });
+                }
+                Some('\\') if in_fence && self.source[self.byte_offset()..].starts_with("\\right") => break,
+                Some(_) => {
+                    if let Some(atom) = self.atom(in_fence) {
+                        atoms.push(atom);
+                    }
+                }
+            }
+        }
+        atoms
+    }
+
+    fn byte_offset(&self) -> usize {
+        self.chars[..self.pos].iter().map(|c| c.len_utf8()).sum()
+    }
+
+    /// A delimiter after `\left`, `\right` or `\big`; `.` is none.
+    fn delimiter(&mut self) -> Option<Box<Atom>> {
+        self.skip_spaces();
+        let c = self.peek()?;
+        self.pos += 1;
+        let atom = match c {
+            '.' => return None,
+            '\\' => match self.command().as_str() {
+                "{" | "lbrace" => Atom::glyph("{", Font::Roman, Class::Open),
+                "}" | "rbrace" => Atom::glyph("}", Font::Roman, Class::Close),
+                "|" | "Vert" => Atom::glyph("||", Font::Roman, Class::Ord),
+                name => self.named(name)?,
+            },
+            c => Atom::glyph(c.to_string(), Font::Roman, Class::Ord),
+        };
+        Some(Box::new(atom))
+    }
+
+    fn atom(&mut self, in_fence: bool) -> Option<Atom> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(match c {
+            '{' => Atom::Group(self.list(false)),
+            '\\' => {
+                let name = self.command();
+                return self.command_atom(&name, in_fence);
+            }
+            '&' => Atom::Space(1.0),
+            '~' => Atom::Space(0.33),
+            '\'' => Atom::glyph("\u{a2}", Font::Symbol, Class::Ord),
+            '-' => Atom::glyph("-", Font::Symbol, Class::Bin),
+            '+' | '*' => Atom::glyph(c.to_string(), Font::Roman, Class::Bin),
+            '=' | '<' | '>' | ':' => Atom::glyph(c.to_string(), Font::Roman, Class::Rel),
+            ',' | ';' => Atom::glyph(c.to_string(), Font::Roman, Class::Punct),
+            '(' | '[' => Atom::glyph(c.to_string(), Font::Roman, Class::Open),
+            ')' | ']' | '!' | '?' => Atom::glyph(c.to_string(), Font::Roman, Class::Close),
+            c if c.is_alphabetic() => Atom::glyph(c.to_string(), Font::Italic, Class::Ord),
+            c => Atom::glyph(c.to_string(), Font::Roman, Class::Ord),
+        })
+    }
+
+    fn command_atom(&mut self, name: &str, in_fence: bool) -> Option<Atom> {
+        let spaced = |em: f32| Some(Atom::Space(em));
+        match name {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let num = self.argument();
+                let den = self.argument();
+                Some(Atom::Frac(num, den))
+            }
+            "binom" | "dbinom" | "tbinom" => {
+                let top = self.argument();
+                let bottom = self.argument();
+                let paren = |c: &str| Some(Box::new(Atom::glyph(c, Font::Roman, Class::Ord)));
+                Some(Atom::Fenced(paren("("), vec![Atom::Frac(top, bottom)], paren(")")))
+            }
+            "sqrt" => {
+                self.skip_optional();
+                Some(Atom::Sqrt(self.argument()))
+            }
+            "text" | "textrm" | "mathrm" | "operatorname" | "mbox" | "textnormal" => Some(Atom::glyph(self.raw_group(), Font::Roman, Class::Ord)),
+            "textit" | "mathit" | "emph" => Some(Atom::glyph(self.raw_group(), Font::Italic, Class::Ord)),
+            "textbf" | "mathbf" | "boldsymbol" | "bm" | "mathbb" | "mathcal" | "mathscr" | "mathfrak" => {
+                Some(Atom::glyph(self.raw_group(), Font::Bold, Class::Ord))
+            }
+            "texttt" | "mathtt" => Some(Atom::glyph(self.raw_group(), Font::Mono, Class::Ord)),
+            "mathsf" | "textsf" => Some(Atom::glyph(self.raw_group(), Font::Roman, Class::Ord)),
+            "left" => {
+                let left = self.delimiter();
+                let body = self.list(true);
+                if self.source[self.byte_offset()..].starts_with("\\right") {
+                    self.pos += "\\right".len();
+                }
+                let right = self.delimiter();
+                Some(Atom::Fenced(left, body, right))
+            }
+            "right" if !in_fence => {
+                self.delimiter();
+                None
+            }
+            "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl" | "Bigr" | "biggl" | "biggr" | "middle" => {
+                self.delimiter().map(|d| *d)
+            }
+            "hat" | "widehat" => Some(Atom::Accent(Accent::Hat, self.argument())),
+            "bar" | "overline" => Some(Atom::Accent(Accent::Bar, self.argument())),
+            "tilde" | "widetilde" => Some(Atom::Accent(Accent::Tilde, self.argument())),
+            "vec" | "overrightarrow" => Some(Atom::Accent(Accent::Vec, self.argument())),
+            "dot" => Some(Atom::Accent(Accent::Dot, self.argument())),
+            "ddot" => Some(Atom::Accent(Accent::DoubleDot, self.argument())),
+            "underline" | "displaystyle" | "textstyle" | "scriptstyle" | "limits" | "nolimits" | "nonumber" | "notag" => None,
+            "label" | "tag" | "begin" | "end" => {
+                self.raw_group();
+                None
+            }
+            "," | "thinspace" => spaced(3.0 / 18.0),
+            ":" | ">" | "medspace" => spaced(4.0 / 18.0),
+            ";" | "thickspace" => spaced(5.0 / 18.0),
+            "!" => spaced(-3.0 / 18.0),
+            " " => spaced(0.33),
+            "quad" => spaced(1.0),
+            "qquad" => spaced(2.0),
+            "\\" | "cr" => spaced(1.0),
+            "{" | "lbrace" => Some(Atom::glyph("{", Font::Roman, Class::Open)),
+            "}" | "rbrace" => Some(Atom::glyph("}", Font::Roman, Class::Close)),
+            "|" | "Vert" => Some(Atom::glyph("||", Font::Roman, Class::Ord)),
+            "%" | "$" | "#" | "&" | "_" => Some(Atom::glyph(name, Font::Roman, Class::Ord)),
+            name => self.named(name),
+        }
+    }
+
+    /// Symbols and function names; anything else is shown by name so it is not lost silently.
+    fn named(&self, name: &str) -> Option<Atom> {
+        if let Some((code, class)) = symbol(name) {
+            return Some(Atom::glyph((code as char).to_string(), Font::Symbol, class));
+        }
+        if FUNCTIONS.contains(&name) {
+            return Some(Atom::glyph(name, Font::Roman, Class::Op));
+        }
+        match name {
+            "lfloor" | "lceil" => Some(Atom::glyph("[", Font::Roman, Class::Open)),
+            "rfloor" | "rceil" => Some(Atom::glyph("]", Font::Roman, Class::Close)),
+            "vert" => Some(Atom::glyph("|", Font::Roman, Class::Ord)),
+            "backslash" | "setminus" => Some(Atom::glyph("\\", Font::Roman, Class::Bin)),
+            "colon" => Some(Atom::glyph(":", Font::Roman, Class::Punct)),
+            "ell" => Some(Atom::glyph("l", Font::Italic, Class::Ord)),
+            "hbar" => Some(Atom::glyph("h", Font::Italic, Class::Ord)),
+            _ => Some(Atom::glyph(name, Font::Roman, Class::Ord)),
+        }
+    }
+}
+
+/// Laid out math: marks relative to the start of the baseline, with the extent above and below it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MathBox {
+    pub width: f32,
+    pub height: f32,
+    pub depth: f32,
+    pub marks: Vec<Mark>,
+}
+
+impl MathBox {
+    /// Place another box `dx` along and `dy` below this baseline.
+    fn put(&mut self, other: MathBox, dx: f32, dy: f32) {
+        self.height = self.height.max(other.height - dy);
+        self.depth = self.depth.max(other.depth + dy);
+        self.width = self.width.max(dx + other.width);
+        self.marks.extend(other.marks.into_iter().map(|m| m.translated(dx, dy)));
+    }
+
+    fn glyph(text: &str, font: Font, size: f32) -> Self {
+        Self {
+            width: font.text_width(text, size),
+            height: 0.7 * size,
+            depth: 0.2 * size,
+            marks: vec![Mark::Text { x: 0.0, y: 0.0, font, size, text: text.to_string() }],
+        }
+    }
+
+    fn rule(x: f32, y: f32, width: f32, thickness: f32) -> Mark {
+        Mark::Rule { x, y, width, height: thickness }
+    }
+}
+
+/// Space between neighbouring atoms in ems, after TeX's spacing table; scripts are set tight.
+fn spacing(left: Class, right: Class, script: bool) -> f32 {
+    use Class::*;
+    let (thin, medium, thick) = (3.0 / 18.0, 4.0 / 18.0, 5.0 / 18.0);
+    match (left, right) {
+        (Ord | Close, Op) | (Op, Ord | Op) => thin,
+        (Punct, _) if !script => thin,
+        (Bin, _) | (_, Bin) if !script => medium,
+        (Rel, _) | (_, Rel) if !script => thick,
+        _ => 0.0,
+    }
+}
+
+/// A binary operator with nothing to operate on the left is a sign, like the minus in `-x`.
+fn effective_classes(atoms: &[Atom]) -> Vec<Class> {
+    let mut classes: Vec<Class> = Vec::with_capacity(atoms.len());
+    for atom in atoms {
+        let class = match atom {
+            Atom::Space(_) => {
+                classes.push(Class::Punct);
+                continue;
+            }
+            atom => atom.class(),
+        };
+        let after_operand = matches!(classes.iter().rev().copied().next(), Some(Class::Ord | Class::Close));
+        classes.push(if class == Class::Bin && !after_operand { Class::Ord } else { class });
+    }
+    classes
+}
+
+struct Layout {
+    /// The size of ordinary symbols at the top level
+    base: f32,
+    display: bool,
+}
+
+impl Layout {
+    fn list(&self, atoms: &[Atom], size: f32) -> MathBox {
+        let script = size < self.base * 0.9;
+        let classes = effective_classes(atoms);
+        let mut out = MathBox::default();
+        let mut x = 0.0;
+        let mut previous: Option<Class> = None;
+        for (atom, class) in atoms.iter().zip(classes) {
+            if let Atom::Space(em) = atom {
+                x += em * size;
+                out.width = out.width.max(x);
+                previous = None;
+                continue;
+            }
+            if let Some(previous) = previous {
+                x += spacing(previous, class, script) * size;
+            }
+            let laid = self.atom(atom, size);
+            let width = laid.width;
+            out.put(laid, x, 0.0);
+            x += width;
+            previous = Some(class);
+        }
+        out.width = x.max(0.0);
+        out
+    }
+
+    fn atom(&self, atom: &Atom, size: f32) -> MathBox {
+        match atom {
+            Atom::Glyph { text, font, class } => {
+                // Big operators grow in displays
+                let size = if *class == Class::Op && *font == Font::Symbol && self.display && size >= self.base { size * 1.4 } else { size };
+                MathBox::glyph(text, *font, size)
+            }
+            Atom::Group(atoms) => self.list(atoms, size),
+            Atom::Space(em) => MathBox { width: em * size, ..Default::default() },
+            Atom::Frac(num, den) => self.fraction(num, den, size),
+            Atom::Sqrt(body) => self.root(body, size),
+            Atom::Scripts { base, sup, sub } => self.scripts(base, sup.as_deref(), sub.as_deref(), size),
+            Atom::Accent(accent, body) => self.accent(*accent, body, size),
+            Atom::Fenced(left, body, right) => self.fenced(left.as_deref(), body, right.as_deref(), size),
+        }
+    }
+
+    fn script_size(&self, size: f32) -> f32 {
+        (size * 0.7).max(self.base * 0.5)
+    }
+
+    fn fraction(&self, num: &[Atom], den: &[Atom], size: f32) -> MathBox {
+        let part = if self.display && size >= self.base { size } else { self.script_size(size) };
+        let (num, den) = (self.list(num, part), self.list(den, part));
+        let axis = 0.25 * size;
+        let thickness = (0.05 * size).max(0.4);
+        let gap = 0.15 * size;
+        let width = num.width.max(den.width) + 0.2 * size;
+
+        let mut out = MathBox::default();
+        let num_shift = axis + thickness / 2.0 + gap + num.depth;
+        let den_shift = -(axis - thickness / 2.0 - gap - den.height);
+        let (num_width, den_width) = (num.width, den.width);
+        out.put(num, (width - num_width) / 2.0, -num_shift);
+        out.put(den, (width - den_width) / 2.0, den_shift);
+        out.marks.push(MathBox::rule(0.0, -axis - thickness / 2.0, width, thickness));
+        out.width = width;
+        out
+    }
+
+    fn root(&self, body: &[Atom], size: f32) -> MathBox {
+        let body = self.list(body, size);
+        let pad = 0.1 * size;
+        // The Symbol radical spans about -0.05 to 0.91 of its size; scale it to cover the body
+        let radical_size = ((body.height + body.depth + 2.0 * pad) / 0.96).max(size);
+        let baseline = body.depth + pad - 0.05 * radical_size;
+        let top = baseline - 0.91 * radical_size;
+        let thickness = (0.05 * size).max(0.4);
+
+        let mut out = MathBox::glyph("\u{d6}", Font::Symbol, radical_size);
+        out.marks[0] = out.marks[0].clone().translated(0.0, baseline);
+        out.height = -top + thickness;
+        out.depth = baseline + 0.05 * radical_size;
+        let start = 0.52 * radical_size;
+        let body_width = body.width;
+        out.put(body, 0.549 * radical_size, 0.0);
+        out.marks.push(MathBox::rule(start, top, 0.549 * radical_size - start + body_width + pad, thickness));
+        out.width = 0.549 * radical_size + body_width + pad;
+        out
+    }
+
+    fn scripts(&self, base: &Atom, sup: Option<&[Atom]>, sub: Option<&[Atom]>, size: f32) -> MathBox {
+        let base_box = self.atom(base, size);
+        let small = self.script_size(size);
+        let (sup, sub) = (sup.map(|s| self.list(s, small)), sub.map(|s| self.list(s, small)));
+
+        // Limits of sums and products in displays go above and below
+        let limits = self.display && matches!(base, Atom::Glyph { class: Class::Op, font: Font::Symbol, text } if text != "\u{f2}");
+        let mut out = MathBox::default();
+        if limits {
+            let width = base_box.width.max(sup.as_ref().map_or(0.0, |s| s.width)).max(sub.as_ref().map_or(0.0, |s| s.width));
+            let (base_height, base_depth, base_width) = (base_box.height, base_box.depth, base_box.width);
+            out.put(base_box, (width - base_width) / 2.0, 0.0);
+            if let Some(sup) = sup {
+                let shift = base_height + 0.1 * size + sup.depth;
+                let w = sup.width;
+                out.put(sup, (width - w) / 2.0, -shift);
+            }
+            if let Some(sub) = sub {
+                let shift = base_depth + 0.1 * size + sub.height;
+                let w = sub.width;
+                out.put(sub, (width - w) / 2.0, shift);
+            }
+            out.width = width;
+            return out;
+        }
+
+        let base_width = base_box.width;
+        let base_height = base_box.height;
+        out.put(base_box, 0.0, 0.0);
+        let mut width = base_width;
+        if let Some(sup) = sup {
+            let shift = (0.42 * size).max(base_height - 0.3 * size);
+            width = width.max(base_width + sup.width + 0.05 * size);
+            out.put(sup, base_width + 0.05 * size, -shift);
+        }
+        if let Some(sub) = sub {
+            let shift = if out.height > base_height { 0.25 * size } else { 0.18 * size };
+            width = width.max(base_width + sub.width + 0.05 * size);
+            out.put(sub, base_width + 0.05 * size, shift);
+        }
+        out.width = width;
+        out
+    }
+
+    fn accent(&self, accent: Accent, body: &[Atom], size: f32) -> MathBox {
+        let body = self.list(body, size);
+        let (width, height) = (body.width, body.height);
+        let mut out = MathBox::default();
+        out.put(body, 0.0, 0.0);
+        let centered = |mark: MathBox, lift: f32| {
+            let w = mark.width;
+            (mark, (width - w) / 2.0, -(height + lift))
+        };
+        let (mark, dx, dy) = match accent {
+            Accent::Bar => {
+                out.marks.push(MathBox::rule(0.0, -(height + 0.1 * size), width, (0.05 * size).max(0.4)));
+                out.height = height + 0.15 * size;
+                out.width = width;
+                return out;
+            }
+            Accent::Hat => centered(MathBox::glyph("^", Font::Roman, size), -0.45 * size),
+            Accent::Tilde => centered(MathBox::glyph("~", Font::Roman, size), -0.25 * size),
+            Accent::Vec => centered(MathBox::glyph("\u{ae}", Font::Symbol, size * 0.6), 0.05 * size),
+            Accent::Dot => centered(MathBox::glyph(".", Font::Roman, size), 0.05 * size),
+            Accent::DoubleDot => centered(MathBox::glyph("..", Font::Roman, size), 0.05 * size),
+        };
+        out.put(mark, dx, dy);
+        out.height = height + 0.3 * size;
+        out.width = width;
+        out
+    }
+
+    fn fenced(&self, left: Option<&Atom>, body: &[Atom], right: Option<&Atom>, size: f32) -> MathBox {
+        let body = self.list(body, size);
+        // Delimiters span about -0.18 to 0.69 of their size; center them on the body
+        let delimiter_size = ((body.height + body.depth) * 1.05).max(size);
+        let middle = (body.height - body.depth) / 2.0;
+        let lift = middle - 0.255 * delimiter_size;
+        let delimiter = |atom: Option<&Atom>| match atom {
+            Some(Atom::Glyph { text, font, .. }) => {
+                let mut laid = MathBox::glyph(text, *font, delimiter_size);
+                laid.height = 0.69 * delimiter_size;
+                laid.depth = 0.18 * delimiter_size;
+                Some(laid)
+            }
+            _ => None,
+        };
+
+        let mut out = MathBox::default();
+        let mut x = 0.0;
+        if let Some(left) = delimiter(left) {
+            let w = left.width;
+            out.put(left, 0.0, -lift);
+            x += w + 0.05 * size;
+        }
+        let body_width = body.width;
+        out.put(body, x, 0.0);
+        x += body_width;
+        if let Some(right) = delimiter(right) {
+            let w = right.width;
+            out.put(right, x + 0.05 * size, -lift);
+            x += w + 0.05 * size;
+        }
+        out.width = x;
+        out
+    }
+}
+
+/// Lay out math source at a size, as a display (big operators, limits above and below) or in a line of text.
+pub fn layout(source: &str, size: f32, display: bool) -> MathBox {
+    let atoms = Parser::new(source).list(false);
+    let laid = Layout { base: size, display }.list(&atoms, size);
+    // Empty math still takes the height of a line
+    MathBox { height: laid.height.max(0.7 * size), depth: laid.depth.max(0.2 * size), ..laid }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(laid: &MathBox) -> Vec<(String, Font)> {
+        laid.marks.iter().filter_map(|m| match m {
+            Mark::Text { text, font, .. } => Some((text.clone(), *font)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn test_symbols_fonts_and_spacing() {
+        let laid = layout(r"\alpha \leq x_1 - \sin y", 10.0, false);
+        assert_eq!(texts(&laid), vec![
+            ("a".to_string(), Font::Symbol),
+            ("\u{a3}".to_string(), Font::Symbol),
+            ("x".to_string(), Font::Italic),
+            ("1".to_string(), Font::Roman),
+            ("-".to_string(), Font::Symbol),
+            ("sin".to_string(), Font::Roman),
+            ("y".to_string(), Font::Italic),
+        ]);
+        // The subscript is smaller and below the baseline
+        let sub = laid.marks.iter().find_map(|m| match m {
+            Mark::Text { text, size, y, .. } if text == "1" => Some((*size, *y)),
+            _ => None,
+        }).unwrap();
+        assert!(sub.0 < 10.0 && sub.1 > 0.0);
+
+        // A leading minus is a sign and is not spaced like a binary operator
+        let sign = layout("-x", 10.0, false);
+        let binary = layout("y-x", 10.0, false);
+        let y_width = Font::Italic.text_width("y", 10.0);
+        assert!((binary.width - y_width - sign.width - 2.0 * 10.0 * 4.0 / 18.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_fraction_stacks_around_the_bar() {
+        let laid = layout(r"\frac{a+b}{2}", 10.0, true);
+        let (num_y, den_y) = laid.marks.iter().fold((0.0, 0.0), |(n, d), m| match m {
+            Mark::Text { text, y, .. } if text == "a" => (*y, d),
+            Mark::Text { text, y, .. } if text == "2" => (n, *y),
+            _ => (n, d),
+        });
+        let bar_y = laid.marks.iter().find_map(|m| match m {
+            Mark::Rule { y, .. } => Some(*y),
+            _ => None,
+        }).unwrap();
+        assert!(num_y < bar_y && bar_y < den_y);
+        assert!(laid.height > 10.0 && laid.depth > 5.0);
+    }
+
+    #[test]
+    fn test_unknown_commands_and_fences_degrade_gracefully() {
+        let laid = layout(r"\left( \frac{1}{x} \right) + \foo", 10.0, false);
+        let found = texts(&laid);
+        assert_eq!(found.first().map(|t| t.0.as_str()), Some("("));
+        assert!(found.iter().any(|t| t.0 == ")"));
+        assert_eq!(found.last().map(|t| t.0.as_str()), Some("foo"));
+        // Unbalanced input still lays out
+        assert!(layout(r"\frac{1}{", 10.0, false).width > 0.0);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::config::{CompileBackend, TexEngine};
+use crate::diagnostics::{Diagnostic, Severity};
+use regex::Regex;
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Raw output lines the build log keeps; older ones scroll away.
+const LOG_LINES: usize = 2000;
+/// latexmk gives up after this many runs of a rule (its `$max_repeat`).
+const LATEXMK_MAX_PASSES: u32 = 5;
+
+static RUN_RULE_REGEX: OnceLock<Regex> = OnceLock::new();
+static TECTONIC_RUN_REGEX: OnceLock<Regex> = OnceLock::new();
+static OUTPUT_WRITTEN_REGEX: OnceLock<Regex> = OnceLock::new();
+static SHIPOUT_REGEX: OnceLock<Regex> = OnceLock::new();
+
+/// What the compiler daemon is doing, published as it happens.
+#[derive(Debug, Clone)]
+pub enum BuildEvent {
+    /// A request arrived and supersedes whatever was building
+    Queued,
+    Started { backend: CompileBackend, engine: TexEngine },
+    /// Engine run `current`, of at most `total` when the driver has a limit
+    Pass { current: u32, total: Option<u32> },
+    /// A helper such as bibtex, biber or makeindex is running
+    Tool(String),
+    /// Pages shipped out so far in this pass
+    Pages(u32),
+    /// A raw line of tool output
+    Log(String),
+    Finished { duration: Duration },
+    Failed { duration: Duration, diagnostics: Vec<Diagnostic> },
+}
+
+/// Reads latexmk, TeX and Tectonic output line by line and picks out the progress in it.
+#[derive(Debug, Default)]
+pub struct OutputParser {
+    /// Tectonic announces its TeX runs without numbering them
+    tex_runs: u32,
+}
+
+impl OutputParser {
+    pub fn parse(&mut self, line: &str) -> Option<BuildEvent> {
+        let run_rule = RUN_RULE_REGEX.get_or_init(|| Regex::new(r"Run number (\d+) of rule '([^']+)'").unwrap());
+        if let Some(caps) = run_rule.captures(line) {
+            let rule = caps[2].split_whitespace().next().unwrap_or_default();
+            return Some(if is_engine(rule) {
+                BuildEvent::Pass { current: caps[1].parse().unwrap_or(1), total: Some(LATEXMK_MAX_PASSES) }
+            } else {
+                BuildEvent::Tool(rule.to_string())
+            });
+        }
+
+        let tectonic_run = TECTONIC_RUN_REGEX.get_or_init(|| Regex::new(r"^note: (?:Re)?[Rr]unning (\S+)").unwrap());
+        if let Some(caps) = tectonic_run.captures(line) {
+            let program = caps[1].to_ascii_lowercase();
+            if program == "tex" {
+                self.tex_runs += 1;
+                return Some(BuildEvent::Pass { current: self.tex_runs, total: None });
+            }
+            return Some(BuildEvent::Tool(program));
+        }
+
+        let output_written = OUTPUT_WRITTEN_REGEX.get_or_init(|| Regex::new(r"^Output written on .*\((\d+) pages?").unwrap());
+        if let Some(caps) = output_written.captures(line) {
+            return caps[1].parse().ok().map(BuildEvent::Pages);
+        }
+
+        // TeX prints `[1] [2{pdftex.map}] [3]` as it ships pages out
+        let shipout = SHIPOUT_REGEX.get_or_init(|| Regex::new(r"(?:^|[\s\]])\[(\d+)(?:[\]{<\s]|$)").unwrap());
+        shipout.captures_iter(line).filter_map(|caps| caps[1].parse().ok()).max().map(BuildEvent::Pages)
+    }
+}
+
+fn is_engine(rule: &str) -> bool {
+    matches!(rule, "latex" | "pdflatex" | "xelatex" | "lualatex" | "dvilualatex")
+}
+
+/// Where a build stands, as the top bar shows it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BuildState {
+    #[default]
+    Idle,
+    Queued,
+    Running,
+    Finished(Duration),
+    Failed { errors: usize },
+}
+
+/// The current build and its raw output, fed by `BuildEvent`s.
+#[derive(Debug, Default)]
+pub struct BuildLog {
+    pub lines: VecDeque<String>,
+    pub state: BuildState,
+    pub backend: Option<(CompileBackend, TexEngine)>,
+    pub pass: Option<(u32, Option<u32>)>,
+    pub tool: Option<String>,
+    pub pages: Option<u32>,
+}
+
+impl BuildLog {
+    pub fn apply(&mut self, event: BuildEvent) {
+        match event {
+            BuildEvent::Queued => self.state = BuildState::Queued,
+            BuildEvent::Started { backend, engine } => {
+                *self = Self { backend: Some((backend, engine)), state: BuildState::Running, ..Self::default() };
+            }
+            BuildEvent::Pass { current, total } => {
+                self.pass = Some((current, total));
+                self.tool = None;
+            }
+            BuildEvent::Tool(tool) => self.tool = Some(tool),
+            BuildEvent::Pages(pages) => self.pages = Some(pages),
+            BuildEvent::Log(line) => self.push(line),
+            BuildEvent::Finished { duration } => {
+                self.state = BuildState::Finished(duration);
+                self.tool = None;
+            }
+            BuildEvent::Failed { duration, diagnostics } => {
+                let errors: Vec<&Diagnostic> = diagnostics.iter().filter(|d| d.severity == Severity::Error).collect();
+                self.push(format!("Build failed after {:.2}s", duration.as_secs_f32()));
+                for error in &errors {
+                    let location = error.location();
+                    self.push(if location.is_empty() { format!("! {}", error.message) } else { format!("! {}: {}", location, error.message) });
+                }
+                self.state = BuildState::Failed { errors: errors.len() };
+                self.tool = None;
+            }
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.lines.len() == LOG_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    /// One line for the top bar, e.g. `Latexmk · LuaLaTeX · pass 2/5 · biber · 12 pages`.
+    pub fn status(&self) -> String {
+        let pages = self.pages.map(|p| format!("{} page{}", p, if p == 1 { "" } else { "s" }));
+        match self.state {
+            BuildState::Idle => "Idle".to_string(),
+            BuildState::Queued => "Queued".to_string(),
+            BuildState::Running => {
+                let mut parts = Vec::new();
+                if let Some((backend, engine)) = self.backend {
+                    parts.push(format!("{:?}", backend));
+                    parts.push(engine.label().to_string());
+                }
+                match self.pass {
+                    Some((current, Some(total))) => parts.push(format!("pass {}/{}", current, total)),
+                    Some((current, None)) => parts.push(format!("pass {}", current)),
+                    None => {}
+                }
+                parts.extend(self.tool.clone());
+                parts.extend(pages);
+                if parts.is_empty() { "Building".to_string() } else { parts.join(" · ") }
+            }
+            BuildState::Finished(duration) => match pages {
+                Some(pages) => format!("Built in {:.2}s · {}", duration.as_secs_f32(), pages),
+                None => format!("Built in {:.2}s", duration.as_secs_f32()),
+            },
+            BuildState::Failed { errors } => format!("Failed: {} error{}", errors, if errors == 1 { "" } else { "s" }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostics::DiagnosticKind;
+
+    fn parse_all(lines: &[&str]) -> Vec<String> {
+        let mut parser = OutputParser::default();
+        lines.iter().filter_map(|line| parser.parse(line)).map(|event| format!("{:?}", event)).collect()
+    }
+
+    #[test]
+    fn test_parse_progress_lines() {
+        let latexmk = parse_all(&[
+            "Latexmk: Run number 1 of rule 'lualatex'",
+            "[1{/usr/share/texmf/fonts/map/pdftex/updmap/pdftex.map}] [2] [3]",
+            "Run number 1 of rule 'biber main'",
+            "Output written on main.pdf (12 pages, 80211 bytes).",
+            "Overfull \\hbox (1.5pt too wide) in paragraph at lines 3--4 [] []",
+        ]);
+        assert_eq!(latexmk, vec![
+            "Pass { current: 1, total: Some(5) }",
+            "Pages(3)",
+            "Tool(\"biber\")",
+            "Pages(12)",
+        ]);
+
+        let tectonic = parse_all(&["note: Running TeX ...", "note: Running BibTeX ...", "note: Rerunning TeX because \"main.aux\" changed ...", "note: Running xdvipdfmx ..."]);
+        assert_eq!(tectonic, vec![
+            "Pass { current: 1, total: None }",
+            "Tool(\"bibtex\")",
+            "Pass { current: 2, total: None }",
+            "Tool(\"xdvipdfmx\")",
+        ]);
+    }
+
+    #[test]
+    fn test_build_log_status() {
+        let mut log = BuildLog::default();
+        log.apply(BuildEvent::Log("stale".to_string()));
+        log.apply(BuildEvent::Queued);
+        assert_eq!(log.status(), "Queued");
+
+        log.apply(BuildEvent::Started { backend: CompileBackend::Latexmk, engine: TexEngine::LuaLatex });
+        assert!(log.lines.is_empty());
+        log.apply(BuildEvent::Pass { current: 2, total: Some(5) });
+        log.apply(BuildEvent::Tool("biber".to_string()));
+        log.apply(BuildEvent::Pages(12));
+        assert_eq!(log.status(), "Latexmk · LuaLaTeX · pass 2/5 · biber · 12 pages");
+
+        log.apply(BuildEvent::Finished { duration: Duration::from_millis(1250) });
+        assert_eq!(log.status(), "Built in 1.25s · 12 pages");
+
+        let error = Diagnostic {
+            severity: Severity::Error,
+            kind: DiagnosticKind::TexError,
+            file: Some("main.tex".to_string()),
+            line: Some(3),
+            message: "Undefined control sequence".to_string(),
+        };
+        log.apply(BuildEvent::Failed { duration: Duration::from_secs(2), diagnostics: vec![error] });
+        assert_eq!(log.status(), "Failed: 1 error");
+        assert_eq!(log.lines.back().map(String::as_str), Some("! main.tex:3: Undefined control sequence"));
+    }
+}
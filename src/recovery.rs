@@ -0,0 +1,190 @@
+use crate::io::{state_file_name, state_path, STATE_DIR};
+use ahash::AHashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const AREA: &str = "recovery";
+/// Unsaved text is copied to the recovery directory at most this often.
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(2);
+/// Above this many line pairs the diff falls back to "everything changed".
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// A recovery copy left behind by a session that did not save, newer than the file on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovery {
+    pub file_name: String,
+    pub recovered: String,
+    pub on_disk: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Copies of unsaved buffers under `<root>/.sokutex/recovery`, one per file.
+pub struct RecoveryStore {
+    root_dir: String,
+    pending: AHashMap<String, String>,
+    last_flush: Option<Instant>,
+}
+
+impl RecoveryStore {
+    pub fn new(root_dir: &str) -> Self {
+        Self {
+            root_dir: root_dir.to_string(),
+            pending: AHashMap::new(),
+            last_flush: None,
+        }
+    }
+
+    fn dir(&self) -> PathBuf {
+        Path::new(&self.root_dir).join(STATE_DIR).join(AREA)
+    }
+
+    /// Queue the latest unsaved text of a file for the next write.
+    pub fn note_change(&mut self, file_name: &str, text: &str) {
+        self.pending.insert(file_name.to_string(), text.to_string());
+    }
+
+    /// The recovery writes due now, throttled to one batch per `AUTOSAVE_INTERVAL`.
+    /// The caller writes them (atomically) before anything else can `discard` them, or a stale copy comes back.
+    pub fn take_due(&mut self) -> Vec<(PathBuf, String)> {
+        if self.pending.is_empty() || self.last_flush.is_some_and(|t| t.elapsed() < AUTOSAVE_INTERVAL) {
+            return Vec::new();
+        }
+        self.last_flush = Some(Instant::now());
+        self.pending.drain()
+            .map(|(file, text)| (state_path(&self.root_dir, AREA, &file), text))
+            .collect()
+    }
+
+    /// Forget a file's recovery copy, once it is saved or its changes were thrown away.
+    pub fn discard(&mut self, file_name: &str) {
+        self.pending.remove(file_name);
+        let _ = std::fs::remove_file(state_path(&self.root_dir, AREA, file_name));
+    }
+
+    /// Recovery copies worth offering on startup. Copies that match the disk or are older than it are stale and removed.
+    pub fn scan(&self) -> Vec<Recovery> {
+        let Ok(entries) = std::fs::read_dir(self.dir()) else {
+            return Vec::new();
+        };
+
+        let mut found = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Leftover temp files from an interrupted write
+            if name.starts_with('.') {
+                let _ = std::fs::remove_file(entry.path());
+                continue;
+            }
+            let Ok(recovered) = std::fs::read(entry.path()) else { continue };
+            let file_name = state_file_name(&name);
+            let disk_path = Path::new(&self.root_dir).join(&file_name);
+            let on_disk = std::fs::read(&disk_path).unwrap_or_default();
+
+            let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+            let outdated = match (modified(&entry.path()), modified(&disk_path)) {
+                (Some(copy), Some(disk)) => copy < disk,
+                _ => false,
+            };
+            if recovered == on_disk || outdated {
+                let _ = std::fs::remove_file(entry.path());
+                continue;
+            }
+            found.push(Recovery {
+                file_name,
+                recovered: String::from_utf8_lossy(&recovered).to_string(),
+                on_disk: String::from_utf8_lossy(&on_disk).to_string(),
+            });
+        }
+        found.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        found
+    }
+}
+
+/// Line diff from `old` to `new` (longest common subsequence after trimming the shared head and tail).
+pub fn line_diff(old: &str, new: &str) -> Vec<DiffLine> {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+    let head = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let tail = a[head..].iter().rev().zip(b[head..].iter().rev()).take_while(|(x, y)| x == y).count();
+    let (mid_a, mid_b) = (&a[head..a.len() - tail], &b[head..b.len() - tail]);
+
+    let mut diff: Vec<DiffLine> = a[..head].iter().map(|l| DiffLine::Same(l.to_string())).collect();
+    if mid_a.len() * mid_b.len() > MAX_DIFF_CELLS {
+        diff.extend(mid_a.iter().map(|l| DiffLine::Removed(l.to_string())));
+        diff.extend(mid_b.iter().map(|l| DiffLine::Added(l.to_string())));
+    } else {
+        // lcs[i][j]: common lines of mid_a[i..] and mid_b[j..]
+        let (n, m) = (mid_a.len(), mid_b.len());
+        let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i][j] = if mid_a[i] == mid_b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && mid_a[i] == mid_b[j] {
+                diff.push(DiffLine::Same(mid_a[i].to_string()));
+                i += 1;
+                j += 1;
+            } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+                diff.push(DiffLine::Removed(mid_a[i].to_string()));
+                i += 1;
+            } else {
+                diff.push(DiffLine::Added(mid_b[j].to_string()));
+                j += 1;
+            }
+        }
+    }
+    diff.extend(a[a.len() - tail..].iter().map(|l| DiffLine::Same(l.to_string())));
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_scan_and_diff() {
+        let dir = std::env::temp_dir().join(format!("sokutex_recovery_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("chapters")).unwrap();
+        std::fs::write(dir.join("main.tex"), "a\nb\nc\n").unwrap();
+        std::fs::write(dir.join("chapters/intro.tex"), "same").unwrap();
+        let root = dir.to_str().unwrap();
+
+        let mut store = RecoveryStore::new(root);
+        store.note_change("main.tex", "a\nB\nc\nd\n");
+        store.note_change("chapters/intro.tex", "same");
+        let writes = store.take_due();
+        assert_eq!(writes.len(), 2);
+        // Throttled until the interval passes
+        store.note_change("main.tex", "newer");
+        assert!(store.take_due().is_empty());
+        for (path, text) in writes {
+            crate::io::IoHandler::write_atomic_sync(&path, text.as_bytes()).unwrap();
+        }
+
+        // The unchanged copy is dropped, the other one offered
+        let found = store.scan();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].file_name, "main.tex");
+        assert_eq!(line_diff(&found[0].on_disk, &found[0].recovered), vec![
+            DiffLine::Same("a".into()),
+            DiffLine::Removed("b".into()),
+            DiffLine::Added("B".into()),
+            DiffLine::Same("c".into()),
+            DiffLine::Added("d".into()),
+        ]);
+        assert!(!state_path(root, AREA, "chapters/intro.tex").exists());
+
+        store.discard("main.tex");
+        assert!(store.scan().is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
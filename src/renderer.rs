@@ -1,12 +1,138 @@
 use winit::window::Window;
 use bytemuck::{Pod, Zeroable};
+use ahash::AHashMap;
+use crate::pdf_renderer::{PageChanges, Quality, RenderedTile, TileKey, TileRequest, TILE_SIZE};
+use crate::viewer::PdfFilter;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
 pub struct PdfUniforms {
     pub transform: [[f32; 4]; 4],
+    black: [f32; 4],
+    white: [f32; 4],
+    mode: u32,
+    _padding: [u32; 3],
 }
 
+impl PdfUniforms {
+    fn set_filter(&mut self, filter: PdfFilter) {
+        let (mode, black, white) = filter.shader_params();
+        self.mode = mode;
+        self.black = [black[0], black[1], black[2], 1.0];
+        self.white = [white[0], white[1], white[2], 1.0];
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+struct PdfVertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+}
+
+/// What the PDF pane shows in a frame, in egui points: the pane and the pages on it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PdfScene {
+    pub clip: egui::Rect,
+    /// Screen points per PDF point
+    pub zoom: f32,
+    pub pages: Vec<(usize, egui::Rect)>,
+    pub filter: PdfFilter,
+}
+
+impl Default for PdfScene {
+    fn default() -> Self {
+        Self { clip: egui::Rect::NOTHING, zoom: 1.0, pages: Vec::new(), filter: PdfFilter::None }
+    }
+}
+
+/// A tile's place on the grid regardless of revision; a newer build of a tile takes over its slot.
+type TilePosition = (usize, i32, u16, u16);
+
+/// A rendered tile held in the atlas.
+struct AtlasSlot {
+    key: TileKey,
+    quality: Quality,
+    width: u32,
+    height: u32,
+    /// The part of the page it covers, in PDF points
+    rect: crate::pdf_renderer::Rect,
+    /// The last frame it was drawn at the zoom level in use
+    last_used: u64,
+}
+
+/// One texture holding every uploaded tile in a grid of `TILE_SIZE` slots.
+struct TileAtlas {
+    texture: wgpu::Texture,
+    bind_group: wgpu::BindGroup,
+    slots: Vec<Option<AtlasSlot>>,
+    index: AHashMap<TilePosition, usize>,
+    frame: u64,
+}
+
+impl TileAtlas {
+    /// Top left corner of a slot in the texture.
+    fn origin(slot: usize) -> (u32, u32) {
+        let per_row = (ATLAS_SIZE / TILE_SIZE) as usize;
+        ((slot % per_row) as u32 * TILE_SIZE, (slot / per_row) as u32 * TILE_SIZE)
+    }
+
+    /// The tile's own slot, a free one, or the one least recently drawn if it is not on screen.
+    fn slot_for(&mut self, position: TilePosition) -> Option<usize> {
+        if let Some(&slot) = self.index.get(&position) {
+            return Some(slot);
+        }
+        let slot = self.slots.iter().position(Option::is_none).or_else(|| {
+            self.slots.iter().enumerate()
+                .filter_map(|(i, slot)| slot.as_ref().map(|s| (i, s.last_used)))
+                .filter(|(_, last_used)| *last_used < self.frame)
+                .min_by_key(|(_, last_used)| *last_used)
+                .map(|(i, _)| i)
+        })?;
+        if let Some(old) = self.slots[slot].take() {
+            self.index.remove(&(old.key.page, old.key.level, old.key.x, old.key.y));
+        }
+        self.index.insert(position, slot);
+        Some(slot)
+    }
+}
+
+/// Side of the tile atlas texture, in pixels.
+const ATLAS_SIZE: u32 = 4096;
+
+/// Room for this many page quads before the vertex buffer grows.
+const INITIAL_QUADS: usize = 16;
+
+/// The tile grid over the scene's pages, a screen above and below the pane: (position, tile rect in pixels).
+fn scene_tiles(scene: &PdfScene, pixels_per_point: f32) -> Vec<(TilePosition, egui::Rect)> {
+    let to_pixels = |r: egui::Rect| egui::Rect::from_min_max((r.min.to_vec2() * pixels_per_point).to_pos2(), (r.max.to_vec2() * pixels_per_point).to_pos2());
+    let clip = to_pixels(scene.clip);
+    let area = clip.expand2(egui::vec2(0.0, clip.height()));
+    let scale = scene.zoom * pixels_per_point;
+    let level = TileKey::level_for(scale);
+    // Grid tiles are resampled to the exact zoom on screen
+    let step = TILE_SIZE as f32 * scale / TileKey::scale(level);
+
+    let mut tiles = Vec::new();
+    for (page, rect) in &scene.pages {
+        let page_rect = to_pixels(*rect);
+        let shown = page_rect.intersect(area);
+        if !shown.is_positive() {
+            continue;
+        }
+        let size = rect.size() / scene.zoom;
+        let (columns, rows) = TileKey::grid((size.x, size.y), level);
+        let first = (shown.min - page_rect.min) / step;
+        let last = (shown.max - page_rect.min) / step;
+        for y in (first.y.floor() as u16)..(last.y.ceil() as u16).min(rows) {
+            for x in (first.x.floor() as u16)..(last.x.ceil() as u16).min(columns) {
+                let min = page_rect.min + egui::vec2(x as f32, y as f32) * step;
+                tiles.push(((*page, level, x, y), egui::Rect::from_min_size(min, egui::vec2(step, step))));
+            }
+        }
+    }
+    tiles
+}
 
 pub struct State<'a> {
     surface: wgpu::Surface<'a>,
@@ -14,23 +140,21 @@ pub struct State<'a> {
     pub queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
-    #[allow(dead_code)]
     render_pipeline: wgpu::RenderPipeline,
+    /// Plain white, for pages that are not rasterized yet
     bind_group: wgpu::BindGroup,
-    #[allow(dead_code)]
-    texture_bind_group_layout: wgpu::BindGroupLayout, 
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
     app_config: crate::config::Config,
     
     // Egui
     pub egui_ctx: egui::Context,
     pub egui_winit: egui_winit::State,
     pub egui_renderer: egui_wgpu::Renderer,
-    pub pdf_texture_id: Option<egui::TextureId>,
-    #[allow(dead_code)]
-    pdf_view: Option<wgpu::TextureView>,
-    pdf_texture: Option<wgpu::Texture>,
 
-    // Tile-based rendering and custom shader
+    // PDF pages, drawn in tiles with the custom shader underneath egui
+    atlas: TileAtlas,
+    vertex_buffer: wgpu::Buffer,
     pub uniforms: PdfUniforms,
     pub uniform_buffer: wgpu::Buffer,
     pub uniform_bind_group: wgpu::BindGroup,
@@ -96,14 +220,16 @@ impl<'a> State<'a> {
         });
 
         // Create uniform buffer
-        let uniforms = PdfUniforms {
+        let mut uniforms = PdfUniforms {
             transform: [
                 [1.0, 0.0, 0.0, 0.0],
                 [0.0, 1.0, 0.0, 0.0],
                 [0.0, 0.0, 1.0, 0.0],
                 [0.0, 0.0, 0.0, 1.0],
             ],
+            ..Zeroable::zeroed()
         };
+        uniforms.set_filter(PdfFilter::None);
         let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Uniform Buffer"),
             size: std::mem::size_of::<PdfUniforms>() as u64,
@@ -177,7 +303,11 @@ impl<'a> State<'a> {
             vertex: wgpu::VertexState {
                 module: &shader,
                 entry_point: "vs_main",
-                buffers: &[], // Trigger via vertex_index
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: std::mem::size_of::<PdfVertex>() as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2],
+                }],
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader,
@@ -192,7 +322,7 @@ impl<'a> State<'a> {
                 topology: wgpu::PrimitiveTopology::TriangleList,
                 strip_index_format: None,
                 front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
+                cull_mode: None,
                 polygon_mode: wgpu::PolygonMode::Fill,
                 unclipped_depth: false,
                 conservative: false,
@@ -265,6 +395,45 @@ impl<'a> State<'a> {
             label: Some("diffuse_bind_group"),
         });
 
+        // Every tile goes into one atlas, so all tiles draw with a single bind group
+        let atlas_texture = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: ATLAS_SIZE,
+                height: ATLAS_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Bgra8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: Some("pdf_tile_atlas"),
+            view_formats: &[],
+        });
+        let atlas_view = atlas_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let atlas_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &texture_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&atlas_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("pdf_tile_atlas_bind_group"),
+        });
+        let slot_count = ((ATLAS_SIZE / TILE_SIZE) * (ATLAS_SIZE / TILE_SIZE)) as usize;
+        let atlas = TileAtlas {
+            texture: atlas_texture,
+            bind_group: atlas_bind_group,
+            slots: (0..slot_count).map(|_| None).collect(),
+            index: AHashMap::new(),
+            frame: 0,
+        };
+
         // Egui setup
         let egui_ctx = egui::Context::default();
         let egui_winit = egui_winit::State::new(
@@ -276,6 +445,7 @@ impl<'a> State<'a> {
         );
 
         let egui_renderer = egui_wgpu::Renderer::new(&device, config.format, None, 1);
+        let vertex_buffer = Self::create_vertex_buffer(&device, INITIAL_QUADS);
 
         Self {
             surface,
@@ -286,13 +456,13 @@ impl<'a> State<'a> {
             render_pipeline,
             bind_group,
             texture_bind_group_layout,
+            sampler,
             app_config: crate::config::Config::default(),
             egui_ctx,
             egui_winit,
             egui_renderer,
-            pdf_texture_id: None,
-            pdf_view: None,
-            pdf_texture: None,
+            atlas,
+            vertex_buffer,
             uniforms,
             uniform_buffer,
             uniform_bind_group,
@@ -312,87 +482,118 @@ impl<'a> State<'a> {
         self.egui_winit.on_window_event(window, event)
     }
     
-    // Helper to update texture from RGBA bytes
-    pub fn update_texture_region(&mut self, x: u32, y: u32, width: u32, height: u32, data: &[u8]) {
-        if let Some(ref texture) = self.pdf_texture {
-            let size = wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            };
-
-            self.queue.write_texture(
-                wgpu::ImageCopyTexture {
-                    texture: texture,
-                    mip_level: 0,
-                    origin: wgpu::Origin3d { x, y, z: 0 },
-                    aspect: wgpu::TextureAspect::All,
-                },
-                data,
-                wgpu::ImageDataLayout {
-                    offset: 0,
-                    bytes_per_row: Some(4 * width),
-                    rows_per_image: Some(height),
-                },
-                size,
-            );
-        }
+    fn create_vertex_buffer(device: &wgpu::Device, quads: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("pdf_vertex_buffer"),
+            size: (quads * 6 * std::mem::size_of::<PdfVertex>()) as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
     }
 
-    pub fn update_texture(&mut self, width: u32, height: u32, data: &[u8]) {
-        let create_new = match &self.pdf_texture {
-            Some(tex) => tex.width() != width || tex.height() != height,
-            None => true,
+    /// What the viewer wants rendered this frame: the pane in pixels, and the tiles around it with the quality on hand.
+    pub fn tile_requests(&self, scene: &PdfScene, revision: u64, pixels_per_point: f32) -> (crate::pdf_renderer::Rect, Vec<TileRequest>) {
+        let clip = scene.clip;
+        let viewport = crate::pdf_renderer::Rect {
+            x: clip.min.x * pixels_per_point,
+            y: clip.min.y * pixels_per_point,
+            width: clip.width() * pixels_per_point,
+            height: clip.height() * pixels_per_point,
         };
+        let requests = scene_tiles(scene, pixels_per_point).into_iter().map(|((page, level, x, y), rect)| {
+            let key = TileKey { revision, page, level, x, y };
+            let have = self.atlas.index.get(&(page, level, x, y))
+                .and_then(|&slot| self.atlas.slots[slot].as_ref())
+                .filter(|slot| slot.key.revision == revision)
+                .map(|slot| slot.quality);
+            TileRequest {
+                key,
+                rect: crate::pdf_renderer::Rect { x: rect.min.x, y: rect.min.y, width: rect.width(), height: rect.height() },
+                have,
+            }
+        }).collect();
+        (viewport, requests)
+    }
 
-        if create_new {
-            let texture_size = wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            };
-            let texture = self.device.create_texture(&wgpu::TextureDescriptor {
-                size: texture_size,
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Bgra8Unorm,
-                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-                label: Some("pdf_texture"),
-                view_formats: &[],
-            });
-
-            let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-            let sampler = self.device.create_sampler(&wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Linear,
-                mipmap_filter: wgpu::FilterMode::Linear,
-                ..Default::default()
-            });
+    /// Put a finished tile into the atlas, unless a newer or sharper one already took its place.
+    pub fn upload_tile(&mut self, tile: &RenderedTile) {
+        let key = tile.key;
+        let position = (key.page, key.level, key.x, key.y);
+        if let Some(existing) = self.atlas.index.get(&position).and_then(|&slot| self.atlas.slots[slot].as_ref()) {
+            if (existing.key.revision, existing.quality) > (key.revision, tile.quality) {
+                return;
+            }
+        }
+        let Some(slot) = self.atlas.slot_for(position) else {
+            return;
+        };
+        let last_used = self.atlas.slots[slot].as_ref().map_or(self.atlas.frame, |s| s.last_used);
+        self.atlas.slots[slot] = Some(AtlasSlot {
+            key,
+            quality: tile.quality,
+            width: tile.width,
+            height: tile.height,
+            rect: tile.rect,
+            last_used,
+        });
+        let (x, y) = TileAtlas::origin(slot);
+        self.update_texture_region(x, y, tile.width, tile.height, &tile.pixels);
+    }
 
-            self.bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &self.texture_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&texture_view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&sampler),
-                    },
-                ],
-                label: Some("pdf_bind_group"),
-            });
+    /// Relabel the tiles of pages a rebuild left alone as tiles of the new build.
+    pub fn carry_tiles_forward(&mut self, revision: u64, changes: &PageChanges) {
+        let moves: AHashMap<usize, usize> = changes.carried.iter().copied().collect();
+        for tile in self.atlas.slots.iter_mut().flatten() {
+            if tile.key.revision == changes.previous {
+                if let Some(&page) = moves.get(&tile.key.page) {
+                    tile.key = TileKey { revision, page, ..tile.key };
+                }
+            }
+        }
 
-            self.pdf_view = Some(texture_view);
-            self.pdf_texture = Some(texture);
+        // Shifted pages can land where other tiles are; the newest, sharpest tile keeps the position
+        self.atlas.index.clear();
+        for slot in 0..self.atlas.slots.len() {
+            let Some(tile) = self.atlas.slots[slot].as_ref() else { continue };
+            let position = (tile.key.page, tile.key.level, tile.key.x, tile.key.y);
+            let rank = Some((tile.key.revision, tile.quality));
+            let held = self.atlas.index.get(&position).copied();
+            let held_rank = held.and_then(|other| self.atlas.slots[other].as_ref()).map(|t| (t.key.revision, t.quality));
+            match held {
+                Some(_) if held_rank >= rank => self.atlas.slots[slot] = None,
+                _ => {
+                    if let Some(other) = held {
+                        self.atlas.slots[other] = None;
+                    }
+                    self.atlas.index.insert(position, slot);
+                }
+            }
         }
+    }
+
+    // Helper to update the tile atlas from BGRA bytes
+    pub fn update_texture_region(&mut self, x: u32, y: u32, width: u32, height: u32, data: &[u8]) {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
 
-        self.update_texture_region(0, 0, width, height, data);
+        self.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.atlas.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * width),
+                rows_per_image: Some(height),
+            },
+            size,
+        );
     }
 
     pub fn update_uniforms(&mut self, transform: [[f32; 4]; 4]) {
@@ -400,9 +601,14 @@ impl<'a> State<'a> {
         self.queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniforms]));
     }
 
-    pub fn render(&mut self, window: &Window, run_ui: impl FnOnce(&egui::Context)) -> Result<(), wgpu::SurfaceError> {
+    pub fn render(&mut self, window: &Window, run_ui: impl FnOnce(&egui::Context) -> PdfScene) -> Result<(), wgpu::SurfaceError> {
         let raw_input = self.egui_winit.take_egui_input(window);
-        let full_output = self.egui_ctx.run(raw_input, run_ui);
+        let mut scene = PdfScene::default();
+        let full_output = self.egui_ctx.run(raw_input, |ctx| scene = run_ui(ctx));
+
+        // Colour filters are only uniforms: switching one redraws the same tiles
+        self.uniforms.set_filter(scene.filter);
+        self.queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniforms]));
         
         self.egui_winit.handle_platform_output(window, full_output.platform_output);
         
@@ -422,6 +628,57 @@ impl<'a> State<'a> {
 
         self.egui_renderer.update_buffers(&self.device, &self.queue, &mut encoder, &paint_jobs, &screen_descriptor);
 
+        // Page quads in physical pixels, clipped to the PDF pane
+        let ppp = full_output.pixels_per_point;
+        let surface_rect = egui::Rect::from_min_size(egui::Pos2::ZERO, egui::vec2(self.config.width as f32, self.config.height as f32));
+        let clip = egui::Rect::from_min_max((scene.clip.min.to_vec2() * ppp).round().to_pos2(), (scene.clip.max.to_vec2() * ppp).round().to_pos2())
+            .intersect(surface_rect);
+        let quad = |rect: egui::Rect, uv: egui::Rect| {
+            let corner = |x: f32, y: f32, u: f32, v: f32| PdfVertex { position: [x, y], tex_coords: [u, v] };
+            [
+                corner(rect.min.x, rect.min.y, uv.min.x, uv.min.y), corner(rect.min.x, rect.max.y, uv.min.x, uv.max.y), corner(rect.max.x, rect.max.y, uv.max.x, uv.max.y),
+                corner(rect.min.x, rect.min.y, uv.min.x, uv.min.y), corner(rect.max.x, rect.max.y, uv.max.x, uv.max.y), corner(rect.max.x, rect.min.y, uv.max.x, uv.min.y),
+            ]
+        };
+        let to_pixels = |r: egui::Rect| egui::Rect::from_min_max((r.min.to_vec2() * ppp).to_pos2(), (r.max.to_vec2() * ppp).to_pos2());
+
+        // Blank pages first, then tiles from other zoom levels as a stand-in, then the tiles for this zoom on top
+        let mut vertices: Vec<PdfVertex> = scene.pages.iter()
+            .flat_map(|(_, rect)| quad(to_pixels(*rect), egui::Rect::from_min_max(egui::Pos2::ZERO, egui::pos2(1.0, 1.0))))
+            .collect();
+        let page_vertices = vertices.len() as u32;
+        self.atlas.frame += 1;
+        let level = TileKey::level_for(scene.zoom * ppp);
+        let current: Vec<usize> = scene_tiles(&scene, ppp).into_iter()
+            .filter_map(|(position, _)| self.atlas.index.get(&position).copied())
+            .collect();
+        let stand_ins = self.atlas.slots.iter().enumerate()
+            .filter(|(_, slot)| slot.as_ref().is_some_and(|s| s.key.level != level))
+            .map(|(i, _)| i);
+        for slot in stand_ins.collect::<Vec<_>>().into_iter().chain(current.iter().copied()) {
+            let Some(tile) = self.atlas.slots[slot].as_ref() else { continue };
+            let Some((_, page_rect)) = scene.pages.iter().find(|(page, _)| *page == tile.key.page) else { continue };
+            let at = |x: f32, y: f32| page_rect.min + egui::vec2(x, y) * scene.zoom;
+            let screen = to_pixels(egui::Rect::from_min_max(at(tile.rect.x, tile.rect.y), at(tile.rect.x + tile.rect.width, tile.rect.y + tile.rect.height)));
+            // Half a texel in from the edges so neighbouring slots do not bleed in
+            let (x, y) = TileAtlas::origin(slot);
+            let texel = |tx: f32, ty: f32| egui::pos2(tx / ATLAS_SIZE as f32, ty / ATLAS_SIZE as f32);
+            let uv = egui::Rect::from_min_max(
+                texel(x as f32 + 0.5, y as f32 + 0.5),
+                texel((x + tile.width) as f32 - 0.5, (y + tile.height) as f32 - 0.5),
+            );
+            vertices.extend(quad(screen, uv));
+        }
+        for slot in current {
+            if let Some(tile) = self.atlas.slots[slot].as_mut() {
+                tile.last_used = self.atlas.frame;
+            }
+        }
+        if self.vertex_buffer.size() < (vertices.len() * std::mem::size_of::<PdfVertex>()) as u64 {
+            self.vertex_buffer = Self::create_vertex_buffer(&self.device, (vertices.len() / 6).next_power_of_two());
+        }
+        self.queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+
         let output = self.surface.get_current_texture()?;
         let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
 
@@ -446,11 +703,20 @@ impl<'a> State<'a> {
                 timestamp_writes: None,
             });
             
-            // Custom PDF background rendering with shader
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.bind_group, &[]);
-            render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
-            render_pass.draw(0..3, 0..1);
+            // PDF pages with the custom shader, underneath egui
+            if !vertices.is_empty() && clip.width() >= 1.0 && clip.height() >= 1.0 {
+                render_pass.set_pipeline(&self.render_pipeline);
+                render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+                render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+                render_pass.set_scissor_rect(clip.min.x as u32, clip.min.y as u32, clip.width() as u32, clip.height() as u32);
+                render_pass.set_bind_group(0, &self.bind_group, &[]);
+                render_pass.draw(0..page_vertices, 0..1);
+                if vertices.len() as u32 > page_vertices {
+                    render_pass.set_bind_group(0, &self.atlas.bind_group, &[]);
+                    render_pass.draw(page_vertices..vertices.len() as u32, 0..1);
+                }
+                render_pass.set_scissor_rect(0, 0, self.config.width, self.config.height);
+            }
 
             self.egui_renderer.render(&mut render_pass, &paint_jobs, &screen_descriptor);
         }
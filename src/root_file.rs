@@ -0,0 +1,113 @@
+use crate::magic_comments::{resolve_relative, MagicComments};
+use crate::vfs::Vfs;
+use regex::Regex;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+static DOCUMENTCLASS_REGEX: OnceLock<Regex> = OnceLock::new();
+static INCLUDE_REGEX: OnceLock<Regex> = OnceLock::new();
+
+/// A file that can be compiled on its own: an uncommented `\documentclass` and a `document` environment.
+///
+/// `subfiles` and `standalone` documents compile on their own too, but they are parts of a bigger project.
+pub fn is_root_document(text: &str) -> bool {
+    let re = DOCUMENTCLASS_REGEX.get_or_init(|| Regex::new(r"(?m)^[^%\n]*?\\documentclass\s*(?:\[[^\]]*\])?\s*\{([^}]*)\}").unwrap());
+    let Some(caps) = re.captures(text) else { return false };
+    !matches!(caps[1].trim(), "subfiles" | "standalone") && text.contains("\\begin{document}")
+}
+
+/// The `.tex` files that look like root files, most likely first.
+///
+/// Files that another candidate includes come last; then shallower paths, `main.tex`, and names in order.
+pub fn candidates(vfs: &Vfs) -> Vec<String> {
+    let include_re = INCLUDE_REGEX.get_or_init(|| Regex::new(r"\\(?:include|input|subfile)\{([^}]+)\}").unwrap());
+    let documents: Vec<(String, String)> = vfs
+        .get_all_files()
+        .iter()
+        .filter(|entry| entry.key().ends_with(".tex"))
+        .map(|entry| (entry.key().clone(), String::from_utf8_lossy(entry.value()).into_owned()))
+        .filter(|(_, text)| is_root_document(text))
+        .collect();
+
+    let included: HashSet<String> = documents
+        .iter()
+        .flat_map(|(file, text)| {
+            include_re.captures_iter(text).map(|cap| {
+                let target = cap[1].trim();
+                let target = if target.ends_with(".tex") { target.to_string() } else { format!("{}.tex", target) };
+                resolve_relative(file, &target)
+            }).collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut files: Vec<String> = documents.into_iter().map(|(file, _)| file).collect();
+    files.sort_by_key(|file| {
+        let name = file.rsplit('/').next().unwrap_or(file);
+        (included.contains(file), file.matches('/').count(), name != "main.tex", file.clone())
+    });
+    files
+}
+
+/// The root file of a project.
+///
+/// In order: the `% !TEX root` of the file that was opened, that file if it is a document itself,
+/// the project setting, the best `\documentclass` candidate, and finally `main.tex`.
+pub fn resolve(vfs: &Vfs, opened: Option<&str>, setting: Option<&str>) -> String {
+    let exists = |file: &str| vfs.read_file(file).is_some();
+    if let Some(file) = opened {
+        if let Some(text) = vfs.read_file(file).map(|data| String::from_utf8_lossy(&data).into_owned()) {
+            let magic_root = MagicComments::parse(&text).root.map(|root| resolve_relative(file, &root));
+            if let Some(root) = magic_root.filter(|root| exists(root)) {
+                return root;
+            }
+            if is_root_document(&text) {
+                return file.to_string();
+            }
+        }
+    }
+    if let Some(setting) = setting.filter(|setting| exists(setting)) {
+        return setting.to_string();
+    }
+    candidates(vfs)
+        .into_iter()
+        .next()
+        .or_else(|| opened.map(|file| file.to_string()))
+        .unwrap_or_else(|| "main.tex".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &[u8] = b"\\documentclass{report}\n\\begin{document}\n\\include{chapters/intro}\n\\end{document}\n";
+
+    #[test]
+    fn test_root_document_detection() {
+        assert!(is_root_document("\\documentclass[11pt]{article}\n\\begin{document}\n\\end{document}"));
+        assert!(!is_root_document("% \\documentclass{article}\n\\begin{document}"));
+        assert!(!is_root_document("\\documentclass[../thesis.tex]{subfiles}\n\\begin{document}"));
+        assert!(!is_root_document("\\section{Intro}"));
+    }
+
+    #[test]
+    fn test_resolve_root_file() {
+        let vfs = Vfs::new();
+        vfs.write_file("thesis.tex", DOCUMENT.to_vec());
+        vfs.write_file("chapters/intro.tex", b"% !TEX root = ../thesis.tex\n\\chapter{Intro}".to_vec());
+        vfs.write_file("chapters/notes.tex", b"\\chapter{Notes}".to_vec());
+        vfs.write_file("figures/plot.tex", b"\\documentclass{standalone}\n\\begin{document}\n\\end{document}".to_vec());
+
+        assert_eq!(resolve(&vfs, Some("chapters/intro.tex"), None), "thesis.tex");
+        assert_eq!(resolve(&vfs, Some("chapters/notes.tex"), None), "thesis.tex");
+        assert_eq!(resolve(&vfs, None, None), "thesis.tex");
+
+        // A second document: the opened one wins, then the setting, then the one at the top
+        vfs.write_file("drafts/letter.tex", DOCUMENT.to_vec());
+        assert_eq!(resolve(&vfs, Some("drafts/letter.tex"), Some("thesis.tex")), "drafts/letter.tex");
+        assert_eq!(resolve(&vfs, None, Some("drafts/letter.tex")), "drafts/letter.tex");
+        assert_eq!(resolve(&vfs, None, Some("missing.tex")), "thesis.tex");
+        assert_eq!(candidates(&vfs), vec!["thesis.tex".to_string(), "drafts/letter.tex".to_string()]);
+
+        assert_eq!(resolve(&Vfs::new(), None, None), "main.tex");
+    }
+}
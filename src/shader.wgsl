@@ -1,7 +1,14 @@
 struct PdfUniforms {
     transform: mat4x4<f32>,
+    // Where black and white of the page end up after filtering
+    black: vec4<f32>,
+    white: vec4<f32>,
+    // 0 keeps the colours, 1 inverts luminance, 2 takes luminance, 3 raises contrast
+    mode: u32,
 };
 
+const LUMA: vec3<f32> = vec3<f32>(0.2126, 0.7152, 0.0722);
+
 @group(1) @binding(0)
 var<uniform> uniforms: PdfUniforms;
 
@@ -12,21 +19,13 @@ struct VertexOutput {
 
 @vertex
 fn vs_main(
-    @builtin(vertex_index) in_vertex_index: u32,
+    @location(0) position: vec2<f32>,
+    @location(1) tex_coords: vec2<f32>,
 ) -> VertexOutput {
     var out: VertexOutput;
-    // CCW Fullscreen Triangle
-    // 0: (-1, -1), UV (0, 1)
-    // 1: ( 3, -1), UV (2, 1)
-    // 2: (-1,  3), UV (0, -1)
-    
-    let x = f32(i32(in_vertex_index == 1u) * 4 - 1);
-    let y = f32(i32(in_vertex_index == 2u) * 4 - 1);
-    
-    // Apply zoom/pan transform to the base full-screen triangle
-    let raw_pos = vec4<f32>(x, y, 0.0, 1.0);
-    out.clip_position = uniforms.transform * raw_pos;
-    out.tex_coords = vec2<f32>(x * 0.5 + 0.5, 1.0 - (y * 0.5 + 0.5));
+    // Page quads come in window pixels; the transform maps them to clip space
+    out.clip_position = uniforms.transform * vec4<f32>(position, 0.0, 1.0);
+    out.tex_coords = tex_coords;
     return out;
 }
 
@@ -35,7 +34,25 @@ var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
 
+fn filter_color(color: vec3<f32>) -> vec3<f32> {
+    var v = color;
+    switch uniforms.mode {
+        case 1u: {
+            // Moving every channel by the same amount flips the luminance and leaves the hue
+            v = clamp(color + vec3<f32>(1.0 - 2.0 * dot(color, LUMA)), vec3<f32>(0.0), vec3<f32>(1.0));
+        }
+        case 2u: {
+            v = vec3<f32>(dot(color, LUMA));
+        }
+        case 3u: {
+            v = smoothstep(vec3<f32>(0.15), vec3<f32>(0.85), color);
+        }
+        default: {}
+    }
+    return mix(uniforms.black.rgb, uniforms.white.rgb, v);
+}
+
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return vec4<f32>(textureSample(t_diffuse, s_diffuse, in.tex_coords).rgb, 1.0);
+    return vec4<f32>(filter_color(textureSample(t_diffuse, s_diffuse, in.tex_coords).rgb), 1.0);
 }
@@ -0,0 +1,292 @@
+use crate::dependencies::DependencyNode;
+use crate::diagnostics::{Diagnostic, DiagnosticKind, Severity};
+use crate::vfs::Vfs;
+use ahash::{AHashMap, AHashSet};
+use regex::Regex;
+use std::sync::OnceLock;
+
+static LABEL_REGEX: OnceLock<Regex> = OnceLock::new();
+static REF_REGEX: OnceLock<Regex> = OnceLock::new();
+static CITE_REGEX: OnceLock<Regex> = OnceLock::new();
+static NEWCOMMAND_REGEX: OnceLock<Regex> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// `\label{name}`
+    Label,
+    /// `\ref`, `\eqref`, `\cref`, `\autoref`, ... pointing at a label
+    Reference,
+    /// A key inside any `\cite*{...}` command
+    Citation,
+    /// `@article{key, ...}` in a `.bib` file
+    BibEntry,
+    /// `\newcommand{\name}`, `\def\name`, ...
+    CommandDefinition,
+}
+
+impl SymbolKind {
+    pub fn is_definition(self) -> bool {
+        matches!(self, SymbolKind::Label | SymbolKind::BibEntry | SymbolKind::CommandDefinition)
+    }
+
+    /// Symbols that share a namespace refer to each other by name.
+    fn namespace(self) -> u8 {
+        match self {
+            SymbolKind::Label | SymbolKind::Reference => 0,
+            SymbolKind::Citation | SymbolKind::BibEntry => 1,
+            SymbolKind::CommandDefinition => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub name: String,
+    pub file_name: String,
+    /// 1-based, like `OutlineItem::line`
+    pub line: usize,
+    /// Byte offset of `name` within the line
+    pub column: usize,
+}
+
+pub struct SymbolScanner;
+
+impl SymbolScanner {
+    /// Record every label, reference, citation and command definition in one file.
+    pub fn scan(file_name: &str, content: &str) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+
+        if file_name.ends_with(".bib") {
+            for entry in crate::bib::BibParser::parse(content) {
+                symbols.push(Symbol { kind: SymbolKind::BibEntry, name: entry.key, file_name: file_name.to_string(), line: entry.line, column: entry.column });
+            }
+            return symbols;
+        }
+
+        let label_re = LABEL_REGEX.get_or_init(|| Regex::new(r"\\label\s*\{([^}]*)\}").unwrap());
+        let ref_re = REF_REGEX.get_or_init(|| Regex::new(r"\\(?:ref|eqref|pageref|vref|nameref|autoref|cref|Cref|cpageref|Cpageref)\*?\s*\{([^}]*)\}").unwrap());
+        let cite_re = CITE_REGEX.get_or_init(|| Regex::new(r"\\[a-zA-Z]*cite[a-zA-Z]*\*?\s*(?:\[[^\]]*\]\s*)*\{([^}]*)\}").unwrap());
+        let newcommand_re = NEWCOMMAND_REGEX.get_or_init(|| Regex::new(r"\\(?:(?:re|provide)?newcommand|DeclareRobustCommand|DeclareMathOperator)\*?\s*\{?\s*(\\[a-zA-Z@]+)|\\[gex]?def\s*(\\[a-zA-Z@]+)").unwrap());
+
+        for (i, line) in content.lines().enumerate() {
+            let code = strip_comment(line);
+            let mut push = |kind, name: &str, column| {
+                symbols.push(Symbol { kind, name: name.to_string(), file_name: file_name.to_string(), line: i + 1, column });
+            };
+
+            for cap in label_re.captures_iter(code) {
+                let name = cap.get(1).unwrap();
+                let trimmed = name.as_str().trim();
+                if !trimmed.is_empty() {
+                    push(SymbolKind::Label, trimmed, name.start() + name.as_str().find(trimmed).unwrap_or(0));
+                }
+            }
+            for (kind, re) in [(SymbolKind::Reference, ref_re), (SymbolKind::Citation, cite_re)] {
+                for cap in re.captures_iter(code) {
+                    let list = cap.get(1).unwrap();
+                    for (offset, name) in split_keys(list.as_str()) {
+                        // \nocite{*} pulls in the whole bibliography rather than naming a key
+                        if name != "*" {
+                            push(kind, name, list.start() + offset);
+                        }
+                    }
+                }
+            }
+            for cap in newcommand_re.captures_iter(code) {
+                let name = cap.get(1).or_else(|| cap.get(2)).unwrap();
+                push(SymbolKind::CommandDefinition, name.as_str(), name.start());
+            }
+        }
+        symbols
+    }
+}
+
+/// Cut a line at its first unescaped `%`.
+fn strip_comment(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        if b == b'%' {
+            let backslashes = bytes[..i].iter().rev().take_while(|&&c| c == b'\\').count();
+            if backslashes % 2 == 0 {
+                return &line[..i];
+            }
+        }
+    }
+    line
+}
+
+/// Split a comma-separated key list, yielding each trimmed key with its byte offset.
+fn split_keys(list: &str) -> Vec<(usize, &str)> {
+    let mut keys = Vec::new();
+    let mut start = 0;
+    for part in list.split(',') {
+        let trimmed = part.trim();
+        if !trimmed.is_empty() {
+            keys.push((start + part.find(trimmed).unwrap_or(0), trimmed));
+        }
+        start += part.len() + 1;
+    }
+    keys
+}
+
+/// The control sequence (e.g. `\\foo`) under byte `column` of a source line.
+pub fn command_at(line: &str, column: usize) -> Option<&str> {
+    let start = line[..column.min(line.len())].rfind('\\')?;
+    let end = line[start + 1..].find(|c: char| !(c.is_ascii_alphabetic() || c == '@')).map_or(line.len(), |i| start + 1 + i);
+    (end >= column && end > start + 1).then(|| &line[start..end])
+}
+
+/// Every symbol across a project's include tree.
+#[derive(Default)]
+pub struct SymbolIndex {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolIndex {
+    pub fn from_tree(tree: &DependencyNode) -> Self {
+        let mut symbols = Vec::new();
+        Self::collect(tree, &mut symbols);
+        Self { symbols }
+    }
+
+    fn collect(node: &DependencyNode, symbols: &mut Vec<Symbol>) {
+        symbols.extend(node.symbols.iter().cloned());
+        for child in &node.children {
+            Self::collect(child, symbols);
+        }
+    }
+
+    /// The symbol whose name covers the byte `column` of `line` in `file`.
+    pub fn symbol_at(&self, file: &str, line: usize, column: usize) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.file_name == file && s.line == line && column >= s.column && column <= s.column + s.name.len())
+    }
+
+    /// Where the symbol is defined: the label for a reference, the bib entry for a citation.
+    pub fn definition(&self, symbol: &Symbol) -> Option<&Symbol> {
+        let namespace = symbol.kind.namespace();
+        self.symbols.iter().find(|s| s.kind.is_definition() && s.kind.namespace() == namespace && s.name == symbol.name)
+    }
+
+    /// The definition of a user command such as `\foo`.
+    pub fn command_definition(&self, command: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.kind == SymbolKind::CommandDefinition && s.name == command)
+    }
+
+    /// The definition and every use of the same name.
+    pub fn references(&self, symbol: &Symbol) -> Vec<&Symbol> {
+        let namespace = symbol.kind.namespace();
+        self.symbols.iter().filter(|s| s.kind.namespace() == namespace && s.name == symbol.name).collect()
+    }
+
+    /// Rewrite every occurrence of a label or cite key, returning the new content per file.
+    /// Fails without touching anything if the name is taken or the index is out of date.
+    pub fn rename(&self, symbol: &Symbol, new_name: &str, vfs: &Vfs) -> Result<Vec<(String, String)>, String> {
+        if symbol.kind == SymbolKind::CommandDefinition {
+            return Err("Only labels and citation keys can be renamed".to_string());
+        }
+        if new_name.is_empty() || new_name.contains(|c: char| c.is_whitespace() || "{}%,\\#".contains(c)) {
+            return Err(format!("'{}' is not a valid name", new_name));
+        }
+        if new_name == symbol.name {
+            return Ok(Vec::new());
+        }
+        let namespace = symbol.kind.namespace();
+        if self.symbols.iter().any(|s| s.kind.namespace() == namespace && s.name == new_name) {
+            return Err(format!("'{}' is already used in this project", new_name));
+        }
+
+        let mut by_file: AHashMap<&str, Vec<&Symbol>> = AHashMap::new();
+        for s in self.references(symbol) {
+            by_file.entry(s.file_name.as_str()).or_default().push(s);
+        }
+
+        let mut edits = Vec::new();
+        for (file, mut occurrences) in by_file {
+            let content = vfs.read_file(file).ok_or_else(|| format!("{} is no longer in the project", file))?;
+            let content = String::from_utf8_lossy(&content).to_string();
+            let line_starts: Vec<usize> = std::iter::once(0).chain(content.match_indices('\n').map(|(i, _)| i + 1)).collect();
+
+            // Apply back to front so earlier offsets stay valid
+            occurrences.sort_by_key(|s| std::cmp::Reverse((s.line, s.column)));
+            let mut updated = content.clone();
+            for s in occurrences {
+                let start = line_starts.get(s.line - 1).map(|l| l + s.column);
+                let current = start.and_then(|start| content.get(start..start + s.name.len()));
+                let Some(start) = start.filter(|_| current == Some(s.name.as_str())) else {
+                    return Err(format!("{} changed since it was indexed; try again", file));
+                };
+                updated.replace_range(start..start + s.name.len(), new_name);
+            }
+            edits.push((file.to_string(), updated));
+        }
+        Ok(edits)
+    }
+
+    /// Warnings for references to labels that do not exist and labels nothing refers to.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let labels: AHashSet<&str> = self.symbols.iter().filter(|s| s.kind == SymbolKind::Label).map(|s| s.name.as_str()).collect();
+        let referenced: AHashSet<&str> = self.symbols.iter().filter(|s| s.kind == SymbolKind::Reference).map(|s| s.name.as_str()).collect();
+
+        let mut diagnostics = Vec::new();
+        for s in &self.symbols {
+            let (kind, message) = match s.kind {
+                SymbolKind::Reference if !labels.contains(s.name.as_str()) => {
+                    (DiagnosticKind::UndefinedReference(s.name.clone()), format!("Reference `{}' has no matching \\label", s.name))
+                }
+                SymbolKind::Label if !referenced.contains(s.name.as_str()) => {
+                    (DiagnosticKind::UnusedLabel(s.name.clone()), format!("Label `{}' is never referenced", s.name))
+                }
+                _ => continue,
+            };
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                kind,
+                file: Some(s.file_name.clone()),
+                line: Some(s.line),
+                message,
+            });
+        }
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependencies::DependencyScanner;
+
+    #[test]
+    fn test_symbol_index_rename_and_warnings() {
+        let vfs = Vfs::new();
+        vfs.write_file("main.tex", b"\\newcommand{\\R}{\\mathbb{R}}\n\\input{intro}\nSee \\cref{sec:intro,fig:plot} % \\ref{sec:commented}\n\\cite[p.~2]{knuth1984, lamport}\n\\bibliography{refs}\n".to_vec());
+        vfs.write_file("intro.tex", b"\\section{Intro}\\label{sec:intro}\nAs in \\eqref{sec:intro}.\n\\label{eq:unused}\n".to_vec());
+        vfs.write_file("refs.bib", b"@string{tub = \"TUGboat\"}\n@book{knuth1984,\n  title = {The TeXbook}\n}\n".to_vec());
+
+        let index = SymbolIndex::from_tree(&DependencyScanner::scan("main.tex", &vfs));
+
+        let reference = index.symbol_at("main.tex", 3, 12).unwrap();
+        assert_eq!(reference.kind, SymbolKind::Reference);
+        assert_eq!(reference.name, "sec:intro");
+        let label = index.definition(reference).unwrap();
+        assert_eq!((label.file_name.as_str(), label.line, label.column), ("intro.tex", 1, 22));
+        assert_eq!(index.references(reference).len(), 3);
+
+        let cite = index.symbol_at("main.tex", 4, 12).unwrap();
+        assert_eq!(index.definition(cite).unwrap().file_name, "refs.bib");
+        assert_eq!(index.command_definition("\\R").unwrap().line, 1);
+
+        let mut edits = index.rename(reference, "sec:introduction", &vfs).unwrap();
+        edits.sort();
+        assert_eq!(edits[0].1, "\\section{Intro}\\label{sec:introduction}\nAs in \\eqref{sec:introduction}.\n\\label{eq:unused}\n");
+        assert!(edits[1].1.contains("\\cref{sec:introduction,fig:plot} % \\ref{sec:commented}"));
+        assert!(index.rename(reference, "eq:unused", &vfs).is_err());
+        assert!(index.rename(reference, "has space", &vfs).is_err());
+
+        let warnings: Vec<DiagnosticKind> = index.diagnostics().into_iter().map(|d| d.kind).collect();
+        assert_eq!(warnings, vec![
+            DiagnosticKind::UndefinedReference("fig:plot".to_string()),
+            DiagnosticKind::UnusedLabel("eq:unused".to_string()),
+        ]);
+    }
+}
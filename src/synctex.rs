@@ -1,11 +1,40 @@
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use ahash::AHashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use flate2::read::GzDecoder;
+use crate::build_dir::source_path;
 
+/// TeX scaled points per PDF big point.
+const SP_PER_BP: f32 = 65781.76;
+/// Side of a cell of the per-page spatial index, in big points.
+const CELL_SIZE: f32 = 32.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    VBox,
+    HBox,
+    VoidVBox,
+    VoidHBox,
+    /// A `x` record: the current position at some point of the input
+    Current,
+    Kern,
+    Glue,
+    Math,
+}
+
+impl NodeKind {
+    pub fn is_box(self) -> bool {
+        matches!(self, NodeKind::VBox | NodeKind::HBox | NodeKind::VoidVBox | NodeKind::VoidHBox)
+    }
+}
+
+/// One SyncTeX record. Coordinates are in PDF points from the top left corner of the page,
+/// `y` being the baseline.
 #[derive(Debug, Clone)]
 pub struct SyncTexNode {
+    pub kind: NodeKind,
     pub tag: u32,
     pub line: u32,
     #[allow(dead_code)]
@@ -16,12 +45,41 @@ pub struct SyncTexNode {
     pub height: f32,
     pub depth: f32,
     pub page: u32,
+    /// The box this record sits in
+    pub parent: Option<usize>,
+}
+
+impl SyncTexNode {
+    fn left_right(&self) -> (f32, f32) {
+        // Right-to-left boxes have a negative width
+        if self.width >= 0.0 { (self.x, self.x + self.width) } else { (self.x + self.width, self.x) }
+    }
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        let (left, right) = self.left_right();
+        x >= left && x <= right && y >= self.y - self.height && y <= self.y + self.depth
+    }
+}
+
+/// The boxes of one page, bucketed by the grid cells they cover.
+#[derive(Default)]
+struct PageIndex {
+    nodes: std::ops::Range<usize>,
+    grid: AHashMap<(i32, i32), Vec<usize>>,
 }
 
 pub struct SyncTex {
     pub inputs: AHashMap<u32, PathBuf>,
+    /// Every record in document order; the records of a page are contiguous
     pub nodes: Vec<SyncTexNode>,
-    pub unit: f32, // Unit scaling from SyncTeX (usually 72/65536 or similar)
+    pub unit: f32, // Scaled points per unit of the coordinates in the file
+    pub magnification: f32,
+    /// Added to every coordinate, in PDF points
+    pub x_offset: f32,
+    pub y_offset: f32,
+    pages: AHashMap<u32, PageIndex>,
+    /// tag -> line -> records, for forward search
+    lines: AHashMap<u32, BTreeMap<u32, Vec<usize>>>,
 }
 
 impl SyncTex {
@@ -30,13 +88,18 @@ impl SyncTex {
             inputs: AHashMap::new(),
             nodes: Vec::new(),
             unit: 1.0,
+            magnification: 1000.0,
+            x_offset: 0.0,
+            y_offset: 0.0,
+            pages: AHashMap::new(),
+            lines: AHashMap::new(),
         }
     }
 
     pub fn load<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
         let path = path.as_ref();
         let file = File::open(path)?;
-        
+
         if path.extension().map_or(false, |ext| ext == "gz") {
             self.load_from_reader(BufReader::new(GzDecoder::new(file)))
         } else {
@@ -44,102 +107,322 @@ impl SyncTex {
         }
     }
 
+    /// Load SyncTeX data handed over in memory, gzip-compressed or plain.
+    pub fn load_from_bytes(&mut self, data: &[u8]) -> std::io::Result<()> {
+        use std::io::{Cursor, Read};
+
+        let mut decoded = Vec::new();
+        if GzDecoder::new(data).read_to_end(&mut decoded).is_ok() {
+            self.load_from_reader(Cursor::new(decoded))
+        } else {
+            self.load_from_reader(Cursor::new(data))
+        }
+    }
+
     pub fn load_from_reader<R: BufRead>(&mut self, reader: R) -> std::io::Result<()> {
-        let mut current_page = 0;
-        let mut in_content = false;
+        let mut current_page = None;
+        // Open boxes of the current page, innermost last
+        let mut open: Vec<usize> = Vec::new();
+        let (mut x_offset, mut y_offset) = (0.0, 0.0);
 
         for line in reader.lines() {
             let line = line?;
             if line.is_empty() { continue; }
 
-            if line.starts_with("Input:") {
-                let parts: Vec<&str> = line["Input:".len()..].split(':').collect();
-                if parts.len() >= 2 {
-                    if let Ok(tag) = parts[0].parse() {
-                        self.inputs.insert(tag, PathBuf::from(parts[1]));
+            if let Some(input) = line.strip_prefix("Input:") {
+                // The path may contain colons itself
+                if let Some((tag, path)) = input.split_once(':') {
+                    if let Ok(tag) = tag.parse() {
+                        self.inputs.insert(tag, PathBuf::from(path));
                     }
                 }
-            } else if line.starts_with('{') {
-                if let Ok(page) = line[1..].parse() {
-                    current_page = page;
-                    in_content = true;
-                }
-            } else if line.starts_with('}') {
-                in_content = false;
-            } else if in_content {
+                continue;
+            }
+
+            if let Some(page) = current_page {
                 let first_char = line.chars().next().unwrap_or(' ');
-                if "[(vhxkg".contains(first_char) {
-                    self.parse_node(&line, current_page);
+                match first_char {
+                    '}' => {
+                        current_page = None;
+                        open.clear();
+                    }
+                    ']' | ')' => {
+                        open.pop();
+                    }
+                    '[' | '(' | 'v' | 'h' | 'x' | 'k' | 'g' | '$' => {
+                        if let Some(node) = Self::parse_node(&line, page, open.last().copied()) {
+                            self.nodes.push(node);
+                            if first_char == '[' || first_char == '(' {
+                                open.push(self.nodes.len() - 1);
+                            }
+                        }
+                    }
+                    // Byte offsets, forms and anything newer than this parser
+                    _ => {}
                 }
-            } else if line.starts_with("Unit:") {
-                if let Ok(unit) = line["Unit:".len()..].parse() {
-                    self.unit = unit;
+                continue;
+            }
+
+            if let Some(page) = line.strip_prefix('{') {
+                if let Ok(page) = page.parse() {
+                    current_page = Some(page);
+                }
+            } else if let Some((key, value)) = line.split_once(':') {
+                // Preamble and post scriptum settings; later values win
+                let value = value.trim();
+                match key {
+                    "Unit" => self.unit = value.parse().unwrap_or(self.unit),
+                    "Magnification" => self.magnification = value.parse().unwrap_or(self.magnification),
+                    "X Offset" => x_offset = value.parse().unwrap_or(x_offset),
+                    "Y Offset" => y_offset = value.parse().unwrap_or(y_offset),
+                    "Offset" => {
+                        x_offset = value.parse().unwrap_or(x_offset);
+                        y_offset = x_offset;
+                    }
+                    _ => {}
                 }
             }
         }
+
+        self.finish(x_offset, y_offset);
         Ok(())
     }
 
-    fn parse_node(&mut self, line: &str, page: u32) {
-        let content = &line[1..];
-        let parts: Vec<&str> = content.split(|c| c == ',' || c == ':').collect();
-        
-        if parts.len() >= 5 {
-            let tag = parts[0].parse().unwrap_or(0);
-            let line_num = parts[1].parse().unwrap_or(0);
-            let x = parts[2].parse().unwrap_or(0.0);
-            let y = parts[3].parse().unwrap_or(0.0);
-            
-            let mut width = 0.0;
-            let mut height = 0.0;
-            let mut depth = 0.0;
-            
-            if parts.len() >= 7 {
-                width = parts[4].parse().unwrap_or(0.0);
-                height = parts[5].parse().unwrap_or(0.0);
-                if parts.len() >= 8 {
-                    depth = parts[6].parse().unwrap_or(0.0);
+    /// `<type><tag>,<line>[,<column>]:<x>,<y>[:<width>[,<height>,<depth>]]`, coordinates still in file units.
+    fn parse_node(line: &str, page: u32, parent: Option<usize>) -> Option<SyncTexNode> {
+        let kind = match line.chars().next()? {
+            '[' => NodeKind::VBox,
+            '(' => NodeKind::HBox,
+            'v' => NodeKind::VoidVBox,
+            'h' => NodeKind::VoidHBox,
+            'x' => NodeKind::Current,
+            'k' => NodeKind::Kern,
+            'g' => NodeKind::Glue,
+            _ => NodeKind::Math,
+        };
+        let mut sections = line[1..].split(':');
+        let mut link = sections.next()?.split(',');
+        let tag = link.next()?.parse().ok()?;
+        let line_num = link.next()?.parse().ok()?;
+        let column = link.next().and_then(|c| c.parse().ok()).unwrap_or(0);
+
+        let mut point = sections.next()?.split(',').map(|v| v.parse::<f32>().unwrap_or(0.0));
+        let x = point.next()?;
+        let y = point.next()?;
+        let mut size = sections.next().into_iter().flat_map(|s| s.split(',')).map(|v| v.parse::<f32>().unwrap_or(0.0));
+        let width = size.next().unwrap_or(0.0);
+        let height = size.next().unwrap_or(0.0);
+        let depth = size.next().unwrap_or(0.0);
+
+        Some(SyncTexNode { kind, tag, line: line_num, column, x, y, width, height, depth, page, parent })
+    }
+
+    /// Convert every coordinate to PDF points and build the lookup indexes.
+    fn finish(&mut self, x_offset: f32, y_offset: f32) {
+        let scale = self.unit * self.magnification / 1000.0 / SP_PER_BP;
+        self.x_offset = x_offset * self.unit / SP_PER_BP;
+        self.y_offset = y_offset * self.unit / SP_PER_BP;
+
+        self.pages.clear();
+        self.lines.clear();
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            node.x = node.x * scale + self.x_offset;
+            node.y = node.y * scale + self.y_offset;
+            node.width *= scale;
+            node.height *= scale;
+            node.depth *= scale;
+
+            self.lines.entry(node.tag).or_default().entry(node.line).or_default().push(i);
+
+            let page = self.pages.entry(node.page).or_insert_with(|| PageIndex { nodes: i..i, ..Default::default() });
+            page.nodes.end = i + 1;
+            if node.kind.is_box() {
+                let (left, right) = node.left_right();
+                let (columns, rows) = (cell(left)..=cell(right), cell(node.y - node.height)..=cell(node.y + node.depth));
+                for column in columns {
+                    for row in rows.clone() {
+                        page.grid.entry((column, row)).or_default().push(i);
+                    }
                 }
             }
+        }
+    }
 
-            self.nodes.push(SyncTexNode {
-                tag,
-                line: line_num,
-                column: 0,
-                x,
-                y,
-                width,
-                height,
-                depth,
-                page,
-            });
+    /// The `Input:` tag of a project file, given relative to the project root as in the VFS.
+    pub fn tag_for_file(&self, file_name: &str, root_dir: Option<&str>) -> Option<u32> {
+        let wanted = normalize(Path::new(file_name));
+        let bare = wanted.strip_suffix(".tex").unwrap_or(&wanted);
+        let exact = self.inputs.iter()
+            .find(|(_, path)| {
+                let input = relative_input(path, root_dir);
+                input == wanted || input == bare
+            })
+            .map(|(tag, _)| *tag);
+        // Inputs outside the root as we know it: match the trailing path components
+        exact.or_else(|| self.inputs.iter().find(|(_, path)| path.ends_with(&wanted)).map(|(tag, _)| *tag))
+    }
+
+    /// The project file behind an `Input:` tag, relative to the project root.
+    pub fn file_for_tag(&self, tag: u32, root_dir: Option<&str>) -> Option<String> {
+        let input = relative_input(self.inputs.get(&tag)?, root_dir);
+        // TeX records `\input{chapter}` without the extension it added
+        if Path::new(&input).extension().is_none() {
+            return Some(format!("{}.tex", input));
         }
+        Some(input)
     }
 
+    /// How many boxes enclose a record.
+    fn nesting(&self, mut index: usize) -> usize {
+        let mut level = 0;
+        while let Some(parent) = self.nodes[index].parent {
+            level += 1;
+            index = parent;
+        }
+        level
+    }
+
+    /// Where a source line ended up: the first line box made from it, or from the next line that produced output.
     pub fn forward_sync(&self, target_line: u32, target_tag: u32) -> Option<&SyncTexNode> {
-        self.nodes.iter()
-            .filter(|n| n.tag == target_tag && n.line >= target_line)
-            .min_by_key(|n| n.line)
+        let (_, nodes) = self.lines.get(&target_tag)?.range(target_line..).next()?;
+        let pick = nodes.iter().copied().find(|&i| self.nodes[i].kind == NodeKind::HBox)
+            .or_else(|| nodes.iter().copied().find(|&i| self.nodes[i].kind.is_box()))
+            // Glyph level records have no extent; use the box around them
+            .or_else(|| nodes.first().and_then(|&i| self.nodes[i].parent))
+            .or_else(|| nodes.first().copied())?;
+        Some(&self.nodes[pick])
     }
 
+    /// The innermost box under a point of a page, or the record closest to it.
     pub fn backward_sync(&self, page: u32, x: f32, y: f32) -> Option<&SyncTexNode> {
-        self.nodes.iter()
-            .filter(|n| n.page == page)
-            .filter(|n| {
-                x >= n.x && x <= (n.x + n.width) &&
-                y >= (n.y - n.height) && y <= (n.y + n.depth)
-            })
-            .next()
-            .or_else(|| {
-                self.nodes.iter()
-                    .filter(|n| n.page == page)
-                    .min_by_key(|n| {
-                        let dx = x - n.x;
-                        let dy = y - n.y;
-                        (dx * dx + dy * dy) as i32
-                    })
-            })
+        let index = self.pages.get(&page)?;
+        let area = |n: &SyncTexNode| n.width.abs() * (n.height + n.depth);
+        let innermost = index.grid.get(&(cell(x), cell(y))).into_iter().flatten().copied()
+            .filter(|&i| self.nodes[i].contains(x, y))
+            .max_by(|&a, &b| {
+                self.nesting(a).cmp(&self.nesting(b))
+                    .then_with(|| area(&self.nodes[b]).total_cmp(&area(&self.nodes[a])))
+            });
+        if let Some(i) = innermost {
+            return Some(&self.nodes[i]);
+        }
+
+        self.nodes[index.nodes.clone()].iter().min_by(|a, b| {
+            let distance = |n: &SyncTexNode| (x - n.x).powi(2) + (y - n.y).powi(2);
+            distance(a).total_cmp(&distance(b))
+        })
     }
 }
 
+/// An input path relative to the project root, with `/` separators and no `.` components.
+fn relative_input(path: &Path, root_dir: Option<&str>) -> String {
+    if let Some(root) = root_dir.filter(|_| path.is_absolute()) {
+        let root = Path::new(root);
+        // Engines compile the copies in the build directory, which mirrors the project
+        if let Ok(relative) = path.strip_prefix(root) {
+            return normalize(source_path(relative));
+        }
+        let canonical = |p: &Path| std::fs::canonicalize(p).ok();
+        if let (Some(path), Some(root)) = (canonical(path), canonical(root)) {
+            if let Ok(relative) = path.strip_prefix(&root) {
+                return normalize(source_path(relative));
+            }
+        }
+    }
+    normalize(path)
+}
+
+fn normalize(path: &Path) -> String {
+    path.components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn cell(coordinate: f32) -> i32 {
+    (coordinate / CELL_SIZE).floor() as i32
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synctex_hierarchy_units_and_lookups() {
+        // 65781.76 sp per point; magnification 2000 doubles everything but the offsets
+        let data = "SyncTeX Version:1
+Input:1:/home/user/main.tex
+Input:2:C:/thesis/chapter.tex
+Output:pdf
+Magnification:2000
+Unit:1
+X Offset:0
+Y Offset:0
+Content:
+!120
+{1
+[1,3:3289088,3289088:32890880,3289088,0
+(1,5:3289088,1644544:16445440,657817,131563
+x1,5:3289088,1644544
+k1,6,4:3947000,1644544:65781
+)
+(2,10:3289088,3289088:16445440,657817,131563
+h2,11:6578176,3289088:657817,657817,0
+)
+]
+}1
+{2
+(1,20:3289088,3289088:16445440,657817,131563
+)
+}2
+Postamble:
+Count:9
+Post scriptum:
+X Offset:657817
+";
+        let mut stx = SyncTex::new();
+        stx.load_from_reader(std::io::Cursor::new(data)).unwrap();
+        assert_eq!(stx.inputs[&2], PathBuf::from("C:/thesis/chapter.tex"));
+        assert_eq!(stx.nodes.len(), 7);
+
+        // Offsets are not magnified: 10pt; coordinates are: 50sp-units * 2
+        let vbox = &stx.nodes[0];
+        assert_eq!(vbox.kind, NodeKind::VBox);
+        assert!((vbox.x - 110.0).abs() < 0.01 && (vbox.y - 100.0).abs() < 0.01);
+        assert!((vbox.width - 1000.0).abs() < 0.01);
+
+        let kern = &stx.nodes[3];
+        assert_eq!((kern.kind, kern.column, kern.parent), (NodeKind::Kern, 4, Some(1)));
+
+        // Forward: the line box, the next line with output, the box around a glyph level record
+        assert_eq!(stx.forward_sync(5, 1).unwrap().kind, NodeKind::HBox);
+        assert_eq!(stx.forward_sync(4, 1).unwrap().line, 5);
+        assert_eq!(stx.forward_sync(6, 1).unwrap().line, 5);
+        assert_eq!(stx.forward_sync(12, 1).unwrap().page, 2);
+        assert!(stx.forward_sync(1, 3).is_none());
+
+        // Backward: the void box inside the hbox inside the vbox
+        let hit = stx.backward_sync(1, 215.0, 90.0).unwrap();
+        assert_eq!((hit.kind, hit.tag, hit.line), (NodeKind::VoidHBox, 2, 11));
+        let hit = stx.backward_sync(1, 120.0, 50.0).unwrap();
+        assert_eq!((hit.kind, hit.line), (NodeKind::HBox, 5));
+        let hit = stx.backward_sync(1, 900.0, 90.0).unwrap();
+        assert_eq!((hit.kind, hit.line), (NodeKind::VBox, 3));
+        // Outside every box: the nearest record of that page
+        assert_eq!(stx.backward_sync(2, 5000.0, 5000.0).unwrap().line, 20);
+
+        // Inputs map to project files and back
+        let mut stx = SyncTex::new();
+        stx.load_from_reader(std::io::Cursor::new("Input:1:/work/thesis/./main.tex\nInput:2:./chapters/intro\nInput:3:/usr/share/texmf/tex/latex/base/size10.clo\nInput:4:/work/thesis/.sokutex/build/appendix.tex\n")).unwrap();
+        assert_eq!(stx.tag_for_file("main.tex", Some("/work/thesis")), Some(1));
+        assert_eq!(stx.tag_for_file("chapters/intro.tex", Some("/work/thesis")), Some(2));
+        assert_eq!(stx.tag_for_file("chapters/other.tex", Some("/work/thesis")), None);
+        assert_eq!(stx.file_for_tag(1, Some("/work/thesis")).as_deref(), Some("main.tex"));
+        assert_eq!(stx.file_for_tag(2, Some("/work/thesis")).as_deref(), Some("chapters/intro.tex"));
+        assert_eq!(stx.file_for_tag(2, None).as_deref(), Some("chapters/intro.tex"));
+        // Copies in the build directory stand for the sources
+        assert_eq!(stx.file_for_tag(4, Some("/work/thesis")).as_deref(), Some("appendix.tex"));
+        assert_eq!(stx.tag_for_file("appendix.tex", Some("/work/thesis")), Some(4));
+    }
+}
@@ -1,6 +1,20 @@
 use egui::{Color32, FontId, RichText, Visuals};
 use crate::dependencies::DependencyNode;
-
+use crate::diagnostics::{Diagnostic, DiagnosticKind, Severity};
+use crate::symbols::{Symbol, SymbolIndex, SymbolKind};
+use crate::viewer::{FitMode, NavHistory, PageLayout, PdfFilter, Place, PAGE_GAP};
+use crate::pdf_renderer::{LinkTarget, OutlineItem, PageChanges, PageLink};
+use crate::pdf_diff::{DiffMode, PageDiff};
+use crate::pdf_text::{PageText, TextHit};
+use crate::progress::{BuildEvent, BuildLog, BuildState};
+
+static INCLUDEGRAPHICS_REGEX: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+
+/// Horizontal space reserved for the macOS traffic lights drawn over the content view.
+#[cfg(target_os = "macos")]
+const TITLEBAR_INSET: f32 = 60.0;
+#[cfg(not(target_os = "macos"))]
+const TITLEBAR_INSET: f32 = 0.0;
 
 #[derive(PartialEq)]
 pub enum View {
@@ -14,6 +28,15 @@ pub struct ProjectItem {
     pub path: String,
 }
 
+/// A page diff uploaded for the diff view.
+struct DiffTextures {
+    page: usize,
+    old: egui::TextureHandle,
+    new: egui::TextureHandle,
+    overlay: egui::TextureHandle,
+    changed: usize,
+}
+
 #[derive(PartialEq)]
 pub enum DashTab {
     Dashboard,
@@ -29,17 +52,22 @@ pub struct Template {
     pub icon: String,
 }
 
-pub struct CompileError {
-    pub line: usize,
-    pub message: String,
-}
-
 #[derive(PartialEq, Clone, Copy)]
 pub enum LatexTheme {
     Midnight,
     SoftGray,
 }
 
+impl LatexTheme {
+    /// The PDF colour filter that goes with the theme.
+    pub fn pdf_filter(self) -> PdfFilter {
+        match self {
+            LatexTheme::Midnight => PdfFilter::Dark,
+            LatexTheme::SoftGray => PdfFilter::None,
+        }
+    }
+}
+
 pub struct Gui {
     pub view: View,
     pub theme: LatexTheme,
@@ -52,8 +80,13 @@ pub struct Gui {
     pub search_text: String,
     pub projects: Vec<ProjectItem>,
     pub templates: Vec<Template>,
-    pub errors: Vec<CompileError>,
+    pub errors: Vec<Diagnostic>,
+    pub build_diagnostics: Vec<Diagnostic>,
+    bib_diagnostics: Vec<Diagnostic>,
     pub show_errors: bool,
+    /// Progress and raw output of the latest build, streamed from the compiler daemon
+    pub build_log: BuildLog,
+    pub show_build_log: bool,
     pub show_command_palette: bool,
     pub command_search_text: String,
     pub last_compile_text: String,
@@ -62,7 +95,21 @@ pub struct Gui {
     pub draft_mode: bool,
     pub focus_mode: bool,
     pub compile_backend: crate::config::CompileBackend,
+    /// The project's engine setting; `% !TEX program` comments override it per file
+    pub tex_engine: crate::config::TexEngine,
+    /// The file the project compiles
+    pub root_file: String,
+    /// The root file chosen in the project settings; detected when `None`
+    pub root_setting: Option<String>,
+    /// Set when the engine or root setting changed and should be saved with the project
+    pub settings_changed: bool,
     pub dependency_tree: Option<DependencyNode>,
+    pub symbol_index: SymbolIndex,
+    pub references: Option<(String, Vec<Symbol>)>, // (name, occurrences)
+    pub rename_target: Option<Symbol>,
+    pub rename_input: String,
+    pub rename_error: Option<String>,
+    pub rename_edits: Option<Vec<(String, String)>>, // (file, new text)
     pub show_dependencies: bool,
     pub show_bib_panel: bool,
     pub bib_entries: Vec<crate::bib::BibEntry>,
@@ -71,16 +118,57 @@ pub struct Gui {
     pub sync_to_editor_request: Option<usize>, // line to scroll to
     pub sync_to_pdf_request: bool,
     pub pdf_scroll_target: Option<(usize, f32, f32)>, // (page, x, y)
-    pub pdf_highlight_rect: Option<egui::Rect>,
+    pub pdf_page: usize, // Page in the middle of the pane, 0-based
+    pub pdf_highlight_rect: Option<(usize, egui::Rect)>, // (page, rect in page points)
     pub active_file_path: String,
-    pub pdf_page_size: egui::Vec2, // Width, Height in points
     pub file_change_request: Option<String>,
+    pub history_request: Option<crate::undo::HistoryMove>,
+    pub tabs: Vec<crate::buffers::Tab>,
+    pub save_request: bool,
+    pub close_request: Option<(String, bool)>, // (file, save first)
+    pub close_prompt: Option<String>,
+    pub quit_prompt: bool,
+    pub quit_request: Option<bool>, // save all first
+    pub buffer_error: Option<String>,
+    pub recoveries: Vec<crate::recovery::Recovery>,
+    pub recovery_request: Option<(crate::recovery::Recovery, bool)>, // (copy, restore)
+    pub recovery_diff: Option<String>,
     pub cursor_override: Option<usize>,
     pub selection_override: Option<(usize, usize)>,
     
     // PDF Interactive State
-    pub pdf_zoom: f32,
-    pub pdf_pan: egui::Vec2,
+    pub pdf_zoom: f32, // Screen points per PDF point
+    pub pdf_pan: egui::Vec2, // Document-space point at the top left of the pane
+    pub pdf_fit: FitMode,
+    pub pdf_spreads: bool,
+    pub pdf_follow_changes: bool, // Scroll to the first page a rebuild changed
+    pdf_page_sizes: Vec<egui::Vec2>,
+    pdf_layout: PageLayout,
+    pdf_goto: String,
+    pub pdf_text_wanted: bool, // Search or selection needs the text layer
+    pdf_text: Vec<PageText>,
+    pdf_search_open: bool,
+    pdf_search_focus: bool,
+    pdf_search: String,
+    pdf_hits: Vec<TextHit>,
+    pdf_hit: usize,
+    pdf_selection: Option<((usize, usize), (usize, usize))>, // (anchor, head) as (page, char boundary)
+    pdf_selecting: bool,
+    pub show_pdf_nav: bool,
+    pub pdf_filter: PdfFilter,
+    show_pdf_diff: bool,
+    pdf_diff_mode: DiffMode,
+    pdf_diff_changes: Option<PageChanges>,
+    pdf_diff_page: usize,
+    /// A page to diff and the page it replaced, waiting for the main loop to render both builds
+    pub pdf_diff_wanted: Option<(usize, Option<usize>)>,
+    pdf_diff: Option<DiffTextures>,
+    pdf_diff_swipe: f32,
+    pdf_outline: Vec<OutlineItem>,
+    pdf_links: Vec<Vec<PageLink>>,
+    pdf_thumbnails: Vec<Option<egui::TextureHandle>>,
+    pdf_history: NavHistory,
+    pub pdf_scene: crate::renderer::PdfScene,
     pub vfs: Option<std::sync::Arc<crate::vfs::Vfs>>,
     pub image_cache: std::collections::HashMap<String, egui::TextureHandle>,
     pub prev_ui_text: String,
@@ -111,10 +199,12 @@ impl Gui {
                 Template { name: "Presentation".into(), description: "Beamer-based slide deck".into(), icon: "🖼".into() },
                 Template { name: "Lab Report".into(), description: "Structured data and formulas".into(), icon: "🧪".into() },
             ],
-            errors: vec![
-                CompileError { line: 12, message: "Undefined control sequence \\textbfz".into() },
-            ],
+            errors: Vec::new(),
+            build_diagnostics: Vec::new(),
+            bib_diagnostics: Vec::new(),
             show_errors: false,
+            build_log: BuildLog::default(),
+            show_build_log: false,
             show_command_palette: false,
             command_search_text: String::new(),
             last_compile_text: String::new(),
@@ -123,7 +213,17 @@ impl Gui {
             draft_mode: false,
             focus_mode: false,
             compile_backend: crate::config::CompileBackend::Tectonic,
+            tex_engine: crate::config::TexEngine::default(),
+            root_file: "main.tex".to_string(),
+            root_setting: None,
+            settings_changed: false,
             dependency_tree: None,
+            symbol_index: SymbolIndex::default(),
+            references: None,
+            rename_target: None,
+            rename_input: String::new(),
+            rename_error: None,
+            rename_edits: None,
             show_dependencies: true,
             show_bib_panel: false,
             bib_entries: Vec::new(),
@@ -132,25 +232,210 @@ impl Gui {
             sync_to_editor_request: None,
             sync_to_pdf_request: false,
             pdf_scroll_target: None,
+            pdf_page: 0,
             pdf_highlight_rect: None,
             active_file_path: "main.tex".to_string(),
-            pdf_page_size: egui::vec2(612.0, 792.0), // Default to Letter
             file_change_request: None,
+            history_request: None,
+            tabs: Vec::new(),
+            save_request: false,
+            close_request: None,
+            close_prompt: None,
+            quit_prompt: false,
+            quit_request: None,
+            buffer_error: None,
+            recoveries: Vec::new(),
+            recovery_request: None,
+            recovery_diff: None,
             cursor_override: None,
             selection_override: None,
             pdf_zoom: 1.0,
             pdf_pan: egui::vec2(0.0, 0.0),
+            pdf_fit: FitMode::Width,
+            pdf_spreads: false,
+            pdf_follow_changes: true,
+            pdf_page_sizes: Vec::new(),
+            pdf_layout: PageLayout::default(),
+            pdf_goto: String::new(),
+            pdf_text_wanted: false,
+            pdf_text: Vec::new(),
+            pdf_search_open: false,
+            pdf_search_focus: false,
+            pdf_search: String::new(),
+            pdf_hits: Vec::new(),
+            pdf_hit: 0,
+            pdf_selection: None,
+            pdf_selecting: false,
+            show_pdf_nav: false,
+            pdf_filter: LatexTheme::Midnight.pdf_filter(),
+            show_pdf_diff: false,
+            pdf_diff_mode: DiffMode::Overlay,
+            pdf_diff_changes: None,
+            pdf_diff_page: 0,
+            pdf_diff_wanted: None,
+            pdf_diff: None,
+            pdf_diff_swipe: 0.5,
+            pdf_outline: Vec::new(),
+            pdf_links: Vec::new(),
+            pdf_thumbnails: Vec::new(),
+            pdf_history: NavHistory::default(),
+            pdf_scene: crate::renderer::PdfScene::default(),
             vfs: None,
             image_cache: std::collections::HashMap::new(),
             prev_ui_text: String::new(),
         }
     }
 
-    pub fn refresh_bibliography(&mut self, bib_contents: Vec<String>) {
+    /// Reparse the project's `.bib` files, given as `(file name, content)`, and report their syntax errors.
+    pub fn refresh_bibliography(&mut self, bib_files: Vec<(String, String)>) {
         self.bib_entries.clear();
-        for content in bib_contents {
-            let mut entries = crate::bib::BibParser::parse(&content);
-            self.bib_entries.append(&mut entries);
+        self.bib_diagnostics.clear();
+        for (file_name, content) in bib_files {
+            let bib = crate::bib::BibParser::parse_file(&content);
+            self.bib_entries.append(&mut bib.entries());
+            self.bib_diagnostics.extend(bib.errors.into_iter().map(|e| Diagnostic {
+                severity: Severity::Error,
+                kind: DiagnosticKind::BibSyntax,
+                file: Some(file_name.clone()),
+                line: Some(e.line),
+                message: e.message,
+            }));
+        }
+        self.refresh_errors();
+    }
+
+    pub fn set_diagnostics(&mut self, diagnostics: Vec<Diagnostic>) {
+        let failed = diagnostics.iter().any(|d| d.severity == Severity::Error);
+        // Surface the panel when a build breaks, but never force it closed
+        if failed {
+            self.show_errors = true;
+        }
+        self.build_diagnostics = diagnostics;
+        self.refresh_errors();
+    }
+
+    pub fn apply_build_event(&mut self, event: BuildEvent) {
+        self.build_log.apply(event);
+        self.compile_status = self.build_log.status();
+    }
+
+    pub fn set_dependency_tree(&mut self, tree: DependencyNode) {
+        self.symbol_index = SymbolIndex::from_tree(&tree);
+        self.dependency_tree = Some(tree);
+        self.refresh_errors();
+    }
+
+    /// Merge build diagnostics with the label warnings from the symbol index.
+    fn refresh_errors(&mut self) {
+        let mut errors = self.build_diagnostics.clone();
+        errors.extend(self.bib_diagnostics.iter().cloned());
+        for warning in self.symbol_index.diagnostics() {
+            // The TeX log reports undefined references too; keep just one of them
+            if !errors.iter().any(|d| d.kind == warning.kind) {
+                errors.push(warning);
+            }
+        }
+        errors.sort_by_key(|d| d.severity);
+        self.errors = errors;
+    }
+
+    /// Jump the editor to a file and line, switching files if needed.
+    fn jump_to_location(&mut self, file: Option<String>, line: Option<usize>) {
+        if let Some(file) = file {
+            if file != self.active_file_path {
+                let known = self.vfs.as_ref().is_some_and(|vfs| vfs.read_file(&file).is_some());
+                if !known {
+                    return;
+                }
+                self.file_change_request = Some(file);
+            }
+        }
+        if let Some(line) = line {
+            self.sync_to_editor_request = Some(line);
+        }
+    }
+
+    /// The 1-based line and byte column of a character index in the editor buffer.
+    fn cursor_location(&self, char_idx: usize) -> (usize, usize) {
+        let byte_idx = self.ui_text.char_indices().nth(char_idx).map_or(self.ui_text.len(), |(i, _)| i);
+        let before = &self.ui_text[..byte_idx];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (before.matches('\n').count() + 1, byte_idx - line_start)
+    }
+
+    /// The symbol under the cursor, or the definition of the user command under it.
+    fn symbol_under_cursor(&self, char_idx: usize) -> Option<Symbol> {
+        let (line, column) = self.cursor_location(char_idx);
+        if let Some(symbol) = self.symbol_index.symbol_at(&self.active_file_path, line, column) {
+            return Some(symbol.clone());
+        }
+
+        let text = self.ui_text.lines().nth(line - 1)?;
+        self.symbol_index.command_definition(crate::symbols::command_at(text, column)?).cloned()
+    }
+
+    /// Image data for the `\includegraphics` in the float that holds a label, if any.
+    fn figure_for_label(&self, label: &Symbol) -> Option<Vec<u8>> {
+        let vfs = self.vfs.as_ref()?;
+        let content = vfs.read_file(&label.file_name)?;
+        let content = String::from_utf8_lossy(&content);
+        let re = INCLUDEGRAPHICS_REGEX.get_or_init(|| regex::Regex::new(r"\\includegraphics\s*(?:\[[^\]]*\])?\s*\{([^}]+)\}").unwrap());
+
+        // Labels follow the caption, so walk up to the start of the float
+        let lines: Vec<&str> = content.lines().take(label.line).collect();
+        let mut path = None;
+        for line in lines.iter().rev().take(15) {
+            if let Some(cap) = re.captures(line) {
+                path = Some(cap[1].trim().to_string());
+                break;
+            }
+            if line.contains("\\begin{") {
+                break;
+            }
+        }
+        let path = path?;
+        ["", ".png", ".jpg", ".jpeg"].iter().find_map(|ext| vfs.read_file(&format!("{}{}", path, ext)))
+    }
+
+    fn go_to_definition(&mut self, char_idx: usize) {
+        let definition = self.symbol_under_cursor(char_idx).and_then(|s| self.symbol_index.definition(&s).cloned());
+        if let Some(definition) = definition {
+            self.jump_to_location(Some(definition.file_name), Some(definition.line));
+        }
+    }
+
+    fn find_references(&mut self, char_idx: usize) {
+        if let Some(symbol) = self.symbol_under_cursor(char_idx) {
+            let occurrences = self.symbol_index.references(&symbol).into_iter().cloned().collect();
+            self.references = Some((symbol.name, occurrences));
+        }
+    }
+
+    fn start_rename(&mut self, char_idx: usize) {
+        if let Some(symbol) = self.symbol_under_cursor(char_idx).filter(|s| s.kind != SymbolKind::CommandDefinition) {
+            self.rename_input = symbol.name.clone();
+            self.rename_error = None;
+            self.rename_target = Some(symbol);
+        }
+    }
+
+    /// Apply the pending rename to every file in the project, or report why it is unsafe.
+    fn apply_rename(&mut self) {
+        let (Some(symbol), Some(vfs)) = (self.rename_target.clone(), self.vfs.clone()) else {
+            return;
+        };
+        // The index may predate the latest keystrokes in the open buffer
+        vfs.write_file(&self.active_file_path, self.ui_text.as_bytes().to_vec());
+
+        match self.symbol_index.rename(&symbol, self.rename_input.trim(), &vfs) {
+            Ok(edits) => {
+                // Applied through the buffers, so files in other tabs or not open at all are tracked as unsaved
+                self.rename_edits = Some(edits);
+                self.rename_target = None;
+                self.references = None;
+                self.compile_requested = true;
+            }
+            Err(e) => self.rename_error = Some(e),
         }
     }
 
@@ -203,10 +488,13 @@ impl Gui {
         ctx.set_fonts(fonts);
     }
 
-    pub fn draw(&mut self, ctx: &egui::Context, pdf_tex_id: Option<egui::TextureId>) {
+    pub fn draw(&mut self, ctx: &egui::Context) {
         if ctx.input(|i| i.modifiers.command && i.key_pressed(egui::Key::K)) {
             self.show_command_palette = !self.show_command_palette;
         }
+        if self.view == View::Editor && ctx.input(|i| i.modifiers.command && i.key_pressed(egui::Key::F)) {
+            self.open_pdf_search();
+        }
 
         // Auto-Compile Detection (Near-instant latency)
         if self.ui_text != self.last_compile_text {
@@ -215,12 +503,158 @@ impl Gui {
 
         match self.view {
             View::Dashboard => self.draw_dashboard(ctx),
-            View::Editor => self.draw_editor(ctx, pdf_tex_id),
+            View::Editor => self.draw_editor(ctx),
+        }
+
+        if self.show_pdf_diff {
+            self.draw_pdf_diff(ctx);
         }
 
         if self.show_command_palette {
             self.draw_command_palette(ctx);
         }
+
+        self.draw_unsaved_prompt(ctx);
+        self.draw_recovery_prompt(ctx);
+    }
+
+    /// Offer the unsaved changes a previous session left in the recovery directory.
+    fn draw_recovery_prompt(&mut self, ctx: &egui::Context) {
+        if self.recoveries.is_empty() || self.recovery_request.is_some() {
+            return;
+        }
+
+        let mut choice = None;
+        egui::Window::new("Recover unsaved changes")
+            .collapsible(false)
+            .resizable(true)
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 80.0))
+            .show(ctx, |ui| {
+                ui.label(RichText::new("SokuTeX was closed without saving these files:").size(12.0));
+                ui.add_space(6.0);
+                for recovery in &self.recoveries {
+                    ui.horizontal(|ui| {
+                        ui.label(RichText::new(&recovery.file_name).size(11.0).monospace().color(Color32::WHITE));
+                        if ui.small_button("Restore").clicked() {
+                            choice = Some((recovery.clone(), true));
+                        }
+                        let showing = self.recovery_diff.as_ref() == Some(&recovery.file_name);
+                        if ui.small_button(if showing { "Hide Diff" } else { "Diff" }).clicked() {
+                            self.recovery_diff = if showing { None } else { Some(recovery.file_name.clone()) };
+                        }
+                        if ui.small_button("Discard").clicked() {
+                            choice = Some((recovery.clone(), false));
+                        }
+                    });
+                    if self.recovery_diff.as_ref() == Some(&recovery.file_name) {
+                        egui::ScrollArea::vertical().id_source(("recovery_diff", &recovery.file_name)).max_height(300.0).show(ui, |ui| {
+                            for line in crate::recovery::line_diff(&recovery.on_disk, &recovery.recovered) {
+                                let (prefix, text, color) = match line {
+                                    crate::recovery::DiffLine::Same(t) => ("  ", t, Color32::from_rgb(100, 110, 120)),
+                                    crate::recovery::DiffLine::Removed(t) => ("- ", t, Color32::from_rgb(220, 90, 100)),
+                                    crate::recovery::DiffLine::Added(t) => ("+ ", t, Color32::from_rgb(110, 200, 130)),
+                                };
+                                ui.label(RichText::new(format!("{}{}", prefix, text)).size(11.0).monospace().color(color));
+                            }
+                        });
+                    }
+                }
+            });
+
+        if let Some((recovery, restore)) = choice {
+            self.recoveries.retain(|r| r.file_name != recovery.file_name);
+            self.recovery_request = Some((recovery, restore));
+        }
+    }
+
+    /// Ask what to do with unsaved changes before closing a tab or quitting.
+    fn draw_unsaved_prompt(&mut self, ctx: &egui::Context) {
+        let files: Vec<String> = if self.quit_prompt {
+            self.tabs.iter().filter(|t| t.dirty).map(|t| t.file_name.clone()).collect()
+        } else if let Some(ref file) = self.close_prompt {
+            vec![file.clone()]
+        } else {
+            return;
+        };
+
+        let mut choice = None;
+        egui::Window::new("Unsaved changes")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 80.0))
+            .show(ctx, |ui| {
+                ui.label(RichText::new("Save changes before closing?").size(12.0).strong());
+                for file in &files {
+                    ui.label(RichText::new(file).size(11.0).monospace().color(Color32::from_rgb(160, 170, 180)));
+                }
+                if let Some(ref error) = self.buffer_error {
+                    ui.label(RichText::new(error).size(11.0).color(Color32::from_rgb(220, 90, 100)));
+                }
+                ui.add_space(6.0);
+                ui.horizontal(|ui| {
+                    if ui.button("Save").clicked() {
+                        choice = Some(Some(true));
+                    }
+                    if ui.button("Don't Save").clicked() {
+                        choice = Some(Some(false));
+                    }
+                    if ui.button("Cancel").clicked() {
+                        choice = Some(None);
+                    }
+                });
+            });
+        match choice {
+            Some(Some(save)) if self.quit_prompt => self.quit_request = Some(save),
+            Some(Some(save)) => self.close_request = self.close_prompt.clone().map(|file| (file, save)),
+            Some(None) => {
+                self.quit_prompt = false;
+                self.close_prompt = None;
+                self.buffer_error = None;
+            }
+            None => {}
+        }
+    }
+
+    fn draw_tabs(&mut self, ui: &mut egui::Ui) {
+        let closable = self.tabs.len() > 1;
+        let mut close = None;
+        egui::ScrollArea::horizontal().id_source("buffer_tabs").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.add_space(16.0);
+                ui.spacing_mut().item_spacing.x = 2.0;
+                for tab in &self.tabs {
+                    let active = tab.file_name == self.active_file_path;
+                    let name = tab.file_name.rsplit('/').next().unwrap_or(&tab.file_name);
+                    let label = if tab.dirty { format!("{} \u{25cf}", name) } else { name.to_string() };
+                    let color = if active { Color32::WHITE } else { Color32::from_rgb(100, 110, 120) };
+                    let resp = ui.selectable_label(active, RichText::new(label).size(11.0).color(color))
+                        .on_hover_text(&tab.file_name);
+                    if resp.clicked() && !active {
+                        self.file_change_request = Some(tab.file_name.clone());
+                    }
+                    if closable && ui.small_button(RichText::new("\u{d7}").size(10.0)).clicked() {
+                        close = Some(tab.clone());
+                    }
+                    ui.add_space(8.0);
+                }
+            });
+        });
+        if let Some(tab) = close {
+            if tab.dirty {
+                self.close_prompt = Some(tab.file_name);
+                self.buffer_error = None;
+            } else {
+                self.close_request = Some((tab.file_name, false));
+            }
+        }
+        if let Some(ref error) = self.buffer_error {
+            if self.close_prompt.is_none() && !self.quit_prompt {
+                ui.horizontal(|ui| {
+                    ui.add_space(16.0);
+                    ui.label(RichText::new(error).size(10.0).color(Color32::from_rgb(220, 90, 100)));
+                });
+            }
+        }
     }
 
     fn draw_command_palette(&mut self, ctx: &egui::Context) {
@@ -274,8 +708,28 @@ impl Gui {
                             self.compile_status = "BUSY".to_string();
                             self.show_command_palette = false;
                         }
+                        if self.command_item(ui, "🔎 Search PDF", "Find text in the preview").clicked() {
+                            self.open_pdf_search();
+                            self.show_command_palette = false;
+                        }
+                        if self.command_item(ui, "🆚 Compare with Previous Build", "Show what changed on each page").clicked() {
+                            self.open_pdf_diff();
+                            self.show_command_palette = false;
+                        }
                         self.command_item(ui, "📚 Open Library", "Browse your LaTeX collection");
-                        self.command_item(ui, "🎨 Change Theme", "Switch high-contrast or light mode");
+                        if self.command_item(ui, "🎨 Change Theme", "Switch high-contrast or light mode").clicked() {
+                            self.set_theme(if self.theme == LatexTheme::Midnight { LatexTheme::SoftGray } else { LatexTheme::Midnight });
+                            self.show_command_palette = false;
+                        }
+                        if self.command_item(ui, "🌗 Toggle PDF Dark Mode", "Invert the preview without re-rendering").clicked() {
+                            self.pdf_filter = if self.pdf_filter == PdfFilter::Dark { PdfFilter::None } else { PdfFilter::Dark };
+                            self.show_command_palette = false;
+                        }
+                        let subtitle = format!("Now: {}, next: {}", self.pdf_filter.label(), self.pdf_filter.next().label());
+                        if self.command_item(ui, "🖌 Cycle PDF Color Filter", &subtitle).clicked() {
+                            self.pdf_filter = self.pdf_filter.next();
+                            self.show_command_palette = false;
+                        }
                         ui.add_space(8.0);
                     });
                 });
@@ -325,7 +779,7 @@ impl Gui {
                     .inner_margin(egui::Margin { left: 16.0, right: 16.0, top: 12.0, bottom: 4.0 })
                     .show(ui, |ui| {
                         ui.horizontal(|ui| {
-                            ui.add_space(64.0); // Clear traffic lights
+                            ui.add_space(TITLEBAR_INSET + 4.0); // Clear traffic lights
                             ui.label(RichText::new("SokuTeX")
                                 .font(FontId::new(16.0, egui::FontFamily::Name("logo_font".into())))
                                 .color(Color32::WHITE)
@@ -390,14 +844,38 @@ impl Gui {
                     
                     ui.horizontal(|ui| {
                         if self.theme_option(ui, "Midnight", self.theme == LatexTheme::Midnight) {
-                            self.theme = LatexTheme::Midnight;
+                            self.set_theme(LatexTheme::Midnight);
                         }
                         ui.add_space(12.0);
                         if self.theme_option(ui, "Soft Gray", self.theme == LatexTheme::SoftGray) {
-                            self.theme = LatexTheme::SoftGray;
+                            self.set_theme(LatexTheme::SoftGray);
                         }
                     });
 
+                    ui.add_space(32.0);
+                    ui.label(RichText::new("PDF COLORS").size(10.0).color(Color32::from_rgb(60, 65, 75)));
+                    ui.add_space(12.0);
+
+                    ui.horizontal(|ui| {
+                        for filter in PdfFilter::ALL {
+                            let selected = std::mem::discriminant(&filter) == std::mem::discriminant(&self.pdf_filter);
+                            if self.theme_option(ui, filter.label(), selected) && !selected {
+                                self.pdf_filter = filter;
+                            }
+                            ui.add_space(12.0);
+                        }
+                    });
+                    if let PdfFilter::Custom { paper, ink } = &mut self.pdf_filter {
+                        ui.add_space(12.0);
+                        ui.horizontal(|ui| {
+                            ui.label(RichText::new("Paper").size(11.0).color(Color32::from_rgb(150, 155, 165)));
+                            ui.color_edit_button_rgb(paper);
+                            ui.add_space(16.0);
+                            ui.label(RichText::new("Ink").size(11.0).color(Color32::from_rgb(150, 155, 165)));
+                            ui.color_edit_button_rgb(ink);
+                        });
+                    }
+
                     ui.add_space(32.0);
                     ui.label(RichText::new("EDITOR BEHAVIOR").size(10.0).color(Color32::from_rgb(60, 65, 75)));
                     ui.add_space(12.0);
@@ -412,6 +890,12 @@ impl Gui {
         });
     }
 
+    /// Switching theme also brings the PDF colours that go with it.
+    fn set_theme(&mut self, theme: LatexTheme) {
+        self.theme = theme;
+        self.pdf_filter = theme.pdf_filter();
+    }
+
     fn theme_option(&self, ui: &mut egui::Ui, label: &str, selected: bool) -> bool {
         let (bg, border) = if selected {
             (Color32::from_rgb(30, 35, 45), Color32::from_rgb(60, 100, 200))
@@ -679,7 +1163,7 @@ impl Gui {
         response
     }
 
-    fn draw_editor(&mut self, ctx: &egui::Context, pdf_tex_id: Option<egui::TextureId>) {
+    fn draw_editor(&mut self, ctx: &egui::Context) {
         egui::SidePanel::left("editor_panel")
             .min_width(350.0)
             .frame(egui::Frame::none().fill(Color32::from_rgb(10, 12, 14)))
@@ -691,7 +1175,7 @@ impl Gui {
                     .show(ui, |ui| {
                         ui.horizontal(|ui| {
                             ui.spacing_mut().item_spacing.x = 24.0;
-                            ui.add_space(60.0); // Offset for macOS traffic lights
+                            ui.add_space(TITLEBAR_INSET); // Offset for macOS traffic lights
 
                             // Visuals for buttons - integrated into the title bar
                             ui.visuals_mut().widgets.inactive.bg_fill = Color32::from_rgb(30, 32, 35);
@@ -745,17 +1229,78 @@ impl Gui {
                                     ui.selectable_value(&mut self.compile_backend, CompileBackend::Latexmk, "Latexmk");
                                 });
 
+                            egui::ComboBox::from_id_source("engine_selector")
+                                .selected_text(RichText::new(self.tex_engine.label()).size(9.0).strong())
+                                .width(80.0)
+                                .show_ui(ui, |ui| {
+                                    for engine in crate::config::TexEngine::ALL {
+                                        if ui.selectable_value(&mut self.tex_engine, engine, engine.label()).changed() {
+                                            self.settings_changed = true;
+                                            self.compile_requested = true;
+                                        }
+                                    }
+                                })
+                                .response
+                                .on_hover_text("Project engine; a `% !TEX program` comment in the file takes precedence");
+
+                            egui::ComboBox::from_id_source("root_selector")
+                                .selected_text(RichText::new(self.root_file.rsplit('/').next().unwrap_or(&self.root_file)).size(9.0).strong())
+                                .width(90.0)
+                                .show_ui(ui, |ui| {
+                                    let candidates = self.vfs.as_deref().map(crate::root_file::candidates).unwrap_or_default();
+                                    if ui.selectable_value(&mut self.root_setting, None, "Detect automatically").changed() {
+                                        self.settings_changed = true;
+                                    }
+                                    for file in candidates {
+                                        if ui.selectable_value(&mut self.root_setting, Some(file.clone()), file).changed() {
+                                            self.settings_changed = true;
+                                        }
+                                    }
+                                })
+                                .response
+                                .on_hover_text(format!("Root file: {}", self.root_file));
+
                             if ui.button(RichText::new("TREE").size(9.0).strong()).clicked() {
                                 self.show_dependencies = !self.show_dependencies;
                             }
 
+                            if ui.button(RichText::new("PAGES").size(9.0).strong()).clicked() {
+                                self.show_pdf_nav = !self.show_pdf_nav;
+                            }
+
+                            if ui.button(RichText::new("DIFF").size(9.0).strong()).clicked() {
+                                if self.show_pdf_diff {
+                                    self.show_pdf_diff = false;
+                                } else {
+                                    self.open_pdf_diff();
+                                }
+                            }
+
                             if ui.button(RichText::new("BIB").size(9.0).strong()).clicked() {
                                 self.show_bib_panel = !self.show_bib_panel;
                             }
+
+                            let diag_text = format!("DIAG {}", self.errors.len());
+                            if ui.button(RichText::new(diag_text).size(9.0).strong()).clicked() {
+                                self.show_errors = !self.show_errors;
+                            }
+
+                            if ui.button(RichText::new("LOG").size(9.0).strong()).clicked() {
+                                self.show_build_log = !self.show_build_log;
+                            }
+
+                            ui.separator();
+                            let status_color = match self.build_log.state {
+                                BuildState::Failed { .. } => Color32::from_rgb(220, 90, 100),
+                                BuildState::Queued | BuildState::Running => Color32::from_rgb(90, 160, 220),
+                                BuildState::Idle | BuildState::Finished(_) => Color32::from_rgb(100, 110, 120),
+                            };
+                            ui.label(RichText::new(&self.compile_status).size(9.0).color(status_color).strong());
                         });
                     });
                 
                 ui.add_space(4.0);
+                self.draw_tabs(ui);
 
                 if self.show_dependencies {
                     egui::SidePanel::left("dependency_tree_panel")
@@ -793,6 +1338,17 @@ impl Gui {
                         });
                 }
 
+                if self.show_pdf_nav {
+                    egui::SidePanel::left("pdf_nav_panel")
+                        .resizable(true)
+                        .default_width(170.0)
+                        .width_range(120.0..=320.0)
+                        .frame(egui::Frame::none().fill(Color32::from_rgb(13, 15, 17)))
+                        .show_inside(ui, |ui| {
+                            self.draw_pdf_nav(ui);
+                        });
+                }
+
                 if self.show_errors {
                     egui::TopBottomPanel::bottom("error_gutter")
                         .resizable(true)
@@ -806,31 +1362,147 @@ impl Gui {
                             });
                             ui.add_space(8.0);
                             
+                            let mut jump = None;
                             egui::ScrollArea::vertical().show(ui, |ui| {
-                                for error in &self.errors {
+                                if self.errors.is_empty() {
                                     ui.horizontal(|ui| {
                                         ui.add_space(16.0);
-                                        ui.label(RichText::new(format!("L{}", error.line)).color(Color32::from_rgb(100, 110, 120)).font(FontId::monospace(11.0)));
+                                        ui.label(RichText::new("No problems reported").size(11.0).color(Color32::from_rgb(60, 65, 75)));
+                                    });
+                                }
+                                for error in &self.errors {
+                                    let (tag, tag_color) = match error.severity {
+                                        Severity::Error => ("ERR", Color32::from_rgb(220, 90, 100)),
+                                        Severity::Warning => ("WARN", Color32::from_rgb(220, 170, 80)),
+                                        Severity::Info => ("BOX", Color32::from_rgb(100, 110, 120)),
+                                    };
+                                    let response = ui.horizontal(|ui| {
+                                        ui.add_space(16.0);
+                                        ui.label(RichText::new(tag).size(9.0).color(tag_color).strong());
+                                        ui.add_space(4.0);
+                                        ui.label(RichText::new(error.location()).color(Color32::from_rgb(100, 110, 120)).font(FontId::monospace(11.0)));
                                         ui.add_space(8.0);
                                         ui.label(RichText::new(&error.message).color(Color32::from_rgb(200, 210, 220)).font(FontId::proportional(12.0)));
-                                    });
+                                    }).response.interact(egui::Sense::click());
+
+                                    if response.hovered() {
+                                        ui.output_mut(|o| o.cursor_icon = egui::CursorIcon::PointingHand);
+                                    }
+                                    if response.clicked() {
+                                        jump = Some((error.file.clone(), error.line));
+                                    }
                                     ui.add_space(4.0);
                                 }
                             });
+                            if let Some((file, line)) = jump {
+                                self.jump_to_location(file, line);
+                            }
                         });
                 }
                 
+                if self.show_build_log {
+                    egui::TopBottomPanel::bottom("build_log_panel")
+                        .resizable(true)
+                        .default_height(140.0)
+                        .frame(egui::Frame::none().fill(Color32::from_rgb(13, 15, 17)))
+                        .show_inside(ui, |ui| {
+                            ui.add_space(8.0);
+                            ui.horizontal(|ui| {
+                                ui.add_space(16.0);
+                                ui.label(RichText::new("BUILD LOG").size(10.0).color(Color32::from_rgb(100, 110, 120)).strong());
+                                ui.add_space(8.0);
+                                ui.label(RichText::new(&self.compile_status).size(10.0).color(Color32::from_rgb(60, 65, 75)));
+                            });
+                            ui.add_space(8.0);
+
+                            let font = FontId::monospace(11.0);
+                            let row_height = ui.fonts(|f| f.row_height(&font));
+                            // Follow the output as it streams in, unless scrolled up to read
+                            egui::ScrollArea::vertical()
+                                .stick_to_bottom(true)
+                                .auto_shrink([false, false])
+                                .show_rows(ui, row_height, self.build_log.lines.len(), |ui, rows| {
+                                    for line in self.build_log.lines.range(rows) {
+                                        ui.horizontal(|ui| {
+                                            ui.add_space(16.0);
+                                            ui.label(RichText::new(line).font(font.clone()).color(Color32::from_rgb(160, 170, 180)));
+                                        });
+                                    }
+                                });
+                        });
+                }
+
+                if let Some((name, occurrences)) = self.references.clone() {
+                    egui::TopBottomPanel::bottom("references_panel")
+                        .resizable(true)
+                        .default_height(100.0)
+                        .frame(egui::Frame::none().fill(Color32::from_rgb(13, 15, 17)))
+                        .show_inside(ui, |ui| {
+                            ui.add_space(8.0);
+                            ui.horizontal(|ui| {
+                                ui.add_space(16.0);
+                                ui.label(RichText::new(format!("REFERENCES: {}", name)).size(10.0).color(Color32::from_rgb(100, 110, 120)).strong());
+                                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                                    ui.add_space(16.0);
+                                    if ui.button(RichText::new("CLOSE").size(9.0).strong()).clicked() {
+                                        self.references = None;
+                                    }
+                                });
+                            });
+                            ui.add_space(8.0);
+
+                            let mut jump = None;
+                            egui::ScrollArea::vertical().show(ui, |ui| {
+                                for occurrence in &occurrences {
+                                    let kind = match occurrence.kind {
+                                        SymbolKind::Label | SymbolKind::BibEntry | SymbolKind::CommandDefinition => "DEF",
+                                        SymbolKind::Reference | SymbolKind::Citation => "USE",
+                                    };
+                                    let response = ui.horizontal(|ui| {
+                                        ui.add_space(16.0);
+                                        ui.label(RichText::new(kind).size(9.0).color(Color32::from_rgb(90, 160, 220)).strong());
+                                        ui.add_space(4.0);
+                                        ui.label(RichText::new(format!("{}:{}", occurrence.file_name, occurrence.line)).color(Color32::from_rgb(100, 110, 120)).font(FontId::monospace(11.0)));
+                                    }).response.interact(egui::Sense::click());
+
+                                    if response.hovered() {
+                                        ui.output_mut(|o| o.cursor_icon = egui::CursorIcon::PointingHand);
+                                    }
+                                    if response.clicked() {
+                                        jump = Some((occurrence.file_name.clone(), occurrence.line));
+                                    }
+                                    ui.add_space(4.0);
+                                }
+                            });
+                            if let Some((file, line)) = jump {
+                                self.jump_to_location(Some(file), Some(line));
+                            }
+                        });
+                }
+
                 egui::ScrollArea::vertical()
-                    .id_source("editor_scroll")
+                    .id_source(("editor_scroll", self.active_file_path.clone()))
                     .show(ui, |ui| {
                         let mut tab_pressed = false;
                         ui.input_mut(|i| {
                             if i.consume_key(egui::Modifiers::NONE, egui::Key::Tab) {
                                 tab_pressed = true;
                             }
+                            if i.consume_key(egui::Modifiers::COMMAND, egui::Key::S) {
+                                self.save_request = true;
+                            }
+                            // Keep the text box's own undoer out of it; main applies these to the editor history
+                            if i.consume_key(egui::Modifiers::COMMAND | egui::Modifiers::SHIFT, egui::Key::Z)
+                                || i.consume_key(egui::Modifiers::COMMAND, egui::Key::Y) {
+                                self.history_request = Some(crate::undo::HistoryMove::Redo);
+                            } else if i.consume_key(egui::Modifiers::COMMAND, egui::Key::Z) {
+                                self.history_request = Some(crate::undo::HistoryMove::Undo);
+                            }
                         });
 
+                        // Keyed per file so every tab keeps its own cursor and selection
                         let edit_output = egui::TextEdit::multiline(&mut self.ui_text)
+                            .id_source(("editor_text", self.active_file_path.clone()))
                             .font(FontId::monospace(13.0))
                             .frame(false)
                             .margin(egui::Margin::same(32.0))
@@ -877,68 +1549,70 @@ impl Gui {
                                                             .inner_margin(egui::Margin::same(12.0))
                                                             .show(ui, |ui| {
                                                                 ui.set_max_width(320.0);
-                                                                ui.label(RichText::new(entry.title.as_deref().unwrap_or("")).strong().color(Color32::WHITE));
+                                                                ui.label(RichText::new(entry.plain("title").unwrap_or_default()).strong().color(Color32::WHITE));
                                                                 ui.add_space(4.0);
-                                                                ui.label(RichText::new(entry.author.as_deref().unwrap_or("")).color(Color32::from_rgb(150, 160, 170)));
+                                                                ui.label(RichText::new(entry.plain("author").unwrap_or_default()).color(Color32::from_rgb(150, 160, 170)));
+                                                                if let Some(venue) = entry.venue() {
+                                                                    ui.label(RichText::new(venue).italics().size(11.0).color(Color32::from_rgb(130, 140, 150)));
+                                                                }
+                                                                if let Some(doi) = entry.field("doi") {
+                                                                    ui.label(RichText::new(format!("doi:{}", doi)).size(10.0).color(Color32::from_rgb(60, 120, 220)));
+                                                                }
                                                                 ui.add_space(8.0);
                                                                 ui.label(RichText::new(&entry.key).size(10.0).color(Color32::from_rgb(100, 110, 120)));
                                                             });
                                                     });
                                                 }
-                                            } else if hovered_text.starts_with("\\ref{") {
-                                                let label = &hovered_text[5..hovered_text.len()-1];
-                                                if let Some(ref vfs) = self.vfs {
-                                                    if !self.image_cache.contains_key(label) {
-                                                        let search_term = label.replace("fig:", "");
-                                                        let mut image_data = None;
-                                                        for entry in vfs.get_all_files().iter() {
-                                                            let file_name = entry.key();
-                                                            if (file_name.ends_with(".png") || file_name.ends_with(".jpg") || file_name.ends_with(".jpeg")) && file_name.contains(&search_term) {
-                                                                image_data = Some(entry.value().clone());
-                                                                break;
-                                                            }
-                                                        }
-                                                        
+                                            } else if let Some(reference) = self.symbol_under_cursor(char_idx).filter(|s| s.kind == SymbolKind::Reference) {
+                                                if let Some(definition) = self.symbol_index.definition(&reference).cloned() {
+                                                    if !self.image_cache.contains_key(&reference.name) {
                                                         let dummy_img = egui::ColorImage::from_rgba_unmultiplied([1, 1], &[0, 0, 0, 0]);
-                                                        let mut texture = ui.ctx().load_texture(format!("dummy_{}", label), dummy_img, egui::TextureOptions::LINEAR);
-                                                        
-                                                        if let Some(data) = image_data {
+                                                        let mut texture = ui.ctx().load_texture(format!("dummy_{}", reference.name), dummy_img, egui::TextureOptions::LINEAR);
+
+                                                        if let Some(data) = self.figure_for_label(&definition) {
                                                             if let Ok(img) = image::load_from_memory(&data) {
                                                                 let size = [img.width() as _, img.height() as _];
                                                                 let image_buffer = img.to_rgba8();
                                                                 let pixels = image_buffer.as_flat_samples();
                                                                 let slice = pixels.as_slice();
                                                                 let color_image = egui::ColorImage::from_rgba_unmultiplied(size, slice);
-                                                                texture = ui.ctx().load_texture(format!("img_{}", label), color_image, egui::TextureOptions::LINEAR);
+                                                                texture = ui.ctx().load_texture(format!("img_{}", reference.name), color_image, egui::TextureOptions::LINEAR);
                                                             }
                                                         }
-                                                        self.image_cache.insert(label.to_string(), texture);
+                                                        self.image_cache.insert(reference.name.clone(), texture);
                                                     }
-                                                    
-                                                    if let Some(texture) = self.image_cache.get(label) {
-                                                        if texture.size() != [1, 1] { // not dummy
-                                                            egui::show_tooltip_at_pointer(ui.ctx(), resp.id.with("hover_ref"), |ui| {
-                                                                egui::Frame::none()
-                                                                    .fill(Color32::from_rgb(25, 28, 35))
-                                                                    .stroke(egui::Stroke::new(1.0, Color32::from_rgb(45, 50, 60)))
-                                                                    .rounding(4.0)
-                                                                    .inner_margin(egui::Margin::same(8.0))
-                                                                    .show(ui, |ui| {
-                                                                        let size = texture.size();
-                                                                        let aspect = size[0] as f32 / size[1] as f32;
-                                                                        let mut w = 250.0;
-                                                                        let mut h = w / aspect;
-                                                                        if h > 200.0 {
-                                                                            h = 200.0;
-                                                                            w = h * aspect;
-                                                                        }
-                                                                        ui.image(egui::load::SizedTexture::new(texture.id(), egui::vec2(w, h)));
-                                                                        ui.add_space(4.0);
-                                                                        ui.label(RichText::new(format!("Figure: {}", label)).size(10.0).color(Color32::from_rgb(150, 160, 170)));
-                                                                    });
+
+                                                    let source = self.vfs.as_ref()
+                                                        .and_then(|vfs| vfs.read_file(&definition.file_name))
+                                                        .and_then(|c| String::from_utf8_lossy(&c).lines().nth(definition.line - 1).map(|l| l.trim().to_string()))
+                                                        .unwrap_or_default();
+                                                    let texture = self.image_cache.get(&reference.name).filter(|t| t.size() != [1, 1]); // not dummy
+
+                                                    egui::show_tooltip_at_pointer(ui.ctx(), resp.id.with("hover_ref"), |ui| {
+                                                        egui::Frame::none()
+                                                            .fill(Color32::from_rgb(25, 28, 35))
+                                                            .stroke(egui::Stroke::new(1.0, Color32::from_rgb(45, 50, 60)))
+                                                            .rounding(4.0)
+                                                            .inner_margin(egui::Margin::same(8.0))
+                                                            .show(ui, |ui| {
+                                                                ui.set_max_width(320.0);
+                                                                if let Some(texture) = texture {
+                                                                    let size = texture.size();
+                                                                    let aspect = size[0] as f32 / size[1] as f32;
+                                                                    let mut w = 250.0;
+                                                                    let mut h = w / aspect;
+                                                                    if h > 200.0 {
+                                                                        h = 200.0;
+                                                                        w = h * aspect;
+                                                                    }
+                                                                    ui.image(egui::load::SizedTexture::new(texture.id(), egui::vec2(w, h)));
+                                                                    ui.add_space(4.0);
+                                                                }
+                                                                ui.label(RichText::new(source).font(FontId::monospace(11.0)).color(Color32::WHITE));
+                                                                ui.add_space(4.0);
+                                                                ui.label(RichText::new(format!("{}:{}", definition.file_name, definition.line)).size(10.0).color(Color32::from_rgb(100, 110, 120)));
                                                             });
-                                                        }
-                                                    }
+                                                    });
                                                 }
                                             }
                                         }
@@ -950,7 +1624,9 @@ impl Gui {
                             self.sync_to_pdf_request = true;
                         }
 
-                        if let Some(line) = self.sync_to_editor_request.take() {
+                        // Wait for a pending file switch so the jump lands in the right buffer
+                        let pending_line = if self.file_change_request.is_none() { self.sync_to_editor_request.take() } else { None };
+                        if let Some(line) = pending_line {
                             if let Some(mut state) = egui::TextEdit::load_state(ui.ctx(), resp.id) {
                                 let mut char_idx = 0;
                                 for (i, l) in self.ui_text.lines().enumerate() {
@@ -1047,6 +1723,21 @@ impl Gui {
                                 }
                             }
 
+                            // Symbol navigation: F12 or Ctrl+click for definition, Shift+F12 for references, F2 to rename
+                            if resp.has_focus() {
+                                let (definition, references, rename) = ui.input(|i| {
+                                    let f12 = i.key_pressed(egui::Key::F12);
+                                    ((f12 && !i.modifiers.shift) || (resp.clicked() && i.modifiers.command), f12 && i.modifiers.shift, i.key_pressed(egui::Key::F2))
+                                });
+                                if definition {
+                                    self.go_to_definition(char_idx);
+                                } else if references {
+                                    self.find_references(char_idx);
+                                } else if rename {
+                                    self.start_rename(char_idx);
+                                }
+                            }
+
                             // Handle Forward Sync
                             if self.sync_to_pdf_request {
                                 self.sync_to_pdf_request = false;
@@ -1063,20 +1754,14 @@ impl Gui {
                                         line_num += 1;
                                     }
                                     
-                                    if let Some(node) = stx.forward_sync(line_num, 1) {
+                                    let root = self.vfs.as_ref().and_then(|vfs| vfs.root_dir.clone());
+                                    let node = stx.tag_for_file(&self.active_file_path, root.as_deref()).and_then(|tag| stx.forward_sync(line_num, tag));
+                                    if let Some(node) = node {
                                         self.pdf_scroll_target = Some((node.page as usize, node.x, node.y));
-                                        
-                                        // Calculate highlight rect (Letter size: 612 x 792)
-                                        let x_ratio = node.x / 612.0;
-                                        let y_ratio = node.y / 792.0;
-                                        let w_ratio = node.width / 612.0;
-                                        let h_ratio = node.height / 792.0;
-                                        let d_ratio = node.depth / 792.0;
-                                        
-                                        self.pdf_highlight_rect = Some(egui::Rect::from_min_size(
-                                            egui::pos2(x_ratio, y_ratio - h_ratio),
-                                            egui::vec2(w_ratio, h_ratio + d_ratio)
-                                        ));
+                                        self.pdf_highlight_rect = Some(((node.page as usize).saturating_sub(1), egui::Rect::from_min_max(
+                                            egui::pos2(node.x, node.y - node.height),
+                                            egui::pos2(node.x + node.width, node.y + node.depth),
+                                        )));
                                     }
                                 }
                             }
@@ -1094,103 +1779,711 @@ impl Gui {
                 }
             });
 
+        if let Some(target) = self.rename_target.clone() {
+            let mut open = true;
+            egui::Window::new(format!("Rename {}", target.name))
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 80.0))
+                .open(&mut open)
+                .show(ctx, |ui| {
+                    let input = ui.add(egui::TextEdit::singleline(&mut self.rename_input).desired_width(280.0));
+                    input.request_focus();
+                    if let Some(ref error) = self.rename_error {
+                        ui.label(RichText::new(error).size(11.0).color(Color32::from_rgb(220, 90, 100)));
+                    }
+                    let uses = self.symbol_index.references(&target).len();
+                    ui.label(RichText::new(format!("{} occurrence(s) across the project", uses)).size(10.0).color(Color32::from_rgb(100, 110, 120)));
+                    if ui.input(|i| i.key_pressed(egui::Key::Enter)) || ui.button("Rename").clicked() {
+                        self.apply_rename();
+                    }
+                });
+            if !open || ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+                self.rename_target = None;
+            }
+        }
+
+        // The pages themselves are drawn by the wgpu pipeline underneath this panel
         egui::CentralPanel::default()
-            .frame(egui::Frame::none().fill(Color32::from_rgb(255, 255, 255))) // PDF usually white base
+            .frame(egui::Frame::none())
             .show(ctx, |ui| {
-                if let Some(tex_id) = pdf_tex_id {
-                    let image_size = ui.available_size();
-                    
-                    // Handle Zoom/Pan Interactions
-                    let response = ui.interact(ui.available_rect_before_wrap(), ui.id(), egui::Sense::drag());
-                    
-                    if response.dragged() {
-                        self.pdf_pan += response.drag_delta() / (image_size / 2.0);
-                    }
+                if self.pdf_layout.is_empty() {
+                    self.pdf_scene = crate::renderer::PdfScene::default();
+                    ui.centered_and_justified(|ui| {
+                        ui.label(RichText::new("...").color(Color32::from_rgb(200, 200, 200)));
+                    });
+                } else {
+                    self.draw_pdf_view(ui);
+                }
+            });
+    }
+
+    /// Lay out the pages of a newly built PDF, keeping the reader's place on the current page.
+    pub fn set_pdf_pages(&mut self, page_sizes: Vec<egui::Vec2>) {
+        self.pdf_page_sizes = page_sizes;
+        self.relayout_pdf();
+        self.pdf_links.clear();
+        self.pdf_thumbnails.truncate(self.pdf_layout.len());
+        // The old text layer no longer matches; the search runs again once the new one arrives
+        self.pdf_text.clear();
+        self.pdf_hits.clear();
+        self.pdf_selection = None;
+    }
+
+    pub fn set_pdf_navigation(&mut self, outline: Vec<OutlineItem>, links: Vec<Vec<PageLink>>) {
+        self.pdf_outline = outline;
+        self.pdf_links = links;
+    }
+
+    pub fn set_pdf_thumbnail(&mut self, ctx: &egui::Context, page: usize, image: egui::ColorImage) {
+        if self.pdf_thumbnails.len() <= page {
+            self.pdf_thumbnails.resize(page + 1, None);
+        }
+        self.pdf_thumbnails[page] = Some(ctx.load_texture(format!("pdf_thumbnail_{}", page), image, egui::TextureOptions::LINEAR));
+    }
+
+    /// Where the reader is now, to come back to after a jump.
+    fn pdf_place(&self) -> Place {
+        let origin = self.pdf_layout.pages.get(self.pdf_page).map_or(egui::Vec2::ZERO, |rect| rect.min.to_vec2());
+        (self.pdf_page, self.pdf_pan - origin)
+    }
+
+    fn go_to_pdf_place(&mut self, (page, offset): Place) {
+        if let Some(rect) = self.pdf_layout.pages.get(page) {
+            self.pdf_pan = rect.min.to_vec2() + offset;
+            self.pdf_page = page;
+        }
+    }
+
+    /// Jump to a height on a page, remembering where we came from.
+    fn jump_in_pdf(&mut self, page: usize, y: f32) {
+        if page < self.pdf_layout.len() {
+            self.pdf_history.push(self.pdf_place());
+            self.go_to_pdf_page(page);
+            self.pdf_pan.y += y;
+        }
+    }
+
+    fn pdf_back(&mut self) {
+        if let Some(place) = self.pdf_history.back(self.pdf_place()) {
+            self.go_to_pdf_place(place);
+        }
+    }
+
+    fn pdf_forward(&mut self) {
+        if let Some(place) = self.pdf_history.forward(self.pdf_place()) {
+            self.go_to_pdf_place(place);
+        }
+    }
 
-                    // Handle Scroll-based Zoom
-                    let scroll_delta = ui.input(|i| i.smooth_scroll_delta.y);
-                    if scroll_delta != 0.0 {
-                        let zoom_factor = 1.0 + (scroll_delta / 100.0);
-                        self.pdf_zoom *= zoom_factor;
-                        // Limit zoom
-                        self.pdf_zoom = self.pdf_zoom.clamp(0.1, 10.0);
+    /// The link under a point on a page, in page points.
+    fn pdf_link_at(&self, page: usize, local: egui::Pos2) -> Option<&PageLink> {
+        self.pdf_links.get(page)?.iter().find(|link| link.rect.contains(local))
+    }
+
+    pub fn set_pdf_text(&mut self, pages: Vec<PageText>) {
+        self.pdf_text = pages;
+        if self.pdf_search_open {
+            self.run_pdf_search();
+        }
+    }
+
+    fn open_pdf_search(&mut self) {
+        if !self.pdf_search_open {
+            self.pdf_search_open = true;
+            self.run_pdf_search();
+        }
+        self.pdf_search_focus = true;
+        self.pdf_text_wanted = true;
+    }
+
+    fn run_pdf_search(&mut self) {
+        self.pdf_hits = crate::pdf_text::search(&self.pdf_text, &self.pdf_search);
+        // Start from the first hit at or after the page being read
+        self.pdf_hit = self.pdf_hits.iter().position(|hit| hit.page >= self.pdf_page).unwrap_or(0);
+        if !self.pdf_hits.is_empty() {
+            self.go_to_pdf_hit(self.pdf_hit);
+        }
+    }
+
+    /// Scroll a search hit into view.
+    fn go_to_pdf_hit(&mut self, index: usize) {
+        let Some(hit) = self.pdf_hits.get(index).copied() else { return };
+        self.pdf_hit = index;
+        if let Some(rect) = self.pdf_text[hit.page].rects(hit.start..hit.end).first() {
+            self.pdf_scroll_target = Some((hit.page + 1, rect.center().x, rect.center().y));
+        }
+    }
+
+    /// Inverse search: open the source behind a point on a page, given in page points.
+    fn inverse_sync(&mut self, page: usize, local: egui::Pos2) {
+        let root = self.vfs.as_ref().and_then(|vfs| vfs.root_dir.clone());
+        let hit = self.synctex.as_ref().and_then(|stx| {
+            let node = stx.backward_sync(page as u32 + 1, local.x, local.y)?;
+            Some((node.clone(), stx.file_for_tag(node.tag, root.as_deref())))
+        });
+
+        if let Some((node, file)) = hit {
+            self.jump_to_location(file, Some(node.line as usize));
+            // Update highlight for inverse sync too
+            self.pdf_highlight_rect = Some((page, egui::Rect::from_min_max(
+                egui::pos2(node.x, node.y - node.height),
+                egui::pos2(node.x + node.width, node.y + node.depth),
+            )));
+        }
+    }
+
+    /// The page nearest a screen point and the point in that page's coordinates, if its text is loaded.
+    fn pdf_text_point(&self, viewport: egui::Rect, pos: egui::Pos2) -> Option<(usize, egui::Pos2)> {
+        let doc_pos = self.pdf_pan.to_pos2() + (pos - viewport.min) / self.pdf_zoom;
+        let page = self.pdf_layout.nearest_page(doc_pos).filter(|page| *page < self.pdf_text.len())?;
+        Some((page, doc_pos - self.pdf_layout.pages[page].min.to_vec2()))
+    }
+
+    /// Bring the first page a rebuild changed into view, unless it is on screen already.
+    pub fn show_pdf_changes(&mut self, changed: &[usize]) {
+        let Some(&first) = changed.first() else { return };
+        let on_screen = self.pdf_scene.pages.iter().any(|(page, rect)| *page == first && rect.intersects(self.pdf_scene.clip));
+        if self.pdf_follow_changes && !on_screen {
+            self.go_to_pdf_page(first);
+        }
+    }
+
+    /// A new build arrived with the pages that differ from the one before it.
+    pub fn set_pdf_diff_changes(&mut self, changes: PageChanges) {
+        let page = if changes.changed.contains(&self.pdf_diff_page) { self.pdf_diff_page } else { changes.changed.first().copied().unwrap_or(0) };
+        self.pdf_diff_changes = Some(changes);
+        self.pdf_diff = None;
+        if self.show_pdf_diff {
+            self.request_pdf_diff(page);
+        }
+    }
+
+    pub fn set_pdf_diff(&mut self, ctx: &egui::Context, page: usize, diff: PageDiff) {
+        if page != self.pdf_diff_page {
+            return;
+        }
+        let load = |name: &str, image: egui::ColorImage| ctx.load_texture(format!("pdf_diff_{}", name), image, egui::TextureOptions::LINEAR);
+        self.pdf_diff = Some(DiffTextures {
+            page,
+            old: load("old", diff.old),
+            new: load("new", diff.new),
+            overlay: load("overlay", diff.overlay),
+            changed: diff.changed,
+        });
+    }
+
+    fn open_pdf_diff(&mut self) {
+        self.show_pdf_diff = true;
+        if self.pdf_diff.is_none() {
+            let page = self.pdf_diff_changes.as_ref()
+                .and_then(|changes| changes.changed.first().copied())
+                .unwrap_or(self.pdf_page);
+            self.request_pdf_diff(page);
+        }
+    }
+
+    fn request_pdf_diff(&mut self, page: usize) {
+        let Some(ref changes) = self.pdf_diff_changes else { return };
+        self.pdf_diff_page = page;
+        self.pdf_diff_wanted = Some((page, crate::pdf_diff::previous_page(changes, page)));
+    }
+
+    /// The previous and current build of a changed page, overlaid, side by side or behind a swipe.
+    fn draw_pdf_diff(&mut self, ctx: &egui::Context) {
+        let mut open = true;
+        egui::Window::new("Changes Since the Previous Build")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(true)
+            .default_size([820.0, 640.0])
+            .show(ctx, |ui| {
+                let Some(changes) = self.pdf_diff_changes.clone() else {
+                    ui.label(RichText::new("Nothing to compare yet: the diff appears after the next successful build.").size(11.0).color(Color32::from_rgb(150, 155, 165)));
+                    return;
+                };
+
+                ui.horizontal(|ui| {
+                    for (mode, label) in [(DiffMode::Overlay, "Overlay"), (DiffMode::SideBySide, "Side by Side"), (DiffMode::Swipe, "Swipe")] {
+                        if ui.selectable_label(self.pdf_diff_mode == mode, RichText::new(label).size(11.0)).clicked() {
+                            self.pdf_diff_mode = mode;
+                        }
                     }
-                    
-                    // We draw the PDF as a background in wgpu now, but we still want to show the texture in egui
-                    // if we want to use egui's layout. However, the requirement was to use a custom shader.
-                    // My custom shader is already drawing the background.
-                    // Let's draw a transparent image here to capture clicks and provide interaction.
-                    let image_response = ui.image(egui::load::SizedTexture::new(tex_id, image_size));
-                    
-                    // Render SyncTeX highlight (needs to be adjusted for zoom/pan)
-                    if let Some(rect_ratio) = self.pdf_highlight_rect {
-                        // Adjusted for zoom/pan
-                        let scaled_rect = egui::Rect::from_min_size(
-                            egui::pos2(rect_ratio.min.x * self.pdf_zoom + self.pdf_pan.x, rect_ratio.min.y * self.pdf_zoom + self.pdf_pan.y),
-                            rect_ratio.size() * self.pdf_zoom
-                        );
-
-                        let screen_rect = egui::Rect::from_min_size(
-                            image_response.rect.min + egui::vec2(scaled_rect.min.x * image_size.x, scaled_rect.min.y * image_size.y),
-                            egui::vec2(scaled_rect.width() * image_size.x, scaled_rect.height() * image_size.y)
-                        );
-                        ui.painter().rect_filled(screen_rect, 0.0, Color32::from_rgba_unmultiplied(255, 255, 0, 80));
+                    ui.separator();
+                    match self.pdf_diff_mode {
+                        DiffMode::Overlay => {
+                            ui.label(RichText::new("■ added").size(11.0).color(crate::pdf_diff::ADDED));
+                            ui.label(RichText::new("■ removed").size(11.0).color(crate::pdf_diff::REMOVED));
+                        }
+                        DiffMode::SideBySide => {
+                            ui.label(RichText::new("previous | current").size(11.0).color(Color32::from_rgb(150, 155, 165)));
+                        }
+                        DiffMode::Swipe => {
+                            ui.add(egui::Slider::new(&mut self.pdf_diff_swipe, 0.0..=1.0).show_value(false).text("current"));
+                        }
                     }
+                });
+                ui.separator();
 
-                    // Floating Controls for PDF
-                    egui::Area::new(egui::Id::new("pdf_controls"))
-                        .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-10.0, -10.0))
-                        .show(ctx, |ui| {
-                            egui::Frame::none()
-                                .fill(Color32::from_rgb(30, 32, 35))
-                                .rounding(4.0)
-                                .inner_margin(egui::Margin::same(8.0))
-                                .show(ui, |ui| {
-                                    ui.horizontal(|ui| {
-                                        ui.label(RichText::new(format!("{:.0}%", self.pdf_zoom * 100.0)).size(10.0).color(Color32::WHITE));
-                                        if ui.button(RichText::new("RESET").size(10.0)).clicked() {
-                                            self.pdf_zoom = 1.0;
-                                            self.pdf_pan = egui::vec2(0.0, 0.0);
-                                        }
-                                    });
-                                });
+                egui::SidePanel::left("pdf_diff_pages")
+                    .resizable(false)
+                    .exact_width(120.0)
+                    .show_inside(ui, |ui| {
+                        ui.label(RichText::new("CHANGED PAGES").size(10.0).color(Color32::from_rgb(100, 110, 120)).strong());
+                        ui.add_space(8.0);
+                        if changes.changed.is_empty() {
+                            ui.label(RichText::new("No page changed").size(11.0).color(Color32::from_rgb(60, 65, 75)));
+                        }
+                        egui::ScrollArea::vertical().show(ui, |ui| {
+                            for &page in &changes.changed {
+                                let label = match crate::pdf_diff::previous_page(&changes, page) {
+                                    Some(_) => format!("Page {}", page + 1),
+                                    None => format!("Page {} (new)", page + 1),
+                                };
+                                let response = ui.selectable_label(page == self.pdf_diff_page, RichText::new(label).size(11.0));
+                                if response.clicked() {
+                                    self.request_pdf_diff(page);
+                                }
+                                if response.double_clicked() {
+                                    self.jump_in_pdf(page, 0.0);
+                                }
+                            }
                         });
+                    });
 
-                    if response.double_clicked() {
-                        if let Some(pos) = response.interact_pointer_pos() {
-                            let relative_pos = pos - response.rect.min;
-                            let x_ratio = relative_pos.x / image_size.x;
-                            let y_ratio = relative_pos.y / image_size.y;
-                            
-                            if let Some(ref stx) = self.synctex {
-                                // Use the actual page dimensions instead of hardcoded 612x792
-                                let pdf_x = x_ratio * self.pdf_page_size.x;
-                                let pdf_y = y_ratio * self.pdf_page_size.y;
-                                
-                                if let Some(node) = stx.backward_sync(1, pdf_x, pdf_y) {
-                                    self.sync_to_editor_request = Some(node.line as usize);
-                                    
-                                    // Update highlight for inverse sync too
-                                    let x_ratio = node.x / self.pdf_page_size.x;
-                                    let y_ratio = node.y / self.pdf_page_size.y;
-                                    let w_ratio = node.width / self.pdf_page_size.x;
-                                    let h_ratio = node.height / self.pdf_page_size.y;
-                                    let d_ratio = node.depth / self.pdf_page_size.y;
-                                    
-                                    self.pdf_highlight_rect = Some(egui::Rect::from_min_size(
-                                        egui::pos2(x_ratio, y_ratio - h_ratio),
-                                        egui::vec2(w_ratio, h_ratio + d_ratio)
-                                    ));
+                egui::CentralPanel::default().show_inside(ui, |ui| {
+                    let Some(diff) = self.pdf_diff.as_ref().filter(|d| d.page == self.pdf_diff_page) else {
+                        ui.centered_and_justified(|ui| ui.spinner());
+                        return;
+                    };
+                    ui.label(RichText::new(format!("Page {}: {} pixels differ", diff.page + 1, diff.changed)).size(10.0).color(Color32::from_rgb(100, 110, 120)));
+
+                    let available = ui.available_size();
+                    let image_size = diff.new.size_vec2();
+                    let fit = |area: egui::Vec2| image_size * (area.x / image_size.x).min(area.y / image_size.y).min(1.0);
+                    let full_uv = egui::Rect::from_min_max(egui::Pos2::ZERO, egui::pos2(1.0, 1.0));
+                    match self.pdf_diff_mode {
+                        DiffMode::Overlay => {
+                            let (rect, _) = ui.allocate_exact_size(fit(available), egui::Sense::hover());
+                            ui.painter().image(diff.overlay.id(), rect, full_uv, Color32::WHITE);
+                        }
+                        DiffMode::SideBySide => {
+                            let size = fit(egui::vec2((available.x - 8.0) / 2.0, available.y));
+                            ui.horizontal(|ui| {
+                                for texture in [&diff.old, &diff.new] {
+                                    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+                                    ui.painter().image(texture.id(), rect, full_uv, Color32::WHITE);
                                 }
+                            });
+                        }
+                        DiffMode::Swipe => {
+                            let (rect, response) = ui.allocate_exact_size(fit(available), egui::Sense::click_and_drag());
+                            if let Some(pos) = response.interact_pointer_pos() {
+                                self.pdf_diff_swipe = ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+                            }
+                            let split = rect.left() + rect.width() * self.pdf_diff_swipe;
+                            ui.painter().image(diff.old.id(), rect, full_uv, Color32::WHITE);
+                            ui.painter()
+                                .with_clip_rect(egui::Rect::from_min_max(rect.min, egui::pos2(split, rect.bottom())))
+                                .image(diff.new.id(), rect, full_uv, Color32::WHITE);
+                            ui.painter().vline(split, rect.y_range(), egui::Stroke::new(2.0, Color32::from_rgb(100, 160, 255)));
+                            if response.hovered() {
+                                ui.output_mut(|o| o.cursor_icon = egui::CursorIcon::ResizeHorizontal);
                             }
-
                         }
                     }
-                } else {
-                    ui.centered_and_justified(|ui| {
-                        ui.label(RichText::new("...").color(Color32::from_rgb(200, 200, 200)));
+                });
+            });
+        if !open {
+            self.show_pdf_diff = false;
+        }
+    }
+
+    fn relayout_pdf(&mut self) {
+        let anchor = self.pdf_layout.pages.get(self.pdf_page).map(|rect| self.pdf_pan - rect.min.to_vec2());
+        self.pdf_layout = PageLayout::new(&self.pdf_page_sizes, self.pdf_spreads);
+        self.pdf_page = self.pdf_page.min(self.pdf_layout.len().saturating_sub(1));
+        if let (Some(offset), Some(rect)) = (anchor, self.pdf_layout.pages.get(self.pdf_page)) {
+            self.pdf_pan = rect.min.to_vec2() + offset;
+        }
+    }
+
+    /// Scroll so a page starts at the top of the pane.
+    fn go_to_pdf_page(&mut self, page: usize) {
+        if let Some(rect) = self.pdf_layout.pages.get(page) {
+            self.pdf_pan.y = rect.top() - PAGE_GAP;
+            self.pdf_page = page;
+        }
+    }
+
+    /// The continuous page view: zoom and pan move a window over the document-space page layout.
+    fn draw_pdf_view(&mut self, ui: &mut egui::Ui) {
+        let viewport = ui.available_rect_before_wrap();
+        let response = ui.interact(viewport, ui.id().with("pdf_view"), egui::Sense::click_and_drag());
+
+        match self.pdf_fit {
+            FitMode::Free => {}
+            FitMode::Width => self.pdf_zoom = viewport.width() / self.pdf_layout.size.x,
+            FitMode::Page => {
+                let row = self.pdf_layout.row_rect(self.pdf_page).expand(PAGE_GAP);
+                self.pdf_zoom = (viewport.width() / row.width()).min(viewport.height() / row.height());
+            }
+        }
+
+        self.pdf_text_wanted |= response.hovered();
+        let pointer_on_text = ui.input(|i| i.pointer.hover_pos())
+            .and_then(|pos| self.pdf_text_point(viewport, pos))
+            .is_some_and(|(page, local)| self.pdf_text[page].hit_test(local));
+        if response.hovered() && pointer_on_text {
+            ui.output_mut(|o| o.cursor_icon = egui::CursorIcon::Text);
+        }
+
+        // Links: internal ones jump within the viewer, others open in the browser
+        let hovered_link = ui.input(|i| i.pointer.hover_pos()).and_then(|pos| {
+            let doc_pos = self.pdf_pan.to_pos2() + (pos - viewport.min) / self.pdf_zoom;
+            let page = self.pdf_layout.page_at(doc_pos)?;
+            self.pdf_link_at(page, doc_pos - self.pdf_layout.pages[page].min.to_vec2()).map(|link| link.target.clone())
+        }).filter(|_| response.hovered());
+        if let Some(ref target) = hovered_link {
+            ui.output_mut(|o| o.cursor_icon = egui::CursorIcon::PointingHand);
+            if let LinkTarget::Uri(uri) = target {
+                egui::show_tooltip_at_pointer(ui.ctx(), response.id.with("pdf_link"), |ui| {
+                    ui.label(RichText::new(uri).size(10.0));
+                });
+            }
+        }
+        if response.clicked() {
+            match hovered_link {
+                Some(LinkTarget::Page(page)) => self.jump_in_pdf(page, 0.0),
+                Some(LinkTarget::Uri(uri)) => ui.ctx().open_url(egui::OpenUrl::new_tab(uri)),
+                None => {}
+            }
+        }
+        if response.hovered() {
+            let (back, forward) = ui.input(|i| (i.modifiers.alt && i.key_pressed(egui::Key::ArrowLeft), i.modifiers.alt && i.key_pressed(egui::Key::ArrowRight)));
+            if back {
+                self.pdf_back();
+            } else if forward {
+                self.pdf_forward();
+            }
+        }
+
+        // Dragging from text selects it; dragging anywhere else pans
+        if response.drag_started() {
+            let origin = ui.input(|i| i.pointer.press_origin()).and_then(|pos| self.pdf_text_point(viewport, pos));
+            self.pdf_selecting = false;
+            if let Some((page, local)) = origin.filter(|(page, local)| self.pdf_text[*page].hit_test(*local)) {
+                let at = (page, self.pdf_text[page].boundary_at(local));
+                self.pdf_selection = Some((at, at));
+                self.pdf_selecting = true;
+            }
+        }
+        if response.dragged() {
+            if self.pdf_selecting {
+                let head = response.interact_pointer_pos().and_then(|pos| self.pdf_text_point(viewport, pos));
+                if let (Some((page, local)), Some((_, end))) = (head, self.pdf_selection.as_mut()) {
+                    *end = (page, self.pdf_text[page].boundary_at(local));
+                }
+            } else {
+                self.pdf_pan -= response.drag_delta() / self.pdf_zoom;
+            }
+        } else {
+            self.pdf_selecting = false;
+        }
+        if response.clicked() {
+            self.pdf_selection = None;
+        }
+        if let Some((anchor, head)) = self.pdf_selection {
+            let copy = ui.input(|i| i.events.iter().any(|e| matches!(e, egui::Event::Copy)));
+            if copy && ui.memory(|m| m.focused().is_none()) {
+                let text = crate::pdf_text::selected_text(&self.pdf_text, anchor, head);
+                ui.output_mut(|o| o.copied_text = text);
+            }
+        }
+
+        if response.hovered() {
+            let (scroll, zoom_delta, pointer) = ui.input(|i| (i.smooth_scroll_delta, i.zoom_delta(), i.pointer.hover_pos()));
+            self.pdf_pan -= scroll / self.pdf_zoom;
+            if zoom_delta != 1.0 {
+                // Keep the point under the pointer in place
+                let anchor = pointer.unwrap_or(viewport.center()) - viewport.min;
+                let zoom = (self.pdf_zoom * zoom_delta).clamp(0.1, 10.0);
+                self.pdf_pan += anchor / self.pdf_zoom - anchor / zoom;
+                self.pdf_zoom = zoom;
+                self.pdf_fit = FitMode::Free;
+            }
+        }
+
+        // Forward sync: bring the target into the upper third of the pane
+        if let Some((page, x, y)) = self.pdf_scroll_target.take() {
+            if let Some(rect) = self.pdf_layout.pages.get(page.saturating_sub(1)) {
+                let view = viewport.size() / self.pdf_zoom;
+                self.pdf_pan = rect.min.to_vec2() + egui::vec2(x - view.x / 2.0, y - view.y / 3.0);
+            }
+        }
+
+        let view = viewport.size() / self.pdf_zoom;
+        let size = self.pdf_layout.size;
+        self.pdf_pan.x = if size.x <= view.x { (size.x - view.x) / 2.0 } else { self.pdf_pan.x.clamp(0.0, size.x - view.x) };
+        self.pdf_pan.y = self.pdf_pan.y.clamp(0.0, (size.y - view.y).max(0.0));
+
+        let (pan, zoom) = (self.pdf_pan.to_pos2(), self.pdf_zoom);
+        let to_screen = |p: egui::Pos2| viewport.min + (p - pan) * zoom;
+        let doc_view = egui::Rect::from_min_size(pan, view);
+        if let Some(page) = self.pdf_layout.nearest_page(doc_view.center()) {
+            self.pdf_page = page;
+        }
+
+        // A screen above and below is rendered ahead of scrolling
+        let ahead = doc_view.expand2(egui::vec2(0.0, view.y));
+        self.pdf_scene = crate::renderer::PdfScene {
+            clip: viewport,
+            zoom,
+            pages: self.pdf_layout.visible(ahead).into_iter()
+                .map(|i| (i, egui::Rect::from_min_max(to_screen(self.pdf_layout.pages[i].min), to_screen(self.pdf_layout.pages[i].max))))
+                .collect(),
+            filter: self.pdf_filter,
+        };
+
+        // Render SyncTeX highlight
+        if let Some((page, rect)) = self.pdf_highlight_rect {
+            if let Some(page_rect) = self.pdf_layout.pages.get(page) {
+                let offset = page_rect.min.to_vec2();
+                let screen_rect = egui::Rect::from_min_max(to_screen(rect.min + offset), to_screen(rect.max + offset));
+                ui.painter().with_clip_rect(viewport).rect_filled(screen_rect, 0.0, Color32::from_rgba_unmultiplied(255, 255, 0, 80));
+            }
+        }
+
+        // Search hits, the current one stronger, and the selection
+        let painter = ui.painter().with_clip_rect(viewport);
+        let page_offset = |page: usize| self.pdf_layout.pages[page].min.to_vec2();
+        let shown: Vec<usize> = self.pdf_scene.pages.iter().map(|(page, _)| *page).collect();
+        for (i, hit) in self.pdf_hits.iter().enumerate().filter(|(_, hit)| shown.contains(&hit.page)) {
+            let color = if i == self.pdf_hit { Color32::from_rgba_unmultiplied(255, 140, 0, 120) } else { Color32::from_rgba_unmultiplied(255, 200, 0, 70) };
+            for rect in self.pdf_text[hit.page].rects(hit.start..hit.end) {
+                let offset = page_offset(hit.page);
+                painter.rect_filled(egui::Rect::from_min_max(to_screen(rect.min + offset), to_screen(rect.max + offset)), 0.0, color);
+            }
+        }
+        if let Some((anchor, head)) = self.pdf_selection {
+            for (page, range) in crate::pdf_text::selection_ranges(&self.pdf_text, anchor, head) {
+                if !shown.contains(&page) {
+                    continue;
+                }
+                let offset = page_offset(page);
+                for rect in self.pdf_text[page].rects(range) {
+                    painter.rect_filled(egui::Rect::from_min_max(to_screen(rect.min + offset), to_screen(rect.max + offset)), 0.0, Color32::from_rgba_unmultiplied(60, 130, 255, 80));
+                }
+            }
+        }
+
+        if response.double_clicked() {
+            if let Some(pos) = response.interact_pointer_pos() {
+                let doc_pos = pan + (pos - viewport.min) / zoom;
+                if let Some(page) = self.pdf_layout.page_at(doc_pos) {
+                    self.inverse_sync(page, doc_pos - self.pdf_layout.pages[page].min.to_vec2());
+                }
+            }
+        }
+
+        if self.pdf_search_open {
+            self.draw_pdf_search(ui, viewport);
+        }
+
+        // Floating Controls for PDF
+        egui::Area::new(egui::Id::new("pdf_controls"))
+            .fixed_pos(viewport.right_bottom() - egui::vec2(10.0, 10.0))
+            .pivot(egui::Align2::RIGHT_BOTTOM)
+            .show(ui.ctx(), |ui| {
+                egui::Frame::none()
+                    .fill(Color32::from_rgb(30, 32, 35))
+                    .rounding(4.0)
+                    .inner_margin(egui::Margin::same(8.0))
+                    .show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            if ui.add_enabled(self.pdf_history.can_go_back(), egui::Button::new(RichText::new("⟵").size(10.0))).on_hover_text("Back (Alt+Left)").clicked() {
+                                self.pdf_back();
+                            }
+                            if ui.add_enabled(self.pdf_history.can_go_forward(), egui::Button::new(RichText::new("⟶").size(10.0))).on_hover_text("Forward (Alt+Right)").clicked() {
+                                self.pdf_forward();
+                            }
+                            ui.separator();
+
+                            if ui.small_button("◀").clicked() {
+                                self.go_to_pdf_page(self.pdf_page.saturating_sub(1));
+                            }
+                            let goto = ui.add(egui::TextEdit::singleline(&mut self.pdf_goto).desired_width(28.0).font(FontId::monospace(10.0)));
+                            if goto.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                                if let Ok(page) = self.pdf_goto.trim().parse::<usize>() {
+                                    self.go_to_pdf_page(page.clamp(1, self.pdf_layout.len()) - 1);
+                                }
+                            }
+                            if !goto.has_focus() {
+                                self.pdf_goto = (self.pdf_page + 1).to_string();
+                            }
+                            ui.label(RichText::new(format!("/ {}", self.pdf_layout.len())).size(10.0).color(Color32::WHITE));
+                            if ui.small_button("▶").clicked() {
+                                self.go_to_pdf_page((self.pdf_page + 1).min(self.pdf_layout.len() - 1));
+                            }
+                            ui.separator();
+
+                            if ui.selectable_label(self.pdf_fit == FitMode::Width, RichText::new("WIDTH").size(10.0)).clicked() {
+                                self.pdf_fit = FitMode::Width;
+                            }
+                            if ui.selectable_label(self.pdf_fit == FitMode::Page, RichText::new("PAGE").size(10.0)).clicked() {
+                                self.pdf_fit = FitMode::Page;
+                            }
+                            if ui.selectable_label(self.pdf_spreads, RichText::new("2-UP").size(10.0)).clicked() {
+                                self.pdf_spreads = !self.pdf_spreads;
+                                self.relayout_pdf();
+                            }
+                            if ui.selectable_label(self.pdf_follow_changes, RichText::new("FOLLOW").size(10.0)).on_hover_text("Scroll to the first page a rebuild changed").clicked() {
+                                self.pdf_follow_changes = !self.pdf_follow_changes;
+                            }
+                            ui.separator();
+
+                            ui.label(RichText::new(format!("{:.0}%", self.pdf_zoom * 100.0)).size(10.0).color(Color32::WHITE));
+                            if ui.button(RichText::new("RESET").size(10.0)).clicked() {
+                                self.pdf_zoom = 1.0;
+                                self.pdf_fit = FitMode::Free;
+                            }
+                        });
+                    });
+            });
+    }
+
+    /// Bookmarks from the PDF outline above a strip of page thumbnails.
+    fn draw_pdf_nav(&mut self, ui: &mut egui::Ui) {
+        ui.add_space(8.0);
+        ui.horizontal(|ui| {
+            ui.add_space(16.0);
+            ui.label(RichText::new("BOOKMARKS").size(10.0).color(Color32::from_rgb(100, 110, 120)).strong());
+        });
+        ui.add_space(8.0);
+        egui::ScrollArea::vertical()
+            .id_source("pdf_outline_scroll")
+            .max_height(ui.available_height() * 0.4)
+            .show(ui, |ui| {
+                if self.pdf_outline.is_empty() {
+                    ui.horizontal(|ui| {
+                        ui.add_space(16.0);
+                        ui.label(RichText::new("No bookmarks in this PDF").size(11.0).color(Color32::from_rgb(60, 65, 75)));
                     });
                 }
+                let outline = std::mem::take(&mut self.pdf_outline);
+                for item in &outline {
+                    self.render_pdf_outline_recursive(ui, item, 0);
+                }
+                self.pdf_outline = outline;
+            });
+
+        ui.add_space(16.0);
+        ui.horizontal(|ui| {
+            ui.add_space(16.0);
+            ui.label(RichText::new("PAGES").size(10.0).color(Color32::from_rgb(100, 110, 120)).strong());
+        });
+        ui.add_space(8.0);
+        egui::ScrollArea::vertical().id_source("pdf_thumbnail_scroll").show(ui, |ui| {
+            let width = (ui.available_width() - 32.0).max(40.0);
+            for page in 0..self.pdf_layout.len() {
+                let size = self.pdf_page_sizes[page] * (width / self.pdf_page_sizes[page].x);
+                let current = page == self.pdf_page;
+                let response = ui.horizontal(|ui| {
+                    ui.add_space(16.0);
+                    ui.vertical(|ui| {
+                        let (rect, response) = ui.allocate_exact_size(size, egui::Sense::click());
+                        match self.pdf_thumbnails.get(page).and_then(Option::as_ref) {
+                            Some(texture) => {
+                                egui::Image::new(texture).paint_at(ui, rect);
+                            }
+                            None => ui.painter().rect_filled(rect, 0.0, Color32::WHITE),
+                        }
+                        let stroke = if current { egui::Stroke::new(2.0, Color32::from_rgb(100, 160, 255)) } else { egui::Stroke::new(1.0, Color32::from_rgb(40, 45, 50)) };
+                        ui.painter().rect_stroke(rect, 0.0, stroke);
+                        ui.label(RichText::new((page + 1).to_string()).size(10.0).color(if current { Color32::WHITE } else { Color32::from_rgb(100, 110, 120) }));
+                        response
+                    }).inner
+                }).inner;
+                if response.clicked() {
+                    self.jump_in_pdf(page, 0.0);
+                }
+                ui.add_space(6.0);
+            }
+        });
+    }
+
+    fn render_pdf_outline_recursive(&mut self, ui: &mut egui::Ui, item: &OutlineItem, depth: usize) {
+        ui.horizontal(|ui| {
+            ui.add_space(16.0 + depth as f32 * 12.0);
+            let label = RichText::new(&item.title).size(11.0).color(Color32::from_rgb(200, 200, 200));
+            let current = item.page == Some(self.pdf_page);
+            if ui.selectable_label(current, label).clicked() {
+                if let Some(page) = item.page {
+                    self.jump_in_pdf(page, item.y);
+                }
+            }
+        });
+        for child in &item.children {
+            self.render_pdf_outline_recursive(ui, child, depth + 1);
+        }
+    }
+
+    /// The find bar over the top right of the PDF pane.
+    fn draw_pdf_search(&mut self, ui: &mut egui::Ui, viewport: egui::Rect) {
+        egui::Area::new(egui::Id::new("pdf_search"))
+            .fixed_pos(viewport.right_top() + egui::vec2(-10.0, 10.0))
+            .pivot(egui::Align2::RIGHT_TOP)
+            .show(ui.ctx(), |ui| {
+                egui::Frame::none()
+                    .fill(Color32::from_rgb(30, 32, 35))
+                    .rounding(4.0)
+                    .inner_margin(egui::Margin::same(8.0))
+                    .show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            let field = ui.add(egui::TextEdit::singleline(&mut self.pdf_search)
+                                .hint_text("Find in PDF")
+                                .desired_width(160.0)
+                                .font(FontId::monospace(11.0)));
+                            if std::mem::take(&mut self.pdf_search_focus) {
+                                field.request_focus();
+                            }
+                            if field.changed() {
+                                self.run_pdf_search();
+                            }
+                            let (enter, shift, escape) = ui.input(|i| (i.key_pressed(egui::Key::Enter), i.modifiers.shift, i.key_pressed(egui::Key::Escape)));
+                            let count = self.pdf_hits.len();
+                            if field.lost_focus() && enter && count > 0 {
+                                self.go_to_pdf_hit(if shift { (self.pdf_hit + count - 1) % count } else { (self.pdf_hit + 1) % count });
+                                field.request_focus();
+                            }
+
+                            let status = if self.pdf_text.is_empty() && !self.pdf_search.is_empty() {
+                                "…".to_string()
+                            } else if count == 0 {
+                                "0/0".to_string()
+                            } else {
+                                format!("{}/{}", self.pdf_hit + 1, count)
+                            };
+                            ui.label(RichText::new(status).size(10.0).color(Color32::WHITE));
+                            if ui.small_button("▲").clicked() && count > 0 {
+                                self.go_to_pdf_hit((self.pdf_hit + count - 1) % count);
+                            }
+                            if ui.small_button("▼").clicked() && count > 0 {
+                                self.go_to_pdf_hit((self.pdf_hit + 1) % count);
+                            }
+                            // Jump from the phrase in the output to the source that produced it
+                            if ui.add_enabled(count > 0 && self.synctex.is_some(), egui::Button::new(RichText::new("SOURCE").size(10.0))).clicked() {
+                                let hit = self.pdf_hits[self.pdf_hit];
+                                if let Some(rect) = self.pdf_text[hit.page].rects(hit.start..hit.end).first() {
+                                    self.inverse_sync(hit.page, rect.center());
+                                }
+                            }
+                            if ui.small_button("✕").clicked() || (escape && (field.has_focus() || field.lost_focus())) {
+                                self.pdf_search_open = false;
+                                self.pdf_hits.clear();
+                            }
+                        });
+                    });
             });
     }
 
@@ -1303,8 +2596,7 @@ impl Gui {
             for entry in &self.bib_entries {
                 let matches = search.is_empty() || 
                     entry.key.to_lowercase().contains(&search) ||
-                    entry.author.as_ref().map(|a| a.to_lowercase().contains(&search)).unwrap_or(false) ||
-                    entry.title.as_ref().map(|t| t.to_lowercase().contains(&search)).unwrap_or(false);
+                    entry.fields.iter().any(|(_, value)| value.to_lowercase().contains(&search));
 
                 if matches {
                     let response = egui::Frame::none()
@@ -1317,12 +2609,19 @@ impl Gui {
                                         ui.label(RichText::new(&entry.entry_type).size(9.0).color(Color32::from_rgb(80, 85, 95)));
                                     });
                                 });
-                                if let Some(title) = &entry.title {
+                                if let Some(title) = entry.plain("title") {
                                     ui.label(RichText::new(title).size(12.0).color(Color32::WHITE));
                                 }
-                                if let Some(author) = &entry.author {
+                                if let Some(author) = entry.plain("author") {
                                     ui.label(RichText::new(author).size(10.0).color(Color32::from_rgb(120, 130, 140)));
                                 }
+                                let venue = [entry.venue(), entry.field("year").map(str::to_string)].into_iter().flatten().collect::<Vec<_>>().join(", ");
+                                if !venue.is_empty() {
+                                    ui.label(RichText::new(venue).italics().size(10.0).color(Color32::from_rgb(100, 110, 120)));
+                                }
+                                if let Some(doi) = entry.field("doi") {
+                                    ui.label(RichText::new(format!("doi:{}", doi)).size(9.0).color(Color32::from_rgb(60, 120, 220)));
+                                }
                             });
                         }).response;
 
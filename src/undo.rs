@@ -0,0 +1,553 @@
+use ropey::Rope;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Upper bound on the text held by one file's history (removed plus inserted).
+const DEFAULT_MAX_BYTES: usize = 4 * 1024 * 1024;
+/// Upper bound on undo steps, like Vim's `undolevels`.
+const DEFAULT_MAX_NODES: usize = 1000;
+/// Typing separated by a longer pause starts a new undo step.
+const TYPING_PAUSE: Duration = Duration::from_millis(1000);
+const FORMAT_VERSION: u64 = 1;
+
+/// One replacement in the buffer: `removed` at char `pos` became `inserted`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub pos: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+impl Edit {
+    /// The smallest edit turning `before` into `after`, found by trimming the common prefix and suffix.
+    pub fn diff(before: &Rope, after: &Rope) -> Option<Edit> {
+        let (before_len, after_len) = (before.len_chars(), after.len_chars());
+        let prefix = before.chars().zip(after.chars()).take_while(|(a, b)| a == b).count();
+        if prefix == before_len && prefix == after_len {
+            return None;
+        }
+
+        let max_suffix = before_len.min(after_len) - prefix;
+        let mut a = before.chars_at(before_len);
+        let mut b = after.chars_at(after_len);
+        let mut suffix = 0;
+        while suffix < max_suffix {
+            match (a.prev(), b.prev()) {
+                (Some(x), Some(y)) if x == y => suffix += 1,
+                _ => break,
+            }
+        }
+
+        Some(Edit {
+            pos: prefix,
+            removed: before.slice(prefix..before_len - suffix).to_string(),
+            inserted: after.slice(prefix..after_len - suffix).to_string(),
+        })
+    }
+
+    fn apply(&self, buffer: &mut Rope) {
+        buffer.remove(self.pos..self.pos + self.removed.chars().count());
+        buffer.insert(self.pos, &self.inserted);
+    }
+
+    fn revert(&self, buffer: &mut Rope) {
+        buffer.remove(self.pos..self.pos + self.inserted.chars().count());
+        buffer.insert(self.pos, &self.removed);
+    }
+
+    fn bytes(&self) -> usize {
+        self.removed.len() + self.inserted.len()
+    }
+
+    /// Whether `apply` fits `buffer`: the removed text is at `pos`.
+    fn applies_to(&self, buffer: &Rope) -> bool {
+        occurs_at(buffer, self.pos, &self.removed)
+    }
+
+    /// Whether `revert` fits `buffer`: the inserted text is at `pos`.
+    fn reverts_from(&self, buffer: &Rope) -> bool {
+        occurs_at(buffer, self.pos, &self.inserted)
+    }
+
+    /// Fold `next`, which directly continues this edit, into it.
+    fn extend(&mut self, next: &Edit) -> bool {
+        let end = self.pos + self.inserted.chars().count();
+        if next.removed.is_empty() && next.pos == end {
+            // Typing on
+            self.inserted.push_str(&next.inserted);
+        } else if next.inserted.is_empty() && next.pos + next.removed.chars().count() == end {
+            if next.pos >= self.pos {
+                // Backspacing over what was just typed
+                self.inserted = self.inserted.chars().take(next.pos - self.pos).collect();
+            } else {
+                // ... and on past where the run started
+                let before: String = next.removed.chars().take(self.pos - next.pos).collect();
+                self.inserted.clear();
+                self.removed.insert_str(0, &before);
+                self.pos = next.pos;
+            }
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+/// How a new edit relates to the open undo step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Group {
+    /// Start a new undo step, left open for `Continue`
+    New,
+    /// Add to the open step (Vim's insert session)
+    Continue,
+    /// Add to the open step if it continues a typing run without a pause
+    Typing,
+}
+
+/// A move through the history requested by the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryMove {
+    Undo,
+    Redo,
+    /// Back in time across branches (Vim's `g-`)
+    Earlier,
+    /// Forward in time across branches (Vim's `g+`)
+    Later,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// The child `redo` follows: the most recently created or undone one
+    redo: Option<usize>,
+    edits: Vec<Edit>,
+    cursor_before: usize,
+    cursor_after: usize,
+    /// Creation order, for `Earlier`/`Later`
+    seq: u64,
+}
+
+impl Node {
+    fn new(parent: Option<usize>, seq: u64, cursor: usize) -> Self {
+        Self { parent, children: Vec::new(), redo: None, edits: Vec::new(), cursor_before: cursor, cursor_after: cursor, seq }
+    }
+}
+
+/// Branching undo history for one buffer.
+///
+/// Node 0 is the oldest state still reachable; every other node holds the edits that lead to it from its
+/// parent. Undoing and then editing starts a new branch, so the undone changes stay reachable through
+/// `Earlier`/`Later`.
+#[derive(Debug, Clone)]
+pub struct UndoTree {
+    nodes: Vec<Node>,
+    current: usize,
+    next_seq: u64,
+    /// Whether `current` still accepts `Continue`/`Typing` edits
+    open: bool,
+    last_edit: Option<Instant>,
+    bytes: usize,
+    pub max_bytes: usize,
+    pub max_nodes: usize,
+}
+
+impl Default for UndoTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UndoTree {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![Node::new(None, 0, 0)],
+            current: 0,
+            next_seq: 1,
+            open: false,
+            last_edit: None,
+            bytes: 0,
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_nodes: DEFAULT_MAX_NODES,
+        }
+    }
+
+    /// Record an edit that has already been applied to the buffer.
+    pub fn record(&mut self, edit: Edit, cursor_before: usize, cursor_after: usize, group: Group) {
+        let now = Instant::now();
+        let merge = self.open && self.current != 0 && match group {
+            Group::New => false,
+            Group::Continue => true,
+            Group::Typing => self.last_edit.is_some_and(|t| now.duration_since(t) < TYPING_PAUSE),
+        };
+        self.last_edit = Some(now);
+
+        if merge {
+            let node = &mut self.nodes[self.current];
+            if let Some(last) = node.edits.last_mut() {
+                let old = last.bytes();
+                if last.extend(&edit) {
+                    self.bytes = self.bytes + last.bytes() - old;
+                    node.cursor_after = cursor_after;
+                    return;
+                }
+            }
+            // A typing run broken by a jump elsewhere starts a new step
+            if group == Group::Continue {
+                self.bytes += edit.bytes();
+                node.edits.push(edit);
+                node.cursor_after = cursor_after;
+                return;
+            }
+        }
+
+        self.bytes += edit.bytes();
+        let index = self.nodes.len();
+        let mut node = Node::new(Some(self.current), self.next_seq, cursor_before);
+        node.cursor_after = cursor_after;
+        node.edits.push(edit);
+        self.next_seq += 1;
+        self.nodes.push(node);
+        let parent = &mut self.nodes[self.current];
+        parent.children.push(index);
+        parent.redo = Some(index);
+        self.current = index;
+        self.open = true;
+        self.prune();
+    }
+
+    /// Close the open step so the next edit starts a new one.
+    pub fn seal(&mut self) {
+        self.open = false;
+    }
+
+    /// Revert the current step. Returns where the cursor goes.
+    pub fn undo(&mut self, buffer: &mut Rope) -> Option<usize> {
+        let parent = self.nodes[self.current].parent?;
+        let node = &self.nodes[self.current];
+        for edit in node.edits.iter().rev() {
+            edit.revert(buffer);
+        }
+        let cursor = node.cursor_before;
+        self.nodes[parent].redo = Some(self.current);
+        self.current = parent;
+        self.open = false;
+        Some(cursor)
+    }
+
+    /// Reapply the most recently undone step below the current one.
+    pub fn redo(&mut self, buffer: &mut Rope) -> Option<usize> {
+        let child = self.nodes[self.current].redo?;
+        for edit in &self.nodes[child].edits {
+            edit.apply(buffer);
+        }
+        self.current = child;
+        self.open = false;
+        Some(self.nodes[child].cursor_after)
+    }
+
+    /// Move to the state created just before (or after) the current one, whichever branch it is on.
+    pub fn travel(&mut self, buffer: &mut Rope, later: bool) -> Option<usize> {
+        let seq = self.nodes[self.current].seq;
+        let target = if later {
+            (0..self.nodes.len()).filter(|&i| self.nodes[i].seq > seq).min_by_key(|&i| self.nodes[i].seq)?
+        } else {
+            (0..self.nodes.len()).filter(|&i| self.nodes[i].seq < seq).max_by_key(|&i| self.nodes[i].seq)?
+        };
+
+        // Undo up to the common ancestor, then redo down to the target
+        let path = self.path_from_root(target);
+        let mut cursor = None;
+        while !path.contains(&self.current) {
+            cursor = self.undo(buffer);
+        }
+        let from = path.iter().position(|&n| n == self.current).unwrap_or(0);
+        for &node in &path[from + 1..] {
+            self.nodes[self.current].redo = Some(node);
+            cursor = self.redo(buffer);
+        }
+        cursor
+    }
+
+    pub fn apply(&mut self, buffer: &mut Rope, step: HistoryMove) -> Option<usize> {
+        match step {
+            HistoryMove::Undo => self.undo(buffer),
+            HistoryMove::Redo => self.redo(buffer),
+            HistoryMove::Earlier => self.travel(buffer, false),
+            HistoryMove::Later => self.travel(buffer, true),
+        }
+    }
+
+    fn path_from_root(&self, node: usize) -> Vec<usize> {
+        let mut path = vec![node];
+        let mut n = node;
+        while let Some(parent) = self.nodes[n].parent {
+            path.push(parent);
+            n = parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Drop the oldest history until the tree fits its limits again.
+    ///
+    /// The root's child on the way to the current state becomes the new root, and the branches
+    /// that hang off the old root are discarded with it.
+    fn prune(&mut self) {
+        while (self.bytes > self.max_bytes || self.nodes.len() > self.max_nodes + 1) && self.current != 0 {
+            let path = self.path_from_root(self.current);
+            let keep = path[1];
+            let mut retained = vec![false; self.nodes.len()];
+            let mut stack = vec![keep];
+            while let Some(n) = stack.pop() {
+                retained[n] = true;
+                stack.extend(self.nodes[n].children.iter().copied());
+            }
+            self.nodes[keep].parent = None;
+            self.nodes[keep].edits.clear();
+            self.compact(&retained, keep);
+        }
+    }
+
+    /// Keep only `retained` nodes, renumbering them so `root` becomes node 0.
+    fn compact(&mut self, retained: &[bool], root: usize) {
+        let mut order = vec![root];
+        order.extend((0..self.nodes.len()).filter(|&i| retained[i] && i != root));
+        let mut remap = vec![usize::MAX; self.nodes.len()];
+        for (new, &old) in order.iter().enumerate() {
+            remap[old] = new;
+        }
+
+        let old_nodes = std::mem::take(&mut self.nodes);
+        self.nodes = order.iter().map(|&old| {
+            let mut node = old_nodes[old].clone();
+            node.parent = node.parent.map(|p| remap[p]);
+            node.children = node.children.iter().map(|&c| remap[c]).collect();
+            node.redo = node.redo.map(|r| remap[r]);
+            node
+        }).collect();
+        self.current = remap[self.current];
+        self.bytes = self.nodes.iter().flat_map(|n| &n.edits).map(Edit::bytes).sum();
+    }
+
+    /// Serialise the history. `text` is the buffer at the current state; loading checks it still matches.
+    pub fn to_json(&self, text: &str) -> Value {
+        let nodes: Vec<Value> = self.nodes.iter().map(|node| {
+            let edits: Vec<Value> = node.edits.iter().map(|e| json!([e.pos, e.removed, e.inserted])).collect();
+            json!({
+                "parent": node.parent,
+                "redo": node.redo,
+                "seq": node.seq,
+                "cursor": [node.cursor_before, node.cursor_after],
+                "edits": edits,
+            })
+        }).collect();
+        json!({
+            "version": FORMAT_VERSION,
+            "text_hash": text_hash(text),
+            "current": self.current,
+            "next_seq": self.next_seq,
+            "nodes": nodes,
+        })
+    }
+
+    /// Restore a history saved by `to_json`. Returns `None` if it is malformed or `text` changed since.
+    pub fn from_json(value: &Value, text: &str) -> Option<Self> {
+        if value["version"].as_u64()? != FORMAT_VERSION || value["text_hash"].as_str()? != text_hash(text) {
+            return None;
+        }
+
+        let mut nodes = Vec::new();
+        for node in value["nodes"].as_array()? {
+            let index = |v: &Value| -> Option<Option<usize>> {
+                if v.is_null() { Some(None) } else { v.as_u64().map(|n| Some(n as usize)) }
+            };
+            let mut edits = Vec::new();
+            for edit in node["edits"].as_array()? {
+                edits.push(Edit {
+                    pos: edit[0].as_u64()? as usize,
+                    removed: edit[1].as_str()?.to_string(),
+                    inserted: edit[2].as_str()?.to_string(),
+                });
+            }
+            nodes.push(Node {
+                parent: index(&node["parent"])?,
+                children: Vec::new(),
+                redo: index(&node["redo"])?,
+                edits,
+                cursor_before: node["cursor"][0].as_u64()? as usize,
+                cursor_after: node["cursor"][1].as_u64()? as usize,
+                seq: node["seq"].as_u64()?,
+            });
+        }
+
+        let count = nodes.len();
+        let current = value["current"].as_u64()? as usize;
+        if count == 0 || current >= count || nodes[0].parent.is_some() {
+            return None;
+        }
+        for i in 1..count {
+            let parent = nodes[i].parent.filter(|&p| p < i)?;
+            nodes[parent].children.push(i);
+        }
+        if nodes.iter().enumerate().any(|(i, n)| n.redo.is_some_and(|r| r >= count || nodes[r].parent != Some(i))) {
+            return None;
+        }
+        // A step that does not fit the text it would be replayed against would panic on undo
+        if !replays(&nodes, current, text) {
+            return None;
+        }
+
+        let mut tree = Self::new();
+        tree.bytes = nodes.iter().flat_map(|n| &n.edits).map(Edit::bytes).sum();
+        tree.nodes = nodes;
+        tree.current = current;
+        tree.next_seq = value["next_seq"].as_u64()?;
+        Some(tree)
+    }
+
+    /// Load the history saved for a file, if it still matches `text`.
+    pub fn load(path: &Path, text: &str) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        let value: Value = serde_json::from_slice(&data).ok()?;
+        Self::from_json(&value, text)
+    }
+
+    pub fn save(&self, path: &Path, text: &str) -> std::io::Result<()> {
+        crate::io::IoHandler::write_atomic_sync(path, self.to_json(text).to_string().as_bytes())
+    }
+}
+
+/// Whether every step of a loaded tree fits its text; `text` is the state at `current`.
+fn replays(nodes: &[Node], current: usize, text: &str) -> bool {
+    // Back up to the root...
+    let mut root = Rope::from_str(text);
+    let mut n = current;
+    while let Some(parent) = nodes[n].parent {
+        for edit in nodes[n].edits.iter().rev() {
+            if !edit.reverts_from(&root) {
+                return false;
+            }
+            edit.revert(&mut root);
+        }
+        n = parent;
+    }
+
+    // ...then down every branch
+    let mut stack = vec![(0, root)];
+    while let Some((n, buffer)) = stack.pop() {
+        for &child in &nodes[n].children {
+            let mut state = buffer.clone();
+            for edit in &nodes[child].edits {
+                if !edit.applies_to(&state) {
+                    return false;
+                }
+                edit.apply(&mut state);
+            }
+            stack.push((child, state));
+        }
+    }
+    true
+}
+
+fn occurs_at(buffer: &Rope, pos: usize, text: &str) -> bool {
+    match pos.checked_add(text.chars().count()) {
+        Some(end) if end <= buffer.len_chars() => buffer.slice(pos..end) == text,
+        _ => false,
+    }
+}
+
+fn text_hash(text: &str) -> String {
+    format!("{:x}", md5::compute(text.as_bytes()))
+}
+
+/// Where the undo history of `file_name` is kept inside the project.
+pub fn history_path(root_dir: &str, file_name: &str) -> PathBuf {
+    crate::io::state_path(root_dir, "undo", &format!("{}.json", file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_text(tree: &mut UndoTree, buffer: &mut Rope, pos: usize, text: &str, group: Group) {
+        for (i, c) in text.chars().enumerate() {
+            let before = buffer.clone();
+            buffer.insert_char(pos + i, c);
+            let edit = Edit::diff(&before, buffer).unwrap();
+            tree.record(edit, pos + i, pos + i + 1, group);
+        }
+    }
+
+    #[test]
+    fn test_undo_tree_groups_branches_and_round_trips() {
+        let mut tree = UndoTree::new();
+        let mut buffer = Rope::new();
+        type_text(&mut tree, &mut buffer, 0, "hello", Group::Typing);
+        tree.seal();
+        type_text(&mut tree, &mut buffer, 5, " world", Group::Typing);
+        assert_eq!(tree.nodes.len(), 3);
+
+        assert_eq!(tree.undo(&mut buffer), Some(5));
+        assert_eq!(buffer.to_string(), "hello");
+
+        // Editing after an undo branches instead of discarding " world"
+        tree.seal();
+        type_text(&mut tree, &mut buffer, 5, "!", Group::New);
+        assert_eq!(buffer.to_string(), "hello!");
+        tree.travel(&mut buffer, false);
+        assert_eq!(buffer.to_string(), "hello world");
+        tree.travel(&mut buffer, true);
+        assert_eq!(buffer.to_string(), "hello!");
+
+        let text = buffer.to_string();
+        let mut restored = UndoTree::from_json(&tree.to_json(&text), &text).unwrap();
+        assert!(UndoTree::from_json(&tree.to_json(&text), "changed").is_none());
+        restored.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "hello");
+        restored.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "");
+        assert_eq!(restored.current, 0);
+
+        // Exceeding the limit drops the oldest steps
+        let mut tree = UndoTree::new();
+        tree.max_nodes = 2;
+        let mut buffer = Rope::new();
+        for i in 0..4 {
+            type_text(&mut tree, &mut buffer, i, "x", Group::New);
+        }
+        assert_eq!(tree.nodes.len(), 3);
+        tree.undo(&mut buffer);
+        tree.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "xx");
+        assert_eq!(tree.current, 0);
+    }
+
+    #[test]
+    fn test_undo_tree_rejects_tampered_history() {
+        let mut tree = UndoTree::new();
+        let mut buffer = Rope::new();
+        type_text(&mut tree, &mut buffer, 0, "hello", Group::Typing);
+        tree.seal();
+        type_text(&mut tree, &mut buffer, 5, " world", Group::Typing);
+        tree.undo(&mut buffer);
+        let text = buffer.to_string();
+        let saved = tree.to_json(&text);
+        assert!(UndoTree::from_json(&saved, &text).is_some());
+
+        // The redo branch " world" would be inserted past the end of "hello"
+        let mut tampered = saved.clone();
+        tampered["nodes"][2]["edits"][0][0] = json!(50);
+        assert!(UndoTree::from_json(&tampered, &text).is_none());
+
+        // Undoing "hello" would remove text that is not there
+        let mut tampered = saved.clone();
+        tampered["nodes"][1]["edits"][0][2] = json!("help");
+        assert!(UndoTree::from_json(&tampered, &text).is_none());
+
+        let mut tampered = saved;
+        tampered["nodes"][0]["redo"] = json!(2);
+        assert!(UndoTree::from_json(&tampered, &text).is_none());
+    }
+}
@@ -0,0 +1,263 @@
+use egui::{pos2, vec2, Pos2, Rect, Vec2};
+
+/// Space around and between pages, in PDF points.
+pub const PAGE_GAP: f32 = 12.0;
+
+/// How the zoom follows the size of the PDF pane.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitMode {
+    /// Zoom only changes when asked to
+    Free,
+    Width,
+    Page,
+}
+
+/// Where every page sits in document space: PDF points, pages top to bottom (or in rows of two for spreads).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PageLayout {
+    pub pages: Vec<Rect>,
+    /// The row each page is on; rows hold one page, or two in a spread
+    rows: Vec<usize>,
+    pub size: Vec2,
+}
+
+impl PageLayout {
+    /// Lay pages out centered in one column, or as spreads with the first page on its own like a book cover.
+    pub fn new(page_sizes: &[Vec2], spreads: bool) -> Self {
+        let row_of = |i: usize| if spreads { i.div_ceil(2) } else { i };
+        let row_count = page_sizes.len().checked_sub(1).map_or(0, |last| row_of(last) + 1);
+
+        let mut row_sizes = vec![Vec2::ZERO; row_count];
+        for (i, size) in page_sizes.iter().enumerate() {
+            let row = &mut row_sizes[row_of(i)];
+            row.x += size.x + if row.x > 0.0 { PAGE_GAP } else { 0.0 };
+            row.y = row.y.max(size.y);
+        }
+        let width = row_sizes.iter().map(|r| r.x).fold(0.0, f32::max) + 2.0 * PAGE_GAP;
+
+        let mut row_tops = Vec::with_capacity(row_count);
+        let mut y = PAGE_GAP;
+        for row in &row_sizes {
+            row_tops.push(y);
+            y += row.y + PAGE_GAP;
+        }
+
+        let mut pages = Vec::with_capacity(page_sizes.len());
+        let mut rows = Vec::with_capacity(page_sizes.len());
+        let mut x = 0.0;
+        for (i, size) in page_sizes.iter().enumerate() {
+            let row = row_of(i);
+            if i == 0 || row != row_of(i - 1) {
+                x = (width - row_sizes[row].x) / 2.0;
+            }
+            pages.push(Rect::from_min_size(pos2(x, row_tops[row]), *size));
+            rows.push(row);
+            x += size.x + PAGE_GAP;
+        }
+        Self { pages, rows, size: vec2(width, y) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// The bounding box of the row a page is on.
+    pub fn row_rect(&self, page: usize) -> Rect {
+        let row = self.rows[page];
+        self.pages.iter().zip(&self.rows)
+            .filter(|(_, r)| **r == row)
+            .fold(Rect::NOTHING, |acc, (rect, _)| acc.union(*rect))
+    }
+
+    /// Pages overlapping a document-space rectangle, in order.
+    pub fn visible(&self, area: Rect) -> Vec<usize> {
+        // Page tops only grow: start from the last page beginning above the area, and the pages on its row
+        let mut first = self.pages.partition_point(|p| p.top() <= area.top()).saturating_sub(1);
+        while first > 0 && self.rows[first - 1] == self.rows[first] {
+            first -= 1;
+        }
+        (first..self.pages.len())
+            .take_while(|&i| self.pages[i].top() <= area.bottom())
+            .filter(|&i| self.pages[i].intersects(area))
+            .collect()
+    }
+
+    /// The page under a document-space point.
+    pub fn page_at(&self, pos: Pos2) -> Option<usize> {
+        self.visible(Rect::from_min_size(pos, Vec2::ZERO)).into_iter().find(|&i| self.pages[i].contains(pos))
+    }
+
+    /// The page closest to a document-space point, e.g. the middle of the pane.
+    pub fn nearest_page(&self, pos: Pos2) -> Option<usize> {
+        self.page_at(pos).or_else(|| {
+            (0..self.pages.len()).min_by(|&a, &b| {
+                self.pages[a].distance_sq_to_pos(pos).total_cmp(&self.pages[b].distance_sq_to_pos(pos))
+            })
+        })
+    }
+}
+
+/// A colour filter over the pages. It is applied in the shader, so switching never re-renders tiles.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PdfFilter {
+    #[default]
+    None,
+    /// Luminance inverted with the hue kept, so figures stay recognisable
+    Dark,
+    Sepia,
+    HighContrast,
+    /// White paper and black ink replaced by chosen colours
+    Custom { paper: [f32; 3], ink: [f32; 3] },
+}
+
+impl PdfFilter {
+    /// The filters offered in order, Custom starting from a warm grey.
+    pub const ALL: [PdfFilter; 5] = [
+        PdfFilter::None,
+        PdfFilter::Dark,
+        PdfFilter::Sepia,
+        PdfFilter::HighContrast,
+        PdfFilter::Custom { paper: [0.93, 0.92, 0.89], ink: [0.16, 0.17, 0.2] },
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            PdfFilter::None => "None",
+            PdfFilter::Dark => "Dark",
+            PdfFilter::Sepia => "Sepia",
+            PdfFilter::HighContrast => "High Contrast",
+            PdfFilter::Custom { .. } => "Custom",
+        }
+    }
+
+    /// The filter after this one, for cycling from the palette.
+    pub fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|f| std::mem::discriminant(f) == std::mem::discriminant(&self)).unwrap_or(0);
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+
+    /// What the shader does: a mode (0 keeps the colours, 1 inverts luminance, 2 takes luminance, 3 raises contrast)
+    /// followed by mapping black and white of the result to the two colours.
+    pub fn shader_params(self) -> (u32, [f32; 3], [f32; 3]) {
+        match self {
+            PdfFilter::None => (0, [0.0; 3], [1.0; 3]),
+            // Dark pages a step above the editor background, light grey ink
+            PdfFilter::Dark => (1, [0.11, 0.12, 0.13], [0.86, 0.87, 0.88]),
+            PdfFilter::Sepia => (2, [0.26, 0.19, 0.12], [0.96, 0.91, 0.8]),
+            PdfFilter::HighContrast => (3, [0.0; 3], [1.0; 3]),
+            PdfFilter::Custom { paper, ink } => (0, ink, paper),
+        }
+    }
+}
+
+/// A place in the document: a page, and the top left of the pane relative to that page in points.
+pub type Place = (usize, Vec2);
+
+/// Places kept to go back to.
+const HISTORY_LIMIT: usize = 100;
+
+/// Back and forward through the places the viewer jumped from, like a browser.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NavHistory {
+    back: Vec<Place>,
+    forward: Vec<Place>,
+}
+
+impl NavHistory {
+    /// Remember where a jump started; a new jump forgets the places ahead.
+    pub fn push(&mut self, from: Place) {
+        self.back.push(from);
+        self.forward.clear();
+        if self.back.len() > HISTORY_LIMIT {
+            self.back.remove(0);
+        }
+    }
+
+    pub fn back(&mut self, current: Place) -> Option<Place> {
+        let to = self.back.pop()?;
+        self.forward.push(current);
+        Some(to)
+    }
+
+    pub fn forward(&mut self, current: Place) -> Option<Place> {
+        let to = self.forward.pop()?;
+        self.back.push(current);
+        Some(to)
+    }
+
+    pub fn can_go_back(&self) -> bool {
+        !self.back.is_empty()
+    }
+
+    pub fn can_go_forward(&self) -> bool {
+        !self.forward.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_layout_columns_and_spreads() {
+        let letter = vec2(612.0, 792.0);
+        let landscape = vec2(792.0, 612.0);
+        let sizes = vec![letter, landscape, letter, letter];
+
+        let column = PageLayout::new(&sizes, false);
+        assert_eq!(column.size, vec2(792.0 + 2.0 * PAGE_GAP, 5.0 * PAGE_GAP + 3.0 * 792.0 + 612.0));
+        assert_eq!(column.pages[0].min, pos2(PAGE_GAP + 90.0, PAGE_GAP));
+        assert_eq!(column.pages[1].min, pos2(PAGE_GAP, 2.0 * PAGE_GAP + 792.0));
+        assert_eq!(column.visible(Rect::from_min_max(pos2(0.0, 800.0), pos2(100.0, 1500.0))), vec![1]);
+        assert_eq!(column.visible(Rect::from_min_max(pos2(0.0, 0.0), pos2(900.0, 900.0))), vec![0, 1]);
+        assert_eq!(column.page_at(pos2(400.0, 100.0)), Some(0));
+        assert_eq!(column.page_at(pos2(1.0, 100.0)), None);
+        assert_eq!(column.nearest_page(pos2(1.0, 100.0)), Some(0));
+
+        // The cover alone, then pairs side by side
+        let spread = PageLayout::new(&sizes, true);
+        assert_eq!(spread.pages[1].top(), spread.pages[2].top());
+        assert_eq!(spread.pages[2].left(), spread.pages[1].right() + PAGE_GAP);
+        assert_eq!(spread.pages[3].top(), spread.pages[1].top() + 792.0 + PAGE_GAP);
+        assert_eq!(spread.size.x, 792.0 + 612.0 + 3.0 * PAGE_GAP);
+        assert_eq!(spread.row_rect(1), spread.pages[1].union(spread.pages[2]));
+        assert_eq!(spread.visible(Rect::from_min_max(pos2(900.0, 900.0), pos2(901.0, 901.0))), vec![2]);
+    }
+
+    #[test]
+    fn test_pdf_filter_cycle_and_params() {
+        let mut filter = PdfFilter::None;
+        let labels: Vec<_> = (0..PdfFilter::ALL.len()).map(|_| { filter = filter.next(); filter.label() }).collect();
+        assert_eq!(labels, vec!["Dark", "Sepia", "High Contrast", "Custom", "None"]);
+
+        // A custom filter keeps its colours while cycling, and maps ink to black and paper to white
+        let custom = PdfFilter::Custom { paper: [1.0, 0.9, 0.8], ink: [0.1, 0.2, 0.3] };
+        assert_eq!(custom.next(), PdfFilter::None);
+        assert_eq!(custom.shader_params(), (0, [0.1, 0.2, 0.3], [1.0, 0.9, 0.8]));
+        assert_eq!(PdfFilter::None.shader_params(), (0, [0.0; 3], [1.0; 3]));
+    }
+
+    #[test]
+    fn test_nav_history_back_and_forward() {
+        let place = |page: usize| (page, vec2(0.0, 10.0));
+        let mut history = NavHistory::default();
+        assert_eq!(history.back(place(0)), None);
+
+        // Follow links from page 0 to 4 to 9, then come back
+        history.push(place(0));
+        history.push(place(4));
+        assert_eq!(history.back(place(9)), Some(place(4)));
+        assert_eq!(history.back(place(4)), Some(place(0)));
+        assert!(!history.can_go_back());
+        assert_eq!(history.forward(place(0)), Some(place(4)));
+
+        // A new jump from page 4 drops page 9 from the way forward
+        history.push(place(4));
+        assert!(!history.can_go_forward());
+        assert_eq!(history.back(place(2)), Some(place(4)));
+    }
+}
@@ -22,7 +22,10 @@ impl FileWatcher {
                 match res {
                     Ok(event) => {
                         if event.kind.is_modify() {
-                            for path in event.paths {
+                            // Project state and build outputs under `.sokutex` are not source changes
+                            let sources = event.paths.into_iter()
+                                .filter(|path| !path.components().any(|c| c.as_os_str() == crate::io::STATE_DIR));
+                            for path in sources {
                                 if let Some(path_str) = path.to_str() {
                                     let _ = tx.blocking_send(FileEvent::Modified(path_str.to_string()));
                                 }